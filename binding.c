@@ -3,6 +3,9 @@
 
 #define BUF_SIZE 32768
 
+/* Keep in sync with ERROR_CODE_INVALID_WITNESS_ARGS in src/main.rs */
+#define CWHR_RUST_ERROR_INVALID_WITNESS_ARGS -73
+
 typedef int (*cwhr_rust_bytes_meta_accessor_f)(int present, uint32_t length,
                                                void *context);
 
@@ -31,11 +34,11 @@ int cwhr_rust_read_witness(size_t index, size_t source,
   cwhr_witness_args_reader_t reader;
   ret = cwhr_witness_args_reader_create(&reader, &cursor);
   if (ret != CKB_SUCCESS) {
-    return ret;
+    return CWHR_RUST_ERROR_INVALID_WITNESS_ARGS;
   }
   ret = cwhr_witness_args_reader_verify(&reader, 0);
   if (ret != CKB_SUCCESS) {
-    return ret;
+    return CWHR_RUST_ERROR_INVALID_WITNESS_ARGS;
   }
 
   if (cwhr_witness_args_reader_has_lock(&reader)) {
@@ -0,0 +1,180 @@
+//! Assembles the transaction upgrading a zero lock cell, printing it as JSON
+//! in the format of the CKB RPC. The transaction carries no signature and
+//! pays no fee, the operator adds inputs funding the fee before sending it.
+//!
+//! ```text
+//! zero-lock-tx --old OLD --new NEW --out-point TX_HASH:INDEX --cell CELL --tree TREE
+//! ```
+//!
+//! `OLD` and `NEW` are the files of the old and the new contract, `CELL` is
+//! the JSON of the cell output at `TX_HASH:INDEX` and `TREE` describes the
+//! committee's tree as JSON:
+//!
+//! ```text
+//! {
+//!   "header_dep": "0x...",
+//!   "cell_deps": [{ "out_point": { "tx_hash": "0x...", "index": "0x0" }, "dep_type": "code" }],
+//!   "leaves": ["0x...", "0x..."]
+//! }
+//! ```
+//!
+//! `header_dep` is the block whose extension publishes the root of the tree,
+//! `cell_deps` reference the code of zero lock and `leaves` are all upgrade
+//! leaves of the tree, in order. One of them must be the leaf of this
+//! upgrade. Leaves and the witness are built by `ckb-zero-lock-types`, so
+//! they follow the layout zero lock expects.
+
+use ckb_jsonrpc_types as json_types;
+use ckb_types::{
+    bytes::Bytes,
+    core::{cell::CellMetaBuilder, Capacity, TransactionBuilder},
+    packed::{Byte32, CellDep, CellInput, CellOutput, OutPoint},
+    prelude::*,
+    H256,
+};
+use ckb_zero_lock_types::{
+    compute_upgrade_leaf, serialize_witness, LeafTag, UpgradeProofBuilder,
+    LOCK_FLAG_PRESERVE_STRUCTURE, LOCK_FLAG_SALTED_ROOT, LOCK_FLAG_VERSIONED,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+};
+
+/// Lock flags leaving the leaf and the witness of a plain upgrade as they
+/// are. Other flags commit more than the cells, or move the proof.
+const SUPPORTED_LOCK_FLAGS: u8 =
+    LOCK_FLAG_PRESERVE_STRUCTURE | LOCK_FLAG_SALTED_ROOT | LOCK_FLAG_VERSIONED;
+
+#[derive(Deserialize)]
+struct Tree {
+    header_dep: H256,
+    cell_deps: Vec<json_types::CellDep>,
+    leaves: Vec<H256>,
+}
+
+struct Args {
+    old: PathBuf,
+    new: PathBuf,
+    out_point: OutPoint,
+    cell: PathBuf,
+    tree: PathBuf,
+}
+
+fn parse_out_point(value: &str) -> Result<OutPoint, String> {
+    let (tx_hash, index) = value
+        .split_once(':')
+        .ok_or_else(|| format!("Out point {} is not TX_HASH:INDEX", value))?;
+    let tx_hash = H256::from_str(tx_hash.trim_start_matches("0x"))
+        .map_err(|e| format!("Invalid transaction hash {}: {}", tx_hash, e))?;
+    let index: u32 = index
+        .parse()
+        .map_err(|e| format!("Invalid out point index {}: {}", index, e))?;
+    Ok(OutPoint::new(tx_hash.pack(), index))
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let (mut old, mut new, mut out_point, mut cell, mut tree) = (None, None, None, None, None);
+    while let Some(arg) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("Missing value of {}", arg))?;
+        match arg.as_str() {
+            "--old" => old = Some(PathBuf::from(value)),
+            "--new" => new = Some(PathBuf::from(value)),
+            "--out-point" => out_point = Some(parse_out_point(&value)?),
+            "--cell" => cell = Some(PathBuf::from(value)),
+            "--tree" => tree = Some(PathBuf::from(value)),
+            _ => return Err(format!("Unknown argument {}", arg)),
+        }
+    }
+    Ok(Args {
+        old: old.ok_or("Missing --old")?,
+        new: new.ok_or("Missing --new")?,
+        out_point: out_point.ok_or("Missing --out-point")?,
+        cell: cell.ok_or("Missing --cell")?,
+        tree: tree.ok_or("Missing --tree")?,
+    })
+}
+
+fn read(path: &Path) -> Result<Bytes, String> {
+    std::fs::read(path)
+        .map(Bytes::from)
+        .map_err(|e| format!("Cannot read {}: {}", path.display(), e))
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    serde_json::from_slice(&read(path)?)
+        .map_err(|e| format!("Cannot parse {}: {}", path.display(), e))
+}
+
+fn assemble(args: &Args) -> Result<json_types::Transaction, String> {
+    let old_contract = read(&args.old)?;
+    let new_contract = read(&args.new)?;
+    let cell: CellOutput = read_json::<json_types::CellOutput>(&args.cell)?.into();
+    let tree: Tree = read_json(&args.tree)?;
+
+    let flags = cell.lock().args().raw_data().first().copied().unwrap_or(0);
+    if flags & !SUPPORTED_LOCK_FLAGS != 0 {
+        return Err(format!("Lock flags {:#x} are not supported", flags));
+    }
+    if old_contract == new_contract {
+        return Err("New contract is the same as the old one".to_string());
+    }
+
+    // Zero lock never releases capacity, the output cell only gets more when
+    // the new contract does not fit
+    let input_capacity: Capacity = cell.capacity().unpack();
+    let occupied = Capacity::bytes(new_contract.len())
+        .and_then(|data_capacity| cell.occupied_capacity(data_capacity))
+        .map_err(|e| format!("Cannot compute occupied capacity: {}", e))?;
+    let capacity = input_capacity.as_u64().max(occupied.as_u64());
+    if flags & LOCK_FLAG_PRESERVE_STRUCTURE != 0
+        && (capacity != input_capacity.as_u64() || old_contract.len() != new_contract.len())
+    {
+        return Err("New contract does not preserve the structure of the cell".to_string());
+    }
+    let output = cell.clone().as_builder().capacity(capacity.pack()).build();
+
+    let old_cell = CellMetaBuilder::from_cell_output(cell, old_contract)
+        .out_point(args.out_point.clone())
+        .build();
+    let new_cell = CellMetaBuilder::from_cell_output(output.clone(), new_contract.clone()).build();
+    let leaf = compute_upgrade_leaf(&old_cell, &new_cell, None, None, None);
+
+    let leaves: Vec<Byte32> = tree.leaves.iter().map(|leaf| leaf.pack()).collect();
+    let selected = leaves
+        .iter()
+        .position(|tree_leaf| tree_leaf == &leaf)
+        .ok_or_else(|| format!("Upgrade leaf {:#x} is not in the tree", leaf))?;
+    let proof = UpgradeProofBuilder::default()
+        .leaves(leaves.into_iter().map(|leaf| (LeafTag::Upgrade, leaf)))
+        .build(0, &[selected as u32])
+        .ok_or("Cannot build the merkle proof")?;
+
+    let tx = TransactionBuilder::default()
+        .cell_deps(tree.cell_deps.into_iter().map(CellDep::from))
+        .header_dep(tree.header_dep.pack())
+        .input(CellInput::new(args.out_point.clone(), 0))
+        .output(output)
+        .output_data(new_contract.pack())
+        .witness(serialize_witness(proof, None, None).pack())
+        .build();
+    Ok(tx.data().into())
+}
+
+fn main() {
+    let result = parse_args(std::env::args().skip(1)).and_then(|args| assemble(&args));
+    match result {
+        Ok(tx) => println!(
+            "{}",
+            serde_json::to_string_pretty(&tx).expect("serialize transaction")
+        ),
+        Err(e) => {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+use ckb_hash::blake2b_256;
+use ckb_jsonrpc_types as json_types;
+use ckb_types::{
+    bytes::Bytes,
+    core::{
+        cell::{CellMeta, CellMetaBuilder, ResolvedTransaction},
+        Capacity, DepType,
+    },
+    packed::{self, Byte32, CellDep},
+    prelude::*,
+    H256,
+};
+use ckb_zero_lock_tests::{
+    build_verifier, hash_upgrade_data, header, insert_cell, random_type_id_script, script_cell,
+    zero_lock_cell, DummyDataLoader, ZERO_LOCK_BIN,
+};
+use ckb_zero_lock_types::{LeafTag, UpgradeProofBuilder};
+use serde_json::json;
+use std::{path::PathBuf, process::Command, sync::Arc};
+
+const MAX_CYCLES: u64 = 60_000_000;
+
+/// Writes the inputs of the CLI to a fresh directory, then runs it. The tree
+/// holds the leaf of `input_cell_meta` upgrading into `output_cell_meta`
+/// between leaves of other cells unless `in_tree` is unset.
+fn run_cli(
+    name: &str,
+    dummy: &mut DummyDataLoader,
+    input_cell_meta: &CellMeta,
+    output_cell_meta: &CellMeta,
+    in_tree: bool,
+) -> std::process::Output {
+    let dir: PathBuf = std::env::temp_dir().join(format!("zero-lock-tx-{}", name));
+    std::fs::create_dir_all(&dir).expect("create dir");
+
+    let mut leaves: Vec<Byte32> = (0u8..4).map(|i| Byte32::new(blake2b_256([i]))).collect();
+    if in_tree {
+        leaves.insert(
+            2,
+            hash_upgrade_data(input_cell_meta, output_cell_meta, None, None),
+        );
+    }
+    let root = UpgradeProofBuilder::default()
+        .leaves(leaves.iter().map(|leaf| (LeafTag::Upgrade, leaf.clone())))
+        .root();
+    let header_dep = header(dummy, &root);
+    let zero_lock_cell_meta = script_cell(dummy, &ZERO_LOCK_BIN);
+    let cell_dep = CellDep::new_builder()
+        .out_point(zero_lock_cell_meta.out_point.clone())
+        .dep_type(DepType::Code.into())
+        .build();
+
+    let tree = json!({
+        "header_dep": Unpack::<H256>::unpack(&header_dep),
+        "cell_deps": [json_types::CellDep::from(cell_dep)],
+        "leaves": leaves.iter().map(Unpack::<H256>::unpack).collect::<Vec<_>>(),
+    });
+    let cell = json_types::CellOutput::from(input_cell_meta.cell_output.clone());
+    std::fs::write(
+        dir.join("old.bin"),
+        input_cell_meta.mem_cell_data.as_ref().unwrap(),
+    )
+    .expect("write old contract");
+    std::fs::write(
+        dir.join("new.bin"),
+        output_cell_meta.mem_cell_data.as_ref().unwrap(),
+    )
+    .expect("write new contract");
+    std::fs::write(dir.join("cell.json"), serde_json::to_vec(&cell).unwrap()).expect("write cell");
+    std::fs::write(dir.join("tree.json"), serde_json::to_vec(&tree).unwrap()).expect("write tree");
+
+    let out_point = &input_cell_meta.out_point;
+    let index: u32 = out_point.index().unpack();
+    Command::new(env!("CARGO_BIN_EXE_zero-lock-tx"))
+        .arg("--old")
+        .arg(dir.join("old.bin"))
+        .arg("--new")
+        .arg(dir.join("new.bin"))
+        .arg("--out-point")
+        .arg(format!("{:#x}:{}", out_point.tx_hash(), index))
+        .arg("--cell")
+        .arg(dir.join("cell.json"))
+        .arg("--tree")
+        .arg(dir.join("tree.json"))
+        .output()
+        .expect("run zero-lock-tx")
+}
+
+/// A zero lock cell with the capacity to hold a new contract of the same
+/// size, so the CLI keeps its capacity.
+fn plan(dummy: &mut DummyDataLoader) -> (CellMeta, CellMeta) {
+    let type_id = random_type_id_script();
+    let old_contract: Bytes = vec![1u8; 100].into();
+    let cell_meta = zero_lock_cell(dummy, &old_contract, Some(type_id));
+    let cell_output = cell_meta
+        .cell_output
+        .as_builder()
+        .capacity(Capacity::bytes(1000).unwrap().pack())
+        .build();
+    let input_cell_meta = CellMetaBuilder::from_cell_output(cell_output.clone(), old_contract)
+        .out_point(cell_meta.out_point)
+        .build();
+    insert_cell(dummy, &input_cell_meta);
+    let output_cell_meta =
+        CellMetaBuilder::from_cell_output(cell_output, vec![2u8; 100].into()).build();
+    (input_cell_meta, output_cell_meta)
+}
+
+#[test]
+fn test_cli_transaction_verifies() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let (input_cell_meta, output_cell_meta) = plan(&mut dummy_loader);
+    let output = run_cli(
+        "verifies",
+        &mut dummy_loader,
+        &input_cell_meta,
+        &output_cell_meta,
+        true,
+    );
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    let tx: json_types::Transaction = serde_json::from_slice(&output.stdout).expect("parse tx");
+    let tx = packed::Transaction::from(tx).into_view();
+    let resolved_cell_deps = tx
+        .cell_deps_iter()
+        .map(|cell_dep| {
+            let (cell_output, data) = dummy_loader.cells[&cell_dep.out_point()].clone();
+            CellMetaBuilder::from_cell_output(cell_output, data)
+                .out_point(cell_dep.out_point())
+                .build()
+        })
+        .collect();
+    let rtx = Arc::new(ResolvedTransaction {
+        transaction: tx,
+        resolved_cell_deps,
+        resolved_inputs: vec![input_cell_meta],
+        resolved_dep_groups: vec![],
+    });
+
+    let verifier = build_verifier(rtx, dummy_loader);
+    verifier.verify(MAX_CYCLES).expect("pass verification");
+}
+
+#[test]
+fn test_cli_rejects_leaf_missing_from_tree() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let (input_cell_meta, output_cell_meta) = plan(&mut dummy_loader);
+    let output = run_cli(
+        "missing-leaf",
+        &mut dummy_loader,
+        &input_cell_meta,
+        &output_cell_meta,
+        false,
+    );
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("is not in the tree"));
+}
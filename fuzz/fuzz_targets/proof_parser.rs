@@ -0,0 +1,40 @@
+//! Feeds arbitrary witnesses into the proof parser of the reference
+//! verifier, which mirrors the contract's streaming proof reader. Run with:
+//!
+//! ```text
+//! cd tests; cargo run --example fuzz_corpus
+//! cd ../fuzz; cargo +nightly fuzz run proof_parser corpus/proof_parser
+//! ```
+#![no_main]
+use ckb_types::bytes::Bytes;
+use ckb_zero_lock_types::{
+    verifier::{parse_proof, Error},
+    LOCK_EXT_FLAG_PROOF_FORMAT,
+};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // The first byte picks the extended lock flags, so root indices and
+    // proofs in input_type are covered as well
+    let Some((ext_flags, witness)) = data.split_first() else {
+        return;
+    };
+    match parse_proof(Bytes::copy_from_slice(witness), *ext_flags) {
+        Ok(proof) => {
+            // Every parsed lemma and index was actually in the witness. Under
+            // PROOF_FORMAT_VARINT either may take a single byte, a lemma
+            // sharing all of its bytes with the previous one included
+            let (index_width, lemma_width) = if ext_flags & LOCK_EXT_FLAG_PROOF_FORMAT != 0 {
+                (1, 1)
+            } else {
+                (4, 32)
+            };
+            let parsed = proof.indices.len() * index_width + proof.lemmas.len() * lemma_width;
+            assert!(parsed <= witness.len());
+        }
+        Err(
+            Error::InvalidWitness | Error::InvalidProof | Error::MissingProof | Error::TreeTooDeep,
+        ) => (),
+        Err(e) => panic!("Parser failed with a non-parsing error: {:?}", e),
+    }
+});
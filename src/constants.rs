@@ -0,0 +1,267 @@
+//! Constants of the layouts zero lock reads: lock args flags, leaf tags,
+//! extension fields and proof formats. `ckb-zero-lock-types` builds this file
+//! as well, so off-chain code lays data out by the very values zero lock
+//! checks it against. It has no dependencies and builds without std.
+
+/// Maximum depth of the merkle tree a proof can be verified against, this
+/// supports up to 2^31 leaves, the most whose CBMT node indices fit into u32.
+/// Provers must not build trees deeper than this. A proof declaring more
+/// lemmas than its leaves need at this depth fails with `TreeTooDeep` before
+/// any lemma is read, which bounds the cycles a malicious witness can burn.
+pub const MAX_TREE_DEPTH: usize = 31;
+
+/// Lock args flag: the upgraded cell must keep the exact capacity and data
+/// length of the input cell, only the content of the data can change.
+pub const LOCK_FLAG_PRESERVE_STRUCTURE: u8 = 0x01;
+
+/// Lock args flag: a 32-byte salt follows the flags byte, the extension slot
+/// then holds `blake2b(merkle_root || salt)` instead of the merkle root.
+pub const LOCK_FLAG_SALTED_ROOT: u8 = 0x02;
+
+/// Lock args flag: a little-endian u32 offset follows the flags byte (and the
+/// salt if any), the upgraded cell must carry a strictly greater u32 version
+/// at that offset in its data than the input cell.
+pub const LOCK_FLAG_VERSIONED: u8 = 0x04;
+
+/// Lock args flag: the leaf commits the since value of the input cell, so an
+/// upgrade can only be executed once consensus accepts that since value, e.g.
+/// after an absolute activation block number.
+pub const LOCK_FLAG_ACTIVATION: u8 = 0x08;
+
+/// Lock args flag: the leaf commits a patch carried in witness output_type
+/// instead of the output cell's data, the output cell's data must be the
+/// result of applying the patch to the input cell's data.
+pub const LOCK_FLAG_PATCH: u8 = 0x10;
+
+/// Lock args flag: a little-endian u32 follows the flags byte (and the salt
+/// and version offset if any), the upgrade can only be executed once the
+/// designated header has at least that many confirmations.
+pub const LOCK_FLAG_MIN_CONFIRMATIONS: u8 = 0x20;
+
+/// Lock args flag: the cell is governed by the sparse merkle tree root in
+/// extension instead of a merkle root slot, keyed by the hash of its data.
+/// Witness lock carries a sparse merkle proof, and only a single input cell
+/// can be proven per transaction.
+pub const LOCK_FLAG_SMT: u8 = 0x40;
+
+/// Lock args flag: an extended flags byte follows the flags byte, holding the
+/// `LOCK_EXT_FLAG_*` flags. Every bit of the flags byte is assigned, new flags
+/// go to the extended flags byte.
+pub const LOCK_FLAG_EXTENDED: u8 = 0x80;
+
+/// Extended lock args flag: witness lock of the first input cell starts with
+/// the index of the merkle root slot the proof is verified against as
+/// little-endian u32, instead of the committee id picking the slot. Every
+/// committee with a root in extension can then upgrade the cell. It has no
+/// effect on cells locked with `LOCK_FLAG_SMT`.
+pub const LOCK_EXT_FLAG_ROOT_INDEX: u8 = 0x01;
+
+/// Extended lock args flag: the merkle proof is carried in witness input_type
+/// instead of witness lock, which is left to other scripts sharing the
+/// witness and is not committed in the leaf. Witness input_type is committed
+/// as absent then. It cannot be combined with `LOCK_FLAG_SMT`.
+pub const LOCK_EXT_FLAG_INPUT_TYPE_PROOF: u8 = 0x02;
+
+/// Extended lock args flag: output cell data is the 32-byte data hash of the
+/// new code, which lives in a cell dep instead of being duplicated inline.
+/// The leaf commits the referenced hash in place of the output data hash, and
+/// a cell dep of the transaction must carry data hashing to it. Each output
+/// cell is tied to the code on its own, so output cells of a batch upgrading
+/// to the same code share a single cell dep. It cannot be combined with
+/// `LOCK_FLAG_PATCH`.
+pub const LOCK_EXT_FLAG_CODE_DEP: u8 = 0x04;
+
+/// Extended lock args flag: the type script hash of a governance cell follows
+/// min confirmations in lock args. Merkle roots are read from the data of a
+/// cell dep carrying that type script instead of a header extension, so the
+/// root can be updated without mining a block. The header index in witness
+/// is the index of that cell dep then, its data has the same layout as an
+/// extension. It cannot be combined with `LOCK_FLAG_MIN_CONFIRMATIONS`, nor
+/// with min epochs in cell data, which both need a header. A governance cell
+/// locked by zero lock under its own type script hash rotates its roots with
+/// a `LeafTag::Rotation` leaf, proven against the roots it holds.
+pub const LOCK_EXT_FLAG_GOVERNANCE_CELL: u8 = 0x08;
+
+/// Extended lock args flag: upgrades may keep the data of the input cell,
+/// for changes to nothing but capacity or the rest of the cell. Without it
+/// such an upgrade accomplishes nothing and is rejected as a likely mistake.
+pub const LOCK_EXT_FLAG_ALLOW_SAME_DATA: u8 = 0x10;
+
+/// Extended lock args flag: witness input_type of the first input cell
+/// carries co-proofs of the leaves against the roots of other committees,
+/// which count towards the threshold in extension. Witness input_type is
+/// committed as absent then. It cannot be combined with
+/// `LOCK_EXT_FLAG_INPUT_TYPE_PROOF`, split lemmas, nor with `LOCK_FLAG_SMT`.
+pub const LOCK_EXT_FLAG_THRESHOLD_PROOFS: u8 = 0x20;
+
+/// Extended lock args flag: the merkle proof starts with a format byte,
+/// `PROOF_FORMAT_FIXED` for the layout used without the flag, or
+/// `PROOF_FORMAT_VARINT` for the compact layout of varint counts and
+/// delta-encoded lemmas. Co-proofs keep their layout. It cannot be combined
+/// with `LOCK_FLAG_SMT`.
+pub const LOCK_EXT_FLAG_PROOF_FORMAT: u8 = 0x40;
+
+pub(crate) const KNOWN_LOCK_EXT_FLAGS: u8 = LOCK_EXT_FLAG_ROOT_INDEX
+    | LOCK_EXT_FLAG_INPUT_TYPE_PROOF
+    | LOCK_EXT_FLAG_CODE_DEP
+    | LOCK_EXT_FLAG_GOVERNANCE_CELL
+    | LOCK_EXT_FLAG_ALLOW_SAME_DATA
+    | LOCK_EXT_FLAG_THRESHOLD_PROOFS
+    | LOCK_EXT_FLAG_PROOF_FORMAT;
+
+/// Flag bits of a since value, all unset for an absolute block number.
+pub(crate) const SINCE_FLAGS_MASK: u64 = 0xff00_0000_0000_0000;
+/// Flag bits of a since value for an absolute epoch.
+pub(crate) const SINCE_FLAGS_ABSOLUTE_EPOCH: u64 = 0x2000_0000_0000_0000;
+/// Epoch number bits of an epoch with fraction, in headers and since values.
+pub(crate) const EPOCH_NUMBER_MASK: u64 = 0xff_ffff;
+
+/// Patch operation: copy a range of the input cell's data, followed by offset
+/// and length as little-endian u32.
+pub const PATCH_OP_COPY: u8 = 0x00;
+/// Patch operation: insert new bytes, followed by length as little-endian u32
+/// and the bytes to insert.
+pub const PATCH_OP_INSERT: u8 = 0x01;
+
+/// Data of a frozen cell starts with these bytes. A frozen cell can still be
+/// moved to a new out point by an upgrade leaf keeping its data, but its data
+/// never changes and it is never handed to another lock. It can still be
+/// deleted.
+pub const FROZEN_MAGIC: &[u8; 4] = b"ZLF\x01";
+
+/// Set on the tag of a leaf with an output cell to commit the index of that
+/// output cell as well. Without it a leaf is matched to its output cell by
+/// the order of output cells using zero lock alone, which leaves output cells
+/// of other locks free to be moved around it. Deletion leaves have no output
+/// cell and never set it.
+pub const LEAF_TAG_FLAG_OUTPUT_INDEX: u8 = 0x80;
+
+/// Set on the tag of an upgrade leaf to let the output cell carry another
+/// type script than the input cell. The leaf commits the type script of the
+/// output cell as part of its CellOutput structure, the flag only lifts the
+/// check keeping the type script through the upgrade. Other leaves never set
+/// it.
+pub const LEAF_TAG_FLAG_RETYPE: u8 = 0x40;
+
+/// Tag starting the preimage of every leaf, picking the rules a leaf is
+/// validated by. Tag 3 is reserved for creation, which zero lock cannot
+/// verify: lock scripts only run for input cells, a transaction creating zero
+/// lock cells without consuming any never runs zero lock. Consumers must not
+/// take a zero lock cell as minted by the committee for its lock alone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LeafTag {
+    /// The input cell is replaced by an output cell using zero lock
+    Upgrade = 1,
+    /// The input cell is consumed without any successor
+    Deletion = 2,
+    /// The input cell is replaced by an output cell using another lock,
+    /// handing the cell over from zero lock
+    Migration = 4,
+    /// The governance cell is replaced by an output cell using zero lock,
+    /// proven against the roots in its own data. This hands control of
+    /// future upgrades to the roots in the data of the output cell
+    Rotation = 5,
+    /// The input cell is replaced by an output cell using zero lock, whose
+    /// data starts with `FROZEN_MAGIC`. This is the last leaf changing the
+    /// data of the cell
+    Freeze = 6,
+    /// The input cell is replaced by an output cell using zero lock, changing
+    /// nothing but the data after its code. Witness output_type carries the
+    /// length of the code as little-endian u32, the first that many bytes of
+    /// data are the same in both cells
+    Reconfiguration = 7,
+    /// The input cell is replaced by an output cell using zero lock with
+    /// less capacity. Witness output_type carries the most capacity released
+    /// as little-endian u64, followed by the lock hash of the output cells
+    /// receiving the released capacity
+    Release = 8,
+}
+
+impl LeafTag {
+    pub fn from_u8(tag: u8) -> Option<Self> {
+        match tag {
+            1 => Some(LeafTag::Upgrade),
+            2 => Some(LeafTag::Deletion),
+            4 => Some(LeafTag::Migration),
+            5 => Some(LeafTag::Rotation),
+            6 => Some(LeafTag::Freeze),
+            7 => Some(LeafTag::Reconfiguration),
+            8 => Some(LeafTag::Release),
+            _ => None,
+        }
+    }
+}
+
+/// Script hash types referencing code by data hash, each in a different VM
+/// version.
+pub(crate) const SCRIPT_HASH_TYPE_DATA: u8 = 0;
+pub(crate) const SCRIPT_HASH_TYPE_DATA1: u8 = 2;
+pub(crate) const SCRIPT_HASH_TYPE_DATA2: u8 = 4;
+/// Script hash type referencing code by the type hash of a cell dep.
+pub(crate) const SCRIPT_HASH_TYPE_TYPE: u8 = 1;
+
+/// Structured extensions start with these bytes, followed by fields encoded
+/// as a tag byte, the value length as little-endian u32 and the value. Fields
+/// with unknown tags carry data committed for other consumers of the
+/// extension and are skipped.
+pub const EXTENSION_MAGIC: &[u8; 4] = b"ZLX\x01";
+
+/// Field holding a single byte, the committee sets it to a non-zero value to
+/// pause all upgrades proven against that governance cell. Header extensions
+/// must not carry it: scripts cannot see the tip, so an earlier header
+/// holding the same root with the kill switch clear would still prove them.
+pub const EXTENSION_FIELD_KILL_SWITCH: u8 = 0x01;
+/// Field holding merkle root slots of 32 bytes each.
+pub const EXTENSION_FIELD_ROOTS: u8 = 0x02;
+/// Field holding the minimum number of epochs between the header and the
+/// execution of upgrades proven against it, as little-endian u32.
+pub const EXTENSION_FIELD_MIN_EPOCHS: u8 = 0x03;
+/// Field holding the 32-byte sparse merkle tree root governing cells locked
+/// with `LOCK_FLAG_SMT`.
+pub const EXTENSION_FIELD_SMT_ROOT: u8 = 0x04;
+/// Field holding a single byte picking the hash merging merkle tree nodes,
+/// one of the `MERGE_HASH_*` values. Trees are merged by blake2b without it.
+pub const EXTENSION_FIELD_MERGE_HASH: u8 = 0x05;
+/// Field holding a single non-zero byte, the number of distinct merkle roots
+/// that must each prove the leaves of an upgrade. One root suffices without
+/// it.
+pub const EXTENSION_FIELD_THRESHOLD: u8 = 0x06;
+/// Field holding merkle roots tagged for deployments, each a 32-byte
+/// deployment id followed by the 32-byte root. A cell whose committee id
+/// hashes to one of the deployment ids is governed by the root tagged for it,
+/// so independent deployments share a block without sharing slots.
+pub const EXTENSION_FIELD_TAGGED_ROOTS: u8 = 0x07;
+/// Field holding the lock code hashes migrated cells may be handed to, as a
+/// little-endian u32 count followed by that many 32-byte code hashes.
+/// Migrations are not restricted without it.
+pub const EXTENSION_FIELD_SUCCESSOR_CODE_HASHES: u8 = 0x08;
+
+/// Merkle tree nodes are merged by blake2b, see `Blake2bHash`.
+pub const MERGE_HASH_BLAKE2B: u8 = 0x00;
+/// Merkle tree nodes are merged by SHA-256, see `Sha256Hash`.
+pub const MERGE_HASH_SHA256: u8 = 0x01;
+
+/// Merkle root slot offset of the legacy extension layout.
+pub const EXTENSION_ROOT_OFFSET: usize = 128;
+
+/// Legacy extensions are at least this long, holding a single merkle root
+/// slot at `EXTENSION_ROOT_OFFSET` among arbitrary data. Deployed headers
+/// carry 180 bytes, the data after the slot is never read.
+pub const LEGACY_EXTENSION_MIN_LENGTH: usize = EXTENSION_ROOT_OFFSET + 32;
+
+/// When used as lemma count in witness lock, lemmas are carried by witness
+/// input_type instead, starting with the real lemma count.
+pub const SPLIT_LEMMAS: u32 = u32::MAX;
+
+/// Format byte starting the proof under `LOCK_EXT_FLAG_PROOF_FORMAT`: the
+/// rest of the proof has the same layout as without the flag.
+pub const PROOF_FORMAT_FIXED: u8 = 0x00;
+/// Format byte: root index, header index, counts and indices are LEB128
+/// varints, and each lemma is delta-encoded against the previous one.
+pub const PROOF_FORMAT_VARINT: u8 = 0x01;
+
+/// Union ids of `WitnessLayout`, the successor of WitnessArgs in CKB
+/// transaction cobuild, whose seal can carry the proof.
+pub(crate) const WITNESS_LAYOUT_SIGHASH_ALL: u32 = 0xff00_0001;
+pub(crate) const WITNESS_LAYOUT_SIGHASH_ALL_ONLY: u32 = 0xff00_0002;
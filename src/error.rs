@@ -0,0 +1,126 @@
+/// Reasons zero lock rejects a transaction, each variant is the exit code of
+/// the script. Codes are stable, tooling can tell failures apart by them, so a
+/// new reason gets a new code instead of reusing a retired one.
+/// `ckb-zero-lock-types` builds this file as well, for its reference
+/// verifier.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(i8)]
+pub enum Error {
+    /// More upgraded input cells than output cells use zero lock
+    MissingOutput = 1,
+    /// More output cells than upgraded input cells use zero lock
+    ExtraOutput = 2,
+    /// Lock hash of an output cell cannot be loaded
+    LoadOutputLockHash = 3,
+    /// Input cells are upgraded, but no output cell uses zero lock
+    NoOutput = 4,
+    /// Extension is malformed, or does not have the root governing the cell
+    InvalidExtension = 5,
+    /// Designated header dep is missing or does not have an extension
+    MissingExtension = 6,
+    /// Output cell cannot be loaded for hashing into the leaf
+    LoadOutput = 7,
+    /// Recomputed root differs from the root in extension
+    RootMismatch = 8,
+    /// Precomputed leaf hash in witness differs from the recomputed leaf
+    LeafHashMismatch = 9,
+    /// Witness cannot be read, or its proof cannot be parsed
+    InvalidWitness = 10,
+    /// Witness does not carry a complete proof where one is expected
+    InvalidProof = 11,
+    // 12 is retired: it rejected an output cell reusing the out point of its
+    // input cell, which the transaction hash committing to all inputs rules
+    // out already
+    /// Lock args set extended flags zero lock does not know
+    UnknownLockFlags = 13,
+    /// Output cell changes the capacity or data length under
+    /// `LOCK_FLAG_PRESERVE_STRUCTURE`
+    StructureNotPreserved = 14,
+    /// Root in extension is all zero
+    EmptyRoot = 15,
+    /// Lock args are too short for the fields their flags declare
+    InvalidLockArgs = 16,
+    /// Cell data is too short for the version under `LOCK_FLAG_VERSIONED`
+    MissingVersion = 17,
+    /// Output cell version is not greater than the input cell version
+    VersionNotIncreased = 18,
+    /// Kill switch in the governance cell dep is set. A cell dep must be
+    /// live, so unlike a header it has no older version to fall back on
+    Paused = 19,
+    /// Input since is zero under `LOCK_FLAG_ACTIVATION`
+    MissingActivation = 20,
+    /// Witness output_type does not have a patch under `LOCK_FLAG_PATCH`
+    MissingPatch = 21,
+    /// Patch in witness output_type is malformed or out of bounds
+    InvalidPatch = 22,
+    /// Output cell data is not the result of applying the patch
+    PatchMismatch = 23,
+    /// Input since is below the header number plus min confirmations
+    NotEnoughConfirmations = 24,
+    /// Input since is not an absolute block number under
+    /// `LOCK_FLAG_MIN_CONFIRMATIONS`
+    SinceNotBlockNumber = 25,
+    /// Number of proven leaves differs from the number of input cells
+    LeafCountMismatch = 26,
+    /// Leaf tag is unknown, or not allowed for the cell or its flags
+    UnknownLeafTag = 27,
+    /// Output cell has less capacity than its input cell
+    CapacityLowered = 28,
+    /// Output cell does not keep the type script of its input cell
+    TypeScriptChanged = 29,
+    /// Input since is not an absolute epoch while extension sets min epochs
+    SinceNotEpoch = 30,
+    /// Input since is below the header epoch plus min epochs in extension
+    NotEnoughEpochs = 31,
+    /// Migrated input cell has no type script, or no output cell outside
+    /// zero lock keeps it
+    MissingMigrationOutput = 32,
+    /// Output cell data is not a 32-byte code hash, or no cell dep carries
+    /// the code under `LOCK_EXT_FLAG_CODE_DEP`
+    MissingCodeDep = 33,
+    /// Witness of the first input cell is missing, or the field carrying the
+    /// proof is absent or empty
+    MissingProof = 34,
+    /// Designated cell dep is missing or does not carry the governance type
+    /// script under `LOCK_EXT_FLAG_GOVERNANCE_CELL`
+    GovernanceCellMismatch = 35,
+    /// Upgraded output cell keeps the data of its input cell without
+    /// `LOCK_EXT_FLAG_ALLOW_SAME_DATA`
+    DataUnchanged = 36,
+    /// Fewer distinct roots prove the leaves than the threshold in extension
+    ThresholdNotMet = 37,
+    /// A co-proof is verified against a root already proving the leaves
+    DuplicateRoot = 38,
+    /// Rotation leaf proves anything but a governance cell under
+    /// `LOCK_EXT_FLAG_GOVERNANCE_CELL` carrying its type script, or is
+    /// proven along with other input cells
+    InvalidRotation = 39,
+    // Syscall failures not covered by any of the above
+    IndexOutOfBound = 40,
+    ItemMissing = 41,
+    LengthNotEnough = 42,
+    Encoding = 43,
+    UnknownSysError = 44,
+    /// Input cell data starts with `FROZEN_MAGIC`, but the leaf changes its
+    /// data or hands it to another lock
+    Frozen = 45,
+    /// Output cell data of a freeze leaf does not start with `FROZEN_MAGIC`
+    InvalidFreeze = 46,
+    /// Output cell of a reconfiguration leaf changes the data before the code
+    /// length in witness output_type, or either cell data is shorter than it
+    CodeChanged = 47,
+    /// Output cells locked by the lock hash a release leaf commits receive
+    /// less capacity than released to them
+    ReleaseNotReceived = 48,
+    /// Merkle proof declares more lemmas than its leaves need in a tree of
+    /// `MAX_TREE_DEPTH`
+    TreeTooDeep = 49,
+    /// Leaves of two input cells in the script group are identical
+    DuplicateLeaf = 50,
+    /// Proofs of a script group with several proofs or co-proofs name the
+    /// same header dep twice, or one the transaction does not have
+    ProofHeaderCountMismatch = 51,
+    /// Output cell of a migration uses a lock code hash the extension does
+    /// not list in `EXTENSION_FIELD_SUCCESSOR_CODE_HASHES`
+    SuccessorNotWhitelisted = 52,
+}
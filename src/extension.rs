@@ -0,0 +1,196 @@
+//! Parsing of header extensions, and of governance cell data laid out like
+//! them. `ckb-zero-lock-types` builds this file as well, loading extensions
+//! is left to `loader` in zero lock.
+
+use super::{
+    deployment_id, proof_reader::read_u32, slot_for, EXTENSION_FIELD_KILL_SWITCH,
+    EXTENSION_FIELD_MERGE_HASH, EXTENSION_FIELD_MIN_EPOCHS, EXTENSION_FIELD_ROOTS,
+    EXTENSION_FIELD_SMT_ROOT, EXTENSION_FIELD_SUCCESSOR_CODE_HASHES, EXTENSION_FIELD_TAGGED_ROOTS,
+    EXTENSION_FIELD_THRESHOLD, EXTENSION_MAGIC, EXTENSION_ROOT_OFFSET, LEGACY_EXTENSION_MIN_LENGTH,
+    MERGE_HASH_BLAKE2B, MERGE_HASH_SHA256,
+};
+use alloc::vec::Vec;
+
+/// Fields zero lock reads from a header extension.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Extension {
+    /// Kill switch field, `None` when absent
+    pub kill_switch: Option<bool>,
+    pub min_epochs: u64,
+    pub smt_root: Option<[u8; 32]>,
+    /// One of the `MERGE_HASH_*` values
+    pub merge_hash: u8,
+    /// Number of distinct roots that must prove the leaves
+    pub threshold: u8,
+    /// Lock code hashes the output cells of migrations must use, any lock
+    /// when `None`
+    pub successor_code_hashes: Option<Vec<[u8; 32]>>,
+    roots: Vec<[u8; 32]>,
+    /// Deployment ids and the roots tagged for them
+    tagged_roots: Vec<([u8; 32], [u8; 32])>,
+}
+
+impl Extension {
+    /// Parses an extension, or data laid out like one, `None` when it is
+    /// neither structured nor long enough for the legacy layout.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        match data.strip_prefix(&EXTENSION_MAGIC[..]) {
+            Some(fields) => Self::parse_fields(fields),
+            None if data.len() >= LEGACY_EXTENSION_MIN_LENGTH => {
+                let mut root = [0u8; 32];
+                root.copy_from_slice(&data[EXTENSION_ROOT_OFFSET..LEGACY_EXTENSION_MIN_LENGTH]);
+                Some(Self {
+                    kill_switch: None,
+                    min_epochs: 0,
+                    smt_root: None,
+                    merge_hash: MERGE_HASH_BLAKE2B,
+                    threshold: 1,
+                    successor_code_hashes: None,
+                    roots: alloc::vec![root],
+                    tagged_roots: Vec::new(),
+                })
+            }
+            None => None,
+        }
+    }
+
+    fn parse_fields(mut rest: &[u8]) -> Option<Self> {
+        let mut kill_switch = None;
+        let mut min_epochs = None;
+        let mut smt_root = None;
+        let mut merge_hash = None;
+        let mut threshold = None;
+        let mut successor_code_hashes: Option<Vec<[u8; 32]>> = None;
+        let mut roots = None;
+        let mut tagged_roots: Option<Vec<([u8; 32], [u8; 32])>> = None;
+        while let Some((tag, r)) = rest.split_first() {
+            let (length, r) = read_u32(r)?;
+            if r.len() < length {
+                return None;
+            }
+            let (value, r) = r.split_at(length);
+            rest = r;
+            match *tag {
+                EXTENSION_FIELD_KILL_SWITCH => {
+                    if value.len() != 1 || kill_switch.replace(value[0] != 0).is_some() {
+                        return None;
+                    }
+                }
+                EXTENSION_FIELD_MIN_EPOCHS => {
+                    let (n, r) = read_u32(value)?;
+                    if !r.is_empty() || min_epochs.replace(n as u64).is_some() {
+                        return None;
+                    }
+                }
+                EXTENSION_FIELD_SMT_ROOT => {
+                    if value.len() != 32 || smt_root.is_some() {
+                        return None;
+                    }
+                    let mut root = [0u8; 32];
+                    root.copy_from_slice(value);
+                    smt_root = Some(root);
+                }
+                EXTENSION_FIELD_MERGE_HASH => {
+                    if value.len() != 1
+                        || !matches!(value[0], MERGE_HASH_BLAKE2B | MERGE_HASH_SHA256)
+                        || merge_hash.replace(value[0]).is_some()
+                    {
+                        return None;
+                    }
+                }
+                EXTENSION_FIELD_THRESHOLD => {
+                    if value.len() != 1 || value[0] == 0 || threshold.replace(value[0]).is_some() {
+                        return None;
+                    }
+                }
+                EXTENSION_FIELD_ROOTS => {
+                    if value.is_empty() || value.len() % 32 != 0 || roots.is_some() {
+                        return None;
+                    }
+                    roots = Some(
+                        value
+                            .chunks(32)
+                            .map(|chunk| {
+                                let mut root = [0u8; 32];
+                                root.copy_from_slice(chunk);
+                                root
+                            })
+                            .collect(),
+                    );
+                }
+                EXTENSION_FIELD_TAGGED_ROOTS => {
+                    if value.is_empty() || value.len() % 64 != 0 || tagged_roots.is_some() {
+                        return None;
+                    }
+                    let mut entries = Vec::with_capacity(value.len() / 64);
+                    for entry in value.chunks(64) {
+                        let mut id = [0u8; 32];
+                        id.copy_from_slice(&entry[..32]);
+                        let mut root = [0u8; 32];
+                        root.copy_from_slice(&entry[32..]);
+                        // A deployment tagged twice would have two roots
+                        if entries.iter().any(|(other, _)| *other == id) {
+                            return None;
+                        }
+                        entries.push((id, root));
+                    }
+                    tagged_roots = Some(entries);
+                }
+                EXTENSION_FIELD_SUCCESSOR_CODE_HASHES => {
+                    let (count, hashes) = read_u32(value)?;
+                    if hashes.len() != count.checked_mul(32)? || successor_code_hashes.is_some() {
+                        return None;
+                    }
+                    successor_code_hashes = Some(
+                        hashes
+                            .chunks(32)
+                            .map(|chunk| {
+                                let mut code_hash = [0u8; 32];
+                                code_hash.copy_from_slice(chunk);
+                                code_hash
+                            })
+                            .collect(),
+                    );
+                }
+                _ => (),
+            }
+        }
+        // At least one kind of root must be present
+        if roots.is_none() && smt_root.is_none() && tagged_roots.is_none() {
+            return None;
+        }
+        Some(Self {
+            kill_switch,
+            min_epochs: min_epochs.unwrap_or(0),
+            smt_root,
+            merge_hash: merge_hash.unwrap_or(MERGE_HASH_BLAKE2B),
+            threshold: threshold.unwrap_or(1),
+            successor_code_hashes,
+            roots: roots.unwrap_or_default(),
+            tagged_roots: tagged_roots.unwrap_or_default(),
+        })
+    }
+
+    /// A root tagged for the deployment of the committee id governs the cell
+    /// first. Otherwise zero lock cells without a committee id use the first
+    /// slot, and the committee id picks one out of all slots for the others.
+    /// `None` when neither a tagged root nor a merkle root slot applies.
+    pub fn root_for(&self, committee_id: &[u8]) -> Option<[u8; 32]> {
+        let id = deployment_id(committee_id);
+        if let Some((_, root)) = self.tagged_roots.iter().find(|(other, _)| *other == id) {
+            Some(*root)
+        } else if self.roots.is_empty() {
+            None
+        } else if committee_id.is_empty() {
+            Some(self.roots[0])
+        } else {
+            Some(self.roots[slot_for(committee_id, self.roots.len())])
+        }
+    }
+
+    /// Merkle root in the slot at `index`, for cells locked with
+    /// `LOCK_EXT_FLAG_ROOT_INDEX`. `None` when there is no such slot.
+    pub fn root_at(&self, index: usize) -> Option<[u8; 32]> {
+        self.roots.get(index).copied()
+    }
+}
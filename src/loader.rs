@@ -0,0 +1,121 @@
+//! Loading of the structures parsed by the modules shared with
+//! `ckb-zero-lock-types` through syscalls, which only zero lock can make.
+
+use super::{error::Error, extension::Extension, smt::SmtProof, threshold::CoProof};
+use alloc::vec::Vec;
+use ckb_std::{ckb_constants::Source, debug, error::SysError, high_level, syscalls};
+
+impl Extension {
+    /// Loads the extension of the header dep at `index`, failing when it is
+    /// missing or malformed.
+    pub fn load(index: usize) -> Result<Self, Error> {
+        let length = match syscalls::load_extension(&mut [], 0, index, Source::HeaderDep) {
+            Ok(n) => n,
+            Err(SysError::LengthNotEnough(n)) => n,
+            e => {
+                debug!("Error loading extension: {:?}", e);
+                return Err(Error::MissingExtension);
+            }
+        };
+        let mut data = alloc::vec![0u8; length];
+        match syscalls::load_extension(&mut data, 0, index, Source::HeaderDep) {
+            Ok(n) if n == length => (),
+            e => {
+                debug!("Error loading extension: {:?}", e);
+                return Err(Error::MissingExtension);
+            }
+        }
+        Self::parse(&data).ok_or_else(|| {
+            debug!("Extension is neither structured nor long enough for the legacy layout!");
+            Error::InvalidExtension
+        })
+    }
+
+    /// Loads the extension from data of the cell dep at `index`, which must
+    /// carry the governance type script of `type_hash`.
+    pub fn load_cell_dep(index: usize, type_hash: &[u8]) -> Result<Self, Error> {
+        match high_level::load_cell_type_hash(index, Source::CellDep) {
+            Ok(Some(hash)) if hash[..] == type_hash[..] => (),
+            Ok(_) | Err(SysError::IndexOutOfBound) => {
+                debug!("Designated cell dep is not the governance cell!");
+                return Err(Error::GovernanceCellMismatch);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let data = high_level::load_cell_data(index, Source::CellDep)?;
+        Self::parse(&data).ok_or_else(|| {
+            debug!(
+                "Governance cell data is neither structured nor long enough for the legacy layout!"
+            );
+            Error::InvalidExtension
+        })
+    }
+
+    /// Loads the extension from data of the input cell at `index` in the
+    /// script group, for the governance cell consumed by a rotation.
+    pub fn load_group_input(index: usize) -> Result<Self, Error> {
+        let data = high_level::load_cell_data(index, Source::GroupInput)?;
+        Self::parse(&data).ok_or_else(|| {
+            debug!(
+                "Governance cell data is neither structured nor long enough for the legacy layout!"
+            );
+            Error::InvalidExtension
+        })
+    }
+}
+
+impl CoProof {
+    /// Loads all co-proofs from witness input_type of the input cell at
+    /// `index` in the script group. A witness without input_type, or one
+    /// that is not a valid WitnessArgs, carries none.
+    pub fn load(index: usize) -> Result<Vec<Self>, Error> {
+        let input_type = match high_level::load_witness_args(index, Source::GroupInput) {
+            Ok(witness_args) => witness_args.input_type().to_opt(),
+            Err(SysError::Encoding) => None,
+            Err(e) => return Err(e.into()),
+        };
+        match input_type {
+            Some(input_type) => Self::parse_all(&input_type.raw_data()).ok_or_else(|| {
+                debug!("Malformed co-proofs in witness input_type!");
+                Error::InvalidProof
+            }),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+impl SmtProof {
+    /// Loads the proof from witness lock of the input cell at `index` in the
+    /// script group, which must be a valid WitnessArgs.
+    pub fn load(index: usize) -> Result<Self, Error> {
+        let lock = match high_level::load_witness_args(index, Source::GroupInput) {
+            Ok(witness_args) => witness_args.lock().to_opt().map(|lock| lock.raw_data()),
+            Err(SysError::IndexOutOfBound) => None,
+            Err(_) => {
+                debug!("Witness is not a valid WitnessArgs!");
+                return Err(Error::InvalidWitness);
+            }
+        }
+        .filter(|lock| !lock.is_empty())
+        .ok_or_else(|| {
+            debug!("Witness does not have a sparse merkle proof in lock!");
+            Error::MissingProof
+        })?;
+        Self::parse(&lock).ok_or_else(|| {
+            debug!("Malformed sparse merkle proof!");
+            Error::InvalidProof
+        })
+    }
+}
+
+impl From<SysError> for Error {
+    fn from(err: SysError) -> Self {
+        match err {
+            SysError::IndexOutOfBound => Error::IndexOutOfBound,
+            SysError::ItemMissing => Error::ItemMissing,
+            SysError::LengthNotEnough(_) => Error::LengthNotEnough,
+            SysError::Encoding => Error::Encoding,
+            _ => Error::UnknownSysError,
+        }
+    }
+}
@@ -2,129 +2,592 @@
 #![cfg_attr(not(test), no_main)]
 
 use ckb_std::{
-    ckb_constants::Source, ckb_types::prelude::Entity, debug, default_alloc, error::SysError,
+    ckb_constants::Source,
+    ckb_types::{
+        packed::Script,
+        prelude::{Entity, Unpack},
+    },
+    debug, default_alloc,
+    error::SysError,
     high_level, syscalls,
 };
 
 ckb_std::entry!(program_entry);
 default_alloc!();
 
-use blake2b_ref::Blake2bBuilder;
-use merkle_cbt::merkle_tree::Merge;
+use alloc::vec::Vec;
+use blake2b_ref::{Blake2b, Blake2bBuilder};
+use constants::*;
+use core::ops::Range;
+use error::Error;
+use merge::{Blake2bHash, Data, Sha256Hash};
+use merkle_cbt::{merkle_tree::Merge, MerkleProof};
+use proof_reader::read_u32;
 
+/// Emits a structured message at a decision point through the debug syscall
+/// with the `debug-log` feature, and compiles to nothing without it. Unlike
+/// `debug!`, messages are kept in release builds.
+macro_rules! log {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "debug-log")]
+        syscalls::debug(alloc::format!($($arg)*));
+    };
+}
+
+mod constants;
+mod error;
+mod extension;
+mod loader;
+mod merge;
 mod proof_reader;
+mod smt;
+mod threshold;
 mod witness_reader;
 
 pub const ERROR_CODE_WITNESS_READER: i32 = -70;
-pub const ERROR_CODE_PROOF_READER: i32 = -71;
+pub const ERROR_CODE_INVALID_WITNESS_ARGS: i32 = -73;
 
-#[derive(Debug, Default, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
-pub struct Data([u8; 32]);
+/// Formats bytes as lower case hex in `log!` messages.
+#[cfg(feature = "debug-log")]
+struct Hex<'a>(&'a [u8]);
 
-impl Data {
-    fn from_slice(data: &[u8]) -> Self {
-        assert_eq!(data.len(), 32);
-        let mut d = [0u8; 32];
-        d.copy_from_slice(data);
-        Self(d)
+#[cfg(feature = "debug-log")]
+impl core::fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        for b in self.0 {
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
     }
+}
+
+/// Identifies the deployment of a committee in extensions, the blake2b hash
+/// of its committee id.
+pub fn deployment_id(committee_id: &[u8]) -> [u8; 32] {
+    let mut hasher = Blake2bBuilder::new(32)
+        .personal(b"ckb-default-hash")
+        .build();
+    hasher.update(committee_id);
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash[..]);
+    hash
+}
 
-    fn new(data: [u8; 32]) -> Self {
-        Self(data)
+/// Picks the extension slot holding the merkle root of a committee, so
+/// multiple committees can share one extension deterministically.
+pub fn slot_for(committee_id: &[u8], num_slots: usize) -> usize {
+    let hash = deployment_id(committee_id);
+    let mut t = [0u8; 4];
+    t.copy_from_slice(&hash[0..4]);
+    u32::from_le_bytes(t) as usize % num_slots
+}
+
+fn load_version(offset: usize, index: usize, source: Source) -> Result<u32, Error> {
+    let mut version = [0u8; 4];
+    match syscalls::load_cell_data(&mut version, offset, index, source) {
+        Ok(4) | Err(SysError::LengthNotEnough(_)) => Ok(u32::from_le_bytes(version)),
+        Ok(_) => {
+            debug!("Cell data does not have enough data for version!");
+            Err(Error::MissingVersion)
+        }
+        Err(e) => Err(e.into()),
     }
+}
 
-    fn as_bytes(&self) -> &[u8] {
-        &self.0[..]
+/// Applies a patch to the data of the input cell at `index` in the script
+/// group, returning the hash of the patched data.
+fn hash_patched_data(patch: &[u8], index: usize) -> Result<[u8; 32], Error> {
+    let mut hasher = Blake2bBuilder::new(32)
+        .personal(b"ckb-default-hash")
+        .build();
+    let mut buf = [0u8; 4096];
+    let mut rest = patch;
+    while let Some((op, r)) = rest.split_first() {
+        rest = match *op {
+            PATCH_OP_COPY => {
+                let (offset, r) = read_u32(r).ok_or(Error::InvalidPatch)?;
+                let (length, r) = read_u32(r).ok_or(Error::InvalidPatch)?;
+                let mut copied = 0;
+                while copied < length {
+                    let size = core::cmp::min(buf.len(), length - copied);
+                    let loaded = match syscalls::load_cell_data(
+                        &mut buf[..size],
+                        offset + copied,
+                        index,
+                        Source::GroupInput,
+                    ) {
+                        Ok(n) => n,
+                        Err(SysError::LengthNotEnough(_)) => size,
+                        Err(e) => return Err(e.into()),
+                    };
+                    if loaded < size {
+                        debug!("Patch copies beyond input cell data!");
+                        return Err(Error::InvalidPatch);
+                    }
+                    hasher.update(&buf[..size]);
+                    copied += size;
+                }
+                r
+            }
+            PATCH_OP_INSERT => {
+                let (length, r) = read_u32(r).ok_or(Error::InvalidPatch)?;
+                if r.len() < length {
+                    debug!("Patch does not have enough data to insert!");
+                    return Err(Error::InvalidPatch);
+                }
+                let (data, r) = r.split_at(length);
+                hasher.update(data);
+                r
+            }
+            _ => {
+                debug!("Unknown patch operation: {:#x}", op);
+                return Err(Error::InvalidPatch);
+            }
+        };
     }
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash[..]);
+    Ok(hash)
 }
 
-#[derive(Debug)]
-struct Blake2bHash;
+/// Resolves the data hash of the code a script runs, looking up the cell dep
+/// carrying it for scripts referencing code by type hash. Returns `None` if
+/// the code cannot be found in cell deps.
+fn code_data_hash(script: &Script) -> Result<Option<[u8; 32]>, SysError> {
+    let mut code_hash = [0u8; 32];
+    code_hash.copy_from_slice(script.code_hash().as_slice());
+    match script.hash_type().as_slice()[0] {
+        SCRIPT_HASH_TYPE_DATA | SCRIPT_HASH_TYPE_DATA1 | SCRIPT_HASH_TYPE_DATA2 => {
+            Ok(Some(code_hash))
+        }
+        SCRIPT_HASH_TYPE_TYPE => {
+            let mut i = 0;
+            loop {
+                match high_level::load_cell_type_hash(i, Source::CellDep) {
+                    Ok(Some(type_hash)) if type_hash == code_hash => {
+                        return high_level::load_cell_data_hash(i, Source::CellDep).map(Some);
+                    }
+                    Ok(_) => (),
+                    Err(SysError::IndexOutOfBound) => return Ok(None),
+                    Err(e) => return Err(e),
+                }
+                i += 1;
+            }
+        }
+        _ => Ok(None),
+    }
+}
 
-impl Merge for Blake2bHash {
-    type Item = Data;
+/// True when the lock of an output cell runs the same code with the same
+/// args as the current script, the hash type referencing the code is free to
+/// differ.
+fn is_successor_lock(
+    index: usize,
+    script: &Script,
+    code: &Option<[u8; 32]>,
+) -> Result<bool, SysError> {
+    let lock = high_level::load_cell_lock(index, Source::Output)?;
+    if code.is_none() || lock.args().as_slice() != script.args().as_slice() {
+        return Ok(false);
+    }
+    Ok(code_data_hash(&lock)? == *code)
+}
 
-    fn merge(lhs: &Self::Item, rhs: &Self::Item) -> Self::Item {
-        let mut hasher = Blake2bBuilder::new(32)
-            .personal(b"ckb-default-hash")
-            .build();
-        hasher.update(&lhs.as_bytes());
-        hasher.update(&rhs.as_bytes());
-        let mut hash = [0u8; 32];
-        hasher.finalize(&mut hash[..]);
-        Data::new(hash)
+/// True when the data of the cell starts with `FROZEN_MAGIC`, only the prefix
+/// is loaded.
+fn is_frozen(index: usize, source: Source) -> Result<bool, Error> {
+    let mut prefix = [0u8; FROZEN_MAGIC.len()];
+    let length = match syscalls::load_cell_data(&mut prefix, 0, index, source) {
+        Ok(length) | Err(SysError::LengthNotEnough(length)) => length,
+        Err(e) => return Err(e.into()),
+    };
+    Ok(length >= prefix.len() && prefix == *FROZEN_MAGIC)
+}
+
+/// Finds the successor of a migrated input cell in the script group: the
+/// first output cell keeping its type script which is not in `claimed`, i.e.
+/// neither uses zero lock nor succeeds another migrated input cell. Input
+/// cells without a type script cannot be migrated, nothing identifies their
+/// successor.
+fn migration_output(input_index: usize, claimed: &[usize]) -> Result<usize, Error> {
+    let type_hash =
+        high_level::load_cell_type_hash(input_index, Source::GroupInput)?.ok_or_else(|| {
+            debug!("Migrated input cell does not have a type script!");
+            Error::MissingMigrationOutput
+        })?;
+    let mut i = 0;
+    loop {
+        match high_level::load_cell_type_hash(i, Source::Output) {
+            Ok(Some(hash)) if hash == type_hash && !claimed.contains(&i) => return Ok(i),
+            Ok(_) => (),
+            Err(SysError::IndexOutOfBound) => {
+                debug!("No output cell keeps the type script of migrated input cell!");
+                return Err(Error::MissingMigrationOutput);
+            }
+            Err(e) => return Err(e.into()),
+        }
+        i += 1;
     }
 }
 
-pub fn program_entry() -> i8 {
-    match run() {
-        Ok(()) => 0,
-        Err(e) => {
-            debug!("System error: {:?}", e);
-            -61
+/// Loads the code hash referenced by the data of the output cell at `index`
+/// under `LOCK_EXT_FLAG_CODE_DEP`, requiring a cell dep to carry the code.
+fn load_code_reference(index: usize) -> Result<[u8; 32], Error> {
+    let mut code_hash = [0u8; 32];
+    match syscalls::load_cell_data(&mut code_hash, 0, index, Source::Output) {
+        Ok(32) => (),
+        Ok(_) | Err(SysError::LengthNotEnough(_)) => {
+            debug!("Output cell data is not a 32-byte code hash!");
+            return Err(Error::MissingCodeDep);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let mut i = 0;
+    loop {
+        match high_level::load_cell_data_hash(i, Source::CellDep) {
+            Ok(hash) if hash == code_hash => return Ok(code_hash),
+            Ok(_) => (),
+            Err(SysError::IndexOutOfBound) => {
+                debug!("No cell dep carries the code referenced by output cell!");
+                return Err(Error::MissingCodeDep);
+            }
+            Err(e) => return Err(e.into()),
         }
+        i += 1;
     }
 }
 
-pub fn run() -> Result<(), SysError> {
-    // Only one input cell can use zero lock
-    if high_level::load_cell_lock_hash(1, Source::GroupInput) != Err(SysError::IndexOutOfBound) {
-        debug!("More than one input cell uses zero lock!");
-        return Err(SysError::Unknown(1));
+/// Requires the data of the input cell at `input_index` in the script group
+/// and of the output cell at `output_index` to start with the same code, for
+/// a reconfiguration. The length of the code is read from witness
+/// output_type, both cells are loaded a chunk at a time.
+fn verify_code_unchanged(input_index: usize, output_index: usize) -> Result<(), Error> {
+    let code_length = high_level::load_witness_args(input_index, Source::GroupInput)
+        .ok()
+        .and_then(|witness_args| witness_args.output_type().to_opt())
+        .and_then(|output_type| match read_u32(&output_type.raw_data()) {
+            Some((length, rest)) if rest.is_empty() => Some(length),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            debug!("Witness does not have the code length in output_type!");
+            Error::InvalidWitness
+        })?;
+    let mut input_buf = [0u8; 2048];
+    let mut output_buf = [0u8; 2048];
+    let mut compared = 0;
+    while compared < code_length {
+        let size = core::cmp::min(input_buf.len(), code_length - compared);
+        for (buf, index, source) in [
+            (&mut input_buf, input_index, Source::GroupInput),
+            (&mut output_buf, output_index, Source::Output),
+        ] {
+            let loaded = match syscalls::load_cell_data(&mut buf[..size], compared, index, source) {
+                Ok(n) => n,
+                Err(SysError::LengthNotEnough(_)) => size,
+                Err(e) => return Err(e.into()),
+            };
+            if loaded < size {
+                debug!("Code length goes beyond cell data!");
+                return Err(Error::CodeChanged);
+            }
+        }
+        if input_buf[..size] != output_buf[..size] {
+            debug!("Reconfiguration changes the code of input cell!");
+            return Err(Error::CodeChanged);
+        }
+        compared += size;
     }
-    // Only one output cell can use zero lock, since output locks are not
-    // considered in script groups for current transaction, we will need to
-    // manually iterate over all of them.
-    let current_script_hash = high_level::load_script_hash()?;
+    Ok(())
+}
+
+/// Reads the most capacity a release leaf frees and the lock hash receiving
+/// it from witness output_type of the input cell at `input_index` in the
+/// script group.
+fn load_release(input_index: usize) -> Result<(u64, [u8; 32]), Error> {
+    let output_type = high_level::load_witness_args(input_index, Source::GroupInput)
+        .ok()
+        .and_then(|witness_args| witness_args.output_type().to_opt())
+        .map(|output_type| output_type.raw_data())
+        .filter(|output_type| output_type.len() == 8 + 32)
+        .ok_or_else(|| {
+            debug!("Witness does not have the released capacity in output_type!");
+            Error::InvalidWitness
+        })?;
+    let mut released = [0u8; 8];
+    released.copy_from_slice(&output_type[..8]);
+    let mut lock_hash = [0u8; 32];
+    lock_hash.copy_from_slice(&output_type[8..]);
+    Ok((u64::from_le_bytes(released), lock_hash))
+}
+
+/// Requires the output cells locked by each lock hash of `releases` to hold
+/// at least the capacity released to it. Output cells in `claimed` are
+/// successors of input cells, their capacity is not released to anyone.
+fn verify_releases(releases: &[([u8; 32], u64)], claimed: &[usize]) -> Result<(), Error> {
+    let mut received = alloc::vec![0u64; releases.len()];
     let mut i = 0;
-    let mut output_index = None;
     loop {
         match high_level::load_cell_lock_hash(i, Source::Output) {
-            Ok(hash) => {
-                if hash == current_script_hash {
-                    if output_index.is_some() {
-                        debug!("More than one output cell uses zero lock!");
-                        return Err(SysError::Unknown(2));
-                    } else {
-                        output_index = Some(i);
-                    }
+            Ok(hash) if !claimed.contains(&i) => {
+                if let Some(position) = releases
+                    .iter()
+                    .position(|(lock_hash, _)| *lock_hash == hash)
+                {
+                    let capacity = high_level::load_cell_capacity(i, Source::Output)?;
+                    received[position] = received[position].saturating_add(capacity);
                 }
             }
+            Ok(_) => (),
             Err(SysError::IndexOutOfBound) => break,
-            e => {
-                debug!("Lock hash loading error: {:?}", e);
-                return Err(SysError::Unknown(3));
-            }
+            Err(e) => return Err(e.into()),
         }
         i += 1;
     }
-    if output_index.is_none() {
-        debug!("No output cell uses zero lock!");
-        return Err(SysError::Unknown(4));
-    }
-    let output_index = output_index.unwrap();
-
-    // Generate the leaf we need from concatenation of the following bytes:
-    //
-    // * Byte 0x01, as version for future changes
-    // * Zero lock input cell’s OutPoint
-    // * Zero lock output cell’s data hash
-    // * Zero lock output cell’s CellOutput structure
-    // * Byte 0x01 if witness has input_type field, byte 0x00 otherwise
-    // * (If input_type exists) Length of input_type as little-endian u32
-    // * (If input_type exists) Content of input_type field
-    // * Byte 0x01 if witness has output_type field, byte 0x00 otherwise
-    // * (If output_type exists) Length of output_type as little-endian u32
-    // * (If output_type exists) Content of output_type field
-    let mut hasher = Blake2bBuilder::new(32)
-        .personal(b"ckb-default-hash")
-        .build();
-    hasher.update(&[1u8]);
-    hasher.update(high_level::load_input_out_point(0, Source::GroupInput)?.as_slice());
-    hasher.update(&high_level::load_cell_data_hash(
+    if releases
+        .iter()
+        .zip(received)
+        .any(|((_, released), received)| received < *released)
+    {
+        debug!("Output cells receive less capacity than released to them!");
+        return Err(Error::ReleaseNotReceived);
+    }
+    Ok(())
+}
+
+fn cell_data_length(index: usize, source: Source) -> Result<usize, SysError> {
+    match syscalls::load_cell_data(&mut [], 0, index, source) {
+        Ok(n) => Ok(n),
+        Err(SysError::LengthNotEnough(n)) => Ok(n),
+        Err(e) => Err(e),
+    }
+}
+
+/// Settings of zero lock, read from lock args. Lock args consist of the
+/// following parts, all are optional:
+///
+/// * A flags byte
+/// * (If LOCK_FLAG_EXTENDED is set) An extended flags byte
+/// * (If LOCK_FLAG_SALTED_ROOT is set) A 32-byte salt
+/// * (If LOCK_FLAG_VERSIONED is set) Version offset as little-endian u32
+/// * (If LOCK_FLAG_MIN_CONFIRMATIONS is set) Min confirmations as little-endian u32
+/// * (If LOCK_EXT_FLAG_GOVERNANCE_CELL is set) Type script hash of the
+///   governance cell
+/// * Committee id, the remainder of args
+struct LockArgs<'a> {
+    flags: u8,
+    ext_flags: u8,
+    /// Where witness carries the merkle proof, picked by extended flags
+    layout: witness_reader::ProofLayout,
+    salt: Option<Data>,
+    version_offset: Option<usize>,
+    min_confirmations: Option<u64>,
+    governance_type_hash: Option<&'a [u8]>,
+    committee_id: &'a [u8],
+}
+
+impl<'a> LockArgs<'a> {
+    /// Parses lock args, rejecting flags that cannot be combined.
+    fn parse(args: &'a [u8]) -> Result<Self, Error> {
+        let (flags, committee_id) = match args.split_first() {
+            Some((flags, committee_id)) => (*flags, committee_id),
+            None => (0, args),
+        };
+        let (ext_flags, committee_id) = if flags & LOCK_FLAG_EXTENDED != 0 {
+            let (ext_flags, committee_id) = committee_id.split_first().ok_or_else(|| {
+                debug!("Lock args do not have enough data for extended flags!");
+                Error::InvalidLockArgs
+            })?;
+            (*ext_flags, committee_id)
+        } else {
+            (0, committee_id)
+        };
+        if ext_flags & !KNOWN_LOCK_EXT_FLAGS != 0 {
+            debug!("Unknown extended lock flags: {:#x}", ext_flags);
+            return Err(Error::UnknownLockFlags);
+        }
+        if ext_flags & LOCK_EXT_FLAG_CODE_DEP != 0 && flags & LOCK_FLAG_PATCH != 0 {
+            debug!("Patches cannot be applied to a code hash in cell data!");
+            return Err(Error::InvalidLockArgs);
+        }
+        let (salt, committee_id) = if flags & LOCK_FLAG_SALTED_ROOT != 0 {
+            if committee_id.len() < 32 {
+                debug!("Lock args do not have enough data for salt!");
+                return Err(Error::InvalidLockArgs);
+            }
+            let (salt, committee_id) = committee_id.split_at(32);
+            (Some(Data::from_slice(salt)), committee_id)
+        } else {
+            (None, committee_id)
+        };
+        let (version_offset, committee_id) = if flags & LOCK_FLAG_VERSIONED != 0 {
+            let (version_offset, committee_id) = read_u32(committee_id).ok_or_else(|| {
+                debug!("Lock args do not have enough data for version offset!");
+                Error::InvalidLockArgs
+            })?;
+            (Some(version_offset), committee_id)
+        } else {
+            (None, committee_id)
+        };
+        let (min_confirmations, committee_id) = if flags & LOCK_FLAG_MIN_CONFIRMATIONS != 0 {
+            let (min_confirmations, committee_id) = read_u32(committee_id).ok_or_else(|| {
+                debug!("Lock args do not have enough data for min confirmations!");
+                Error::InvalidLockArgs
+            })?;
+            (Some(min_confirmations as u64), committee_id)
+        } else {
+            (None, committee_id)
+        };
+        let (governance_type_hash, committee_id) = if ext_flags & LOCK_EXT_FLAG_GOVERNANCE_CELL != 0
+        {
+            if committee_id.len() < 32 {
+                debug!("Lock args do not have enough data for governance type hash!");
+                return Err(Error::InvalidLockArgs);
+            }
+            if min_confirmations.is_some() {
+                debug!("Min confirmations need a header, governance cells have none!");
+                return Err(Error::InvalidLockArgs);
+            }
+            let (type_hash, committee_id) = committee_id.split_at(32);
+            (Some(type_hash), committee_id)
+        } else {
+            (None, committee_id)
+        };
+        let layout = witness_reader::ProofLayout {
+            root_index: ext_flags & LOCK_EXT_FLAG_ROOT_INDEX != 0,
+            input_type: ext_flags & LOCK_EXT_FLAG_INPUT_TYPE_PROOF != 0,
+            co_proofs: ext_flags & LOCK_EXT_FLAG_THRESHOLD_PROOFS != 0,
+            format_byte: ext_flags & LOCK_EXT_FLAG_PROOF_FORMAT != 0,
+        };
+        if layout.input_type && layout.co_proofs {
+            debug!("Witness input_type cannot carry both the proof and co-proofs!");
+            return Err(Error::InvalidLockArgs);
+        }
+        if flags & LOCK_FLAG_SMT != 0 {
+            if layout.input_type || layout.co_proofs {
+                debug!("Sparse merkle proofs cannot be carried in witness input_type!");
+                return Err(Error::InvalidLockArgs);
+            }
+            if layout.format_byte {
+                debug!("Sparse merkle proofs have a single format!");
+                return Err(Error::InvalidLockArgs);
+            }
+        }
+        Ok(Self {
+            flags,
+            ext_flags,
+            layout,
+            salt,
+            version_offset,
+            min_confirmations,
+            governance_type_hash,
+            committee_id,
+        })
+    }
+}
+
+/// Upgrade of the input cell at `input_index` in the script group into the
+/// output cell at `output_index`, as declared by its leaf.
+struct Upgrade {
+    input_index: usize,
+    output_index: usize,
+    /// The index of the output cell is committed as well, set by
+    /// `LEAF_TAG_FLAG_OUTPUT_INDEX`
+    commit_output_index: bool,
+    /// Capacity the output cell may hold less than the input cell
+    released: u64,
+    /// The output cell may have another type script, set by
+    /// `LEAF_TAG_FLAG_RETYPE`
+    retype: bool,
+}
+
+/// Validates an upgrade, hashing the output side of the leaf.
+fn hash_upgrade(hasher: &mut Blake2b, args: &LockArgs, upgrade: &Upgrade) -> Result<(), Error> {
+    let Upgrade {
+        input_index,
         output_index,
-        Source::Output,
-    )?);
+        commit_output_index,
+        released,
+        retype,
+    } = *upgrade;
+    // The leaf commits the output capacity already, but a zero lock cell
+    // unlocks without any signature. Releasing capacity through an upgrade
+    // is rejected outright instead of trusting the committee to never commit
+    // such a leaf, unless a release leaf commits the amount and who gets it.
+    let input_capacity = high_level::load_cell_capacity(input_index, Source::GroupInput)?;
+    let output_capacity = high_level::load_cell_capacity(output_index, Source::Output)?;
+    if output_capacity < input_capacity.saturating_sub(released) {
+        debug!(
+            "Output cell has less capacity than input cell! Input: {}, output: {}",
+            input_capacity, output_capacity
+        );
+        return Err(Error::CapacityLowered);
+    }
+
+    // Unless the leaf is flagged to retype the cell, an input cell with a
+    // type script keeps it through the upgrade, so its type id can never be
+    // swapped out by accident.
+    if let Some(input_type_hash) =
+        high_level::load_cell_type_hash(input_index, Source::GroupInput)?.filter(|_| !retype)
+    {
+        if high_level::load_cell_type_hash(output_index, Source::Output)? != Some(input_type_hash) {
+            debug!("Output cell does not keep the type script of input cell!");
+            return Err(Error::TypeScriptChanged);
+        }
+    }
+
+    if args.flags & LOCK_FLAG_PRESERVE_STRUCTURE != 0
+        && (input_capacity != output_capacity
+            || cell_data_length(input_index, Source::GroupInput)?
+                != cell_data_length(output_index, Source::Output)?)
+    {
+        debug!("Output cell does not preserve capacity and data length of input cell!");
+        return Err(Error::StructureNotPreserved);
+    }
+
+    if let Some(version_offset) = args.version_offset {
+        let old_version = load_version(version_offset, input_index, Source::GroupInput)?;
+        let new_version = load_version(version_offset, output_index, Source::Output)?;
+        if new_version <= old_version {
+            debug!(
+                "Version does not increase! Old version: {}, new version: {}",
+                old_version, new_version
+            );
+            return Err(Error::VersionNotIncreased);
+        }
+    }
+
+    if args.ext_flags & LOCK_EXT_FLAG_CODE_DEP != 0 {
+        hasher.update(&load_code_reference(output_index)?);
+    } else if args.flags & LOCK_FLAG_PATCH != 0 {
+        let patch = high_level::load_witness_args(input_index, Source::GroupInput)
+            .ok()
+            .and_then(|witness_args| witness_args.output_type().to_opt())
+            .ok_or_else(|| {
+                debug!("Witness does not have a patch in output_type!");
+                Error::MissingPatch
+            })?
+            .raw_data();
+        let output_data_hash = high_level::load_cell_data_hash(output_index, Source::Output)?;
+        if hash_patched_data(&patch, input_index)? != output_data_hash {
+            debug!("Output cell data is not the result of applying the patch!");
+            return Err(Error::PatchMismatch);
+        }
+        let mut patch_hasher = Blake2bBuilder::new(32)
+            .personal(b"ckb-default-hash")
+            .build();
+        patch_hasher.update(&patch);
+        let mut patch_hash = [0u8; 32];
+        patch_hasher.finalize(&mut patch_hash[..]);
+        hasher.update(&patch_hash);
+    } else {
+        // The data hash is loaded as a cell field, hashed by the VM host. Cell
+        // data never enters VM memory, however large the contract.
+        hasher.update(&high_level::load_cell_data_hash(
+            output_index,
+            Source::Output,
+        )?);
+    }
     let mut loaded = 0;
     let mut buf = [0u8; 4096];
     loop {
@@ -139,58 +602,924 @@ pub fn run() -> Result<(), SysError> {
             }
             Err(e) => {
                 debug!("Error loading first output cell: {:?}", e);
-                return Err(SysError::Unknown(7));
+                return Err(Error::LoadOutput);
             }
         }
     }
+    // The output cell is found at this index, a leaf committing another one
+    // does not match
+    if commit_output_index {
+        hasher.update(&(output_index as u32).to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Leaf tag declared in witness, along with the flags set on it.
+#[derive(Clone, Copy, Debug)]
+struct DeclaredTag {
+    tag: LeafTag,
+    /// Set by `LEAF_TAG_FLAG_OUTPUT_INDEX`
+    indexed_output: bool,
+    /// Set by `LEAF_TAG_FLAG_RETYPE`
+    retyped_output: bool,
+}
+
+impl DeclaredTag {
+    /// Output indices are never committed by deletions, which have no output
+    /// cell, and only upgrades retype cells.
+    fn parse(tag: u8) -> Result<Self, Error> {
+        let indexed_output = tag & LEAF_TAG_FLAG_OUTPUT_INDEX != 0;
+        let retyped_output = tag & LEAF_TAG_FLAG_RETYPE != 0;
+        LeafTag::from_u8(tag & !(LEAF_TAG_FLAG_OUTPUT_INDEX | LEAF_TAG_FLAG_RETYPE))
+            .filter(|leaf_tag| {
+                (!indexed_output || *leaf_tag != LeafTag::Deletion)
+                    && (!retyped_output || *leaf_tag == LeafTag::Upgrade)
+            })
+            .map(|leaf_tag| Self {
+                tag: leaf_tag,
+                indexed_output,
+                retyped_output,
+            })
+            .ok_or_else(|| {
+                debug!("Unknown leaf tag: {}", tag);
+                Error::UnknownLeafTag
+            })
+    }
+
+    /// Byte starting the leaf, flags included.
+    fn byte(self) -> u8 {
+        let mut byte = self.tag as u8;
+        if self.indexed_output {
+            byte |= LEAF_TAG_FLAG_OUTPUT_INDEX;
+        }
+        if self.retyped_output {
+            byte |= LEAF_TAG_FLAG_RETYPE;
+        }
+        byte
+    }
 
-    // Read the following data from witness:
-    //
-    // * Index of header to load merkle root
-    // * Merkle proof
-    // * Remainder of witness data (input_type, output_type) so we can ensure non-malleability
-    let (proof_visitor, hasher) = witness_reader::read_witness(0, Source::GroupInput, hasher)
-        .expect("parsing witness failure!");
-    let (header_index, merkle_proof) = proof_visitor
-        .build::<Blake2bHash>()
-        .expect("parsing merkle proof failure!");
+    /// True for leaves with an output cell using zero lock.
+    fn is_upgrade(self) -> bool {
+        matches!(
+            self.tag,
+            LeafTag::Upgrade
+                | LeafTag::Rotation
+                | LeafTag::Freeze
+                | LeafTag::Reconfiguration
+                | LeafTag::Release
+        )
+    }
+}
 
-    // Now we have all the data for the hasher, we can build the actual merkle leaf.
-    let mut leaf = [0u8; 32];
-    hasher.finalize(&mut leaf[..]);
-    let leaf = Data::new(leaf);
+/// Counts the input cells in the script group.
+fn count_group_inputs() -> Result<usize, Error> {
+    let mut inputs = 0;
+    loop {
+        match high_level::load_cell_lock_hash(inputs, Source::GroupInput) {
+            Ok(_) => inputs += 1,
+            Err(SysError::IndexOutOfBound) => return Ok(inputs),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
 
-    // Find merkle root from extension field at offset 128 in the designated header
-    let mut merkle_root = [0u8; 32];
-    match syscalls::load_extension(
-        &mut merkle_root,
-        128,
-        header_index as usize,
-        Source::HeaderDep,
-    ) {
-        Ok(n) => {
-            if n != 32 {
-                debug!("Extension does not have enough data for merkle root!");
-                return Err(SysError::Unknown(5));
+/// Counts the header deps of the transaction.
+fn count_header_deps() -> Result<usize, Error> {
+    let mut header_deps = 0;
+    loop {
+        match syscalls::load_header(&mut [], 0, header_deps, Source::HeaderDep) {
+            Ok(_) | Err(SysError::LengthNotEnough(_)) => header_deps += 1,
+            Err(SysError::IndexOutOfBound) => return Ok(header_deps),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Leaf tags declared next to the proofs in witness, along with where each
+/// proof starts.
+struct DeclaredProofs {
+    leaf_tags: Vec<DeclaredTag>,
+    /// Index of the first input cell each proof covers
+    starts: Vec<usize>,
+    /// Index of the header each proof names
+    header_indices: Vec<u32>,
+}
+
+/// Each leaf starts with its tag, so the tags declared next to the merkle
+/// proof in witness of the first input cell are read upfront, along with
+/// the index of the first input cell each proof covers and the header it
+/// names. There must be exactly one proven leaf per input cell, the compact
+/// form and sparse merkle proofs only fit a single input cell. A proof
+/// covering fewer input cells than the group holds is followed by another
+/// one in witness of the first input cell it leaves out, which may name
+/// another header. Cells committed in different blocks are upgraded
+/// together this way, each proof resolving its own leaves against its own
+/// root.
+fn load_leaf_tags(
+    args: &LockArgs,
+    inputs: usize,
+    smt_proof: Option<&smt::SmtProof>,
+) -> Result<DeclaredProofs, Error> {
+    let mut leaf_tags = Vec::with_capacity(inputs);
+    let mut proof_starts = alloc::vec![0];
+    let mut header_indices = Vec::with_capacity(1);
+    match smt_proof {
+        Some(smt_proof) => {
+            leaf_tags.push(smt_proof.leaf_tag);
+            header_indices.push(smt_proof.header_index);
+        }
+        None => {
+            let mut proof = witness_reader::read_witness_proof(0, Source::GroupInput, args.layout)?;
+            loop {
+                leaf_tags.extend_from_slice(proof.leaf_tags().ok_or(Error::InvalidProof)?);
+                header_indices.push(proof.header_index().ok_or(Error::InvalidProof)?);
+                let next = leaf_tags.len();
+                if next >= inputs {
+                    break;
+                }
+                proof =
+                    match witness_reader::read_witness_proof(next, Source::GroupInput, args.layout)
+                    {
+                        Ok(_) if args.layout.co_proofs => {
+                            debug!("Co-proofs only cover the leaves of a single merkle proof!");
+                            return Err(Error::InvalidProof);
+                        }
+                        Ok(proof) => proof,
+                        Err(Error::MissingProof) => break,
+                        Err(e) => return Err(e),
+                    };
+                proof_starts.push(next);
             }
         }
-        Err(SysError::LengthNotEnough(_)) => (),
-        e => {
-            debug!("Error loading merkle root from extension: {:?}", e);
-            return Err(SysError::Unknown(6));
+    }
+    let leaf_tags = leaf_tags
+        .into_iter()
+        .map(DeclaredTag::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+    // Witness output_type carries the patch under LOCK_FLAG_PATCH, and output
+    // cell data the hash of the code under LOCK_EXT_FLAG_CODE_DEP, which
+    // leaves a reconfiguration no code to compare and a release no room for
+    // the released capacity
+    let has_tag = |tag| leaf_tags.iter().any(|declared| declared.tag == tag);
+    if has_tag(LeafTag::Reconfiguration)
+        && (args.flags & LOCK_FLAG_PATCH != 0 || args.ext_flags & LOCK_EXT_FLAG_CODE_DEP != 0)
+    {
+        debug!("Reconfigurations cannot be proven for cells with patches or code deps!");
+        return Err(Error::UnknownLeafTag);
+    }
+    if has_tag(LeafTag::Release) && args.flags & LOCK_FLAG_PATCH != 0 {
+        debug!("Releases cannot be proven for cells with patches!");
+        return Err(Error::UnknownLeafTag);
+    }
+    if leaf_tags.len() != inputs {
+        debug!(
+            "Merkle proof covers {} leaves, but {} input cells use zero lock!",
+            leaf_tags.len(),
+            inputs
+        );
+        return Err(Error::LeafCountMismatch);
+    }
+    Ok(DeclaredProofs {
+        leaf_tags,
+        starts: proof_starts,
+        header_indices,
+    })
+}
+
+/// Collects the output cells using zero lock, one for each of the `upgrades`
+/// input cells with an output cell. Since output locks are not considered in
+/// script groups for current transaction, we will need to manually iterate
+/// over all of them. An output cell also uses zero lock when its lock
+/// references the same code by another hash type, e.g. Data1 or Type, with
+/// the same args.
+fn load_upgrade_outputs(script: &Script, upgrades: usize) -> Result<Vec<usize>, Error> {
+    let current_script_hash = high_level::load_script_hash()?;
+    let code = code_data_hash(script)?;
+    let mut i = 0;
+    let mut output_indices = Vec::with_capacity(upgrades);
+    loop {
+        match high_level::load_cell_lock_hash(i, Source::Output) {
+            Ok(hash) => {
+                if hash == current_script_hash || is_successor_lock(i, script, &code)? {
+                    if output_indices.len() >= upgrades {
+                        debug!("More output cells than upgraded input cells use zero lock!");
+                        return Err(Error::ExtraOutput);
+                    } else {
+                        output_indices.push(i);
+                    }
+                }
+            }
+            Err(SysError::IndexOutOfBound) => break,
+            e => {
+                debug!("Lock hash loading error: {:?}", e);
+                return Err(Error::LoadOutputLockHash);
+            }
         }
+        i += 1;
+    }
+    if output_indices.is_empty() && upgrades > 0 {
+        debug!("No output cell uses zero lock!");
+        return Err(Error::NoOutput);
+    }
+    if output_indices.len() < upgrades {
+        debug!("More upgraded input cells than output cells use zero lock!");
+        return Err(Error::MissingOutput);
     }
+    Ok(output_indices)
+}
+
+/// Output cells succeeding the input cells whose leaves are computed so far.
+struct Successors {
+    /// Output cells using zero lock that are left for upgrades, in order
+    upgrades: alloc::vec::IntoIter<usize>,
+    /// Successors of migrated input cells are found by type script, skipping
+    /// output cells already claimed by upgrades or earlier migrations.
+    claimed: Vec<usize>,
+    /// Capacity released by release leaves, summed by the lock hash
+    /// receiving it
+    releases: Vec<([u8; 32], u64)>,
+}
+
+/// Validates what the leaf of the input cell at `input_index` in the script
+/// group declares and hashes the cells it commits, see `compute_leaves`.
+/// Migrations must hand the cell to a lock with one of
+/// `successor_code_hashes`, when the extension proving the leaf lists them.
+fn hash_cells(
+    hasher: &mut Blake2b,
+    args: &LockArgs,
+    input_index: usize,
+    declared: DeclaredTag,
+    successors: &mut Successors,
+    successor_code_hashes: Option<&[[u8; 32]]>,
+) -> Result<(), Error> {
+    let tag = declared.tag;
+    match tag {
+        LeafTag::Upgrade
+        | LeafTag::Rotation
+        | LeafTag::Freeze
+        | LeafTag::Reconfiguration
+        | LeafTag::Release => {
+            let output_index = successors.upgrades.next().expect("output cell for upgrade");
+            // Only the data after the code changes, the committee still
+            // authorizes the exact data on both sides
+            if tag == LeafTag::Reconfiguration {
+                hasher.update(&high_level::load_cell_data_hash(
+                    input_index,
+                    Source::GroupInput,
+                )?);
+                verify_code_unchanged(input_index, output_index)?;
+            }
+            // Witness output_type is committed as part of the remainder,
+            // so the committee authorizes both the amount and its
+            // receiver
+            let release = if tag == LeafTag::Release {
+                Some(load_release(input_index)?)
+            } else {
+                None
+            };
+            let upgrade = Upgrade {
+                input_index,
+                output_index,
+                commit_output_index: declared.indexed_output,
+                released: release.map_or(0, |(released, _)| released),
+                retype: declared.retyped_output,
+            };
+            hash_upgrade(hasher, args, &upgrade)?;
+            if let Some((_, lock_hash)) = release {
+                let released = high_level::load_cell_capacity(input_index, Source::GroupInput)?
+                    .saturating_sub(high_level::load_cell_capacity(
+                        output_index,
+                        Source::Output,
+                    )?);
+                let releases = &mut successors.releases;
+                match releases.iter_mut().find(|(hash, _)| *hash == lock_hash) {
+                    Some((_, total)) => *total = total.saturating_add(released),
+                    None => releases.push((lock_hash, released)),
+                }
+            }
+            let same_data = high_level::load_cell_data_hash(input_index, Source::GroupInput)?
+                == high_level::load_cell_data_hash(output_index, Source::Output)?;
+            // A frozen cell is only ever moved to a new out point, which
+            // keeps its data and so keeps it frozen
+            let frozen = is_frozen(input_index, Source::GroupInput)?;
+            if frozen && (tag != LeafTag::Upgrade || !same_data) {
+                debug!("Input cell is frozen, its data cannot change!");
+                return Err(Error::Frozen);
+            }
+            if tag == LeafTag::Freeze && !is_frozen(output_index, Source::Output)? {
+                debug!("Frozen output cell data does not start with frozen magic!");
+                return Err(Error::InvalidFreeze);
+            }
+            if args.ext_flags & LOCK_EXT_FLAG_ALLOW_SAME_DATA == 0 && same_data && !frozen {
+                debug!("Upgrade keeps the data of input cell!");
+                return Err(Error::DataUnchanged);
+            }
+            // The rotated governance cell must keep governing, a cell
+            // whose data is no extension would lock every cell out
+            if tag == LeafTag::Rotation
+                && extension::Extension::parse(&high_level::load_cell_data(
+                    output_index,
+                    Source::Output,
+                )?)
+                .is_none()
+            {
+                debug!("Rotated governance cell data is not an extension!");
+                return Err(Error::InvalidExtension);
+            }
+        }
+        // The leaf commits the full output cell including its lock, so
+        // the committee authorizes the exact lock the cell is handed to.
+        // Another lock could change the data of a frozen cell at will.
+        LeafTag::Migration => {
+            if is_frozen(input_index, Source::GroupInput)? {
+                debug!("Input cell is frozen, it cannot leave zero lock!");
+                return Err(Error::Frozen);
+            }
+            let output_index = migration_output(input_index, &successors.claimed)?;
+            if let Some(code_hashes) = successor_code_hashes {
+                let lock = high_level::load_cell_lock(output_index, Source::Output)?;
+                if !code_hashes
+                    .iter()
+                    .any(|code_hash| &code_hash[..] == lock.code_hash().as_slice())
+                {
+                    debug!("Migration output cell uses a lock the extension does not list!");
+                    return Err(Error::SuccessorNotWhitelisted);
+                }
+            }
+            successors.claimed.push(output_index);
+            let upgrade = Upgrade {
+                input_index,
+                output_index,
+                commit_output_index: declared.indexed_output,
+                released: 0,
+                retype: false,
+            };
+            hash_upgrade(hasher, args, &upgrade)?;
+        }
+        // The out point binds the leaf to the deleted cell, so it can
+        // never retire another cell with the same data and type script.
+        LeafTag::Deletion => {
+            hasher.update(&high_level::load_cell_data_hash(
+                input_index,
+                Source::GroupInput,
+            )?);
+            let type_hash = high_level::load_cell_type_hash(input_index, Source::GroupInput)?;
+            hasher.update(&type_hash.unwrap_or_default());
+        }
+    }
+    Ok(())
+}
+
+/// Computes the leaf of every input cell in the script group, along with
+/// the merkle proofs parsed from witnesses of the input cells at
+/// `proof_starts`. None are parsed for sparse merkle proofs, which are read
+/// upfront. `extensions` holds the extension of each proof.
+fn compute_leaves(
+    args: &LockArgs,
+    leaf_tags: &[DeclaredTag],
+    output_indices: Vec<usize>,
+    proof_starts: &[usize],
+    extensions: &[extension::Extension],
+    smt: bool,
+) -> Result<(Vec<Data>, Vec<proof_reader::WitnessProof<Blake2bHash>>), Error> {
+    let mut leaves = Vec::with_capacity(leaf_tags.len());
+    let mut witness_proofs = Vec::with_capacity(proof_starts.len());
+    let mut successors = Successors {
+        claimed: output_indices.clone(),
+        upgrades: output_indices.into_iter(),
+        releases: Vec::new(),
+    };
+    for (input_index, declared) in leaf_tags.iter().copied().enumerate() {
+        // Generate the leaf we need from concatenation of the following bytes:
+        //
+        // * Leaf tag, byte 0x01 for upgrades, byte 0x02 for deletions, byte
+        //   0x04 for migrations, byte 0x05 for rotations, byte 0x06 for
+        //   freezes, byte 0x07 for reconfigurations, byte 0x08 for releases,
+        //   with LEAF_TAG_FLAG_OUTPUT_INDEX and LEAF_TAG_FLAG_RETYPE set as
+        //   declared
+        // * Zero lock input cell’s OutPoint, which pins the data of the
+        //   input cell as well since a live cell never changes
+        // * (Reconfigurations) Zero lock input cell’s data hash
+        // * (All but deletions) Output cell’s data hash, hash of the patch in
+        //   witness output_type if LOCK_FLAG_PATCH is set, or the code hash
+        //   in output cell data if LOCK_EXT_FLAG_CODE_DEP is set
+        // * (All but deletions) Output cell’s CellOutput structure
+        // * (If LEAF_TAG_FLAG_OUTPUT_INDEX is set) Index of output cell as
+        //   little-endian u32
+        // * (Deletions) Zero lock input cell’s data hash
+        // * (Deletions) Zero lock input cell’s type script hash, or 32 zero
+        //   bytes without a type script
+        // * Byte 0x01 if witness has input_type field, byte 0x00 otherwise
+        // * (If input_type exists) Length of input_type as little-endian u32
+        // * (If input_type exists) Content of input_type field
+        // * Byte 0x01 if witness has output_type field, byte 0x00 otherwise
+        // * (If output_type exists) Length of output_type as little-endian u32
+        // * (If output_type exists) Content of output_type field
+        // * (If LOCK_FLAG_ACTIVATION is set) Since of input cell as little-endian u64
+        //
+        // The witness is the one at the same index as the input cell.
+        let input_out_point = high_level::load_input_out_point(input_index, Source::GroupInput)?;
+        let mut hasher = Blake2bBuilder::new(32)
+            .personal(b"ckb-default-hash")
+            .build();
+        hasher.update(&[declared.byte()]);
+        hasher.update(input_out_point.as_slice());
+        let proof_index = proof_starts
+            .iter()
+            .filter(|start| **start <= input_index)
+            .count()
+            - 1;
+        hash_cells(
+            &mut hasher,
+            args,
+            input_index,
+            declared,
+            &mut successors,
+            extensions[proof_index].successor_code_hashes.as_deref(),
+        )?;
+
+        // Read the following data from witness of the first input cell, or
+        // the first input cell a later proof covers:
+        //
+        // * Index of header to load merkle root
+        // * Merkle proof covering the leaves of the input cells from this one
+        //   on, their indices must be strictly increasing in the order of
+        //   their leaves sorted by hash. It is omitted entirely for single
+        //   leaf trees. Lemmas can be split into input_type by using
+        //   SPLIT_LEMMAS as lemma count in lock
+        // * (Optional) Precomputed leaf hash of this input cell
+        // * Remainder of witness data (input_type, output_type) so we can ensure non-malleability
+        //
+        // All of the above except the remainder is read from input_type in
+        // place of lock under LOCK_EXT_FLAG_INPUT_TYPE_PROOF. The proof field
+        // of the other input cells must be empty, only their remainders are
+        // read. A witness that is not a valid WitnessArgs is read as the raw
+        // content of witness lock, or the seal of a WitnessLayout, with
+        // neither input_type nor output_type. Sparse merkle proofs are
+        // already parsed, only the remainder of witness is read for them.
+        // Co-proofs in input_type under LOCK_EXT_FLAG_THRESHOLD_PROOFS are
+        // parsed separately, only the first input cell carries them.
+        let mut hasher = if smt {
+            witness_reader::read_witness_remainder(input_index, Source::GroupInput, hasher)
+                .ok_or(Error::InvalidWitness)?
+        } else {
+            let layout = witness_reader::ProofLayout {
+                co_proofs: args.layout.co_proofs && input_index == 0,
+                ..args.layout
+            };
+            let (proof_visitor, hasher) =
+                witness_reader::read_witness(input_index, Source::GroupInput, layout, hasher)?;
+            if proof_starts.contains(&input_index) {
+                witness_proofs.push(
+                    proof_visitor
+                        .build::<Blake2bHash>()
+                        .ok_or(Error::InvalidProof)?,
+                );
+            } else if !proof_visitor.is_empty() {
+                debug!("Only witness of the first input cell of a proof can carry it!");
+                return Err(Error::InvalidProof);
+            }
+            hasher
+        };
+
+        // Scripts cannot see the tip block, the committed since value is enforced
+        // by consensus instead. A zero since would not delay anything.
+        if args.flags & LOCK_FLAG_ACTIVATION != 0 {
+            let since = high_level::load_input_since(input_index, Source::GroupInput)?;
+            if since == 0 {
+                debug!("Input cell does not have a since value for activation!");
+                return Err(Error::MissingActivation);
+            }
+            hasher.update(&since.to_le_bytes());
+        }
+
+        // Now we have all the data for the hasher, we can build the actual merkle leaf.
+        let mut leaf = [0u8; 32];
+        hasher.finalize(&mut leaf[..]);
+        log!(
+            "zero-lock leaf index={} tag={:?} hash={}",
+            input_index,
+            declared.tag,
+            Hex(&leaf)
+        );
+        leaves.push(Data::new(leaf));
+    }
+    // Leaves commit the out point of their input cell, so identical leaves
+    // only come from spending one out point twice, which consensus never lets
+    // through. They are rejected regardless, a committed leaf never proves
+    // more than one spend and is never counted by multiplicity.
+    if leaves
+        .iter()
+        .enumerate()
+        .any(|(i, leaf)| leaves[..i].contains(leaf))
+    {
+        debug!("Leaves of two input cells are identical!");
+        return Err(Error::DuplicateLeaf);
+    }
+    verify_releases(&successors.releases, &successors.claimed)?;
+    Ok((leaves, witness_proofs))
+}
+
+/// Loads the extension a proof is verified against. Rotations read the
+/// governance cell being consumed instead of a cell dep, the header index is
+/// unused then. Extensions are only loaded from the header deps of the
+/// transaction, by index, so a header known to the chain but not listed
+/// never supplies a root. Only a live governance cell dep may carry the
+/// kill switch: see `Error::Paused`.
+fn load_extension(
+    args: &LockArgs,
+    rotation: bool,
+    header_index: u32,
+) -> Result<extension::Extension, Error> {
+    match args.governance_type_hash {
+        Some(_) if rotation => extension::Extension::load_group_input(0),
+        Some(type_hash) => {
+            let extension = extension::Extension::load_cell_dep(header_index as usize, type_hash)?;
+            if extension.kill_switch == Some(true) {
+                debug!("Upgrades are paused by the kill switch in governance cell!");
+                return Err(Error::Paused);
+            }
+            Ok(extension)
+        }
+        None => {
+            let extension = extension::Extension::load(header_index as usize)?;
+            if extension.kill_switch.is_some() {
+                debug!("Kill switch in header extension, an older header could bypass it!");
+                return Err(Error::InvalidExtension);
+            }
+            Ok(extension)
+        }
+    }
+}
+
+/// Requires the since of the input cells in `inputs` to hold the upgrade
+/// back for as long as the extension and lock args ask for.
+fn verify_since(
+    args: &LockArgs,
+    extension: &extension::Extension,
+    header_index: u32,
+    inputs: Range<usize>,
+) -> Result<(), Error> {
+    // An absolute epoch since no less than the header's epoch number plus the
+    // minimum number of epochs in extension makes consensus hold the upgrade
+    // back, giving users a window to react to a committed upgrade.
+    if extension.min_epochs > 0 {
+        if args.governance_type_hash.is_some() {
+            debug!("Governance cell sets min epochs, but there is no header to count from!");
+            return Err(Error::InvalidExtension);
+        }
+        let header_epoch: u64 = high_level::load_header(header_index as usize, Source::HeaderDep)?
+            .raw()
+            .epoch()
+            .unpack();
+        let header_epoch_number = header_epoch & EPOCH_NUMBER_MASK;
+        for input_index in inputs.clone() {
+            let since = high_level::load_input_since(input_index, Source::GroupInput)?;
+            if since & SINCE_FLAGS_MASK != SINCE_FLAGS_ABSOLUTE_EPOCH {
+                debug!("Input since is not an absolute epoch!");
+                return Err(Error::SinceNotEpoch);
+            }
+            if since & EPOCH_NUMBER_MASK < header_epoch_number + extension.min_epochs {
+                debug!(
+                    "Not enough epochs elapsed since header! Header epoch: {}, since epoch: {}",
+                    header_epoch_number,
+                    since & EPOCH_NUMBER_MASK
+                );
+                return Err(Error::NotEnoughEpochs);
+            }
+        }
+    }
+
+    // An absolute block number since no less than the header's number plus
+    // min confirmations makes consensus hold the upgrade back until the
+    // header is buried deep enough.
+    if let Some(min_confirmations) = args.min_confirmations {
+        let header_number: u64 = high_level::load_header(header_index as usize, Source::HeaderDep)?
+            .raw()
+            .number()
+            .unpack();
+        for input_index in inputs {
+            let since = high_level::load_input_since(input_index, Source::GroupInput)?;
+            if since & SINCE_FLAGS_MASK != 0 {
+                debug!("Input since is not an absolute block number!");
+                return Err(Error::SinceNotBlockNumber);
+            }
+            if since < header_number.saturating_add(min_confirmations) {
+                debug!(
+                    "Header does not have enough confirmations! Header: {}, since: {}",
+                    header_number, since
+                );
+                return Err(Error::NotEnoughConfirmations);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Verifies `leaves`, sorted by hash, against the root governing the cell
+/// in the extension, returning that root. `first_leaf` is the leaf of the
+/// first input cell the proof covers.
+fn verify_root(
+    args: &LockArgs,
+    extension: &extension::Extension,
+    smt_proof: Option<&smt::SmtProof>,
+    proof: proof_reader::WitnessProof<Blake2bHash>,
+    first_leaf: &Data,
+    leaves: &[Data],
+) -> Result<Data, Error> {
+    // A precomputed leaf hash is never trusted, it only lets us bail out before
+    // walking the merkle proof when it disagrees with the recomputed leaf.
+    if let Some(supplied_leaf) = proof.leaf_hash {
+        if supplied_leaf != *first_leaf {
+            debug!(
+                "Leaf hash mismatch! Supplied leaf: {:?}, actual leaf: {:?}",
+                supplied_leaf, first_leaf
+            );
+            return Err(Error::LeafHashMismatch);
+        }
+    }
+
+    // Find merkle root from the committee's slot in the designated header,
+    // the slot named by witness, or the sparse merkle tree root for cells
+    // governed by it
+    let merkle_root = match (smt_proof, proof.root_index) {
+        (Some(_), _) => extension.smt_root,
+        (None, Some(root_index)) => extension.root_at(root_index as usize),
+        (None, None) => extension.root_for(args.committee_id),
+    }
+    .ok_or_else(|| {
+        debug!("Extension does not have the root governing this cell!");
+        Error::InvalidExtension
+    })?;
+    // An all zero root indicates an uninitialized extension, it must never
+    // authorize anything.
+    if merkle_root.iter().all(|b| *b == 0) {
+        debug!("Empty merkle root in extension!");
+        return Err(Error::EmptyRoot);
+    }
+    // The hash of a header is the hash of its serialized structure
+    #[cfg(feature = "debug-log")]
+    if args.governance_type_hash.is_none() {
+        let header = high_level::load_header(proof.header_index as usize, Source::HeaderDep)?;
+        let mut hasher = Blake2bBuilder::new(32)
+            .personal(b"ckb-default-hash")
+            .build();
+        hasher.update(header.as_slice());
+        let mut header_hash = [0u8; 32];
+        hasher.finalize(&mut header_hash[..]);
+        log!(
+            "zero-lock header index={} hash={}",
+            proof.header_index,
+            Hex(&header_hash)
+        );
+    }
+    log!(
+        "zero-lock root found header={} hash={}",
+        proof.header_index,
+        Hex(&merkle_root)
+    );
     let merkle_root = Data::new(merkle_root);
 
-    // Actual merkle proof verification
-    let actual_root = merkle_proof.root(&[leaf]).expect("no root");
+    // Actual merkle proof verification, a single leaf tree has the leaf as root.
+    // Tree nodes are merged by the hash the extension picks. Sparse merkle
+    // trees hold the leaf under the hash of the old contract.
+    let actual_root = match (smt_proof, proof.merkle_proof) {
+        (Some(smt_proof), _) => {
+            let key = high_level::load_cell_data_hash(0, Source::GroupInput)?;
+            Data::new(smt_proof.root(&key, &first_leaf.0))
+        }
+        (None, Some(merkle_proof)) => match extension.merge_hash {
+            MERGE_HASH_SHA256 => MerkleProof::<Data, Sha256Hash>::new(
+                merkle_proof.indices().to_vec(),
+                merkle_proof.lemmas().to_vec(),
+            )
+            .root(leaves),
+            _ => merkle_proof.root(leaves),
+        }
+        .ok_or_else(|| {
+            debug!("Merkle proof does not produce a root!");
+            Error::InvalidProof
+        })?,
+        (None, None) => first_leaf.clone(),
+    };
+    // A salted root binds the extension value to cells sharing the same salt
+    let actual_root = match &args.salt {
+        Some(salt) => Blake2bHash::merge(&actual_root, salt),
+        None => actual_root,
+    };
     if actual_root != merkle_root {
         debug!(
             "Merkle proof failure! Actual root: {:?}, expected root: {:?}",
             actual_root, merkle_root
         );
-        return Err(SysError::Unknown(8));
+        log!(
+            "zero-lock root mismatch actual={} expected={}",
+            Hex(&actual_root.0),
+            Hex(&merkle_root.0)
+        );
+        return Err(Error::RootMismatch);
+    }
+    log!("zero-lock proof verified leaves={}", leaves.len());
+    Ok(merkle_root)
+}
+
+/// Each co-proof has another committee prove the same leaves against its
+/// own root. A root counts once towards the threshold however many
+/// co-proofs name it, or whichever slots hold it, so a single committee
+/// can never stand in for several.
+fn verify_threshold(
+    args: &LockArgs,
+    extension: &extension::Extension,
+    merkle_root: Data,
+    leaves: &[Data],
+) -> Result<(), Error> {
+    let co_proofs = if args.layout.co_proofs {
+        threshold::CoProof::load(0)?
+    } else {
+        Vec::new()
+    };
+    let mut proven_roots = alloc::vec![merkle_root];
+    for co_proof in co_proofs {
+        let root = extension
+            .root_at(co_proof.root_index as usize)
+            .ok_or_else(|| {
+                debug!("Extension does not have the root named by co-proof!");
+                Error::InvalidExtension
+            })?;
+        if root.iter().all(|b| *b == 0) {
+            debug!("Empty merkle root in extension!");
+            return Err(Error::EmptyRoot);
+        }
+        let root = Data::new(root);
+        if proven_roots.contains(&root) {
+            debug!("Co-proof names a root already proving the leaves!");
+            return Err(Error::DuplicateRoot);
+        }
+        let actual_root = match extension.merge_hash {
+            MERGE_HASH_SHA256 => co_proof.root::<Sha256Hash>(leaves),
+            _ => co_proof.root::<Blake2bHash>(leaves),
+        }
+        .ok_or_else(|| {
+            debug!("Co-proof does not produce a root!");
+            Error::InvalidProof
+        })?;
+        let actual_root = match &args.salt {
+            Some(salt) => Blake2bHash::merge(&actual_root, salt),
+            None => actual_root,
+        };
+        if actual_root != root {
+            debug!(
+                "Co-proof failure! Actual root: {:?}, expected root: {:?}",
+                actual_root, root
+            );
+            return Err(Error::RootMismatch);
+        }
+        proven_roots.push(root);
+    }
+    if proven_roots.len() < extension.threshold as usize {
+        debug!(
+            "Not enough roots prove the leaves! Proven: {}, threshold: {}",
+            proven_roots.len(),
+            extension.threshold
+        );
+        return Err(Error::ThresholdNotMet);
+    }
+    log!("zero-lock threshold met roots={}", proven_roots.len());
+    Ok(())
+}
+
+pub fn program_entry() -> i8 {
+    match run() {
+        Ok(()) => 0,
+        Err(e) => {
+            debug!("Zero lock error: {:?}", e);
+            log!("zero-lock failed error={:?} code={}", e, e as i8);
+            e as i8
+        }
+    }
+}
+
+pub fn run() -> Result<(), Error> {
+    // Every input cell in the script group is upgraded in the same
+    // transaction, the i-th of them into the i-th output cell using zero lock.
+    // Cells are matched by position alone, so they do not need a type script
+    // as identity: each leaf binds the input out point to the full output
+    // cell, which rules out substituting one successor for another.
+    let inputs = count_group_inputs()?;
+    // CKB only runs a lock script for input cells using it, but an empty
+    // group has nothing to upgrade either way. No header, witness or output
+    // cell is loaded for it.
+    if inputs == 0 {
+        return Ok(());
     }
+    let script = high_level::load_script()?;
+    let raw_args = script.args().raw_data();
+    let args = LockArgs::parse(&raw_args)?;
+    let smt_proof = if args.flags & LOCK_FLAG_SMT != 0 {
+        Some(smt::SmtProof::load(0)?)
+    } else {
+        None
+    };
+    let DeclaredProofs {
+        leaf_tags,
+        starts: proof_starts,
+        header_indices,
+    } = load_leaf_tags(&args, inputs, smt_proof.as_ref())?;
+    // Several proofs, or co-proofs standing for several committees, must
+    // each name a distinct header dep of the transaction. Header deps
+    // nothing in the script group names are left to other scripts, every
+    // committee is held to its proof by the threshold of its extension.
+    // Governance cells are read from cell deps, which hold code as well, and
+    // are not counted.
+    if args.governance_type_hash.is_none() && (args.layout.co_proofs || header_indices.len() > 1) {
+        let mut named = header_indices.clone();
+        named.sort_unstable();
+        named.dedup();
+        let header_deps = count_header_deps()?;
+        if named.len() != header_indices.len()
+            || named
+                .iter()
+                .any(|header_index| *header_index as usize >= header_deps)
+        {
+            debug!(
+                "Proofs name {} header deps, {} of them distinct, but the transaction has {}!",
+                header_indices.len(),
+                named.len(),
+                header_deps
+            );
+            return Err(Error::ProofHeaderCountMismatch);
+        }
+    }
+    // A rotation is proven against the roots in the data of the governance
+    // cell it consumes, which must be the only input cell
+    let rotation = leaf_tags
+        .iter()
+        .any(|declared| declared.tag == LeafTag::Rotation);
+    if rotation {
+        let type_hash = high_level::load_cell_type_hash(0, Source::GroupInput)?;
+        if inputs != 1
+            || args.governance_type_hash.is_none()
+            || type_hash.as_ref().map(|hash| &hash[..]) != args.governance_type_hash
+        {
+            debug!("Only the governance cell can be rotated, on its own!");
+            return Err(Error::InvalidRotation);
+        }
+    }
+    // The kill switch halts every upgrade before any leaf is hashed
+    let extensions = header_indices
+        .iter()
+        .map(|header_index| load_extension(&args, rotation, *header_index))
+        .collect::<Result<Vec<_>, _>>()?;
 
+    // Every upgraded, rotated, frozen, reconfigured or released input cell
+    // needs exactly one output cell using zero lock, deleted and migrated
+    // input cells have none.
+    let upgrades = leaf_tags
+        .iter()
+        .filter(|declared| declared.is_upgrade())
+        .count();
+    let output_indices = load_upgrade_outputs(&script, upgrades)?;
+
+    let (leaves, witness_proofs) = compute_leaves(
+        &args,
+        &leaf_tags,
+        output_indices,
+        &proof_starts,
+        &extensions,
+        smt_proof.is_some(),
+    )?;
+    let proofs = match &smt_proof {
+        Some(smt_proof) => alloc::vec![proof_reader::WitnessProof {
+            header_index: smt_proof.header_index,
+            root_index: None,
+            merkle_proof: None,
+            leaf_hash: None,
+        }],
+        None => witness_proofs,
+    };
+    for (proof_index, (proof, extension)) in proofs.into_iter().zip(extensions).enumerate() {
+        let start = proof_starts[proof_index];
+        let end = proof_starts.get(proof_index + 1).copied().unwrap_or(inputs);
+        // Trees hold their leaves sorted by hash, a proof pairs the node
+        // indices it proves with the leaves in that order, whichever order
+        // the input cells come in
+        let mut tree_leaves = leaves[start..end].to_vec();
+        tree_leaves.sort();
+        verify_since(&args, &extension, proof.header_index, start..end)?;
+        let merkle_root = verify_root(
+            &args,
+            &extension,
+            smt_proof.as_ref(),
+            proof,
+            &leaves[start],
+            &tree_leaves,
+        )?;
+        verify_threshold(&args, &extension, merkle_root, &tree_leaves)?;
+    }
+
+    #[cfg(feature = "debug-log")]
+    for input_index in 0..inputs {
+        log!("zero-lock input index={} passed", input_index);
+    }
     Ok(())
 }
@@ -0,0 +1,73 @@
+//! Merkle tree nodes of zero lock and the hashes merging them, blake2b being
+//! the one every blake2b tree of zero lock merges its nodes by.
+//! `ckb-zero-lock-types` builds this file as well, so trees built off chain
+//! are merged exactly as zero lock merges them. It only depends on
+//! `blake2b-ref`, `merkle-cbt` and `sha2`, and builds without std.
+
+use blake2b_ref::Blake2bBuilder;
+use merkle_cbt::merkle_tree::Merge;
+use sha2::{Digest, Sha256};
+
+/// Personalization of blake2b hashes in CKB, the one `ckb_hash::new_blake2b`
+/// uses.
+pub const CKB_HASH_PERSONALIZATION: &[u8] = b"ckb-default-hash";
+
+/// Hashes `lhs` followed by `rhs` into 32 bytes.
+pub fn merge_blake2b(lhs: &[u8; 32], rhs: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Blake2bBuilder::new(32)
+        .personal(CKB_HASH_PERSONALIZATION)
+        .build();
+    hasher.update(lhs);
+    hasher.update(rhs);
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash[..]);
+    hash
+}
+
+/// Node of a merkle tree, leaves and roots included.
+#[derive(Debug, Default, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub struct Data(pub [u8; 32]);
+
+impl Data {
+    pub fn from_slice(data: &[u8]) -> Self {
+        assert_eq!(data.len(), 32);
+        let mut d = [0u8; 32];
+        d.copy_from_slice(data);
+        Self(d)
+    }
+
+    pub fn new(data: [u8; 32]) -> Self {
+        Self(data)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0[..]
+    }
+}
+
+#[derive(Debug)]
+pub struct Blake2bHash;
+
+impl Merge for Blake2bHash {
+    type Item = Data;
+
+    fn merge(lhs: &Self::Item, rhs: &Self::Item) -> Self::Item {
+        Data::new(merge_blake2b(&lhs.0, &rhs.0))
+    }
+}
+
+/// Merges tree nodes of committees publishing SHA-256 trees, picked by
+/// `EXTENSION_FIELD_MERGE_HASH`. Leaves are blake2b hashes either way.
+#[derive(Debug)]
+pub struct Sha256Hash;
+
+impl Merge for Sha256Hash {
+    type Item = Data;
+
+    fn merge(lhs: &Self::Item, rhs: &Self::Item) -> Self::Item {
+        let mut hasher = Sha256::new();
+        hasher.update(lhs.as_bytes());
+        hasher.update(rhs.as_bytes());
+        Data::new(hasher.finalize().into())
+    }
+}
@@ -1,11 +1,35 @@
-use super::{Data, ERROR_CODE_PROOF_READER};
-use alloc::vec::Vec;
-use ckb_std::debug;
+//! Parsing of merkle proofs, which reads nothing but the bytes it is fed.
+//! `ckb-zero-lock-types` builds this file as well, so the verifier on the
+//! host parses proofs exactly as zero lock does.
+
+use super::{
+    debug, merge::Data, LeafTag, MAX_TREE_DEPTH, PROOF_FORMAT_FIXED, PROOF_FORMAT_VARINT,
+    SPLIT_LEMMAS,
+};
+use alloc::{collections::VecDeque, vec::Vec};
 use core::cmp;
 use merkle_cbt::{merkle_tree::Merge, MerkleProof};
 
+/// `ProofVisitor::process` fails with this for malformed proofs.
+pub const ERROR_CODE_PROOF_READER: i32 = -71;
+/// `ProofVisitor::process` fails with this for proofs declaring more lemmas
+/// than `MAX_TREE_DEPTH` allows.
+pub const ERROR_CODE_TREE_TOO_DEEP: i32 = -72;
+
 const FIXED_BUF_SIZE: usize = 4096;
 
+/// Reads a little-endian u32 from the start of `data`, along with the rest
+/// of `data`.
+pub fn read_u32(data: &[u8]) -> Option<(usize, &[u8])> {
+    if data.len() < 4 {
+        return None;
+    }
+    let (n, rest) = data.split_at(4);
+    let mut t = [0u8; 4];
+    t.copy_from_slice(n);
+    Some((u32::from_le_bytes(t) as usize, rest))
+}
+
 #[derive(Clone, PartialEq, Eq, Hash, Debug)]
 struct FixedBuffer {
     data: [u8; FIXED_BUF_SIZE],
@@ -46,51 +70,279 @@ impl FixedBuffer {
     }
 }
 
+/// Number of lemmas needed to compute the root from leaves at the CBMT node
+/// `indices`, walking the nodes up the tree the same way as
+/// `MerkleProof::root` does. `None` when the nodes never merge into a single
+/// root, e.g. when one of them is the ancestor of another.
+pub fn required_lemmas(indices: &[u32]) -> Option<usize> {
+    // Indices are strictly increasing, the walk starts from the deepest node
+    let mut queue: VecDeque<u64> = indices.iter().rev().map(|i| u64::from(*i)).collect();
+    let mut lemmas = 0;
+    while let Some(index) = queue.pop_front() {
+        if index == 0 {
+            return queue.is_empty().then_some(lemmas);
+        }
+        let sibling = ((index + 1) ^ 1) - 1;
+        if queue.front() == Some(&sibling) {
+            queue.pop_front();
+        } else {
+            lemmas += 1;
+        }
+        queue.push_back((index - 1) >> 1);
+    }
+    None
+}
+
+/// Reads an unsigned LEB128 varint from the start of `data`, along with the
+/// number of bytes it takes. `Ok(None)` when `data` ends before the varint
+/// does. Varints must fit into u32 and be minimally encoded, so every number
+/// has exactly one encoding.
+fn read_varint(data: &[u8]) -> Result<Option<(u32, usize)>, i32> {
+    let mut value = 0u32;
+    for i in 0..5 {
+        let Some(byte) = data.get(i) else {
+            return Ok(None);
+        };
+        if i == 4 && *byte > 0x0f {
+            debug!("Varint in merkle proof overflows u32!");
+            return Err(ERROR_CODE_PROOF_READER);
+        }
+        value |= u32::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            if i > 0 && *byte == 0 {
+                debug!("Varint in merkle proof is not minimally encoded!");
+                return Err(ERROR_CODE_PROOF_READER);
+            }
+            return Ok(Some((value, i + 1)));
+        }
+    }
+    unreachable!("the fifth byte of a varint never continues")
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 enum ReadState {
+    Format,
+    RootIndex,
     HeaderIndex,
     IndicesLength,
     Indices,
+    LeafTags,
     LemmasLength,
+    SplitLemmas,
     Lemmas,
+    LeafHash,
     Completed,
 }
 
+/// Merkle proof related data parsed from the witness.
+pub struct WitnessProof<M> {
+    pub header_index: u32,
+    /// Merkle root slot named by witness, for cells locked with
+    /// `LOCK_EXT_FLAG_ROOT_INDEX`
+    pub root_index: Option<u32>,
+    /// `None` for the compact form of single leaf trees
+    pub merkle_proof: Option<MerkleProof<Data, M>>,
+    /// Precomputed leaf hash supplied by the prover, never to be trusted
+    pub leaf_hash: Option<Data>,
+}
+
+/// Parses the merkle proof from witness lock, which is laid out as:
+///
+/// * (If reading with a root index) Root index as little-endian u32
+/// * Header index as little-endian u32
+/// * Indices count as little-endian u32, must not be zero
+/// * Indices, each a little-endian u32 CBMT node index. One per proven leaf
+///   in the order of the leaves sorted by hash, which must be strictly
+///   increasing
+/// * Leaf tags, one byte per index. Indices follow the order of the leaves
+///   sorted by hash, tags the order of the input cells declaring them
+/// * Lemmas count as little-endian u32, or SPLIT_LEMMAS. It must be exactly
+///   the number of lemmas the proven leaves need
+/// * Lemmas, 32 bytes each
+/// * (Optional) Precomputed leaf hash of the first input cell, 32 bytes
+///
+/// Under `LOCK_EXT_FLAG_PROOF_FORMAT` a format byte comes first. With
+/// `PROOF_FORMAT_VARINT`, every little-endian u32 above is a LEB128 varint
+/// instead, split lemmas included. Each lemma is then encoded as the length
+/// of the prefix it shares with the previous lemma, zero for the first one,
+/// followed by the remaining bytes of the lemma.
 #[derive(Debug)]
 pub struct ProofVisitor {
     state: ReadState,
+    /// State the visitor moves to once the format byte is read
+    after_format: ReadState,
+    /// Set by `PROOF_FORMAT_VARINT`
+    varint: bool,
+    /// `None` until the format byte is read, or when there is none
+    format: Option<u8>,
     total: usize,
+    /// Bytes of the field being parsed that are not fed yet, declared counts
+    /// must fit into them and the buffered data
+    unfed: usize,
 
     buffer: FixedBuffer,
 
+    root_index: Option<u32>,
     header_index: u32,
     indices: Vec<u32>,
+    leaf_tags: Vec<u8>,
     lemmas: Vec<Data>,
+    leaf_hash: Option<Data>,
+    /// Set when the lemma count exceeds what `MAX_TREE_DEPTH` allows
+    too_deep: bool,
 }
 
 impl Default for ProofVisitor {
     fn default() -> Self {
         Self {
             state: ReadState::HeaderIndex,
+            after_format: ReadState::HeaderIndex,
+            varint: false,
+            format: None,
             total: 0,
+            unfed: 0,
             buffer: FixedBuffer::default(),
+            root_index: None,
             header_index: u32::MAX,
             indices: Vec::new(),
+            leaf_tags: Vec::new(),
             lemmas: Vec::new(),
+            leaf_hash: None,
+            too_deep: false,
         }
     }
 }
 
 impl ProofVisitor {
-    pub fn build<M: Merge<Item = Data>>(self) -> Option<(u32, MerkleProof<Data, M>)> {
-        if self.state != ReadState::Completed {
-            debug!("Witness does not provide a complete merkle proof!");
-            return None;
+    /// Parses a proof preceded by the index of the merkle root slot it is
+    /// verified against.
+    pub fn with_root_index() -> Self {
+        Self {
+            state: ReadState::RootIndex,
+            ..Default::default()
+        }
+    }
+
+    /// Parses a proof starting with a format byte, for cells locked with
+    /// `LOCK_EXT_FLAG_PROOF_FORMAT`.
+    pub fn with_format_byte(self) -> Self {
+        Self {
+            state: ReadState::Format,
+            after_format: self.state,
+            ..self
+        }
+    }
+
+    /// A witness containing nothing but the header index is the compact form
+    /// for single leaf trees, no merkle proof is returned in this case since
+    /// the root is the leaf itself.
+    pub fn build<M: Merge<Item = Data>>(self) -> Option<WitnessProof<M>> {
+        match self.state {
+            ReadState::IndicesLength if self.buffer.data().is_empty() => {
+                return Some(WitnessProof {
+                    header_index: self.header_index,
+                    root_index: self.root_index,
+                    merkle_proof: None,
+                    leaf_hash: None,
+                });
+            }
+            ReadState::LeafHash if self.buffer.data().is_empty() => (),
+            ReadState::Completed => (),
+            _ => {
+                debug!("Witness does not provide a complete merkle proof!");
+                return None;
+            }
+        }
+        Some(WitnessProof {
+            header_index: self.header_index,
+            root_index: self.root_index,
+            merkle_proof: Some(MerkleProof::new(self.indices, self.lemmas)),
+            leaf_hash: self.leaf_hash,
+        })
+    }
+
+    /// Index of the header dep the proof names, available as soon as it is
+    /// parsed.
+    pub fn header_index(&self) -> Option<u32> {
+        match self.state {
+            ReadState::Format | ReadState::RootIndex | ReadState::HeaderIndex => None,
+            _ => Some(self.header_index),
+        }
+    }
+
+    /// Tags of all proven leaves, available as soon as they are parsed. The
+    /// compact form proves a single upgrade leaf.
+    pub fn leaf_tags(&self) -> Option<&[u8]> {
+        match self.state {
+            ReadState::IndicesLength if self.buffer.data().is_empty() => {
+                Some(&[LeafTag::Upgrade as u8])
+            }
+            ReadState::Format
+            | ReadState::RootIndex
+            | ReadState::HeaderIndex
+            | ReadState::IndicesLength
+            | ReadState::Indices
+            | ReadState::LeafTags => None,
+            _ => Some(&self.leaf_tags),
         }
-        Some((
-            self.header_index,
-            MerkleProof::new(self.indices, self.lemmas),
-        ))
+    }
+
+    /// True when nothing has been fed to the visitor, i.e. witness lock is
+    /// empty.
+    pub fn is_empty(&self) -> bool {
+        let initial = match self.state {
+            ReadState::Format => true,
+            ReadState::RootIndex => self.format.is_none(),
+            ReadState::HeaderIndex => self.format.is_none() && self.root_index.is_none(),
+            _ => false,
+        };
+        initial && self.buffer.data().is_empty()
+    }
+
+    /// True when parsing stopped at a lemma count deeper trees would need
+    /// than `MAX_TREE_DEPTH` allows.
+    pub fn too_deep(&self) -> bool {
+        self.too_deep
+    }
+
+    /// True when witness lock declares that lemmas are split into witness
+    /// input_type.
+    pub fn awaiting_split_lemmas(&self) -> bool {
+        self.state == ReadState::SplitLemmas
+    }
+
+    /// Continues parsing lemmas, lemma count included, from the data fed next.
+    pub fn resume_split_lemmas(&mut self) {
+        self.state = ReadState::LemmasLength;
+    }
+
+    /// Announces the length of the field fed next, i.e. witness lock or the
+    /// input_type carrying split lemmas.
+    pub fn expect(&mut self, length: usize) {
+        self.unfed = length;
+    }
+
+    fn available(&self) -> usize {
+        self.unfed + self.buffer.data().len()
+    }
+
+    /// Takes a number off the buffer in the format of the proof, `Ok(None)`
+    /// when the buffer does not hold all of it yet.
+    fn take_number(&mut self) -> Result<Option<u32>, i32> {
+        let data = self.buffer.data();
+        let number = if self.varint {
+            read_varint(data)?
+        } else if data.len() >= 4 {
+            let mut t = [0u8; 4];
+            t.copy_from_slice(&data[0..4]);
+            Some((u32::from_le_bytes(t), 4))
+        } else {
+            None
+        };
+        Ok(number.map(|(number, len)| {
+            self.buffer.consume(len);
+            number
+        }))
     }
 
     fn process_internal_data(&mut self) -> i32 {
@@ -98,61 +350,165 @@ impl ProofVisitor {
             let mut changed = false;
             let data = self.buffer.data();
             match self.state {
-                ReadState::HeaderIndex => {
-                    if data.len() >= 4 {
-                        let mut t = [0u8; 4];
-                        t.copy_from_slice(&data[0..4]);
-                        self.buffer.consume(4);
-                        self.header_index = u32::from_le_bytes(t);
-                        self.state = ReadState::IndicesLength;
+                ReadState::Format => {
+                    if let Some(format) = data.first().copied() {
+                        self.buffer.consume(1);
+                        match format {
+                            PROOF_FORMAT_FIXED => (),
+                            PROOF_FORMAT_VARINT => self.varint = true,
+                            _ => {
+                                debug!("Unknown merkle proof format: {:#x}", format);
+                                return ERROR_CODE_PROOF_READER;
+                            }
+                        }
+                        self.format = Some(format);
+                        self.state = self.after_format;
                         changed = true;
                     }
                 }
-                ReadState::IndicesLength => {
-                    if data.len() >= 4 {
-                        let mut t = [0u8; 4];
-                        t.copy_from_slice(&data[0..4]);
-                        self.buffer.consume(4);
-                        self.total = u32::from_le_bytes(t) as usize;
+                ReadState::RootIndex => match self.take_number() {
+                    Ok(Some(root_index)) => {
+                        self.root_index = Some(root_index);
+                        self.state = ReadState::HeaderIndex;
+                        changed = true;
+                    }
+                    Ok(None) => (),
+                    Err(code) => return code,
+                },
+                ReadState::HeaderIndex => match self.take_number() {
+                    Ok(Some(header_index)) => {
+                        self.header_index = header_index;
+                        self.state = ReadState::IndicesLength;
+                        changed = true;
+                    }
+                    Ok(None) => (),
+                    Err(code) => return code,
+                },
+                ReadState::IndicesLength => match self.take_number() {
+                    Ok(Some(total)) => {
+                        self.total = total as usize;
+                        if self.total == 0 {
+                            debug!("Merkle proof does not prove any leaf!");
+                            return ERROR_CODE_PROOF_READER;
+                        }
+                        // Each index is followed by a leaf tag in the same
+                        // field, varint indices take at least a byte
+                        let index_length = if self.varint { 2 } else { 5 };
+                        if self.total > self.available() / index_length {
+                            debug!("Merkle proof declares more indices than witness holds!");
+                            return ERROR_CODE_PROOF_READER;
+                        }
                         self.indices = Vec::with_capacity(self.total as usize);
                         self.state = ReadState::Indices;
                         changed = true;
                     }
-                }
+                    Ok(None) => (),
+                    Err(code) => return code,
+                },
                 ReadState::Indices => {
                     if self.indices.len() >= self.total {
+                        self.leaf_tags = Vec::with_capacity(self.total);
+                        self.state = ReadState::LeafTags;
+                        changed = true;
+                    } else {
+                        match self.take_number() {
+                            Ok(Some(index)) => {
+                                // Rules out duplicate leaves, and pins the
+                                // order of indices to the order of leaves
+                                // sorted by hash
+                                if self.indices.last().map_or(false, |last| index <= *last) {
+                                    debug!("Merkle proof indices are not strictly increasing!");
+                                    return ERROR_CODE_PROOF_READER;
+                                }
+                                self.indices.push(index);
+                                changed = true;
+                            }
+                            Ok(None) => (),
+                            Err(code) => return code,
+                        }
+                    }
+                }
+                ReadState::LeafTags => {
+                    if self.leaf_tags.len() >= self.total {
                         self.state = ReadState::LemmasLength;
                         changed = true;
-                    } else if data.len() >= 4 {
-                        let mut t = [0u8; 4];
-                        t.copy_from_slice(&data[0..4]);
-                        self.buffer.consume(4);
-                        self.indices.push(u32::from_le_bytes(t));
+                    } else if !data.is_empty() {
+                        let count = cmp::min(data.len(), self.total - self.leaf_tags.len());
+                        self.leaf_tags.extend_from_slice(&data[..count]);
+                        self.buffer.consume(count);
                         changed = true;
                     }
                 }
-                ReadState::LemmasLength => {
-                    if data.len() >= 4 {
-                        let mut t = [0u8; 4];
-                        t.copy_from_slice(&data[0..4]);
-                        self.buffer.consume(4);
-                        self.total = u32::from_le_bytes(t) as usize;
+                ReadState::LemmasLength => match self.take_number() {
+                    Ok(Some(total)) => {
+                        if total == SPLIT_LEMMAS {
+                            self.state = ReadState::SplitLemmas;
+                            continue;
+                        }
+                        self.total = total as usize;
+                        // Each proven leaf needs at most one lemma per tree level
+                        if self.total > self.indices.len().saturating_mul(MAX_TREE_DEPTH) {
+                            debug!("Merkle proof exceeds maximum tree depth!");
+                            self.too_deep = true;
+                            return ERROR_CODE_TREE_TOO_DEEP;
+                        }
+                        // A lemma sharing all of its bytes takes a single byte
+                        let lemma_length = if self.varint { 1 } else { 32 };
+                        if self.total > self.available() / lemma_length {
+                            debug!("Merkle proof declares more lemmas than witness holds!");
+                            return ERROR_CODE_PROOF_READER;
+                        }
+                        // Lemmas left over or missing would only surface when
+                        // the root is computed, so the count must be exact
+                        if required_lemmas(&self.indices) != Some(self.total) {
+                            debug!("Lemma count does not match the proven leaves!");
+                            return ERROR_CODE_PROOF_READER;
+                        }
                         self.lemmas = Vec::with_capacity(self.total as usize);
                         self.state = ReadState::Lemmas;
                         changed = true;
                     }
-                }
+                    Ok(None) => (),
+                    Err(code) => return code,
+                },
                 ReadState::Lemmas => {
                     if self.lemmas.len() >= self.total {
-                        self.state = ReadState::Completed;
+                        self.state = ReadState::LeafHash;
                         changed = true;
+                    } else if self.varint {
+                        if let Some(shared) = data.first().map(|shared| *shared as usize) {
+                            let previous = self.lemmas.last();
+                            if shared > 32 || (previous.is_none() && shared > 0) {
+                                debug!("Lemma shares more bytes than the previous lemma has!");
+                                return ERROR_CODE_PROOF_READER;
+                            }
+                            let length = 1 + 32 - shared;
+                            if data.len() >= length {
+                                let mut lemma = [0u8; 32];
+                                if let Some(previous) = previous {
+                                    lemma[..shared].copy_from_slice(&previous.as_bytes()[..shared]);
+                                }
+                                lemma[shared..].copy_from_slice(&data[1..length]);
+                                self.lemmas.push(Data::new(lemma));
+                                self.buffer.consume(length);
+                                changed = true;
+                            }
+                        }
                     } else if data.len() >= 32 {
                         self.lemmas.push(Data::from_slice(&data[0..32]));
                         self.buffer.consume(32);
                         changed = true;
                     }
                 }
-                ReadState::Completed => break,
+                ReadState::LeafHash => {
+                    if data.len() >= 32 {
+                        self.leaf_hash = Some(Data::from_slice(&data[0..32]));
+                        self.buffer.consume(32);
+                        self.state = ReadState::Completed;
+                        changed = true;
+                    }
+                }
+                ReadState::SplitLemmas | ReadState::Completed => break,
             }
             if !changed {
                 break;
@@ -164,12 +520,14 @@ impl ProofVisitor {
     pub fn process(&mut self, data: &[u8]) -> i32 {
         let mut consumed = 0;
         loop {
-            consumed += self.buffer.fill(&data[consumed..]);
+            let filled = self.buffer.fill(&data[consumed..]);
+            self.unfed = self.unfed.saturating_sub(filled);
+            consumed += filled;
             let ret = self.process_internal_data();
             if ret != 0 {
                 return ret;
             }
-            if self.state == ReadState::Completed
+            if (self.state == ReadState::Completed || self.state == ReadState::SplitLemmas)
                 && (self.buffer.data().len() > 0 || consumed < data.len())
             {
                 debug!("Merkle proof is fully parsed, but trailing data is found!");
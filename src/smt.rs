@@ -0,0 +1,102 @@
+//! Parsing and verification of sparse merkle proofs, which read nothing but
+//! the bytes they are given. `ckb-zero-lock-types` builds this file as well.
+
+use super::{merge::merge_blake2b, proof_reader::read_u32};
+use alloc::vec::Vec;
+
+/// Height of the sparse merkle tree, one level per bit of a 32-byte key.
+pub const SMT_HEIGHT: usize = 256;
+
+/// Sparse merkle proof parsed from witness lock, which is laid out as:
+///
+/// * Header index as little-endian u32
+/// * Leaf tag, one byte
+/// * Bitmap of 32 bytes, bit `h` (least significant bit first) is set when
+///   the sibling at height `h` is not an empty subtree
+/// * Siblings that are not empty subtrees, 32 bytes each, from the bottom
+///   of the tree up
+///
+/// Keys are blake2b hashes of the input cell's data, i.e. the old contract.
+/// The path of a key runs from its most significant bit at the root down to
+/// its least significant bit right above the leaf.
+#[derive(Debug)]
+pub struct SmtProof {
+    pub header_index: u32,
+    pub leaf_tag: u8,
+    bitmap: [u8; 32],
+    siblings: Vec<[u8; 32]>,
+}
+
+fn is_empty(node: &[u8; 32]) -> bool {
+    node.iter().all(|b| *b == 0)
+}
+
+/// Two empty subtrees merge into an empty subtree, so a tree without any
+/// leaf has the all zero root.
+fn merge(lhs: &[u8; 32], rhs: &[u8; 32]) -> [u8; 32] {
+    if is_empty(lhs) && is_empty(rhs) {
+        [0u8; 32]
+    } else {
+        merge_blake2b(lhs, rhs)
+    }
+}
+
+impl SmtProof {
+    /// Parses a sparse merkle proof, `None` when it is malformed.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let (header_index, rest) = read_u32(data)?;
+        let (leaf_tag, rest) = rest.split_first()?;
+        if rest.len() < 32 {
+            return None;
+        }
+        let (bitmap_data, rest) = rest.split_at(32);
+        let mut bitmap = [0u8; 32];
+        bitmap.copy_from_slice(bitmap_data);
+        let count = bitmap
+            .iter()
+            .map(|b| b.count_ones() as usize)
+            .sum::<usize>();
+        if rest.len() != count * 32 {
+            return None;
+        }
+        let siblings = rest
+            .chunks(32)
+            .map(|chunk| {
+                let mut sibling = [0u8; 32];
+                sibling.copy_from_slice(chunk);
+                sibling
+            })
+            .collect();
+        Some(Self {
+            header_index: header_index as u32,
+            leaf_tag: *leaf_tag,
+            bitmap,
+            siblings,
+        })
+    }
+
+    /// Computes the root of a tree holding `value` at `key`, an all zero
+    /// value proves that `key` is absent from the tree instead.
+    pub fn root(&self, key: &[u8; 32], value: &[u8; 32]) -> [u8; 32] {
+        let mut node = if is_empty(value) {
+            [0u8; 32]
+        } else {
+            merge_blake2b(key, value)
+        };
+        let mut siblings = self.siblings.iter();
+        for height in 0..SMT_HEIGHT {
+            let sibling = if self.bitmap[height / 8] & (1 << (height % 8)) != 0 {
+                *siblings.next().expect("sibling counted in bitmap")
+            } else {
+                [0u8; 32]
+            };
+            let depth = SMT_HEIGHT - 1 - height;
+            node = if key[depth / 8] & (0x80 >> (depth % 8)) != 0 {
+                merge(&sibling, &node)
+            } else {
+                merge(&node, &sibling)
+            };
+        }
+        node
+    }
+}
@@ -0,0 +1,89 @@
+//! Parsing and verification of co-proofs, which read nothing but the bytes
+//! they are given. `ckb-zero-lock-types` builds this file as well.
+
+use super::{
+    merge::Data,
+    proof_reader::{read_u32, required_lemmas},
+};
+use alloc::vec::Vec;
+use merkle_cbt::{merkle_tree::Merge, MerkleProof};
+
+/// Proof of the same leaves as the merkle proof in witness, against the root
+/// of another committee. Co-proofs are carried one after another in witness
+/// input_type of the first input cell under `LOCK_EXT_FLAG_THRESHOLD_PROOFS`,
+/// each laid out as:
+///
+/// * Root index as little-endian u32
+/// * Indices count as little-endian u32, zero for the compact form of single
+///   leaf trees
+/// * Indices, each a little-endian u32 CBMT node index. One per proven leaf
+///   in the order of the leaves sorted by hash, which must be strictly
+///   increasing
+/// * Lemmas count as little-endian u32. It must be exactly the number of
+///   lemmas the proven leaves need
+/// * Lemmas, 32 bytes each
+#[derive(Debug)]
+pub struct CoProof {
+    pub root_index: u32,
+    indices: Vec<u32>,
+    lemmas: Vec<Data>,
+}
+
+impl CoProof {
+    /// Parses the co-proofs carried one after another in `data`, `None`
+    /// when any of them is malformed.
+    pub fn parse_all(mut data: &[u8]) -> Option<Vec<Self>> {
+        let mut co_proofs = Vec::new();
+        while !data.is_empty() {
+            let (co_proof, rest) = Self::parse(data)?;
+            co_proofs.push(co_proof);
+            data = rest;
+        }
+        Some(co_proofs)
+    }
+
+    fn parse(data: &[u8]) -> Option<(Self, &[u8])> {
+        let (root_index, rest) = read_u32(data)?;
+        let (indices_count, mut rest) = read_u32(rest)?;
+        if rest.len() < indices_count.checked_mul(4)? {
+            return None;
+        }
+        let mut indices = Vec::with_capacity(indices_count);
+        for _ in 0..indices_count {
+            let (index, r) = read_u32(rest)?;
+            if indices.last().map_or(false, |last| *last >= index as u32) {
+                return None;
+            }
+            indices.push(index as u32);
+            rest = r;
+        }
+        let (lemmas_count, rest) = read_u32(rest)?;
+        let required = if indices.is_empty() {
+            0
+        } else {
+            required_lemmas(&indices)?
+        };
+        if lemmas_count != required || rest.len() < lemmas_count * 32 {
+            return None;
+        }
+        let (lemmas, rest) = rest.split_at(lemmas_count * 32);
+        let co_proof = Self {
+            root_index: root_index as u32,
+            indices,
+            lemmas: lemmas.chunks(32).map(Data::from_slice).collect(),
+        };
+        Some((co_proof, rest))
+    }
+
+    /// Computes the root of the committee tree from `leaves`, `None` when the
+    /// co-proof does not cover exactly these leaves.
+    pub fn root<M: Merge<Item = Data>>(&self, leaves: &[Data]) -> Option<Data> {
+        if self.indices.is_empty() {
+            return (leaves.len() == 1).then(|| leaves[0].clone());
+        }
+        if self.indices.len() != leaves.len() {
+            return None;
+        }
+        MerkleProof::<Data, M>::new(self.indices.clone(), self.lemmas.clone()).root(leaves)
+    }
+}
@@ -1,50 +1,128 @@
-use super::{proof_reader::ProofVisitor, ERROR_CODE_WITNESS_READER};
+use super::{
+    error::Error, proof_reader::ProofVisitor, ERROR_CODE_INVALID_WITNESS_ARGS,
+    ERROR_CODE_WITNESS_READER, WITNESS_LAYOUT_SIGHASH_ALL, WITNESS_LAYOUT_SIGHASH_ALL_ONLY,
+};
 use blake2b_ref::Blake2b;
-use ckb_std::{ckb_constants::Source, debug};
+use ckb_std::{ckb_constants::Source, debug, error::SysError, syscalls};
 use core::{ffi::c_void, slice::from_raw_parts};
 
 pub type DataAccessor = unsafe extern "C" fn(*const u8, usize, *mut c_void) -> i32;
 pub type MetaAccessor = unsafe extern "C" fn(i32, u32, *mut c_void) -> i32;
 
+/// Accessors left as `None` skip the corresponding part of the witness.
 #[repr(C)]
 pub struct Accessors {
     context: *mut c_void,
-    lock_meta_accessor: MetaAccessor,
-    lock_data_accessor: DataAccessor,
-    input_type_meta_accessor: MetaAccessor,
-    input_type_data_accessor: DataAccessor,
-    output_type_meta_accessor: MetaAccessor,
-    output_type_data_accessor: DataAccessor,
+    lock_meta_accessor: Option<MetaAccessor>,
+    lock_data_accessor: Option<DataAccessor>,
+    input_type_meta_accessor: Option<MetaAccessor>,
+    input_type_data_accessor: Option<DataAccessor>,
+    output_type_meta_accessor: Option<MetaAccessor>,
+    output_type_data_accessor: Option<DataAccessor>,
 }
 
 extern "C" {
     fn cwhr_rust_read_witness(index: usize, source: usize, accessors: *const Accessors) -> i32;
 }
 
+/// Where the merkle proof is carried in witness, picked by lock args.
+#[derive(Clone, Copy, Default)]
+pub struct ProofLayout {
+    /// Proof starts with a root index
+    pub root_index: bool,
+    /// Proof is carried in witness input_type, leaving witness lock to other
+    /// scripts. Witness lock is neither parsed nor hashed then, and
+    /// input_type is hashed as absent.
+    pub input_type: bool,
+    /// Witness input_type carries co-proofs, which are parsed separately. It
+    /// is neither parsed nor hashed, but hashed as absent.
+    pub co_proofs: bool,
+    /// Proof starts with a format byte
+    pub format_byte: bool,
+}
+
 struct WitnessVisitor {
     proof: ProofVisitor,
-    remainder_hasher: Blake2b,
+    /// False when the proof is skipped
+    lock: bool,
+    input_type: bool,
+    co_proofs: bool,
+    /// `None` when only witness lock is read
+    remainder_hasher: Option<Blake2b>,
+    input_type_has_lemmas: bool,
+    /// Set when the field carrying the proof is absent or empty
+    proof_missing: bool,
 }
 
 impl WitnessVisitor {
-    pub fn new(remainder_hasher: Blake2b) -> Self {
+    pub fn new(lock: bool, layout: ProofLayout, remainder_hasher: Option<Blake2b>) -> Self {
+        let proof = if layout.root_index {
+            ProofVisitor::with_root_index()
+        } else {
+            ProofVisitor::default()
+        };
+        let proof = if layout.format_byte {
+            proof.with_format_byte()
+        } else {
+            proof
+        };
         Self {
-            proof: ProofVisitor::default(),
+            proof,
+            lock,
+            input_type: layout.input_type,
+            co_proofs: layout.co_proofs,
             remainder_hasher,
+            input_type_has_lemmas: false,
+            proof_missing: false,
+        }
+    }
+
+    /// Error of a witness that could not be visited
+    fn error(&self) -> Error {
+        if self.proof.too_deep() {
+            Error::TreeTooDeep
+        } else {
+            Error::InvalidWitness
         }
     }
 
-    pub fn destruct(self) -> (ProofVisitor, Blake2b) {
+    pub fn destruct(self) -> (ProofVisitor, Option<Blake2b>) {
         (self.proof, self.remainder_hasher)
     }
+
+    fn update_remainder(&mut self, data: &[u8]) {
+        if let Some(remainder_hasher) = &mut self.remainder_hasher {
+            remainder_hasher.update(data);
+        }
+    }
 }
 
 #[no_mangle]
-unsafe extern "C" fn visit_lock_meta(present: i32, _length: u32, _context: *mut c_void) -> i32 {
+unsafe extern "C" fn visit_lock_meta(present: i32, length: u32, context: *mut c_void) -> i32 {
+    let visitor = &mut *(context as *mut WitnessVisitor);
+    visitor.proof_missing = present == 0 || length == 0;
     if present == 0 {
         debug!("Required witness lock is missing!");
         return ERROR_CODE_WITNESS_READER;
     }
+    visitor.proof.expect(length as usize);
+    0
+}
+
+#[no_mangle]
+unsafe extern "C" fn visit_input_type_proof_meta(
+    present: i32,
+    length: u32,
+    context: *mut c_void,
+) -> i32 {
+    let visitor = &mut *(context as *mut WitnessVisitor);
+    visitor.proof_missing = present == 0 || length == 0;
+    if present == 0 {
+        debug!("Required witness input_type is missing!");
+        return ERROR_CODE_WITNESS_READER;
+    }
+    visitor.proof.expect(length as usize);
+    visitor.update_remainder(&[0u8]);
     0
 }
 
@@ -59,10 +137,10 @@ unsafe extern "C" fn visit_lock_data(data: *const u8, length: usize, context: *m
 unsafe extern "C" fn visit_remainder_meta(present: i32, length: u32, context: *mut c_void) -> i32 {
     let visitor = &mut *(context as *mut WitnessVisitor);
     if present != 0 {
-        visitor.remainder_hasher.update(&[1u8]);
-        visitor.remainder_hasher.update(&length.to_le_bytes());
+        visitor.update_remainder(&[1u8]);
+        visitor.update_remainder(&length.to_le_bytes());
     } else {
-        visitor.remainder_hasher.update(&[0u8]);
+        visitor.update_remainder(&[0u8]);
     }
     0
 }
@@ -75,31 +153,239 @@ unsafe extern "C" fn visit_remainder_data(
 ) -> i32 {
     let data = from_raw_parts(data, length);
     let visitor = &mut *(context as *mut WitnessVisitor);
-    visitor.remainder_hasher.update(data);
+    visitor.update_remainder(data);
     0
 }
 
+#[no_mangle]
+unsafe extern "C" fn visit_input_type_meta(present: i32, length: u32, context: *mut c_void) -> i32 {
+    let visitor = &mut *(context as *mut WitnessVisitor);
+    if visitor.proof.awaiting_split_lemmas() {
+        if visitor.co_proofs {
+            debug!("Split lemmas cannot share witness input_type with co-proofs!");
+            return ERROR_CODE_WITNESS_READER;
+        }
+        if present == 0 {
+            debug!("Split merkle proof requires lemmas in witness input_type!");
+            return ERROR_CODE_WITNESS_READER;
+        }
+        // input_type carries nothing but lemmas, which are already tied to
+        // the leaf by merkle proof verification, so it is hashed as absent.
+        // This keeps the leaf the same as a unified proof would produce.
+        visitor.input_type_has_lemmas = true;
+        visitor.proof.resume_split_lemmas();
+        visitor.proof.expect(length as usize);
+        visitor.update_remainder(&[0u8]);
+        return 0;
+    }
+    if visitor.co_proofs {
+        // Like split lemmas, co-proofs are tied to the leaf by merkle proof
+        // verification
+        visitor.update_remainder(&[0u8]);
+        return 0;
+    }
+    visit_remainder_meta(present, length, context)
+}
+
+#[no_mangle]
+unsafe extern "C" fn visit_input_type_data(
+    data: *const u8,
+    length: usize,
+    context: *mut c_void,
+) -> i32 {
+    let visitor = &mut *(context as *mut WitnessVisitor);
+    if visitor.input_type_has_lemmas {
+        return visitor.proof.process(from_raw_parts(data, length));
+    }
+    if visitor.co_proofs {
+        return 0;
+    }
+    visit_remainder_data(data, length, context)
+}
+
+/// Parses the proof and hashes the remainder of witness. Fails with
+/// `TreeTooDeep` when the proof declares more lemmas than `MAX_TREE_DEPTH`
+/// allows, and with `InvalidWitness` for any other malformed witness.
 pub fn read_witness(
     index: usize,
     source: Source,
+    layout: ProofLayout,
     remainder_hasher: Blake2b,
-) -> Option<(ProofVisitor, Blake2b)> {
-    let mut visitor = WitnessVisitor::new(remainder_hasher);
+) -> Result<(ProofVisitor, Blake2b), Error> {
+    let mut visitor = WitnessVisitor::new(true, layout, Some(remainder_hasher));
+    visit_witness(index, source, &mut visitor).ok_or_else(|| visitor.error())?;
+    let (proof, remainder_hasher) = visitor.destruct();
+    Ok((proof, remainder_hasher.expect("remainder hasher")))
+}
+
+/// Hashes nothing but the remainder of witness, for witness locks carrying
+/// something other than a merkle proof. Lemmas are never split into
+/// input_type in this case.
+pub fn read_witness_remainder(
+    index: usize,
+    source: Source,
+    remainder_hasher: Blake2b,
+) -> Option<Blake2b> {
+    let mut visitor = WitnessVisitor::new(false, ProofLayout::default(), Some(remainder_hasher));
+    visit_witness(index, source, &mut visitor)?;
+    visitor.destruct().1
+}
+
+/// Parses nothing but the proof, lemmas split into input_type are left out.
+/// This allows learning about the proof before any leaf is hashed. Fails
+/// with `MissingProof` when there is no witness, or the field carrying the
+/// proof is absent or empty, and with `TreeTooDeep` before any lemma is read
+/// when there are too many of them.
+pub fn read_witness_proof(
+    index: usize,
+    source: Source,
+    layout: ProofLayout,
+) -> Result<ProofVisitor, Error> {
+    match syscalls::load_witness(&mut [], 0, index, source) {
+        Ok(_) | Err(SysError::LengthNotEnough(_)) => (),
+        Err(SysError::IndexOutOfBound) => {
+            debug!("Input cell does not have a witness!");
+            return Err(Error::MissingProof);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let mut visitor = WitnessVisitor::new(true, layout, None);
+    let visited = visit_witness(index, source, &mut visitor);
+    if visitor.proof_missing {
+        debug!("Witness does not carry a proof!");
+        return Err(Error::MissingProof);
+    }
+    visited.ok_or_else(|| visitor.error())?;
+    Ok(visitor.destruct().0)
+}
+
+fn visit_witness(index: usize, source: Source, visitor: &mut WitnessVisitor) -> Option<()> {
+    let lock = visitor.lock && !visitor.input_type;
+    let input_type_proof = visitor.lock && visitor.input_type;
+    let remainder = visitor.remainder_hasher.is_some();
+    let (input_type_meta_accessor, input_type_data_accessor) = if input_type_proof {
+        (
+            Some(visit_input_type_proof_meta as MetaAccessor),
+            Some(visit_lock_data as DataAccessor),
+        )
+    } else {
+        (
+            remainder.then_some(visit_input_type_meta as MetaAccessor),
+            remainder.then_some(visit_input_type_data as DataAccessor),
+        )
+    };
     let accessors = Accessors {
-        context: &mut visitor as *mut WitnessVisitor as *mut _,
-        lock_meta_accessor: visit_lock_meta,
-        lock_data_accessor: visit_lock_data,
-        input_type_meta_accessor: visit_remainder_meta,
-        input_type_data_accessor: visit_remainder_data,
-        output_type_meta_accessor: visit_remainder_meta,
-        output_type_data_accessor: visit_remainder_data,
+        context: visitor as *mut WitnessVisitor as *mut _,
+        lock_meta_accessor: lock.then_some(visit_lock_meta as MetaAccessor),
+        lock_data_accessor: lock.then_some(visit_lock_data as DataAccessor),
+        input_type_meta_accessor,
+        input_type_data_accessor,
+        output_type_meta_accessor: remainder.then_some(visit_remainder_meta as MetaAccessor),
+        output_type_data_accessor: remainder.then_some(visit_remainder_data as DataAccessor),
     };
 
+    // WitnessArgs is tried before WitnessLayout, which keeps the common case
+    // to a single pass over the witness. The order cannot change how a
+    // witness is read: read as the total size of a WitnessArgs, the union id
+    // a WitnessLayout starts with exceeds the length of any witness, so no
+    // witness is valid as both.
     let result =
         unsafe { cwhr_rust_read_witness(index, source as usize, &accessors as *const Accessors) };
+    if result == ERROR_CODE_INVALID_WITNESS_ARGS {
+        return read_raw_witness(index, source, visitor);
+    }
     if result != 0 {
         debug!("Error reading witness! Return code: {}", result);
         return None;
     }
-    Some(visitor.destruct())
+    Some(())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> usize {
+    let mut t = [0u8; 4];
+    t.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(t) as usize
+}
+
+/// Finds the seal of a witness laid out as `SighashAll` or `SighashAllOnly`,
+/// returning the offset its content starts at. The seal is the last field of
+/// both, so it runs to the end of witness. `None` for any other witness,
+/// including other `WitnessLayout` variants. A union id read as the total
+/// size of a WitnessArgs exceeds any witness, so the two never overlap.
+fn witness_layout_seal(index: usize, source: Source) -> Option<usize> {
+    // Union id, total size of the table and the offsets of its fields, or
+    // the length of the seal of SighashAllOnly
+    let mut header = [0u8; 16];
+    let total_length = match syscalls::load_witness(&mut header, 0, index, source) {
+        Ok(length) | Err(SysError::LengthNotEnough(length)) => length,
+        Err(_) => return None,
+    };
+    if total_length < header.len() {
+        return None;
+    }
+    let fields = match u32_at(&header, 0) as u32 {
+        WITNESS_LAYOUT_SIGHASH_ALL => 2,
+        WITNESS_LAYOUT_SIGHASH_ALL_ONLY => 1,
+        _ => return None,
+    };
+    if u32_at(&header, 4) != total_length - 4 || u32_at(&header, 8) != 4 + 4 * fields {
+        return None;
+    }
+    let seal_offset = 4 + u32_at(&header, 4 + 4 * fields);
+    let mut seal_length = [0u8; 4];
+    match syscalls::load_witness(&mut seal_length, seal_offset, index, source) {
+        Ok(_) | Err(SysError::LengthNotEnough(_)) => (),
+        Err(_) => return None,
+    }
+    let seal_length = u32_at(&seal_length, 0);
+    if seal_offset.checked_add(4 + seal_length) != Some(total_length) {
+        return None;
+    }
+    Some(seal_offset + 4)
+}
+
+/// Reads a witness that is not a valid WitnessArgs as the raw content of
+/// witness lock, or the seal of a `WitnessLayout` carrying one. The message
+/// of `SighashAll` is left to other scripts, like witness lock under
+/// LOCK_EXT_FLAG_INPUT_TYPE_PROOF.
+fn read_raw_witness(index: usize, source: Source, visitor: &mut WitnessVisitor) -> Option<()> {
+    if !visitor.lock {
+        debug!("Witness is not a valid WitnessArgs!");
+        return None;
+    }
+    let start = witness_layout_seal(index, source).unwrap_or(0);
+    let mut loaded = 0;
+    let mut buf = [0u8; 4096];
+    loop {
+        let (length, completed, total_length) =
+            match syscalls::load_witness(&mut buf, start + loaded, index, source) {
+                Ok(actual_loaded_len) => (actual_loaded_len, true, actual_loaded_len),
+                Err(SysError::LengthNotEnough(total_length)) => (buf.len(), false, total_length),
+                Err(e) => {
+                    debug!("Error loading raw witness: {:?}", e);
+                    return None;
+                }
+            };
+        // The first load reports the length of the whole witness
+        if loaded == 0 {
+            visitor.proof_missing = total_length == 0;
+            visitor.proof.expect(total_length);
+        }
+        let result = visitor.proof.process(&buf[..length]);
+        if result != 0 {
+            debug!(
+                "Witness is neither a valid WitnessArgs nor a raw merkle proof! Return code: {}",
+                result
+            );
+            return None;
+        }
+        if completed {
+            break;
+        }
+        loaded += length;
+    }
+    // Neither a raw witness nor a WitnessLayout has input_type or output_type
+    visitor.update_remainder(&[0u8]);
+    visitor.update_remainder(&[0u8]);
+    Some(())
 }
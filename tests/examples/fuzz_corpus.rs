@@ -0,0 +1,59 @@
+//! Seeds the corpus of the `proof_parser` fuzz target with witnesses of
+//! valid proofs. Each corpus entry is the extended lock flags byte followed
+//! by the witness.
+use ckb_types::{
+    bytes::Bytes,
+    packed::{Byte32, WitnessArgs},
+    prelude::*,
+};
+use ckb_zero_lock_tests::{
+    bury_in_merkle_tree, compact_proof_witness, proof_lock_data, root_indexed_witness,
+    split_proof_witness, zero_lock_cell, DummyDataLoader, LOCK_EXT_FLAG_ROOT_INDEX, MAX_TREE_DEPTH,
+};
+use rand::{rngs::StdRng, SeedableRng};
+
+fn main() {
+    let dir = std::path::Path::new("../fuzz/corpus/proof_parser");
+    std::fs::create_dir_all(dir).expect("create corpus dir");
+
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, None);
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, None);
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut seeds: Vec<(u8, Bytes)> = vec![(0, compact_proof_witness(0))];
+    for entries in [1, 6, 100] {
+        let (_root, proof_witness) = bury_in_merkle_tree(
+            &input_cell_meta,
+            &output_cell_meta,
+            entries,
+            &mut rng,
+            0,
+            None,
+            None,
+        );
+        seeds.push((
+            LOCK_EXT_FLAG_ROOT_INDEX,
+            root_indexed_witness(1, proof_witness.clone()),
+        ));
+        seeds.push((0, split_proof_witness(proof_witness.clone())));
+        seeds.push((0, proof_witness));
+    }
+    // One lemma more than the deepest supported tree needs, rejected with
+    // TreeTooDeep
+    let lemmas = vec![Byte32::zero(); MAX_TREE_DEPTH + 1];
+    let deep_witness = WitnessArgs::new_builder()
+        .lock(Some(proof_lock_data(0, &[0], &lemmas)).pack())
+        .build()
+        .as_bytes();
+    seeds.push((0, deep_witness));
+
+    for (i, (ext_flags, witness)) in seeds.iter().enumerate() {
+        let mut entry = vec![*ext_flags];
+        entry.extend(witness.iter());
+        std::fs::write(dir.join(format!("seed-{}", i)), entry).expect("write seed");
+    }
+    println!("Wrote {} seeds to {}", seeds.len(), dir.display());
+}
@@ -0,0 +1,7 @@
+use ckb_types::core::HeaderView;
+
+/// Checks that `header` is buried under at least `confirmations` blocks as
+/// seen from `tip`.
+pub fn is_final(header: &HeaderView, tip: &HeaderView, confirmations: u64) -> bool {
+    tip.number() >= header.number().saturating_add(confirmations)
+}
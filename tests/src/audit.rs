@@ -0,0 +1,32 @@
+use super::prover::plan_upgrades;
+use ckb_types::{core::cell::CellMeta, packed::Byte32};
+use ckb_zero_lock_types::{LeafTag, UpgradeProofBuilder};
+
+/// Root of the tree holding `leaves`, which sorts them by hash.
+fn tree_root(leaves: &[Byte32]) -> Byte32 {
+    UpgradeProofBuilder::default()
+        .leaves(leaves.iter().map(|leaf| (LeafTag::Upgrade, leaf.clone())))
+        .root()
+}
+
+/// Checks that a committee's published root matches the tree rebuilt from
+/// the full list of announced upgrades.
+pub fn verify_published(plans: &[(&CellMeta, &CellMeta)], published_root: &Byte32) -> bool {
+    tree_root(&plan_upgrades(plans)) == *published_root
+}
+
+/// Checks that a committee's new merkle tree only appends leaves to the old
+/// one: `new_leaves` must start with all of `old_leaves`, and both roots must
+/// match the leaves they are claimed to be built from. Trees sort their
+/// leaves, the lists keep the order leaves were announced in.
+pub fn verify_append(
+    old_leaves: &[Byte32],
+    new_leaves: &[Byte32],
+    old_root: &Byte32,
+    new_root: &Byte32,
+) -> bool {
+    if !new_leaves.starts_with(old_leaves) {
+        return false;
+    }
+    tree_root(old_leaves) == *old_root && tree_root(new_leaves) == *new_root
+}
@@ -1,8 +1,16 @@
 #[cfg(test)]
 mod tests;
 
+pub mod analyze;
+pub mod audit;
+pub mod prover;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+pub mod testgen;
+pub mod testutil;
+
 use ckb_chain_spec::consensus::{ConsensusBuilder, TYPE_ID_CODE_HASH};
-use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_hash::blake2b_256;
 use ckb_script::{TransactionScriptsVerifier, TxVerifyEnv};
 use ckb_traits::{CellDataProvider, ExtensionProvider, HeaderProvider};
 use ckb_types::{
@@ -10,16 +18,38 @@ use ckb_types::{
     core::{
         cell::{CellMeta, CellMetaBuilder, ResolvedTransaction},
         hardfork::{HardForks, CKB2021, CKB2023},
-        Capacity, DepType, EpochExt, HeaderBuilder, HeaderView, ScriptHashType, TransactionBuilder,
+        Capacity, DepType, EpochExt, EpochNumberWithFraction, HeaderBuilder, HeaderView,
+        ScriptHashType, TransactionBuilder,
     },
     packed::{self, Byte32, CellDep, CellInput, CellOutput, OutPoint, Script, WitnessArgs},
     prelude::*,
 };
+pub use ckb_zero_lock_types::{
+    code_dep_data, deployment_id, frozen_data, slot_for, smt, Blake2bHash, LeafTag,
+    EXTENSION_FIELD_KILL_SWITCH, EXTENSION_FIELD_MERGE_HASH, EXTENSION_FIELD_MIN_EPOCHS,
+    EXTENSION_FIELD_ROOTS, EXTENSION_FIELD_SMT_ROOT, EXTENSION_FIELD_SUCCESSOR_CODE_HASHES,
+    EXTENSION_FIELD_TAGGED_ROOTS, EXTENSION_FIELD_THRESHOLD, EXTENSION_MAGIC,
+    EXTENSION_ROOT_OFFSET, FROZEN_MAGIC, LEAF_TAG_FLAG_OUTPUT_INDEX, LEAF_TAG_FLAG_RETYPE,
+    LEGACY_EXTENSION_MIN_LENGTH, LOCK_EXT_FLAG_ALLOW_SAME_DATA, LOCK_EXT_FLAG_CODE_DEP,
+    LOCK_EXT_FLAG_GOVERNANCE_CELL, LOCK_EXT_FLAG_INPUT_TYPE_PROOF, LOCK_EXT_FLAG_PROOF_FORMAT,
+    LOCK_EXT_FLAG_ROOT_INDEX, LOCK_EXT_FLAG_THRESHOLD_PROOFS, LOCK_FLAG_ACTIVATION,
+    LOCK_FLAG_EXTENDED, LOCK_FLAG_MIN_CONFIRMATIONS, LOCK_FLAG_PATCH, LOCK_FLAG_PRESERVE_STRUCTURE,
+    LOCK_FLAG_SALTED_ROOT, LOCK_FLAG_SMT, LOCK_FLAG_VERSIONED, MAX_TREE_DEPTH, MERGE_HASH_BLAKE2B,
+    MERGE_HASH_SHA256, PROOF_FORMAT_FIXED, PROOF_FORMAT_VARINT, SPLIT_LEMMAS,
+};
+use ckb_zero_lock_types::{
+    compute_code_dep_upgrade_leaf, compute_deletion_leaf, compute_freeze_leaf,
+    compute_indexed_upgrade_leaf, compute_migration_leaf, compute_patch_upgrade_leaf,
+    compute_reconfiguration_leaf, compute_release_leaf, compute_retype_upgrade_leaf,
+    compute_rotation_leaf, compute_upgrade_leaf, release_output_type, serialize_compact_proof,
+    serialize_proof, serialize_root_indexed_proof, serialize_threshold_witness, serialize_witness,
+    serialize_witness_layout, UpgradeProofBuilder,
+};
 use lazy_static::lazy_static;
-use merkle_cbt::{merkle_tree::Merge, MerkleTree, CBMT};
+use merkle_cbt::merkle_tree::Merge;
 use rand::{thread_rng, Rng};
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 lazy_static! {
     pub static ref ZERO_LOCK_PATH: String = std::env::var("ZERO_LOCK_PATH").unwrap_or_else(|_| {
@@ -29,6 +59,42 @@ lazy_static! {
         Bytes::from(std::fs::read(&*ZERO_LOCK_PATH).expect("read"));
     pub static ref ALWAYS_SUCCESS_BIN: Bytes =
         Bytes::from(ckb_always_success_script::ALWAYS_SUCCESS.to_vec());
+    pub static ref ALWAYS_FAILURE_BIN: Bytes = always_failure_bin();
+}
+
+/// A minimal RISC-V executable exiting with code 1 right away, the failing
+/// counterpart of always success.
+fn always_failure_bin() -> Bytes {
+    const BASE: u64 = 0x10000;
+    const HEADERS: u64 = 64 + 56;
+    // li a0, 1; li a7, 93 (exit); ecall
+    let code = [0x00100513u32, 0x05d00893, 0x00000073];
+    let size = HEADERS + 4 * code.len() as u64;
+
+    let mut elf = b"\x7fELF\x02\x01\x01".to_vec();
+    elf.resize(16, 0);
+    elf.extend(2u16.to_le_bytes()); // e_type: EXEC
+    elf.extend(0xf3u16.to_le_bytes()); // e_machine: RISC-V
+    elf.extend(1u32.to_le_bytes()); // e_version
+    elf.extend((BASE + HEADERS).to_le_bytes()); // e_entry
+    elf.extend(64u64.to_le_bytes()); // e_phoff
+    elf.extend(0u64.to_le_bytes()); // e_shoff
+    elf.extend(0u32.to_le_bytes()); // e_flags
+    for half in [64u16, 56, 1, 64, 0, 0] {
+        // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+        elf.extend(half.to_le_bytes());
+    }
+    // A single readable and executable segment loading the whole file
+    elf.extend(1u32.to_le_bytes()); // p_type: LOAD
+    elf.extend(5u32.to_le_bytes()); // p_flags: R + X
+    for word in [0, BASE, BASE, size, size, 0x1000u64] {
+        // p_offset, p_vaddr, p_paddr, p_filesz, p_memsz, p_align
+        elf.extend(word.to_le_bytes());
+    }
+    for instruction in code {
+        elf.extend(instruction.to_le_bytes());
+    }
+    elf.into()
 }
 
 #[derive(Default, Clone)]
@@ -62,6 +128,81 @@ impl ExtensionProvider for DummyDataLoader {
     }
 }
 
+/// A single data query issued to the data loader while running scripts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LoaderQuery {
+    CellData(OutPoint),
+    CellDataHash(OutPoint),
+    Header(Byte32),
+    Extension(Byte32),
+}
+
+/// Wraps `DummyDataLoader` and records every query in order. Only queries
+/// served by the data loader are visible here: `load_cell` and `load_witness`
+/// read the resolved transaction directly and never reach the loader.
+#[derive(Default, Clone)]
+pub struct RecordingDataLoader {
+    pub inner: DummyDataLoader,
+    pub queries: Arc<Mutex<Vec<LoaderQuery>>>,
+}
+
+impl RecordingDataLoader {
+    pub fn new(inner: DummyDataLoader) -> Self {
+        Self {
+            inner,
+            queries: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn queries(&self) -> Vec<LoaderQuery> {
+        self.queries.lock().unwrap().clone()
+    }
+
+    fn record(&self, query: LoaderQuery) {
+        self.queries.lock().unwrap().push(query);
+    }
+}
+
+impl CellDataProvider for RecordingDataLoader {
+    fn load_cell_data(&self, cell: &CellMeta) -> Option<Bytes> {
+        self.record(LoaderQuery::CellData(cell.out_point.clone()));
+        cell.mem_cell_data
+            .clone()
+            .or_else(|| self.inner.get_cell_data(&cell.out_point))
+    }
+
+    fn load_cell_data_hash(&self, cell: &CellMeta) -> Option<Byte32> {
+        self.record(LoaderQuery::CellDataHash(cell.out_point.clone()));
+        cell.mem_cell_data_hash
+            .clone()
+            .or_else(|| self.inner.get_cell_data_hash(&cell.out_point))
+    }
+
+    fn get_cell_data(&self, out_point: &OutPoint) -> Option<Bytes> {
+        self.record(LoaderQuery::CellData(out_point.clone()));
+        self.inner.get_cell_data(out_point)
+    }
+
+    fn get_cell_data_hash(&self, out_point: &OutPoint) -> Option<Byte32> {
+        self.record(LoaderQuery::CellDataHash(out_point.clone()));
+        self.inner.get_cell_data_hash(out_point)
+    }
+}
+
+impl HeaderProvider for RecordingDataLoader {
+    fn get_header(&self, block_hash: &Byte32) -> Option<HeaderView> {
+        self.record(LoaderQuery::Header(block_hash.clone()));
+        self.inner.get_header(block_hash)
+    }
+}
+
+impl ExtensionProvider for RecordingDataLoader {
+    fn get_block_extension(&self, hash: &Byte32) -> Option<packed::Bytes> {
+        self.record(LoaderQuery::Extension(hash.clone()));
+        self.inner.get_block_extension(hash)
+    }
+}
+
 pub fn random_out_point() -> OutPoint {
     let tx_hash = {
         let mut rng = thread_rng();
@@ -97,8 +238,17 @@ pub fn insert_cell(dummy: &mut DummyDataLoader, cell_meta: &CellMeta) {
 }
 
 pub fn script_cell(dummy: &mut DummyDataLoader, script_data: &Bytes) -> CellMeta {
+    script_cell_with_type(dummy, script_data, None)
+}
+
+pub fn script_cell_with_type(
+    dummy: &mut DummyDataLoader,
+    script_data: &Bytes,
+    type_script: Option<Script>,
+) -> CellMeta {
     let out_point = random_out_point();
     let cell = CellOutput::new_builder()
+        .type_(type_script.pack())
         .capacity(
             Capacity::bytes(script_data.len())
                 .expect("script capacity")
@@ -112,12 +262,16 @@ pub fn script_cell(dummy: &mut DummyDataLoader, script_data: &Bytes) -> CellMeta
     cell_meta
 }
 
-pub fn always_success_cell(dummy: &mut DummyDataLoader, capacity_bytes: usize) -> CellMeta {
-    let out_point = random_out_point();
-    let lock = Script::new_builder()
+pub fn always_success_lock() -> Script {
+    Script::new_builder()
         .code_hash(CellOutput::calc_data_hash(&ALWAYS_SUCCESS_BIN))
         .hash_type(ScriptHashType::Data2.into())
-        .build();
+        .build()
+}
+
+pub fn always_success_cell(dummy: &mut DummyDataLoader, capacity_bytes: usize) -> CellMeta {
+    let out_point = random_out_point();
+    let lock = always_success_lock();
     let cell = CellOutput::new_builder()
         .lock(lock)
         .capacity(
@@ -133,15 +287,64 @@ pub fn always_success_cell(dummy: &mut DummyDataLoader, capacity_bytes: usize) -
     cell_meta
 }
 
+/// Computes the value to publish in the extension for cells locked with
+/// `LOCK_FLAG_SALTED_ROOT` and the given salt.
+pub fn salted_root(merkle_root: &Byte32, salt: &Byte32) -> Byte32 {
+    Blake2bHash::merge(merkle_root, salt)
+}
+
+/// Builds zero lock args from a flags byte followed by an optional committee id.
+pub fn zero_lock_args(flags: u8, committee_id: &[u8]) -> Bytes {
+    let mut args = vec![flags];
+    args.extend(committee_id);
+    Bytes::from(args)
+}
+
 pub fn zero_lock_cell(
     dummy: &mut DummyDataLoader,
     data: &Bytes,
     type_script: Option<Script>,
+) -> CellMeta {
+    zero_lock_cell_with_args(dummy, data, type_script, Bytes::new())
+}
+
+pub fn zero_lock_cell_with_args(
+    dummy: &mut DummyDataLoader,
+    data: &Bytes,
+    type_script: Option<Script>,
+    args: Bytes,
+) -> CellMeta {
+    zero_lock_cell_with_hash_type(dummy, data, type_script, args, ScriptHashType::Data2)
+}
+
+/// Type script of the cell deploying zero lock in `complete_tx`, so cells can
+/// reference zero lock code by type hash.
+pub fn zero_lock_code_type_script() -> Script {
+    Script::new_builder()
+        .code_hash(TYPE_ID_CODE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(Bytes::from(vec![0x5au8; 32]).pack())
+        .build()
+}
+
+/// Same as `zero_lock_cell_with_args`, but the lock references zero lock code
+/// with the given hash type.
+pub fn zero_lock_cell_with_hash_type(
+    dummy: &mut DummyDataLoader,
+    data: &Bytes,
+    type_script: Option<Script>,
+    args: Bytes,
+    hash_type: ScriptHashType,
 ) -> CellMeta {
     let out_point = random_out_point();
+    let code_hash = match hash_type {
+        ScriptHashType::Type => zero_lock_code_type_script().calc_script_hash(),
+        _ => CellOutput::calc_data_hash(&ZERO_LOCK_BIN),
+    };
     let lock = Script::new_builder()
-        .code_hash(CellOutput::calc_data_hash(&ZERO_LOCK_BIN))
-        .hash_type(ScriptHashType::Data2.into())
+        .code_hash(code_hash)
+        .hash_type(hash_type.into())
+        .args(args.pack())
         .build();
     let cell = CellOutput::new_builder()
         .lock(lock)
@@ -156,57 +359,78 @@ pub fn zero_lock_cell(
 }
 
 pub fn complete_tx(
+    dummy: DummyDataLoader,
+    builder: TransactionBuilder,
+    input_cells: Vec<CellMeta>,
+) -> (
+    TransactionScriptsVerifier<DummyDataLoader>,
+    Arc<ResolvedTransaction>,
+    DummyDataLoader,
+) {
+    complete_tx_with_since(dummy, builder, input_cells, 0)
+}
+
+/// Same as `complete_tx`, but every input uses the given since value.
+pub fn complete_tx_with_since(
+    dummy: DummyDataLoader,
+    builder: TransactionBuilder,
+    input_cells: Vec<CellMeta>,
+    since: u64,
+) -> (
+    TransactionScriptsVerifier<DummyDataLoader>,
+    Arc<ResolvedTransaction>,
+    DummyDataLoader,
+) {
+    complete_tx_with_cell_deps(dummy, builder, input_cells, since, vec![])
+}
+
+/// Same as `complete_tx_with_since`, but the given cells are added as code
+/// cell deps ahead of the ones of zero lock and always success.
+pub fn complete_tx_with_cell_deps(
     mut dummy: DummyDataLoader,
     builder: TransactionBuilder,
     input_cells: Vec<CellMeta>,
+    since: u64,
+    cell_deps: Vec<CellMeta>,
 ) -> (
     TransactionScriptsVerifier<DummyDataLoader>,
     Arc<ResolvedTransaction>,
     DummyDataLoader,
 ) {
     let rtx: Arc<ResolvedTransaction> = {
-        let zero_lock_cell_meta = script_cell(&mut dummy, &ZERO_LOCK_BIN);
+        let zero_lock_cell_meta = script_cell_with_type(
+            &mut dummy,
+            &ZERO_LOCK_BIN,
+            Some(zero_lock_code_type_script()),
+        );
         let always_success_cell_meta = script_cell(&mut dummy, &ALWAYS_SUCCESS_BIN);
 
+        let mut resolved_cell_deps = cell_deps;
+        resolved_cell_deps.push(zero_lock_cell_meta);
+        resolved_cell_deps.push(always_success_cell_meta);
+
         let tx = builder
-            .cell_dep(
-                CellDep::new_builder()
-                    .out_point(zero_lock_cell_meta.out_point.clone())
-                    .dep_type(DepType::Code.into())
-                    .build(),
-            )
-            .cell_dep(
+            .cell_deps(resolved_cell_deps.iter().map(|dep| {
                 CellDep::new_builder()
-                    .out_point(always_success_cell_meta.out_point.clone())
+                    .out_point(dep.out_point.clone())
                     .dep_type(DepType::Code.into())
-                    .build(),
-            )
+                    .build()
+            }))
             .inputs(
                 input_cells
                     .iter()
-                    .map(|input| CellInput::new(input.out_point.clone(), 0)),
+                    .map(|input| CellInput::new(input.out_point.clone(), since)),
             )
             .build();
 
         Arc::new(ResolvedTransaction {
             transaction: tx,
             resolved_inputs: input_cells.clone(),
-            resolved_cell_deps: vec![zero_lock_cell_meta, always_success_cell_meta],
+            resolved_cell_deps,
             resolved_dep_groups: vec![],
         })
     };
 
-    let consensus = Arc::new(
-        ConsensusBuilder::default()
-            .hardfork_switch(HardForks {
-                ckb2021: CKB2021::new_dev_default(),
-                ckb2023: CKB2023::new_dev_default(),
-            })
-            .build(),
-    );
-    let tip = HeaderBuilder::default().number(0.pack()).build();
-    let tx_verify_env = Arc::new(TxVerifyEnv::new_submit(&tip));
-
     let mut groups = HashMap::new();
     for (i, input_cell) in input_cells.iter().enumerate() {
         let lock_hash = input_cell.cell_output.lock().calc_script_hash();
@@ -236,8 +460,7 @@ pub fn complete_tx(
         }
     }
 
-    let verifier =
-        TransactionScriptsVerifier::new(rtx.clone(), dummy.clone(), consensus, tx_verify_env);
+    let verifier = build_verifier(rtx.clone(), dummy.clone());
     // Uncomment to debug tests:
     // verifier.set_debug_printer(move |hash: &Byte32, message: &str| {
     //     let prefix = match groups.get(hash) {
@@ -249,20 +472,25 @@ pub fn complete_tx(
     (verifier, rtx, dummy)
 }
 
-#[derive(Debug)]
-pub struct Blake2bHash;
-
-impl Merge for Blake2bHash {
-    type Item = Byte32;
+pub fn build_verifier<DL>(
+    rtx: Arc<ResolvedTransaction>,
+    data_loader: DL,
+) -> TransactionScriptsVerifier<DL>
+where
+    DL: CellDataProvider + HeaderProvider + ExtensionProvider + Send + Sync + Clone + 'static,
+{
+    let consensus = Arc::new(
+        ConsensusBuilder::default()
+            .hardfork_switch(HardForks {
+                ckb2021: CKB2021::new_dev_default(),
+                ckb2023: CKB2023::new_dev_default(),
+            })
+            .build(),
+    );
+    let tip = HeaderBuilder::default().number(0.pack()).build();
+    let tx_verify_env = Arc::new(TxVerifyEnv::new_submit(&tip));
 
-    fn merge(lhs: &Self::Item, rhs: &Self::Item) -> Self::Item {
-        let mut hasher = new_blake2b();
-        hasher.update(&lhs.as_bytes());
-        hasher.update(&rhs.as_bytes());
-        let mut hash = [0u8; 32];
-        hasher.finalize(&mut hash[..]);
-        Byte32::new(hash)
-    }
+    TransactionScriptsVerifier::new(rtx, data_loader, consensus, tx_verify_env)
 }
 
 pub fn hash_upgrade_data(
@@ -271,36 +499,124 @@ pub fn hash_upgrade_data(
     input_type: Option<Bytes>,
     output_type: Option<Bytes>,
 ) -> Byte32 {
-    let mut hasher = new_blake2b();
-    hasher.update(&[1u8]);
-    hasher.update(old_cell.out_point.as_slice());
-    hasher.update(&blake2b_256(new_cell.mem_cell_data.as_ref().unwrap())[..]);
-    hasher.update(new_cell.cell_output.as_slice());
-    if let Some(input_type) = input_type {
-        hasher.update(&[1u8]);
-        hasher.update(
-            &TryInto::<u32>::try_into(input_type.len())
-                .unwrap()
-                .to_le_bytes(),
-        );
-        hasher.update(&input_type);
-    } else {
-        hasher.update(&[0u8]);
-    }
-    if let Some(output_type) = output_type {
-        hasher.update(&[1u8]);
-        hasher.update(
-            &TryInto::<u32>::try_into(output_type.len())
-                .unwrap()
-                .to_le_bytes(),
-        );
-        hasher.update(&output_type);
-    } else {
-        hasher.update(&[0u8]);
-    }
-    let mut hash = [0u8; 32];
-    hasher.finalize(&mut hash[..]);
-    Byte32::new(hash)
+    hash_upgrade_data_with_since(old_cell, new_cell, input_type, output_type, None)
+}
+
+/// Hashes the leaf of an upgrade, committing the since value of the input
+/// cell when it is locked with `LOCK_FLAG_ACTIVATION`.
+pub fn hash_upgrade_data_with_since(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+    since: Option<u64>,
+) -> Byte32 {
+    compute_upgrade_leaf(old_cell, new_cell, input_type, output_type, since)
+}
+
+/// Hashes the leaf of an upgrade committing that `new_cell` is the output cell
+/// at `output_index`.
+pub fn hash_indexed_upgrade_data(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    output_index: u32,
+) -> Byte32 {
+    compute_indexed_upgrade_leaf(old_cell, new_cell, output_index, None, None)
+}
+
+/// Hashes the leaf of an upgrade letting `new_cell` carry another type script
+/// than `old_cell`.
+pub fn hash_retype_upgrade_data(old_cell: &CellMeta, new_cell: &CellMeta) -> Byte32 {
+    compute_retype_upgrade_leaf(old_cell, new_cell)
+}
+
+/// Hashes the leaf of an upgrade for cells locked with
+/// `LOCK_EXT_FLAG_CODE_DEP`, `new_cell` carries the code hash as data.
+pub fn hash_code_dep_upgrade_data(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    compute_code_dep_upgrade_leaf(old_cell, new_cell, input_type, output_type)
+}
+
+/// Hashes the leaf of an upgrade for cells locked with `LOCK_FLAG_PATCH`,
+/// the patch is carried in witness output_type.
+pub fn hash_patch_upgrade_data(old_cell: &CellMeta, new_cell: &CellMeta, patch: &Bytes) -> Byte32 {
+    compute_patch_upgrade_leaf(old_cell, new_cell, patch)
+}
+
+/// Hashes the leaf migrating a zero lock cell into `new_cell`, whose lock is
+/// free to differ from zero lock.
+pub fn hash_migration_data(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    compute_migration_leaf(old_cell, new_cell, input_type, output_type)
+}
+
+/// Hashes the leaf rotating the governance cell `old_cell` into `new_cell`,
+/// which carries the roots governing from then on.
+pub fn hash_rotation_data(old_cell: &CellMeta, new_cell: &CellMeta) -> Byte32 {
+    compute_rotation_leaf(old_cell, new_cell, None, None)
+}
+
+/// Hashes the leaf freezing a zero lock cell into `new_cell`, whose data is
+/// built by `frozen_data`.
+pub fn hash_freeze_data(old_cell: &CellMeta, new_cell: &CellMeta) -> Byte32 {
+    compute_freeze_leaf(old_cell, new_cell, None, None)
+}
+
+/// Hashes the leaf reconfiguring a zero lock cell into `new_cell`, keeping
+/// the first `code_length` bytes of its data.
+pub fn hash_reconfiguration_data(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    code_length: u32,
+) -> Byte32 {
+    compute_reconfiguration_leaf(old_cell, new_cell, code_length, None)
+}
+
+/// Hashes the leaf releasing up to `released` capacity of a zero lock cell
+/// into `new_cell`, paid to the output cells locked by `lock_hash`.
+pub fn hash_release_data(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    released: u64,
+    lock_hash: &Byte32,
+) -> Byte32 {
+    compute_release_leaf(old_cell, new_cell, released, lock_hash, None)
+}
+
+/// Hashes the leaf deleting a zero lock cell, committing its data hash and
+/// type script hash instead of a successor cell.
+pub fn hash_deletion_data(
+    old_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    compute_deletion_leaf(old_cell, input_type, output_type)
+}
+
+/// Serializes a merkle proof in the layout zero lock expects in the witness
+/// lock field.
+pub fn proof_lock_data(header_index: u32, indices: &[u32], lemmas: &[Byte32]) -> Bytes {
+    let tags = vec![LeafTag::Upgrade as u8; indices.len()];
+    proof_lock_data_with_tags(header_index, indices, &tags, lemmas)
+}
+
+/// Same as `proof_lock_data`, but declares the given tag for each proven
+/// leaf instead of upgrades only.
+pub fn proof_lock_data_with_tags(
+    header_index: u32,
+    indices: &[u32],
+    tags: &[u8],
+    lemmas: &[Byte32],
+) -> Bytes {
+    serialize_proof(header_index, indices, tags, lemmas)
 }
 
 pub fn build_merkle_root_n_proof(
@@ -310,46 +626,209 @@ pub fn build_merkle_root_n_proof(
     input_type: Option<Bytes>,
     output_type: Option<Bytes>,
 ) -> (Byte32, Bytes) {
-    let mut hashed_leaves: Vec<Byte32> = Vec::with_capacity(all_leaves.len());
-    for (i, (old_cell, new_cell)) in all_leaves.iter().enumerate() {
-        let leaf = if i == header_index as usize {
-            hash_upgrade_data(old_cell, new_cell, input_type.clone(), output_type.clone())
-        } else {
-            hash_upgrade_data(old_cell, new_cell, None, None)
-        };
-        hashed_leaves.push(leaf);
-    }
-    let tree: MerkleTree<Byte32, Blake2bHash> = CBMT::build_merkle_tree(&hashed_leaves);
-    let proof = tree.build_proof(&[selected]).expect("build merkle proof");
-
-    let mut data = vec![];
-    data.extend(header_index.to_le_bytes());
-    data.extend(
-        TryInto::<u32>::try_into(proof.indices().len())
-            .unwrap()
-            .to_le_bytes(),
-    );
-    for index in proof.indices() {
-        data.extend(index.to_le_bytes());
-    }
-    data.extend(
-        TryInto::<u32>::try_into(proof.lemmas().len())
-            .unwrap()
-            .to_le_bytes(),
-    );
-    for lemma in proof.lemmas() {
-        data.extend(lemma.as_slice());
-    }
+    build_merkle_root_n_proof_with_merge_hash(
+        all_leaves,
+        selected,
+        header_index,
+        input_type,
+        output_type,
+        MERGE_HASH_BLAKE2B,
+    )
+}
 
-    let witness = WitnessArgs::new_builder()
-        .lock(Some(Bytes::from(data)).pack())
-        .input_type(input_type.pack())
-        .output_type(output_type.pack())
-        .build();
+/// Same as `build_merkle_root_n_proof`, but merges tree nodes by the given
+/// `MERGE_HASH_*` hash.
+pub fn build_merkle_root_n_proof_with_merge_hash(
+    all_leaves: &[(&CellMeta, &CellMeta)],
+    selected: u32,
+    header_index: u32,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+    merge_hash: u8,
+) -> (Byte32, Bytes) {
+    let builder = UpgradeProofBuilder::default()
+        .merge_hash(merge_hash)
+        .leaves(
+            all_leaves
+                .iter()
+                .enumerate()
+                .map(|(i, (old_cell, new_cell))| {
+                    let leaf = if i == header_index as usize {
+                        hash_upgrade_data(
+                            old_cell,
+                            new_cell,
+                            input_type.clone(),
+                            output_type.clone(),
+                        )
+                    } else {
+                        hash_upgrade_data(old_cell, new_cell, None, None)
+                    };
+                    (LeafTag::Upgrade, leaf)
+                }),
+        );
+    let data = builder
+        .build(header_index, &[selected])
+        .expect("build merkle proof");
+
+    (
+        builder.root(),
+        serialize_witness(data, input_type, output_type),
+    )
+}
+
+/// Builds the sparse merkle tree of all upgrades keyed by the hash of the old
+/// contract, and the witness of a transaction upgrading `old_cell`, in which
+/// witness lock holds the proof of its key.
+pub fn build_smt_root_n_proof(
+    all_leaves: &[(&CellMeta, &CellMeta)],
+    old_cell: &CellMeta,
+    header_index: u32,
+) -> (Byte32, Bytes) {
+    let mut tree = smt::SparseMerkleTree::default();
+    for (old_cell, new_cell) in all_leaves {
+        let key = blake2b_256(old_cell.mem_cell_data.as_ref().unwrap());
+        tree.insert(
+            &key.pack(),
+            &hash_upgrade_data(old_cell, new_cell, None, None),
+        );
+    }
+    let key = blake2b_256(old_cell.mem_cell_data.as_ref().unwrap());
+    let proof = tree.proof(&key.pack());
+    let data = smt::smt_proof_lock_data(header_index, LeafTag::Upgrade as u8, &proof);
+    let witness = WitnessArgs::new_builder().lock(Some(data).pack()).build();
 
     (tree.root(), witness.as_bytes())
 }
 
+/// Builds the merkle root of all leaves, and the witnesses of a transaction
+/// upgrading the `selected` leaves in that order. The first witness carries
+/// the merkle proof for all of them, the others are left empty.
+///
+/// Indices in the proof are CBMT node indices of the selected leaves in the
+/// tree sorted by hash, in increasing order whatever the order of `selected`.
+pub fn build_merkle_root_n_batch_proof(
+    all_leaves: &[(&CellMeta, &CellMeta)],
+    selected: &[u32],
+    header_index: u32,
+) -> (Byte32, Vec<Bytes>) {
+    let builder =
+        UpgradeProofBuilder::default().leaves(all_leaves.iter().map(|(old_cell, new_cell)| {
+            (
+                LeafTag::Upgrade,
+                hash_upgrade_data(old_cell, new_cell, None, None),
+            )
+        }));
+    let data = builder
+        .build(header_index, selected)
+        .expect("build merkle proof");
+    let mut witnesses = vec![serialize_witness(data, None, None)];
+    witnesses.resize(selected.len(), Bytes::new());
+
+    (builder.root(), witnesses)
+}
+
+/// Builds a tree per committee holding the upgrade leaf next to a leaf of
+/// its own, returning the root of each committee. The witness proves the
+/// leaf against the root of the first committee, with co-proofs against the
+/// roots of the committees at `co_proof_slots` for cells locked with
+/// `LOCK_EXT_FLAG_THRESHOLD_PROOFS`.
+pub fn build_threshold_roots_n_witness(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    committees: u8,
+    co_proof_slots: &[u32],
+) -> (Vec<Byte32>, Bytes) {
+    let leaf = hash_upgrade_data(old_cell, new_cell, None, None);
+    let builders: Vec<UpgradeProofBuilder> = (0..committees)
+        .map(|i| {
+            UpgradeProofBuilder::default()
+                .leaf(LeafTag::Upgrade, leaf.clone())
+                .leaf(LeafTag::Upgrade, Byte32::new([i + 1; 32]))
+        })
+        .collect();
+    let proof = builders[0].build(0, &[0]).expect("build merkle proof");
+    let co_proofs: Vec<Bytes> = co_proof_slots
+        .iter()
+        .map(|slot| {
+            builders[*slot as usize]
+                .build_co_proof(*slot, &[0])
+                .expect("build co-proof")
+        })
+        .collect();
+    (
+        builders.iter().map(|builder| builder.root()).collect(),
+        serialize_threshold_witness(proof, &co_proofs, None),
+    )
+}
+
+/// Builds the compact witness form for a single leaf tree, which only carries
+/// the header index. The merkle root of such a tree is the leaf itself.
+pub fn compact_proof_witness(header_index: u32) -> Bytes {
+    serialize_witness(serialize_compact_proof(header_index), None, None)
+}
+
+/// Prefixes witness lock of a proof witness with the index of the merkle root
+/// slot to verify against, for cells with `LOCK_EXT_FLAG_ROOT_INDEX`.
+pub fn root_indexed_witness(root_index: u32, proof_witness: Bytes) -> Bytes {
+    let witness = WitnessArgs::new_unchecked(proof_witness);
+    let lock = witness.lock().to_opt().unwrap().raw_data();
+    witness
+        .as_builder()
+        .lock(Some(serialize_root_indexed_proof(root_index, &lock)).pack())
+        .build()
+        .as_bytes()
+}
+
+/// Moves lemmas of a unified proof witness into witness input_type, leaving
+/// header index and indices in witness lock.
+pub fn split_proof_witness(proof_witness: Bytes) -> Bytes {
+    let witness = WitnessArgs::new_unchecked(proof_witness);
+    let lock = witness.lock().to_opt().unwrap().raw_data();
+    let mut t = [0u8; 4];
+    t.copy_from_slice(&lock[4..8]);
+    // Header index and indices count, followed by an index and a leaf tag
+    // per proven leaf
+    let lemmas_start = 8 + 5 * u32::from_le_bytes(t) as usize;
+
+    let mut split_lock = lock[..lemmas_start].to_vec();
+    split_lock.extend(SPLIT_LEMMAS.to_le_bytes());
+    witness
+        .as_builder()
+        .lock(Some(Bytes::from(split_lock)).pack())
+        .input_type(Some(lock.slice(lemmas_start..)).pack())
+        .build()
+        .as_bytes()
+}
+
+/// Strips the WitnessArgs wrapper of a proof witness, leaving the raw content
+/// of witness lock as the whole witness.
+pub fn raw_proof_witness(proof_witness: Bytes) -> Bytes {
+    WitnessArgs::new_unchecked(proof_witness)
+        .lock()
+        .to_opt()
+        .unwrap()
+        .raw_data()
+}
+
+/// Moves the content of witness lock of a proof witness into the seal of a
+/// `WitnessLayout`, as `SighashAll` when a message is given.
+pub fn witness_layout_proof_witness(proof_witness: Bytes, message: Option<Bytes>) -> Bytes {
+    serialize_witness_layout(raw_proof_witness(proof_witness), message)
+}
+
+/// Appends a precomputed leaf hash after the merkle proof in the witness
+/// lock field.
+pub fn append_leaf_hash(proof_witness: Bytes, leaf: &Byte32) -> Bytes {
+    let witness = WitnessArgs::new_unchecked(proof_witness);
+    let mut lock = witness.lock().to_opt().unwrap().raw_data().to_vec();
+    lock.extend(leaf.as_slice());
+    witness
+        .as_builder()
+        .lock(Some(Bytes::from(lock)).pack())
+        .build()
+        .as_bytes()
+}
+
 pub fn bury_in_merkle_tree<R: Rng>(
     input_meta: &CellMeta,
     output_meta: &CellMeta,
@@ -389,29 +868,181 @@ pub fn bury_in_merkle_tree<R: Rng>(
     build_merkle_root_n_proof(&leaves, index as u32, header_index, input_type, output_type)
 }
 
+pub const HEADER_EPOCH_NUMBER: u64 = 10;
+pub const HEADER_EPOCH_START_NUMBER: u64 = 9500;
+pub const HEADER_EPOCH_LENGTH: u64 = 1010;
+
+/// Field of unrelated data committed in the extension, skipped by zero lock.
+pub const EXTENSION_FIELD_UNRELATED: u8 = 0x80;
+
+/// Encodes a structured extension holding `fields` in the given order.
+pub fn structured_extension(fields: &[(u8, &[u8])]) -> Bytes {
+    let mut extension = EXTENSION_MAGIC.to_vec();
+    for (tag, value) in fields {
+        extension.push(*tag);
+        extension.extend((value.len() as u32).to_le_bytes());
+        extension.extend(*value);
+    }
+    Bytes::from(extension)
+}
+
+/// Builds an extension with `num_slots` merkle root slots, each committee's
+/// root is placed in the slot picked by its committee id. Unrelated data is
+/// committed before the slots.
+pub fn build_extension(committees: &[(&[u8], Byte32)], num_slots: usize) -> Bytes {
+    let mut rng = thread_rng();
+    let mut unrelated = [0u8; 32];
+    rng.fill(&mut unrelated);
+    let mut roots = vec![0u8; 32 * num_slots];
+    for (committee_id, root) in committees {
+        let offset = 32 * slot_for(committee_id, num_slots);
+        roots[offset..offset + 32].copy_from_slice(root.as_slice());
+    }
+    structured_extension(&[
+        (EXTENSION_FIELD_UNRELATED, &unrelated[..]),
+        (EXTENSION_FIELD_ROOTS, &roots[..]),
+    ])
+}
+
+/// Builds an extension holding `roots` in consecutive slots, requiring
+/// `threshold` of them to prove the leaves of an upgrade.
+pub fn threshold_extension(roots: &[Byte32], threshold: u8) -> Bytes {
+    let roots: Vec<u8> = roots
+        .iter()
+        .flat_map(|root| root.as_slice().to_vec())
+        .collect();
+    structured_extension(&[
+        (EXTENSION_FIELD_ROOTS, &roots[..]),
+        (EXTENSION_FIELD_THRESHOLD, &[threshold][..]),
+    ])
+}
+
+/// Builds an extension holding `merkle_root` in the only slot, with the given
+/// kill switch value.
+pub fn kill_switch_extension(merkle_root: &Byte32, kill_switch: u8) -> Bytes {
+    structured_extension(&[
+        (EXTENSION_FIELD_KILL_SWITCH, &[kill_switch][..]),
+        (EXTENSION_FIELD_ROOTS, merkle_root.as_slice()),
+    ])
+}
+
+/// Builds an extension holding `merkle_root` in the only slot, letting
+/// migrations hand cells only to locks with one of `code_hashes`.
+pub fn successor_whitelist_extension(merkle_root: &Byte32, code_hashes: &[Byte32]) -> Bytes {
+    let mut whitelist = TryInto::<u32>::try_into(code_hashes.len())
+        .unwrap()
+        .to_le_bytes()
+        .to_vec();
+    for code_hash in code_hashes {
+        whitelist.extend(code_hash.as_slice());
+    }
+    structured_extension(&[
+        (EXTENSION_FIELD_ROOTS, merkle_root.as_slice()),
+        (EXTENSION_FIELD_SUCCESSOR_CODE_HASHES, &whitelist[..]),
+    ])
+}
+
+/// Builds an extension holding `merkle_root` in the only slot, with the
+/// kill switch set.
+pub fn paused_extension(merkle_root: &Byte32) -> Bytes {
+    kill_switch_extension(merkle_root, 1)
+}
+
+/// Builds an extension in the legacy layout deployed headers carry: 180
+/// bytes of arbitrary data, holding `merkle_root` at `EXTENSION_ROOT_OFFSET`.
+pub fn legacy_extension(merkle_root: &Byte32) -> Bytes {
+    let mut rng = thread_rng();
+    let mut extension = vec![0u8; 180];
+    rng.fill(&mut extension[..]);
+    extension[EXTENSION_ROOT_OFFSET..EXTENSION_ROOT_OFFSET + 32]
+        .copy_from_slice(merkle_root.as_slice());
+    Bytes::from(extension)
+}
+
+/// Ways `malformed_header` breaks the extension of a header.
+#[derive(Clone, Copy, Debug)]
+pub enum ExtensionDefect {
+    /// Legacy layout cut to the given length, short of the root slot
+    Truncated(usize),
+    /// Legacy layout with an all zero root
+    ZeroedRoot,
+    /// No extension at all, the header itself is present
+    Missing,
+}
+
+/// Same as `header`, but the extension is broken by `defect`.
+pub fn malformed_header(
+    dummy: &mut DummyDataLoader,
+    merkle_root: &Byte32,
+    defect: ExtensionDefect,
+) -> Byte32 {
+    let extension = match defect {
+        ExtensionDefect::Truncated(length) => {
+            assert!(length < LEGACY_EXTENSION_MIN_LENGTH);
+            Some(legacy_extension(merkle_root).slice(..length))
+        }
+        ExtensionDefect::ZeroedRoot => Some(legacy_extension(&Byte32::zero())),
+        ExtensionDefect::Missing => None,
+    };
+    header_with_extension(dummy, 10000, extension)
+}
+
 pub fn header(dummy: &mut DummyDataLoader, merkle_root: &Byte32) -> Byte32 {
+    header_at(dummy, merkle_root, 10000)
+}
+
+pub fn header_at(dummy: &mut DummyDataLoader, merkle_root: &Byte32, number: u64) -> Byte32 {
+    let extension = build_extension(&[(&[][..], merkle_root.clone())], 1);
+    header_with_extension(dummy, number, Some(extension))
+}
+
+/// Same as `header`, but builds a governance cell carrying `type_script` for
+/// cells locked with `LOCK_EXT_FLAG_GOVERNANCE_CELL`. The cell is to be added
+/// as cell dep, its data is laid out like an extension.
+pub fn governance_cell(
+    dummy: &mut DummyDataLoader,
+    type_script: Script,
+    merkle_root: &Byte32,
+) -> CellMeta {
+    let data = build_extension(&[(&[][..], merkle_root.clone())], 1);
+    script_cell_with_type(dummy, &data, Some(type_script))
+}
+
+/// Since value of an input unlocking at the start of epoch `number`.
+pub fn absolute_epoch_since(number: u64) -> u64 {
+    0x2000_0000_0000_0000 | EpochNumberWithFraction::new(number, 0, 1).full_value()
+}
+
+/// Builds a header at block `number`, which must fall within epoch
+/// `HEADER_EPOCH_NUMBER`. The extension is left out when `None`.
+pub fn header_with_extension(
+    dummy: &mut DummyDataLoader,
+    number: u64,
+    extension: Option<Bytes>,
+) -> Byte32 {
     let mut rng = thread_rng();
     let epoch_ext = EpochExt::new_builder()
-        .number(10)
-        .start_number(9500)
-        .length(1010)
+        .number(HEADER_EPOCH_NUMBER)
+        .start_number(HEADER_EPOCH_START_NUMBER)
+        .length(HEADER_EPOCH_LENGTH)
         .build();
+    assert!(
+        (HEADER_EPOCH_START_NUMBER..HEADER_EPOCH_START_NUMBER + HEADER_EPOCH_LENGTH)
+            .contains(&number)
+    );
     let header = HeaderBuilder::default()
-        .number(10000.pack())
-        .epoch(epoch_ext.number_with_fraction(10000).pack())
+        .number(number.pack())
+        .epoch(epoch_ext.number_with_fraction(number).pack())
         .transactions_root({
             let mut d = [0u8; 32];
             rng.fill(&mut d);
             Byte32::new(d)
         })
         .build();
-    let mut extension = vec![0u8; 180];
-    rng.fill(&mut extension[..]);
-    extension[128..160].copy_from_slice(&merkle_root.as_bytes());
     let hash = header.hash();
     dummy.headers.insert(hash.clone(), header);
-    dummy
-        .extensions
-        .insert(hash.clone(), Bytes::from(extension));
+    if let Some(extension) = extension {
+        dummy.extensions.insert(hash.clone(), extension);
+    }
     hash
 }
@@ -0,0 +1,57 @@
+use super::hash_upgrade_data;
+use ckb_types::{bytes::Bytes, core::cell::CellMeta, packed::Byte32};
+pub use ckb_zero_lock_types::{PATCH_OP_COPY, PATCH_OP_INSERT};
+
+/// Hashes each planned upgrade, given as old and new cell, into the leaves of
+/// a committee's tree in plan order.
+pub fn plan_upgrades(plans: &[(&CellMeta, &CellMeta)]) -> Vec<Byte32> {
+    plans
+        .iter()
+        .map(|(old_cell, new_cell)| hash_upgrade_data(old_cell, new_cell, None, None))
+        .collect()
+}
+
+/// Finds the position of an upgrade's leaf among all leaves of a committee's
+/// tree, to be used as the selected index when building its proof.
+pub fn find_leaf_index(leaves: &[Byte32], leaf: &Byte32) -> Option<u32> {
+    leaves
+        .iter()
+        .position(|l| l == leaf)
+        .map(|i| TryInto::<u32>::try_into(i).unwrap())
+}
+
+/// Generates a patch turning `old` into `new` for cells locked with
+/// `LOCK_FLAG_PATCH`: the common prefix and suffix are copied from the old
+/// data, only the bytes in between are inserted.
+pub fn make_patch(old: &[u8], new: &[u8]) -> Bytes {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut patch = vec![];
+    push_copy(&mut patch, 0, prefix);
+    let inserted = &new[prefix..new.len() - suffix];
+    if !inserted.is_empty() {
+        patch.push(PATCH_OP_INSERT);
+        patch.extend(
+            TryInto::<u32>::try_into(inserted.len())
+                .unwrap()
+                .to_le_bytes(),
+        );
+        patch.extend(inserted);
+    }
+    push_copy(&mut patch, old.len() - suffix, suffix);
+    Bytes::from(patch)
+}
+
+fn push_copy(patch: &mut Vec<u8>, offset: usize, length: usize) {
+    if length > 0 {
+        patch.push(PATCH_OP_COPY);
+        patch.extend(TryInto::<u32>::try_into(offset).unwrap().to_le_bytes());
+        patch.extend(TryInto::<u32>::try_into(length).unwrap().to_le_bytes());
+    }
+}
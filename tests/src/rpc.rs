@@ -0,0 +1,132 @@
+use ckb_jsonrpc_types::{BlockView, HeaderView, TransactionView};
+use ckb_traits::{CellDataProvider, ExtensionProvider, HeaderProvider};
+use ckb_types::{
+    bytes::Bytes,
+    core::{
+        self,
+        cell::{CellMeta, CellMetaBuilder, ResolvedTransaction},
+        DepType,
+    },
+    packed::{self, Byte32, OutPoint, OutPointVec},
+    prelude::*,
+    H256,
+};
+use serde_json::{json, Value};
+
+fn hash_params(hash: &Byte32) -> Value {
+    let hash: H256 = hash.unpack();
+    json!([format!("{:#x}", hash)])
+}
+
+/// Serves cells, headers and extensions from a CKB node over JSON-RPC, so
+/// transactions on a real chain can be verified locally.
+#[derive(Clone)]
+pub struct RpcDataLoader {
+    url: String,
+}
+
+impl RpcDataLoader {
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_string(),
+        }
+    }
+
+    fn call(&self, method: &str, params: Value) -> Option<Value> {
+        let request = json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        });
+        let mut response: Value = ureq::post(&self.url)
+            .send_json(request)
+            .ok()?
+            .into_json()
+            .ok()?;
+        match response.get_mut("result").map(Value::take) {
+            Some(Value::Null) | None => None,
+            Some(result) => Some(result),
+        }
+    }
+
+    pub fn get_transaction(&self, tx_hash: &Byte32) -> Option<core::TransactionView> {
+        let mut result = self.call("get_transaction", hash_params(tx_hash))?;
+        let tx: TransactionView =
+            serde_json::from_value(result.get_mut("transaction")?.take()).ok()?;
+        Some(packed::Transaction::from(tx.inner).into_view())
+    }
+
+    pub fn get_cell(&self, out_point: &OutPoint) -> Option<CellMeta> {
+        let tx = self.get_transaction(&out_point.tx_hash())?;
+        let index: u32 = out_point.index().unpack();
+        let (output, data) = tx.output_with_data(index as usize)?;
+        Some(
+            CellMetaBuilder::from_cell_output(output, data)
+                .out_point(out_point.clone())
+                .build(),
+        )
+    }
+
+    /// Resolves inputs and cell deps of an on chain transaction, expanding
+    /// dep groups.
+    pub fn resolve_transaction(&self, tx_hash: &Byte32) -> Option<ResolvedTransaction> {
+        let transaction = self.get_transaction(tx_hash)?;
+        let resolved_inputs = transaction
+            .input_pts_iter()
+            .map(|out_point| self.get_cell(&out_point))
+            .collect::<Option<Vec<_>>>()?;
+        let mut resolved_cell_deps = vec![];
+        let mut resolved_dep_groups = vec![];
+        for cell_dep in transaction.cell_deps_iter() {
+            let cell = self.get_cell(&cell_dep.out_point())?;
+            let dep_type: DepType = cell_dep.dep_type().try_into().ok()?;
+            if dep_type == DepType::DepGroup {
+                let out_points = OutPointVec::from_slice(cell.mem_cell_data.as_ref()?).ok()?;
+                for out_point in out_points.into_iter() {
+                    resolved_cell_deps.push(self.get_cell(&out_point)?);
+                }
+                resolved_dep_groups.push(cell);
+            } else {
+                resolved_cell_deps.push(cell);
+            }
+        }
+        Some(ResolvedTransaction {
+            transaction,
+            resolved_inputs,
+            resolved_cell_deps,
+            resolved_dep_groups,
+        })
+    }
+
+    fn get_block(&self, block_hash: &Byte32) -> Option<BlockView> {
+        serde_json::from_value(self.call("get_block", hash_params(block_hash))?).ok()
+    }
+}
+
+impl CellDataProvider for RpcDataLoader {
+    fn get_cell_data(&self, out_point: &OutPoint) -> Option<Bytes> {
+        self.get_cell(out_point)?.mem_cell_data
+    }
+
+    fn get_cell_data_hash(&self, out_point: &OutPoint) -> Option<Byte32> {
+        self.get_cell_data(out_point)
+            .map(|data| packed::CellOutput::calc_data_hash(&data))
+    }
+}
+
+impl HeaderProvider for RpcDataLoader {
+    fn get_header(&self, block_hash: &Byte32) -> Option<core::HeaderView> {
+        let header: HeaderView =
+            serde_json::from_value(self.call("get_header", hash_params(block_hash))?).ok()?;
+        Some(header.into())
+    }
+}
+
+impl ExtensionProvider for RpcDataLoader {
+    fn get_block_extension(&self, block_hash: &Byte32) -> Option<packed::Bytes> {
+        self.get_block(block_hash)?
+            .extension
+            .map(|extension| extension.into_bytes().pack())
+    }
+}
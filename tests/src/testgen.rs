@@ -0,0 +1,507 @@
+use super::{
+    absolute_epoch_since, always_success_lock, append_leaf_hash, code_dep_data,
+    compact_proof_witness, frozen_data, hash_upgrade_data, header, header_with_extension,
+    paused_extension, proof_lock_data, proof_lock_data_with_tags,
+    prover::{PATCH_OP_COPY, PATCH_OP_INSERT},
+    random_type_id_script, release_output_type, script_cell_with_type, serialize_compact_proof,
+    serialize_threshold_witness, structured_extension, successor_whitelist_extension,
+    zero_lock_args, zero_lock_cell, zero_lock_cell_with_args, Blake2bHash, DummyDataLoader,
+    LeafTag, EXTENSION_FIELD_MIN_EPOCHS, EXTENSION_FIELD_ROOTS, EXTENSION_FIELD_THRESHOLD,
+    HEADER_EPOCH_NUMBER, LOCK_EXT_FLAG_CODE_DEP, LOCK_EXT_FLAG_GOVERNANCE_CELL,
+    LOCK_EXT_FLAG_THRESHOLD_PROOFS, LOCK_FLAG_ACTIVATION, LOCK_FLAG_EXTENDED,
+    LOCK_FLAG_MIN_CONFIRMATIONS, LOCK_FLAG_PATCH, LOCK_FLAG_PRESERVE_STRUCTURE,
+    LOCK_FLAG_SALTED_ROOT, LOCK_FLAG_VERSIONED, MAX_TREE_DEPTH,
+};
+use ckb_types::{
+    bytes::Bytes,
+    core::{cell::CellMeta, Capacity, TransactionBuilder},
+    packed::{Byte32, WitnessArgs},
+    prelude::*,
+};
+use merkle_cbt::merkle_tree::Merge;
+
+/// Data loader, transaction, input cells, the since of every input and the
+/// cells to add as cell deps, ready to be passed to
+/// `complete_tx_with_cell_deps`.
+pub type Repro = (
+    DummyDataLoader,
+    TransactionBuilder,
+    Vec<CellMeta>,
+    u64,
+    Vec<CellMeta>,
+);
+
+/// Contract error codes a transaction can trigger. The remaining codes are
+/// only reachable through syscall failures (3, 7, and 40 to 44 from
+/// `IndexOutOfBound` to `UnknownSysError`) or are retired (12).
+/// Duplicate leaves (50) need an input cell spent twice, which consensus
+/// rejects but scripts can run.
+pub const REPRODUCIBLE_CODES: &[u32] = &[
+    1, 2, 4, 5, 6, 8, 9, 10, 11, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25, 26, 27, 28,
+    29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 45, 46, 47, 48, 49, 50, 51, 52,
+];
+
+struct Upgrade {
+    dummy: DummyDataLoader,
+    input: CellMeta,
+    output: CellMeta,
+    since: u64,
+}
+
+impl Upgrade {
+    fn new(input_args: Bytes, output_args: Bytes, output_data: Bytes) -> Self {
+        let mut dummy = DummyDataLoader::default();
+        let type_id = random_type_id_script();
+        let input = zero_lock_cell_with_args(
+            &mut dummy,
+            &vec![1u8; 100].into(),
+            Some(type_id.clone()),
+            input_args,
+        );
+        let output = zero_lock_cell_with_args(&mut dummy, &output_data, Some(type_id), output_args);
+        Self {
+            dummy,
+            input,
+            output,
+            since: 0,
+        }
+    }
+
+    fn simple() -> Self {
+        Self::new(Bytes::new(), Bytes::new(), vec![2u8; 100].into())
+    }
+
+    /// Spends the input cell with the given since rather than zero.
+    fn with_since(mut self, since: u64) -> Self {
+        self.since = since;
+        self
+    }
+
+    fn leaf(&self) -> Byte32 {
+        hash_upgrade_data(&self.input, &self.output, None, None)
+    }
+
+    fn builder(&self, header_dep: Byte32, witness: Bytes) -> TransactionBuilder {
+        TransactionBuilder::default()
+            .output(self.output.cell_output.clone())
+            .output_data(self.output.mem_cell_data.clone().unwrap().pack())
+            .header_dep(header_dep)
+            .witness(witness.pack())
+    }
+
+    fn repro_with_root(mut self, root: &Byte32, witness: Bytes) -> Repro {
+        let header_dep = header(&mut self.dummy, root);
+        let builder = self.builder(header_dep, witness);
+        (self.dummy, builder, vec![self.input], self.since, vec![])
+    }
+
+    fn repro_with_extension(mut self, extension: Option<Bytes>) -> Repro {
+        let header_dep = header_with_extension(&mut self.dummy, 10000, extension);
+        let builder = self.builder(header_dep, compact_proof_witness(0));
+        (self.dummy, builder, vec![self.input], self.since, vec![])
+    }
+}
+
+fn lock_witness(lock: Bytes) -> Bytes {
+    WitnessArgs::new_builder()
+        .lock(Some(lock).pack())
+        .build()
+        .as_bytes()
+}
+
+/// A single leaf upgrade passing verification, every repro is a minimal
+/// deviation from it.
+pub fn baseline() -> Repro {
+    let upgrade = Upgrade::simple();
+    let root = upgrade.leaf();
+    upgrade.repro_with_root(&root, compact_proof_witness(0))
+}
+
+/// Generates a minimal transaction failing zero lock with the given contract
+/// error code, or `None` if no transaction can trigger it.
+pub fn repro_for(code: u32) -> Option<Repro> {
+    let repro = match code {
+        1 => {
+            // Both input cells are declared as upgrades, with only one output cell
+            let mut upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let other_input = zero_lock_cell(&mut upgrade.dummy, &vec![3u8; 100].into(), None);
+            let witness = lock_witness(proof_lock_data(0, &[1, 2], &[]));
+            let (dummy, builder, mut inputs, since, cell_deps) =
+                upgrade.repro_with_root(&root, witness);
+            inputs.push(other_input);
+            (dummy, builder, inputs, since, cell_deps)
+        }
+        2 => {
+            let mut upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let other_output = zero_lock_cell(&mut upgrade.dummy, &vec![3u8; 100].into(), None);
+            let (dummy, builder, inputs, since, cell_deps) =
+                upgrade.repro_with_root(&root, compact_proof_witness(0));
+            let builder = builder
+                .output(other_output.cell_output.clone())
+                .output_data(other_output.mem_cell_data.unwrap().pack());
+            (dummy, builder, inputs, since, cell_deps)
+        }
+        4 => {
+            let upgrade = Upgrade::new(Bytes::new(), zero_lock_args(0, &[]), vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        5 => Upgrade::simple().repro_with_extension(Some(vec![0u8; 140].into())),
+        6 => Upgrade::simple().repro_with_extension(None),
+        8 => Upgrade::simple().repro_with_root(&Byte32::new([1u8; 32]), compact_proof_witness(0)),
+        9 => {
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let witness = lock_witness(proof_lock_data(0, &[0], &[]));
+            upgrade.repro_with_root(&root, append_leaf_hash(witness, &Byte32::zero()))
+        }
+        10 => {
+            // The root of the tree needs no lemma
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let witness = lock_witness(proof_lock_data(0, &[0], &[Byte32::zero()]));
+            upgrade.repro_with_root(&root, witness)
+        }
+        11 => {
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, lock_witness(vec![0u8; 2].into()))
+        }
+        13 => {
+            let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[0x80]);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        14 => {
+            let args = zero_lock_args(LOCK_FLAG_PRESERVE_STRUCTURE, &[]);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 101].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        15 => Upgrade::simple().repro_with_root(&Byte32::zero(), compact_proof_witness(0)),
+        16 => {
+            let args = zero_lock_args(LOCK_FLAG_SALTED_ROOT, &[5u8; 31]);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        17 => {
+            let args = zero_lock_args(LOCK_FLAG_VERSIONED, &200u32.to_le_bytes());
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        18 => {
+            // Both input and output data carry version 0x01010101 at offset 0
+            let args = zero_lock_args(LOCK_FLAG_VERSIONED, &0u32.to_le_bytes());
+            let upgrade = Upgrade::new(args.clone(), args, vec![1u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        19 => {
+            // Only a governance cell dep may carry the kill switch, the
+            // header index in witness names it
+            let governance_type = random_type_id_script();
+            let mut ext_args = vec![LOCK_EXT_FLAG_GOVERNANCE_CELL];
+            ext_args.extend(governance_type.calc_script_hash().as_slice());
+            let args = zero_lock_args(LOCK_FLAG_EXTENDED, &ext_args);
+            let mut upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let extension = paused_extension(&upgrade.leaf());
+            let governance_cell =
+                script_cell_with_type(&mut upgrade.dummy, &extension, Some(governance_type));
+            let builder = TransactionBuilder::default()
+                .output(upgrade.output.cell_output.clone())
+                .output_data(upgrade.output.mem_cell_data.clone().unwrap().pack())
+                .witness(compact_proof_witness(0).pack());
+            (
+                upgrade.dummy,
+                builder,
+                vec![upgrade.input],
+                upgrade.since,
+                vec![governance_cell],
+            )
+        }
+        20 => {
+            let args = zero_lock_args(LOCK_FLAG_ACTIVATION, &[]);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        21 => {
+            let args = zero_lock_args(LOCK_FLAG_PATCH, &[]);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        22 | 23 => {
+            // Copying past the end of input cell data, or inserting the wrong data
+            let mut patch = vec![];
+            if code == 22 {
+                patch.push(PATCH_OP_COPY);
+                patch.extend(0u32.to_le_bytes());
+                patch.extend(101u32.to_le_bytes());
+            } else {
+                patch.push(PATCH_OP_INSERT);
+                patch.extend(100u32.to_le_bytes());
+                patch.extend([3u8; 100]);
+            }
+            let args = zero_lock_args(LOCK_FLAG_PATCH, &[]);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            let witness = WitnessArgs::new_unchecked(compact_proof_witness(0))
+                .as_builder()
+                .output_type(Some(Bytes::from(patch)).pack())
+                .build();
+            upgrade.repro_with_root(&root, witness.as_bytes())
+        }
+        24 => {
+            // The input cell is spent with a zero since
+            let args = zero_lock_args(LOCK_FLAG_MIN_CONFIRMATIONS, &1u32.to_le_bytes());
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        25 => {
+            // An absolute epoch since where a block number is expected
+            let args = zero_lock_args(LOCK_FLAG_MIN_CONFIRMATIONS, &1u32.to_le_bytes());
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into())
+                .with_since(absolute_epoch_since(HEADER_EPOCH_NUMBER + 1));
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        26 => {
+            // The compact witness only proves the leaf of the first input cell
+            let mut upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let other_input = zero_lock_cell(&mut upgrade.dummy, &vec![3u8; 100].into(), None);
+            let other_output = zero_lock_cell(&mut upgrade.dummy, &vec![4u8; 100].into(), None);
+            let (dummy, builder, mut inputs, since, cell_deps) =
+                upgrade.repro_with_root(&root, compact_proof_witness(0));
+            let builder = builder
+                .output(other_output.cell_output.clone())
+                .output_data(other_output.mem_cell_data.unwrap().pack())
+                .witness(Bytes::new().pack());
+            inputs.push(other_input);
+            (dummy, builder, inputs, since, cell_deps)
+        }
+        27 => {
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let witness = lock_witness(proof_lock_data_with_tags(0, &[0], &[0], &[]));
+            upgrade.repro_with_root(&root, witness)
+        }
+        28 => {
+            // Capacity of zero lock cells follows their data length
+            let upgrade = Upgrade::new(Bytes::new(), Bytes::new(), vec![2u8; 99].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        29 => {
+            let mut upgrade = Upgrade::simple();
+            upgrade.output.cell_output = upgrade
+                .output
+                .cell_output
+                .as_builder()
+                .type_(Some(random_type_id_script()).pack())
+                .build();
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        30 => {
+            let upgrade = Upgrade::simple();
+            let extension = structured_extension(&[
+                (EXTENSION_FIELD_MIN_EPOCHS, &1u32.to_le_bytes()[..]),
+                (EXTENSION_FIELD_ROOTS, upgrade.leaf().as_slice()),
+            ]);
+            upgrade.repro_with_extension(Some(extension))
+        }
+        31 => {
+            // The since is an absolute epoch, but the header's own
+            let upgrade = Upgrade::simple().with_since(absolute_epoch_since(HEADER_EPOCH_NUMBER));
+            let extension = structured_extension(&[
+                (EXTENSION_FIELD_MIN_EPOCHS, &1u32.to_le_bytes()[..]),
+                (EXTENSION_FIELD_ROOTS, upgrade.leaf().as_slice()),
+            ]);
+            upgrade.repro_with_extension(Some(extension))
+        }
+        32 => {
+            // The output cell leaves zero lock, but with another type script
+            let mut upgrade = Upgrade::simple();
+            upgrade.output.cell_output = upgrade
+                .output
+                .cell_output
+                .as_builder()
+                .lock(always_success_lock())
+                .type_(Some(random_type_id_script()).pack())
+                .build();
+            let root = upgrade.leaf();
+            let tags = [LeafTag::Migration as u8];
+            let witness = lock_witness(proof_lock_data_with_tags(0, &[0], &tags, &[]));
+            upgrade.repro_with_root(&root, witness)
+        }
+        33 => {
+            // The output cell references code no cell dep carries
+            let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_CODE_DEP]);
+            let data = code_dep_data(&[2u8; 100]);
+            let mut upgrade = Upgrade::new(args.clone(), args, data);
+            upgrade.output.cell_output = upgrade
+                .output
+                .cell_output
+                .as_builder()
+                .capacity(upgrade.input.cell_output.capacity())
+                .build();
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        34 => {
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, WitnessArgs::default().as_bytes())
+        }
+        35 => {
+            // The first cell dep is zero lock code rather than a governance cell
+            let mut ext_args = vec![LOCK_EXT_FLAG_GOVERNANCE_CELL];
+            ext_args.extend(random_type_id_script().calc_script_hash().as_slice());
+            let args = zero_lock_args(LOCK_FLAG_EXTENDED, &ext_args);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        36 => {
+            let upgrade = Upgrade::new(Bytes::new(), Bytes::new(), vec![1u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        37 => {
+            let upgrade = Upgrade::simple();
+            let extension = structured_extension(&[
+                (EXTENSION_FIELD_ROOTS, upgrade.leaf().as_slice()),
+                (EXTENSION_FIELD_THRESHOLD, &[2][..]),
+            ]);
+            upgrade.repro_with_extension(Some(extension))
+        }
+        38 => {
+            // A compact co-proof against the only slot, which the proof in
+            // witness lock already names: root index 0 without any index or
+            // lemma
+            let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_THRESHOLD_PROOFS]);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            let witness = serialize_threshold_witness(
+                serialize_compact_proof(0),
+                &[Bytes::from(vec![0u8; 12])],
+                None,
+            );
+            upgrade.repro_with_root(&root, witness)
+        }
+        39 => {
+            // A rotation leaf proving a cell without a governance type hash
+            // in lock args
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let tags = [LeafTag::Rotation as u8];
+            let witness = lock_witness(proof_lock_data_with_tags(0, &[0], &tags, &[]));
+            upgrade.repro_with_root(&root, witness)
+        }
+        45 => {
+            // The frozen input cell is upgraded to other data, long enough
+            // to keep its capacity
+            let mut upgrade = Upgrade::new(Bytes::new(), Bytes::new(), vec![2u8; 104].into());
+            let type_script = upgrade.input.cell_output.type_().to_opt();
+            upgrade.input =
+                zero_lock_cell(&mut upgrade.dummy, &frozen_data(&[1u8; 100]), type_script);
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(0))
+        }
+        46 => {
+            // A freeze leaf whose output cell data lacks the frozen magic
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let tags = [LeafTag::Freeze as u8];
+            let witness = lock_witness(proof_lock_data_with_tags(0, &[0], &tags, &[]));
+            upgrade.repro_with_root(&root, witness)
+        }
+        47 => {
+            // A reconfiguration leaf declaring code the new data rewrites
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let tags = [LeafTag::Reconfiguration as u8];
+            let witness = WitnessArgs::new_builder()
+                .lock(Some(proof_lock_data_with_tags(0, &[0], &tags, &[])).pack())
+                .output_type(Some(Bytes::from(10u32.to_le_bytes().to_vec())).pack())
+                .build()
+                .as_bytes();
+            upgrade.repro_with_root(&root, witness)
+        }
+        48 => {
+            // A release leaf freeing a byte of capacity to a lock no output
+            // cell uses
+            let upgrade = Upgrade::new(Bytes::new(), Bytes::new(), vec![2u8; 99].into());
+            let root = upgrade.leaf();
+            let tags = [LeafTag::Release as u8];
+            let released = Capacity::bytes(1).unwrap().as_u64();
+            let witness = WitnessArgs::new_builder()
+                .lock(Some(proof_lock_data_with_tags(0, &[0], &tags, &[])).pack())
+                .output_type(Some(release_output_type(released, &Byte32::zero())).pack())
+                .build()
+                .as_bytes();
+            upgrade.repro_with_root(&root, witness)
+        }
+        49 => {
+            let upgrade = Upgrade::simple();
+            let root = upgrade.leaf();
+            let lemmas = vec![Byte32::zero(); MAX_TREE_DEPTH + 1];
+            let witness = lock_witness(proof_lock_data(0, &[0], &lemmas));
+            upgrade.repro_with_root(&root, witness)
+        }
+        50 => {
+            // The input cell is spent twice, into two identical output cells
+            let upgrade = Upgrade::simple();
+            let root = Blake2bHash::merge(&upgrade.leaf(), &upgrade.leaf());
+            let output = upgrade.output.clone();
+            let witness = lock_witness(proof_lock_data(0, &[1, 2], &[]));
+            let (dummy, builder, mut inputs, since, cell_deps) =
+                upgrade.repro_with_root(&root, witness);
+            let builder = builder
+                .output(output.cell_output)
+                .output_data(output.mem_cell_data.unwrap().pack());
+            inputs.push(inputs[0].clone());
+            (dummy, builder, inputs, since, cell_deps)
+        }
+        51 => {
+            // Co-proofs are read, but the proof names a header dep the
+            // transaction does not have
+            let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_THRESHOLD_PROOFS]);
+            let upgrade = Upgrade::new(args.clone(), args, vec![2u8; 100].into());
+            let root = upgrade.leaf();
+            upgrade.repro_with_root(&root, compact_proof_witness(1))
+        }
+        52 => {
+            // The extension lets migrations hand cells to no lock at all
+            let mut upgrade = Upgrade::simple();
+            upgrade.output.cell_output = upgrade
+                .output
+                .cell_output
+                .as_builder()
+                .lock(always_success_lock())
+                .build();
+            let extension = successor_whitelist_extension(&upgrade.leaf(), &[]);
+            let header_dep = header_with_extension(&mut upgrade.dummy, 10000, Some(extension));
+            let tags = [LeafTag::Migration as u8];
+            let witness = lock_witness(proof_lock_data_with_tags(0, &[0], &tags, &[]));
+            let builder = upgrade.builder(header_dep, witness);
+            (
+                upgrade.dummy,
+                builder,
+                vec![upgrade.input],
+                upgrade.since,
+                vec![],
+            )
+        }
+        _ => return None,
+    };
+    Some(repro)
+}
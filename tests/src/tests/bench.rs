@@ -0,0 +1,124 @@
+//! Full transaction verification cost of upgrade batches, run with:
+//!
+//! ```text
+//! cargo test --release -- --ignored --nocapture bench_
+//! ```
+use super::*;
+use rand::{rngs::StdRng, SeedableRng};
+use std::time::Instant;
+
+/// Cycle limit of a CKB mainnet block.
+const MAX_BLOCK_CYCLES: u64 = 3_500_000_000;
+
+/// Builds one transaction upgrading `batch_size` zero lock cells at once.
+/// All cells share lock args so they form a single script group, proven
+/// together by one batch proof in the witness of the first input cell.
+fn batch_upgrade(batch_size: usize) -> TransactionScriptsVerifier<DummyDataLoader> {
+    let mut dummy_loader = DummyDataLoader::default();
+    let args = zero_lock_args(0, &[]);
+    let upgrades: Vec<(CellMeta, CellMeta)> = (0..batch_size)
+        .map(|_| {
+            let type_id = random_type_id_script();
+            let old_contract = vec![1u8; 100].into();
+            let new_contract = vec![2u8; 100].into();
+            (
+                zero_lock_cell_with_args(
+                    &mut dummy_loader,
+                    &old_contract,
+                    Some(type_id.clone()),
+                    args.clone(),
+                ),
+                zero_lock_cell_with_args(
+                    &mut dummy_loader,
+                    &new_contract,
+                    Some(type_id),
+                    args.clone(),
+                ),
+            )
+        })
+        .collect();
+    let all_leaves: Vec<(&CellMeta, &CellMeta)> = upgrades.iter().map(|(a, b)| (a, b)).collect();
+    let selected: Vec<u32> = (0..batch_size as u32).collect();
+
+    let (root, witnesses) = build_merkle_root_n_batch_proof(&all_leaves, &selected, 0);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let mut builder = TransactionBuilder::default().header_dep(header_dep);
+    for ((_input_cell_meta, output_cell_meta), witness) in upgrades.iter().zip(witnesses) {
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .witness(witness.pack());
+    }
+
+    let inputs = upgrades.into_iter().map(|(input, _output)| input).collect();
+    complete_tx(dummy_loader, builder, inputs).0
+}
+
+#[test]
+#[ignore]
+fn bench_batch_upgrade_verification() {
+    println!("batch size | cycles        | cycles per upgrade | block limit | wall time");
+    for batch_size in [1, 8, 32, 128] {
+        let verifier = batch_upgrade(batch_size);
+
+        let start = Instant::now();
+        let cycles = verifier
+            .verify(MAX_BLOCK_CYCLES)
+            .expect("pass verification");
+        let elapsed = start.elapsed();
+
+        println!(
+            "{:>10} | {:>13} | {:>18} | {:>10.2}% | {:?}",
+            batch_size,
+            cycles,
+            cycles / batch_size as u64,
+            cycles as f64 * 100.0 / MAX_BLOCK_CYCLES as f64,
+            elapsed
+        );
+    }
+}
+
+/// Cycles of a single upgrade proven against a tree of `leaves` leaves, at a
+/// position fixed by the seed so runs stay comparable.
+fn deep_tree_cycles(leaves: u32) -> Cycle {
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, None);
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, None);
+
+    let mut rng = StdRng::seed_from_u64(1024);
+    let (root, proof_witness) = bury_in_merkle_tree(
+        &input_cell_meta,
+        &output_cell_meta,
+        leaves - 1,
+        &mut rng,
+        0,
+        None,
+        None,
+    );
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    verifier.verify(u64::MAX).expect("pass verification")
+}
+
+#[test]
+#[ignore]
+fn bench_proof_verification_cycles() {
+    // One line per scenario in a fixed order, so runs diff cleanly
+    for (name, cycles) in [
+        ("single_upgrade", batch_cycles(1, true)),
+        ("batch_of_5", batch_cycles(5, true)),
+        ("deep_tree_of_1024", deep_tree_cycles(1024)),
+    ] {
+        println!("cycles {}: {}", name, cycles);
+    }
+}
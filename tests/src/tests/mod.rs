@@ -1,10 +1,22 @@
 use super::*;
 use ckb_error::assert_error_eq;
 use ckb_script::ScriptError;
-use ckb_types::{bytes::Bytes, core::TransactionBuilder, packed::WitnessArgs, prelude::*};
+use ckb_types::{
+    bytes::Bytes,
+    core::{Cycle, HeaderBuilder, TransactionBuilder},
+    packed::WitnessArgs,
+    prelude::*,
+};
+use ckb_zero_lock_types::{
+    serialize_input_type_witness,
+    verifier::{self as reference, Error},
+};
+use merkle_cbt::CBMT;
 use proptest::prelude::*;
 use rand::{rngs::StdRng, SeedableRng};
 
+mod bench;
+
 const MAX_CYCLES: u64 = 60_000_000;
 
 #[test]
@@ -37,6 +49,85 @@ fn test_single_zero_lock_upgrade() {
     verify_result.expect("pass verification");
 }
 
+/// Cycles of upgrading a cell with a contract of `size` bytes into another
+/// one of the same size.
+fn contract_upgrade_cycles(size: usize) -> Cycle {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; size].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; size].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    verifier.verify(MAX_CYCLES).expect("pass verification")
+}
+
+#[test]
+fn test_large_contract_upgrade() {
+    // Data hashes are loaded as cell fields, so contract data never enters
+    // VM memory. Loading and hashing 500KB in the VM would take far more
+    // cycles than the whole upgrade of a small contract.
+    let small = contract_upgrade_cycles(100);
+    let large = contract_upgrade_cycles(500 * 1024);
+    assert!(
+        large < small + small / 10,
+        "small: {}, large: {}",
+        small,
+        large
+    );
+}
+
+#[test]
+fn test_upgrade_committed_for_other_old_contract_fails_verification() {
+    // The leaf commits the out point of the cell it was built for, so the
+    // old contract the committee signed off on cannot be swapped for another
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let committed_contract = vec![1u8; 100].into();
+    let committed_cell_meta = zero_lock_cell(
+        &mut dummy_loader,
+        &committed_contract,
+        Some(type_id.clone()),
+    );
+    let old_contract = vec![3u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let root = hash_upgrade_data(&committed_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::RootMismatch as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
 #[test]
 fn test_single_zero_lock_no_type_script_upgrade() {
     let mut dummy_loader = DummyDataLoader::default();
@@ -97,6 +188,68 @@ fn test_zero_lock_with_other_cells_upgrade() {
     verify_result.expect("pass verification");
 }
 
+#[test]
+fn test_zero_lock_cells_created_without_zero_lock_input() {
+    // Zero lock never runs without a zero lock input cell, so output cells
+    // using it are not checked against any proof. A creation leaf could not
+    // be enforced, which is why tag 3 stays reserved.
+    let mut dummy_loader = DummyDataLoader::default();
+    let input_cell_meta = always_success_cell(&mut dummy_loader, 500);
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &vec![1u8; 100].into(), None);
+    let output_cell_meta2 = zero_lock_cell(&mut dummy_loader, &vec![2u8; 100].into(), None);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .output(output_cell_meta2.cell_output.clone())
+        .output_data(output_cell_meta2.mem_cell_data.clone().unwrap().pack())
+        .witness(Bytes::new().pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_zero_lock_after_other_inputs_upgrade() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 120].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let input_cell2 = always_success_cell(&mut dummy_loader, 150);
+    let input_cell3 = always_success_cell(&mut dummy_loader, 200);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    // The witnesses of the other inputs would fail zero lock if it read them,
+    // the proof sits at the index of the zero lock input
+    let other_witness = lock_only_witness(Bytes::from(vec![0xffu8; 8]))[0].clone();
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(other_witness.pack())
+        .witness(other_witness.pack())
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(
+        dummy_loader,
+        builder,
+        vec![input_cell2, input_cell3, input_cell_meta],
+    );
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), Ok(()));
+}
+
 #[test]
 fn test_single_zero_lock_with_extra_headers_upgrade() {
     let mut dummy_loader = DummyDataLoader::default();
@@ -124,15 +277,153 @@ fn test_single_zero_lock_with_extra_headers_upgrade() {
     verify_result.expect("pass verification");
 }
 
+/// Upgrades a single cell proven against the header dep at `header_index`,
+/// out of two header deps carrying the extensions built from the leaf by
+/// `build`.
+fn verify_two_header_deps_upgrade<F>(
+    header_index: u32,
+    build: F,
+) -> (Result<Cycle, ckb_error::Error>, Script)
+where
+    F: FnOnce(&Byte32) -> [Option<Bytes>; 2],
+{
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let mut builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .witness(compact_proof_witness(header_index).pack());
+    for (number, extension) in (10000..).zip(build(&root)) {
+        builder = builder.header_dep(header_with_extension(&mut dummy_loader, number, extension));
+    }
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_root_in_second_of_two_header_deps_upgrade() {
+    // The first header dep is only there for an unrelated reason
+    let (verify_result, _lock) = verify_two_header_deps_upgrade(1, |root| {
+        [None, Some(threshold_extension(&[root.clone()], 1))]
+    });
+    verify_result.expect("pass verification");
+}
+
 #[test]
-fn test_more_than_one_input_zero_lock_fails_verification() {
+fn test_header_dep_not_carrying_root_fails_verification() {
+    let (verify_result, lock) = verify_two_header_deps_upgrade(0, |root| {
+        [None, Some(threshold_extension(&[root.clone()], 1))]
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::MissingExtension as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_no_header_dep_carrying_root_fails_verification() {
+    // The first header dep has no root slot at all, the second one carries
+    // another root
+    for (header_index, error) in [(0, Error::InvalidExtension), (1, Error::RootMismatch)] {
+        let (verify_result, lock) = verify_two_header_deps_upgrade(header_index, |_root| {
+            [
+                Some(structured_extension(&[(
+                    EXTENSION_FIELD_UNRELATED,
+                    &[1u8; 32][..],
+                )])),
+                Some(threshold_extension(&[Byte32::new([2u8; 32])], 1)),
+            ]
+        });
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, error as i8).input_lock_script(0),
+        );
+    }
+}
+
+/// Upgrades the first cell of a tree whose root is in the first header dep,
+/// and the last cell of a tree whose root is in the second one. Each witness
+/// carries the proof of its own cell, naming the header at `header_indices`.
+fn verify_two_proofs_upgrade(
+    header_indices: [u32; 2],
+) -> (Result<Cycle, ckb_error::Error>, Script, Result<(), Error>) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans = random_plans(&mut dummy_loader, 4);
+    let trees: Vec<Vec<(&CellMeta, &CellMeta)>> = plans
+        .chunks(2)
+        .map(|tree| tree.iter().map(|(a, b)| (a, b)).collect())
+        .collect();
+    let (root0, witness0) = build_merkle_root_n_proof(&trees[0], 0, header_indices[0], None, None);
+    let (root1, witness1) = build_merkle_root_n_proof(&trees[1], 1, header_indices[1], None, None);
+
+    let upgraded = [&plans[0], &plans[3]];
+    let mut builder = TransactionBuilder::default()
+        .header_dep(header(&mut dummy_loader, &root0))
+        .header_dep(header(&mut dummy_loader, &root1))
+        .witness(witness0.pack())
+        .witness(witness1.pack());
+    for (_input_cell_meta, output_cell_meta) in upgraded {
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack());
+    }
+
+    let lock = plans[0].0.cell_output.lock();
+    let input_cells = upgraded.iter().map(|(input, _)| input.clone()).collect();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, input_cells);
+    let reference_result = reference::verify(&rtx, &lock, &dummy_loader);
+
+    (verifier.verify(MAX_CYCLES), lock, reference_result)
+}
+
+#[test]
+fn test_cells_resolved_against_different_header_deps_upgrade() {
+    let (verify_result, _lock, reference_result) = verify_two_proofs_upgrade([0, 1]);
+    verify_result.expect("pass verification");
+    assert_eq!(reference_result, Ok(()));
+}
+
+#[test]
+fn test_cell_resolved_against_header_of_other_cell_fails_verification() {
+    // Both proofs are valid, but each against the root of its own header only
+    let (verify_result, lock, reference_result) = verify_two_proofs_upgrade([1, 0]);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::RootMismatch));
+}
+
+#[test]
+fn test_proofs_naming_one_of_two_header_deps_fail_verification() {
+    for header_indices in [[0, 0], [1, 1]] {
+        let (verify_result, lock, reference_result) = verify_two_proofs_upgrade(header_indices);
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, Error::ProofHeaderCountMismatch as i8)
+                .input_lock_script(0),
+        );
+        assert_eq!(reference_result, Err(Error::ProofHeaderCountMismatch));
+    }
+}
+
+#[test]
+fn test_single_zero_lock_upgrade_loader_queries() {
     let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
     let old_contract = vec![1u8; 100].into();
-    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, None);
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
     let new_contract = vec![2u8; 100].into();
-    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, None);
-    let old_contract2 = vec![3u8; 100].into();
-    let input_cell_meta2 = zero_lock_cell(&mut dummy_loader, &old_contract2, None);
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
 
     let (root, proof_witness) =
         build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
@@ -141,131 +432,166 @@ fn test_more_than_one_input_zero_lock_fails_verification() {
     let builder = TransactionBuilder::default()
         .output(output_cell_meta.cell_output.clone())
         .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
-        .header_dep(header_dep)
+        .header_dep(header_dep.clone())
         .witness(proof_witness.pack());
 
-    let verifier = complete_tx(
-        dummy_loader,
-        builder,
-        vec![input_cell_meta.clone(), input_cell_meta2],
-    )
-    .0;
+    let (_, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    let loader = RecordingDataLoader::new(dummy_loader);
+    let verifier = build_verifier(rtx.clone(), loader.clone());
 
     let verify_result = verifier.verify(MAX_CYCLES);
-    assert_error_eq!(
-        verify_result.unwrap_err(),
-        ScriptError::validation_failure(&input_cell_meta.cell_output.lock(), -61)
-            .input_lock_script(0),
-    );
+    verify_result.expect("pass verification");
+
+    // Script binaries are resolved from cell deps by the verifier itself, only
+    // keep the queries issued on behalf of the zero lock.
+    let cell_deps: Vec<OutPoint> = rtx
+        .resolved_cell_deps
+        .iter()
+        .map(|dep| dep.out_point.clone())
+        .collect();
+    let queries: Vec<LoaderQuery> = loader
+        .queries()
+        .into_iter()
+        .filter(|query| match query {
+            LoaderQuery::CellData(out_point) | LoaderQuery::CellDataHash(out_point) => {
+                !cell_deps.contains(out_point)
+            }
+            _ => true,
+        })
+        .collect();
+    let input = rtx.resolved_inputs[0].out_point.clone();
+    let output = OutPoint::new(rtx.transaction.hash(), 0);
+    // The header hash is only logged under the debug-log feature, once the
+    // proof is verified
+    #[cfg(feature = "debug-log")]
+    let header_query = LoaderQuery::Header(header_dep.clone());
+    #[allow(unused_mut)]
+    let mut expected = vec![
+        // The extension is loaded before any leaf is hashed, first probing
+        // its length, then reading it
+        LoaderQuery::Extension(header_dep.clone()),
+        LoaderQuery::Extension(header_dep),
+        // The leaf commits the output data hash
+        LoaderQuery::CellDataHash(output.clone()),
+        // Both data hashes are compared to reject an upgrade keeping the data
+        LoaderQuery::CellDataHash(input.clone()),
+        LoaderQuery::CellDataHash(output),
+        // Only the prefix of input cell data is loaded, to tell whether the
+        // cell is frozen
+        LoaderQuery::CellData(input),
+    ];
+    #[cfg(feature = "debug-log")]
+    expected.push(header_query);
+    assert_eq!(queries, expected);
 }
 
 #[test]
-fn test_more_than_one_output_zero_lock_fails_verification() {
+fn test_zero_lock_dep_without_zero_lock_inputs_loader_queries() {
+    // Zero lock code is a cell dep and a header dep carries a valid root,
+    // but no input cell is in the group of zero lock
     let mut dummy_loader = DummyDataLoader::default();
-    let old_contract = vec![1u8; 200].into();
-    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, None);
-    let new_contract = vec![2u8; 100].into();
-    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, None);
-    let new_contract2 = vec![3u8; 100].into();
-    let output_cell_meta2 = zero_lock_cell(&mut dummy_loader, &new_contract2, None);
-
-    let (root, proof_witness) =
-        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let input_cell_meta = always_success_cell(&mut dummy_loader, 500);
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &vec![1u8; 100].into(), None);
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
     let header_dep = header(&mut dummy_loader, &root);
 
     let builder = TransactionBuilder::default()
         .output(output_cell_meta.cell_output.clone())
         .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
-        .output(output_cell_meta2.cell_output.clone())
-        .output_data(output_cell_meta2.mem_cell_data.clone().unwrap().pack())
         .header_dep(header_dep)
-        .witness(proof_witness.pack());
+        .witness(Bytes::new().pack());
 
-    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+    let lock = output_cell_meta.cell_output.lock();
+    let (_, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), Ok(()));
+    let loader = RecordingDataLoader::new(dummy_loader);
+    let verifier = build_verifier(rtx.clone(), loader.clone());
 
     let verify_result = verifier.verify(MAX_CYCLES);
-    assert_error_eq!(
-        verify_result.unwrap_err(),
-        ScriptError::validation_failure(&input_cell_meta.cell_output.lock(), -61)
-            .input_lock_script(0),
-    );
+    verify_result.expect("pass verification");
+
+    let cell_deps: Vec<OutPoint> = rtx
+        .resolved_cell_deps
+        .iter()
+        .map(|dep| dep.out_point.clone())
+        .collect();
+    let queries: Vec<LoaderQuery> = loader
+        .queries()
+        .into_iter()
+        .filter(|query| match query {
+            LoaderQuery::CellData(out_point) | LoaderQuery::CellDataHash(out_point) => {
+                !cell_deps.contains(out_point)
+            }
+            _ => true,
+        })
+        .collect();
+    assert!(queries.is_empty(), "{:?}", queries);
 }
 
 #[test]
-fn test_input_zero_lock_at_other_indices() {
+fn test_single_zero_lock_supplied_leaf_hash_upgrade() {
     let mut dummy_loader = DummyDataLoader::default();
     let type_id = random_type_id_script();
     let old_contract = vec![1u8; 100].into();
     let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
-    let new_contract = vec![2u8; 120].into();
+    let new_contract = vec![2u8; 100].into();
     let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
 
-    let input_cell2 = always_success_cell(&mut dummy_loader, 150);
-    let input_cell3 = always_success_cell(&mut dummy_loader, 200);
-    let output_cell2 = always_success_cell(&mut dummy_loader, 320);
-
     let (root, proof_witness) =
         build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let leaf = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let proof_witness = append_leaf_hash(proof_witness, &leaf);
     let header_dep = header(&mut dummy_loader, &root);
 
     let builder = TransactionBuilder::default()
         .output(output_cell_meta.cell_output.clone())
         .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
-        .output(output_cell2.cell_output.clone())
-        .output_data(output_cell2.mem_cell_data.clone().unwrap().pack())
         .header_dep(header_dep)
-        .witness(Bytes::new().pack())
         .witness(proof_witness.pack());
 
-    let verifier = complete_tx(
-        dummy_loader,
-        builder,
-        vec![input_cell2, input_cell_meta, input_cell3],
-    )
-    .0;
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
 
     let verify_result = verifier.verify(MAX_CYCLES);
     verify_result.expect("pass verification");
 }
 
 #[test]
-fn test_output_zero_lock_at_other_indices() {
+fn test_single_zero_lock_wrong_supplied_leaf_hash_fails_verification() {
     let mut dummy_loader = DummyDataLoader::default();
     let type_id = random_type_id_script();
     let old_contract = vec![1u8; 100].into();
     let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
-    let new_contract = vec![2u8; 120].into();
+    let new_contract = vec![2u8; 100].into();
     let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
 
-    let input_cell2 = always_success_cell(&mut dummy_loader, 150);
-    let input_cell3 = always_success_cell(&mut dummy_loader, 200);
-    let output_cell2 = always_success_cell(&mut dummy_loader, 320);
-
     let (root, proof_witness) =
         build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let proof_witness = append_leaf_hash(proof_witness, &Byte32::zero());
     let header_dep = header(&mut dummy_loader, &root);
 
     let builder = TransactionBuilder::default()
-        .output(output_cell2.cell_output.clone())
-        .output_data(output_cell2.mem_cell_data.clone().unwrap().pack())
         .output(output_cell_meta.cell_output.clone())
         .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
         .header_dep(header_dep)
         .witness(proof_witness.pack());
 
-    let verifier = complete_tx(
-        dummy_loader,
-        builder,
-        vec![input_cell_meta, input_cell2, input_cell3],
-    )
-    .0;
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
 
     let verify_result = verifier.verify(MAX_CYCLES);
-    verify_result.expect("pass verification");
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::LeafHashMismatch as i8
+        )
+        .input_lock_script(0),
+    );
 }
 
-#[test]
-fn test_missing_header_fails_verification() {
+/// Proves the upgrade into the committed contract, but writes other bytes of
+/// the same length into the output cell. With `supply_leaf` set the witness
+/// carries the leaf hash of the committed output cell as well.
+fn verify_substituted_output_data(supply_leaf: bool) -> (Result<Cycle, ckb_error::Error>, Script) {
     let mut dummy_loader = DummyDataLoader::default();
     let type_id = random_type_id_script();
     let old_contract = vec![1u8; 100].into();
@@ -273,26 +599,4426 @@ fn test_missing_header_fails_verification() {
     let new_contract = vec![2u8; 100].into();
     let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
 
-    let (root, proof_witness) =
+    let (root, mut proof_witness) =
         build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
-    let _header_dep = header(&mut dummy_loader, &root);
+    if supply_leaf {
+        let leaf = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+        proof_witness = append_leaf_hash(proof_witness, &leaf);
+    }
+    let header_dep = header(&mut dummy_loader, &root);
 
     let builder = TransactionBuilder::default()
         .output(output_cell_meta.cell_output.clone())
-        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .output_data(Bytes::from(vec![0xeeu8; 100]).pack())
+        .header_dep(header_dep)
         .witness(proof_witness.pack());
 
-    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
 
-    let verify_result = verifier.verify(MAX_CYCLES);
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_substituted_output_data_fails_verification() {
+    // The leaf hashes the data the output cell actually holds, so it stops
+    // matching the precomputed leaf committing the new contract before the
+    // proof is walked
+    let (verify_result, lock) = verify_substituted_output_data(true);
     assert_error_eq!(
         verify_result.unwrap_err(),
-        ScriptError::validation_failure(&input_cell_meta.cell_output.lock(), -61)
-            .input_lock_script(0),
+        ScriptError::validation_failure(&lock, Error::LeafHashMismatch as i8).input_lock_script(0),
     );
 }
 
-proptest! {
+#[test]
+fn test_substituted_output_data_without_supplied_leaf_fails_verification() {
+    // Without a precomputed leaf, the substitution only shows as a root
+    // the proof does not lead to
+    let (verify_result, lock) = verify_substituted_output_data(false);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_single_zero_lock_upgrade_header_at_first_block_of_epoch() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header_at(&mut dummy_loader, &root, HEADER_EPOCH_START_NUMBER);
+
+    let epoch = dummy_loader.headers[&header_dep].epoch();
+    assert_eq!(epoch.number(), HEADER_EPOCH_NUMBER);
+    assert_eq!(epoch.index(), 0);
+    assert_eq!(epoch.length(), HEADER_EPOCH_LENGTH);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_single_zero_lock_upgrade_header_at_last_block_of_epoch() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header_at(
+        &mut dummy_loader,
+        &root,
+        HEADER_EPOCH_START_NUMBER + HEADER_EPOCH_LENGTH - 1,
+    );
+
+    let epoch = dummy_loader.headers[&header_dep].epoch();
+    assert_eq!(epoch.number(), HEADER_EPOCH_NUMBER);
+    assert_eq!(epoch.index(), HEADER_EPOCH_LENGTH - 1);
+    assert_eq!(epoch.length(), HEADER_EPOCH_LENGTH);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_proof_deeper_than_max_tree_depth_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    // A tree with more than 2^31 leaves cannot be built here, so hand craft a
+    // proof with one lemma more than the deepest supported tree needs.
+    let lemmas = vec![Byte32::zero(); MAX_TREE_DEPTH + 1];
+    let lock = proof_lock_data(0, &[u32::MAX], &lemmas);
+    let proof_witness = WitnessArgs::new_builder()
+        .lock(Some(lock).pack())
+        .build()
+        .as_bytes();
+    let header_dep = header(&mut dummy_loader, &Byte32::zero());
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) =
+        complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]);
+    assert_eq!(
+        reference::verify(&rtx, &lock, &dummy_loader),
+        Err(Error::TreeTooDeep)
+    );
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::TreeTooDeep as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_proof_at_max_tree_depth() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    // The last node index u32 can hold is a leaf at the deepest level, whose
+    // root follows from the lemmas alone
+    let mut rng = StdRng::seed_from_u64(15);
+    let lemmas: Vec<Byte32> = (0..MAX_TREE_DEPTH)
+        .map(|_| Byte32::new(rng.gen()))
+        .collect();
+    let index = u32::MAX - 1;
+    let leaf = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let root = merkle_cbt::MerkleProof::<Byte32, Blake2bHash>::new(vec![index], lemmas.clone())
+        .root(&[leaf])
+        .unwrap();
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(lock_only_witness(proof_lock_data(0, &[index], &lemmas))[0].pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), Ok(()));
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_truncated_lemmas_fail_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let mut rng = StdRng::seed_from_u64(14);
+    let (root, proof_witness) = bury_in_merkle_tree(
+        &input_cell_meta,
+        &output_cell_meta,
+        10,
+        &mut rng,
+        0,
+        None,
+        None,
+    );
+    // The lemma count still covers the dropped lemma
+    let witness_args = WitnessArgs::new_unchecked(proof_witness);
+    let lock = witness_args.lock().to_opt().unwrap().raw_data();
+    let proof_witness = witness_args
+        .as_builder()
+        .lock(Some(lock.slice(..lock.len() - 32)).pack())
+        .build()
+        .as_bytes();
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::InvalidProof as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+/// Upgrades the first of three cells proven by a tree merged by SHA-256,
+/// against an extension picking `merge_hash` if any.
+fn verify_sha256_tree_upgrade(merge_hash: Option<u8>) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans: Vec<(CellMeta, CellMeta)> = (0..3u8)
+        .map(|i| {
+            let type_id = random_type_id_script();
+            (
+                zero_lock_cell(
+                    &mut dummy_loader,
+                    &vec![i; 100].into(),
+                    Some(type_id.clone()),
+                ),
+                zero_lock_cell(&mut dummy_loader, &vec![i + 100; 100].into(), Some(type_id)),
+            )
+        })
+        .collect();
+    let plans: Vec<(&CellMeta, &CellMeta)> = plans.iter().map(|(a, b)| (a, b)).collect();
+    let (input_cell_meta, output_cell_meta) = plans[0];
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof_with_merge_hash(&plans, 0, 0, None, None, MERGE_HASH_SHA256);
+    let mut fields = vec![(EXTENSION_FIELD_ROOTS, root.as_slice())];
+    let merge_hash = merge_hash.map(|merge_hash| [merge_hash]);
+    if let Some(merge_hash) = &merge_hash {
+        fields.push((EXTENSION_FIELD_MERGE_HASH, &merge_hash[..]));
+    }
+    let extension = structured_extension(&fields);
+    let header_dep = header_with_extension(&mut dummy_loader, 10000, Some(extension));
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_sha256_tree_upgrade() {
+    let (verify_result, _lock) = verify_sha256_tree_upgrade(Some(MERGE_HASH_SHA256));
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_sha256_tree_against_blake2b_merge_fails_verification() {
+    for merge_hash in [None, Some(MERGE_HASH_BLAKE2B)] {
+        let (verify_result, lock) = verify_sha256_tree_upgrade(merge_hash);
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+        );
+    }
+}
+
+#[test]
+fn test_unknown_merge_hash_fails_verification() {
+    let (verify_result, lock) = verify_sha256_tree_upgrade(Some(0x02));
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidExtension as i8).input_lock_script(0),
+    );
+}
+
+fn decode_hex(hex: &str) -> Bytes {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).expect("hex digit"))
+        .collect::<Vec<_>>()
+        .into()
+}
+
+#[test]
+fn test_upgrade_matches_golden_vectors() {
+    // Pins the leaf format, the serialization of proof and witness, sorting
+    // leaves by hash and merging tree nodes by blake2b. Cells reference zero lock by type hash
+    // and out points are fixed, so nothing depends on the build of zero lock
+    let vectors: serde_json::Value =
+        serde_json::from_str(include_str!("../../vectors/upgrade.json")).expect("parse vectors");
+    let field = |name: &str| decode_hex(vectors[name].as_str().expect(name));
+    let old_contract = field("old_contract");
+    let new_contract = field("new_contract");
+
+    let lock = Script::new_builder()
+        .code_hash(zero_lock_code_type_script().calc_script_hash())
+        .hash_type(ScriptHashType::Type.into())
+        .build();
+    let type_script = Script::new_builder()
+        .code_hash(TYPE_ID_CODE_HASH.pack())
+        .hash_type(ScriptHashType::Type.into())
+        .args(field("type_id_args").pack())
+        .build();
+    let cell = |data: &Bytes| {
+        CellOutput::new_builder()
+            .lock(lock.clone())
+            .type_(Some(type_script.clone()).pack())
+            .capacity(Capacity::bytes(data.len()).unwrap().pack())
+            .build()
+    };
+    let upgrades: Vec<(CellMeta, CellMeta)> = vectors["input_tx_hashes"]
+        .as_array()
+        .expect("input_tx_hashes")
+        .iter()
+        .map(|tx_hash| {
+            let tx_hash = Byte32::from_slice(&decode_hex(tx_hash.as_str().unwrap())).unwrap();
+            let input_cell_meta =
+                CellMetaBuilder::from_cell_output(cell(&old_contract), old_contract.clone())
+                    .out_point(OutPoint::new(tx_hash, 0))
+                    .build();
+            let output_cell_meta =
+                CellMetaBuilder::from_cell_output(cell(&new_contract), new_contract.clone())
+                    .build();
+            (input_cell_meta, output_cell_meta)
+        })
+        .collect();
+    let all_leaves: Vec<(&CellMeta, &CellMeta)> = upgrades.iter().map(|(i, o)| (i, o)).collect();
+    let selected = vectors["selected"].as_u64().expect("selected") as u32;
+    let (input_cell_meta, output_cell_meta) = &upgrades[selected as usize];
+
+    let leaf = hash_upgrade_data(input_cell_meta, output_cell_meta, None, None);
+    assert_eq!(leaf.as_bytes(), field("leaf"));
+    let (root, proof_witness) = build_merkle_root_n_proof(&all_leaves, selected, 0, None, None);
+    assert_eq!(root.as_bytes(), field("root"));
+    assert_eq!(proof_witness, field("witness"));
+
+    let mut dummy_loader = DummyDataLoader::default();
+    insert_cell(&mut dummy_loader, input_cell_meta);
+    let header_dep = header(&mut dummy_loader, &root);
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(new_contract.pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+    verifier.verify(MAX_CYCLES).expect("pass verification");
+}
+
+#[test]
+fn test_committees_in_different_extension_slots_upgrade() {
+    let num_slots = 4;
+    let committee_a = vec![1u8; 32];
+    let committee_b = (2u8..)
+        .map(|i| vec![i; 32])
+        .find(|id| slot_for(id, num_slots) != slot_for(&committee_a, num_slots))
+        .unwrap();
+
+    let mut dummy_loader = DummyDataLoader::default();
+    let mut upgrades = vec![];
+    for committee_id in [&committee_a, &committee_b] {
+        let type_id = random_type_id_script();
+        let old_contract = vec![1u8; 100].into();
+        let input_cell_meta = zero_lock_cell_with_args(
+            &mut dummy_loader,
+            &old_contract,
+            Some(type_id.clone()),
+            zero_lock_args(0, committee_id),
+        );
+        let new_contract = vec![2u8; 100].into();
+        let output_cell_meta = zero_lock_cell_with_args(
+            &mut dummy_loader,
+            &new_contract,
+            Some(type_id),
+            zero_lock_args(0, committee_id),
+        );
+        let (root, proof_witness) =
+            build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+        upgrades.push((input_cell_meta, output_cell_meta, root, proof_witness));
+    }
+    let extension = build_extension(
+        &[
+            (committee_a.as_slice(), upgrades[0].2.clone()),
+            (committee_b.as_slice(), upgrades[1].2.clone()),
+        ],
+        num_slots,
+    );
+    let header_dep = header_with_extension(&mut dummy_loader, 10000, Some(extension));
+
+    for (input_cell_meta, output_cell_meta, _root, proof_witness) in upgrades {
+        let builder = TransactionBuilder::default()
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .header_dep(header_dep.clone())
+            .witness(proof_witness.pack());
+
+        let verifier = complete_tx(dummy_loader.clone(), builder, vec![input_cell_meta]).0;
+
+        let verify_result = verifier.verify(MAX_CYCLES);
+        verify_result.expect("pass verification");
+    }
+}
+
+/// Upgrades a cell of a committee against an extension holding three tagged
+/// roots. The second one is tagged for the committee of the cell unless
+/// `tagged` is unset, then no root at all is tagged for it.
+fn verify_tagged_roots_upgrade(tagged: bool) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let committee_id = vec![1u8; 32];
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        zero_lock_args(0, &committee_id),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &new_contract,
+        Some(type_id),
+        zero_lock_args(0, &committee_id),
+    );
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+
+    // The other deployments commit roots of their own
+    let mut entries = vec![];
+    for i in 2u8..5 {
+        if tagged && i == 3 {
+            entries.extend(deployment_id(&committee_id));
+            entries.extend(root.as_slice());
+        } else {
+            entries.extend(deployment_id(&[i; 32]));
+            entries.extend([i; 32]);
+        }
+    }
+    let extension = structured_extension(&[(EXTENSION_FIELD_TAGGED_ROOTS, &entries[..])]);
+    let header_dep = header_with_extension(&mut dummy_loader, 10000, Some(extension));
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    let expected = if tagged {
+        Ok(())
+    } else {
+        Err(Error::InvalidExtension)
+    };
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), expected);
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_tagged_root_upgrade() {
+    let (verify_result, _lock) = verify_tagged_roots_upgrade(true);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_no_root_tagged_for_deployment_fails_verification() {
+    let (verify_result, lock) = verify_tagged_roots_upgrade(false);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidExtension as i8).input_lock_script(0),
+    );
+}
+
+fn root_indexed_upgrade(
+    dummy_loader: &mut DummyDataLoader,
+    committee_id: &[u8],
+) -> (CellMeta, CellMeta, Byte32, Bytes) {
+    let mut args = vec![LOCK_FLAG_EXTENDED, LOCK_EXT_FLAG_ROOT_INDEX];
+    args.extend(committee_id);
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone().into(),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta =
+        zero_lock_cell_with_args(dummy_loader, &new_contract, Some(type_id), args.into());
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    (input_cell_meta, output_cell_meta, root, proof_witness)
+}
+
+#[test]
+fn test_root_indexed_witnesses_select_extension_slots_upgrade() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let upgrades = [
+        root_indexed_upgrade(&mut dummy_loader, &[1u8; 32]),
+        root_indexed_upgrade(&mut dummy_loader, &[2u8; 32]),
+    ];
+    // Slots are picked by the witnesses, not derived from the committee ids
+    let roots = [upgrades[1].2.as_slice(), upgrades[0].2.as_slice()].concat();
+    let extension = structured_extension(&[(EXTENSION_FIELD_ROOTS, &roots)]);
+    let header_dep = header_with_extension(&mut dummy_loader, 10000, Some(extension));
+
+    for (root_index, (input_cell_meta, output_cell_meta, _root, proof_witness)) in
+        [1, 0].into_iter().zip(upgrades)
+    {
+        let builder = TransactionBuilder::default()
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .header_dep(header_dep.clone())
+            .witness(root_indexed_witness(root_index, proof_witness).pack());
+
+        let verifier = complete_tx(dummy_loader.clone(), builder, vec![input_cell_meta]).0;
+
+        let verify_result = verifier.verify(MAX_CYCLES);
+        verify_result.expect("pass verification");
+    }
+}
+
+#[test]
+fn test_root_index_beyond_extension_slots_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let (input_cell_meta, output_cell_meta, root, proof_witness) =
+        root_indexed_upgrade(&mut dummy_loader, &[]);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(root_indexed_witness(1, proof_witness).pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::InvalidExtension as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+/// Upgrades a single cell with the proof in witness input_type when
+/// `input_type` is set and in witness lock otherwise. The other field carries
+/// a signature-like value for a companion script, which is committed in the
+/// leaf for proofs in lock only.
+fn verify_proof_placement(
+    input_type: bool,
+    proof: Option<Bytes>,
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let args = if input_type {
+        zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_INPUT_TYPE_PROOF])
+    } else {
+        Bytes::new()
+    };
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &new_contract, Some(type_id), args);
+
+    let signature: Bytes = vec![7u8; 65].into();
+    let proof = proof.unwrap_or_else(|| proof_lock_data(0, &[0], &[]));
+    let (root, witness) = if input_type {
+        (
+            hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None),
+            serialize_input_type_witness(proof, Some(signature), None),
+        )
+    } else {
+        (
+            hash_upgrade_data(
+                &input_cell_meta,
+                &output_cell_meta,
+                Some(signature.clone()),
+                None,
+            ),
+            serialize_witness(proof, Some(signature), None),
+        )
+    };
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_proof_in_witness_lock_next_to_input_type_upgrade() {
+    let (verify_result, _lock) = verify_proof_placement(false, None);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_proof_in_witness_input_type_next_to_lock_upgrade() {
+    let (verify_result, _lock) = verify_proof_placement(true, None);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_malformed_proof_in_witness_input_type_fails_verification() {
+    let (verify_result, lock) = verify_proof_placement(true, Some(vec![0u8; 2].into()));
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidProof as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_proof_in_witness_lock_under_input_type_flag_fails_verification() {
+    // Witness input_type is required to carry the proof under the flag
+    let mut dummy_loader = DummyDataLoader::default();
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_INPUT_TYPE_PROOF]);
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &old_contract, None, args.clone());
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell_with_args(&mut dummy_loader, &new_contract, None, args);
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::MissingProof as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_single_zero_lock_compact_witness_upgrade() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_compact_witness_against_multiple_leaves_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let mut rng = StdRng::seed_from_u64(1);
+    let (root, _proof_witness) = bury_in_merkle_tree(
+        &input_cell_meta,
+        &output_cell_meta,
+        1,
+        &mut rng,
+        0,
+        None,
+        None,
+    );
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::RootMismatch as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_preserve_structure_same_length_upgrade() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(LOCK_FLAG_PRESERVE_STRUCTURE, &[]);
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &new_contract, Some(type_id), args);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_preserve_structure_changed_length_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(LOCK_FLAG_PRESERVE_STRUCTURE, &[]);
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_contract = vec![2u8; 120].into();
+    let output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &new_contract, Some(type_id), args);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::StructureNotPreserved as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+/// Upgrades a cell into a successor with one byte less capacity, against a
+/// proof committing the lowered successor when `commit_lowered` is set, or
+/// against the valid proof for the original successor otherwise.
+fn verify_lowered_capacity_upgrade(
+    commit_lowered: bool,
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+    let capacity: Capacity = output_cell_meta.cell_output.capacity().unpack();
+    let mut lowered_cell_meta = output_cell_meta.clone();
+    lowered_cell_meta.cell_output = output_cell_meta
+        .cell_output
+        .clone()
+        .as_builder()
+        .capacity(
+            capacity
+                .safe_sub(Capacity::bytes(1).unwrap())
+                .unwrap()
+                .pack(),
+        )
+        .build();
+
+    let committed_cell_meta = if commit_lowered {
+        &lowered_cell_meta
+    } else {
+        &output_cell_meta
+    };
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, committed_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(lowered_cell_meta.cell_output.clone())
+        .output_data(lowered_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_lowered_capacity_with_reused_proof_fails_verification() {
+    let (verify_result, lock) = verify_lowered_capacity_upgrade(false);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::CapacityLowered as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_lowered_capacity_committed_in_leaf_fails_verification() {
+    let (verify_result, lock) = verify_lowered_capacity_upgrade(true);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::CapacityLowered as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_rotated_type_id_committed_in_leaf_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(
+        &mut dummy_loader,
+        &old_contract,
+        Some(random_type_id_script()),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(
+        &mut dummy_loader,
+        &new_contract,
+        Some(random_type_id_script()),
+    );
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::TypeScriptChanged as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+/// Upgrades a cell with a type id into a cell under an always success type
+/// script, by a leaf flagged with `LEAF_TAG_FLAG_RETYPE` if `retype` is set.
+fn verify_retype_upgrade(retype: bool) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(
+        &mut dummy_loader,
+        &old_contract,
+        Some(random_type_id_script()),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(
+        &mut dummy_loader,
+        &new_contract,
+        Some(always_success_lock()),
+    );
+
+    let proof_builder = if retype {
+        UpgradeProofBuilder::default().retype_leaf(hash_retype_upgrade_data(
+            &input_cell_meta,
+            &output_cell_meta,
+        ))
+    } else {
+        UpgradeProofBuilder::default().leaf(
+            LeafTag::Upgrade,
+            hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None),
+        )
+    };
+    let witness = serialize_witness(proof_builder.build(0, &[0]).unwrap(), None, None);
+    let header_dep = header(&mut dummy_loader, &proof_builder.root());
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    let expected = if retype {
+        Ok(())
+    } else {
+        Err(Error::TypeScriptChanged)
+    };
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), expected);
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_retype_upgrade() {
+    let (verify_result, _lock) = verify_retype_upgrade(true);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_type_change_without_retype_flag_fails_verification() {
+    let (verify_result, lock) = verify_retype_upgrade(false);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::TypeScriptChanged as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_rejecting_type_script_fails_verification() {
+    // Zero lock only proves the upgrade, the type script of the cell still
+    // runs and rejects the transaction
+    let mut dummy_loader = DummyDataLoader::default();
+    let always_failure_cell_meta = script_cell(&mut dummy_loader, &ALWAYS_FAILURE_BIN);
+    let type_script = Script::new_builder()
+        .code_hash(CellOutput::calc_data_hash(&ALWAYS_FAILURE_BIN))
+        .hash_type(ScriptHashType::Data2.into())
+        .build();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta =
+        zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_script.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta =
+        zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_script.clone()));
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx_with_cell_deps(
+        dummy_loader,
+        builder,
+        vec![input_cell_meta],
+        0,
+        vec![always_failure_cell_meta],
+    );
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), Ok(()));
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&type_script, 1).input_type_script(0),
+    );
+}
+
+#[test]
+fn test_salted_root_isolates_cells_with_different_salts() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let salt_a = Byte32::new([3u8; 32]);
+    let salt_b = Byte32::new([4u8; 32]);
+    let args_a = zero_lock_args(LOCK_FLAG_SALTED_ROOT, salt_a.as_slice());
+    let args_b = zero_lock_args(LOCK_FLAG_SALTED_ROOT, salt_b.as_slice());
+
+    let type_id_a = random_type_id_script();
+    let input_a = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &vec![1u8; 100].into(),
+        Some(type_id_a.clone()),
+        args_a.clone(),
+    );
+    let output_a = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &vec![2u8; 100].into(),
+        Some(type_id_a),
+        args_a,
+    );
+    let type_id_b = random_type_id_script();
+    let input_b = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &vec![1u8; 100].into(),
+        Some(type_id_b.clone()),
+        args_b.clone(),
+    );
+    let output_b = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &vec![2u8; 100].into(),
+        Some(type_id_b),
+        args_b,
+    );
+
+    // Both upgrades are in the same tree, but the extension only carries the
+    // root salted for cell A
+    let all_leaves = [(&input_a, &output_a), (&input_b, &output_b)];
+    let (root, proof_witness_a) = build_merkle_root_n_proof(&all_leaves, 0, 0, None, None);
+    let (_root, proof_witness_b) = build_merkle_root_n_proof(&all_leaves, 1, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &salted_root(&root, &salt_a));
+
+    let builder = TransactionBuilder::default()
+        .output(output_a.cell_output.clone())
+        .output_data(output_a.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep.clone())
+        .witness(proof_witness_a.pack());
+    let verifier = complete_tx(dummy_loader.clone(), builder, vec![input_a]).0;
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+
+    let builder = TransactionBuilder::default()
+        .output(output_b.cell_output.clone())
+        .output_data(output_b.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness_b.pack());
+    let verifier = complete_tx(dummy_loader, builder, vec![input_b.clone()]).0;
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&input_b.cell_output.lock(), Error::RootMismatch as i8)
+            .input_lock_script(0),
+    );
+}
+
+const VERSION_OFFSET: u32 = 8;
+
+fn versioned_data(version: u32) -> Bytes {
+    let mut data = vec![1u8; 100];
+    data[VERSION_OFFSET as usize..VERSION_OFFSET as usize + 4]
+        .copy_from_slice(&version.to_le_bytes());
+    data.into()
+}
+
+fn verify_versioned_upgrade(
+    old_version: u32,
+    new_version: u32,
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    verify_versioned_upgrade_committing(old_version, new_version, new_version)
+}
+
+/// Same as `verify_versioned_upgrade`, but the committee commits an output
+/// cell carrying `committed_version` instead.
+fn verify_versioned_upgrade_committing(
+    old_version: u32,
+    new_version: u32,
+    committed_version: u32,
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(LOCK_FLAG_VERSIONED, &VERSION_OFFSET.to_le_bytes());
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &versioned_data(old_version),
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let output_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &versioned_data(new_version),
+        Some(type_id),
+        args,
+    );
+    let committed_cell_meta = CellMetaBuilder::from_cell_output(
+        output_cell_meta.cell_output.clone(),
+        versioned_data(committed_version),
+    )
+    .build();
+
+    let (root, proof_witness) = build_merkle_root_n_proof(
+        &[(&input_cell_meta, &committed_cell_meta)],
+        0,
+        0,
+        None,
+        None,
+    );
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_versioned_increasing_version_upgrade() {
+    let (verify_result, _lock) = verify_versioned_upgrade(3, 4);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_versioned_equal_version_fails_verification() {
+    let (verify_result, lock) = verify_versioned_upgrade(3, 3);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::VersionNotIncreased as i8)
+            .input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_versioned_decreasing_version_fails_verification() {
+    let (verify_result, lock) = verify_versioned_upgrade(3, 2);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::VersionNotIncreased as i8)
+            .input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_versioned_uncommitted_version_fails_verification() {
+    // The version is part of the committed output data, bumping it further
+    // than the committee authorized is no way around the leaf.
+    let (verify_result, lock) = verify_versioned_upgrade_committing(3, 5, 4);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+}
+
+/// Upgrades a cell into one with the same data and more capacity, with
+/// `ext_flags` in lock args.
+fn verify_same_data_upgrade(ext_flags: u8) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[ext_flags]);
+    let contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let mut output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &contract, Some(type_id), args);
+    output_cell_meta.cell_output = output_cell_meta
+        .cell_output
+        .as_builder()
+        .capacity(Capacity::bytes(200).unwrap().pack())
+        .build();
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_upgrade_keeping_data_fails_verification() {
+    let (verify_result, lock) = verify_same_data_upgrade(0);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::DataUnchanged as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_upgrade_keeping_data_under_allow_same_data_flag() {
+    let (verify_result, _lock) = verify_same_data_upgrade(LOCK_EXT_FLAG_ALLOW_SAME_DATA);
+    verify_result.expect("pass verification");
+}
+
+/// Upgrades a single cell against the root in a governance cell dep carrying
+/// the kill switch value `kill_switch`, checked by zero lock and by the
+/// reference verifier. The transaction has an output cell only when
+/// `with_output`.
+fn verify_kill_switch_upgrade(
+    kill_switch: u8,
+    with_output: bool,
+) -> (Result<Cycle, ckb_error::Error>, Result<(), Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let governance_type = random_type_id_script();
+    let type_id = random_type_id_script();
+    let mut args = vec![LOCK_EXT_FLAG_GOVERNANCE_CELL];
+    args.extend(governance_type.calc_script_hash().as_slice());
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &args);
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &vec![1u8; 100].into(),
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let output_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &vec![2u8; 100].into(),
+        Some(type_id),
+        args,
+    );
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let governance_cell_meta = script_cell_with_type(
+        &mut dummy_loader,
+        &kill_switch_extension(&root, kill_switch),
+        Some(governance_type),
+    );
+
+    let mut builder = TransactionBuilder::default().witness(proof_witness.pack());
+    if with_output {
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack());
+    }
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx_with_cell_deps(
+        dummy_loader,
+        builder,
+        vec![input_cell_meta],
+        0,
+        vec![governance_cell_meta],
+    );
+
+    (
+        verifier.verify(MAX_CYCLES),
+        reference::verify(&rtx, &lock, &dummy_loader),
+        lock,
+    )
+}
+
+#[test]
+fn test_kill_switch_pauses_valid_upgrade() {
+    let (verify_result, reference_result, lock) = verify_kill_switch_upgrade(1, true);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::Paused as i8).input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::Paused));
+
+    // Once the committee clears the kill switch, the same upgrade goes through
+    let (verify_result, reference_result, _lock) = verify_kill_switch_upgrade(0, true);
+    verify_result.expect("pass verification");
+    assert_eq!(reference_result, Ok(()));
+}
+
+#[test]
+fn test_kill_switch_applies_before_leaves_are_hashed() {
+    // No output cell uses zero lock, which hashing the leaf would report
+    let (verify_result, reference_result, lock) = verify_kill_switch_upgrade(1, false);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::Paused as i8).input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::Paused));
+}
+
+#[test]
+fn test_kill_switch_in_header_extension_fails_verification() {
+    // Scripts cannot see the tip, a header published before the committee
+    // set the kill switch would still prove the same root. Header extensions
+    // must not carry one at all, whatever its value
+    for kill_switch in [0, 1] {
+        let (verify_result, lock) =
+            verify_extension_upgrade(|root| kill_switch_extension(root, kill_switch));
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, Error::InvalidExtension as i8)
+                .input_lock_script(0),
+        );
+    }
+}
+
+/// Upgrades a single cell against a header carrying the extension built from
+/// the leaf by `build`.
+fn verify_extension_upgrade<F>(build: F) -> (Result<Cycle, ckb_error::Error>, Script)
+where
+    F: FnOnce(&Byte32) -> Bytes,
+{
+    verify_extension_upgrade_with_since(0, build)
+}
+
+/// Same as `verify_extension_upgrade`, but the input cell uses the given
+/// since value.
+fn verify_extension_upgrade_with_since<F>(
+    since: u64,
+    build: F,
+) -> (Result<Cycle, ckb_error::Error>, Script)
+where
+    F: FnOnce(&Byte32) -> Bytes,
+{
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header_with_extension(&mut dummy_loader, 10000, Some(build(&root)));
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx_with_since(dummy_loader, builder, vec![input_cell_meta], since).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_structured_extension_with_unrelated_fields_upgrade() {
+    let (verify_result, _lock) = verify_extension_upgrade(|root| {
+        structured_extension(&[
+            (EXTENSION_FIELD_UNRELATED, &[3u8; 64][..]),
+            (EXTENSION_FIELD_ROOTS, root.as_slice()),
+            (EXTENSION_FIELD_UNRELATED, &[][..]),
+            (EXTENSION_FIELD_UNRELATED + 1, &[4u8; 16][..]),
+        ])
+    });
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_root_between_other_commitments_upgrade() {
+    // Several consumers commit 32-byte values to the same extension, the root
+    // is found by its tag wherever it is placed among them
+    let (verify_result, _lock) = verify_extension_upgrade(|root| {
+        let extension = structured_extension(&[
+            (EXTENSION_FIELD_UNRELATED, &[5u8; 32][..]),
+            (EXTENSION_FIELD_UNRELATED + 1, &[6u8; 32][..]),
+            (EXTENSION_FIELD_UNRELATED + 2, &[7u8; 32][..]),
+            (EXTENSION_FIELD_ROOTS, root.as_slice()),
+            (EXTENSION_FIELD_UNRELATED + 3, &[8u8; 32][..]),
+            (EXTENSION_FIELD_UNRELATED + 4, &[9u8; 32][..]),
+        ]);
+        assert_ne!(
+            extension.slice(EXTENSION_ROOT_OFFSET..EXTENSION_ROOT_OFFSET + 32),
+            root.as_bytes()
+        );
+        extension
+    });
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_legacy_extension_upgrade() {
+    // The 180 random bytes deployed headers carry
+    let (verify_result, _lock) = verify_extension_upgrade(|root| {
+        let extension = legacy_extension(root);
+        assert_eq!(extension.len(), 180);
+        extension
+    });
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_legacy_extension_ending_at_root_slot_upgrade() {
+    let (verify_result, _lock) = verify_extension_upgrade(|root| {
+        legacy_extension(root).slice(..LEGACY_EXTENSION_MIN_LENGTH)
+    });
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_legacy_extension_data_before_root_is_never_read() {
+    // Deployed legacy extensions carry arbitrary data there, no byte of it
+    // pauses upgrades
+    let (verify_result, _lock) = verify_extension_upgrade(|root| {
+        let mut extension = legacy_extension(root).to_vec();
+        extension[..EXTENSION_ROOT_OFFSET].fill(0xff);
+        extension.into()
+    });
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_truncated_extension_fails_verification() {
+    // The roots field claims more data than the extension has left
+    let (verify_result, lock) = verify_extension_upgrade(|root| {
+        let extension = structured_extension(&[(EXTENSION_FIELD_ROOTS, root.as_slice())]);
+        extension.slice(..extension.len() - 1)
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidExtension as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_structured_extension_without_roots_fails_verification() {
+    let (verify_result, lock) = verify_extension_upgrade(|_root| {
+        structured_extension(&[(EXTENSION_FIELD_THRESHOLD, &[1][..])])
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidExtension as i8).input_lock_script(0),
+    );
+}
+
+/// Upgrades a cell against a header whose extension is broken by `defect`,
+/// asserting both zero lock and the reference verifier fail with `error`.
+fn assert_malformed_extension_fails(defect: ExtensionDefect, error: Error) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = malformed_header(&mut dummy_loader, &root, defect);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    assert_error_eq!(
+        verifier.verify(MAX_CYCLES).unwrap_err(),
+        ScriptError::validation_failure(&lock, error as i8).input_lock_script(0),
+    );
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), Err(error));
+}
+
+#[test]
+fn test_extension_short_of_root_slot_fails_verification() {
+    // Extensions shorter than the legacy layout are rejected before the root
+    // slot is read, never trapping on an out of bounds slice
+    for length in [
+        1,
+        64,
+        EXTENSION_ROOT_OFFSET,
+        LEGACY_EXTENSION_MIN_LENGTH - 1,
+    ] {
+        assert_malformed_extension_fails(
+            ExtensionDefect::Truncated(length),
+            Error::InvalidExtension,
+        );
+    }
+}
+
+#[test]
+fn test_extension_with_zeroed_root_fails_verification() {
+    assert_malformed_extension_fails(ExtensionDefect::ZeroedRoot, Error::EmptyRoot);
+}
+
+#[test]
+fn test_header_without_extension_fails_verification() {
+    assert_malformed_extension_fails(ExtensionDefect::Missing, Error::MissingExtension);
+}
+
+const ACTIVATION_BLOCK: u64 = 20000;
+
+fn activation_upgrade(
+    dummy_loader: &mut DummyDataLoader,
+) -> (CellMeta, CellMeta, TransactionBuilder) {
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(LOCK_FLAG_ACTIVATION, &[]);
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta =
+        zero_lock_cell_with_args(dummy_loader, &new_contract, Some(type_id), args);
+
+    // An absolute block number since only has the value bits set
+    let root = hash_upgrade_data_with_since(
+        &input_cell_meta,
+        &output_cell_meta,
+        None,
+        None,
+        Some(ACTIVATION_BLOCK),
+    );
+    let header_dep = header(dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+    (input_cell_meta, output_cell_meta, builder)
+}
+
+#[test]
+fn test_activation_upgrade() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let (input_cell_meta, _output_cell_meta, builder) = activation_upgrade(&mut dummy_loader);
+
+    let verifier = complete_tx_with_since(
+        dummy_loader,
+        builder,
+        vec![input_cell_meta],
+        ACTIVATION_BLOCK,
+    )
+    .0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_activation_with_earlier_since_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let (input_cell_meta, _output_cell_meta, builder) = activation_upgrade(&mut dummy_loader);
+
+    // Consensus would accept either since before the activation block
+    for (since, error) in [
+        (0, Error::MissingActivation),
+        (ACTIVATION_BLOCK - 1, Error::RootMismatch),
+    ] {
+        let verifier = complete_tx_with_since(
+            dummy_loader.clone(),
+            builder.clone(),
+            vec![input_cell_meta.clone()],
+            since,
+        )
+        .0;
+
+        let verify_result = verifier.verify(MAX_CYCLES);
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&input_cell_meta.cell_output.lock(), error as i8)
+                .input_lock_script(0),
+        );
+    }
+}
+
+fn patch_upgrade(
+    dummy_loader: &mut DummyDataLoader,
+    old_contract: &Bytes,
+    new_contract: &Bytes,
+    patch: &Bytes,
+) -> (CellMeta, TransactionBuilder) {
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(LOCK_FLAG_PATCH, &[]);
+    let input_cell_meta = zero_lock_cell_with_args(
+        dummy_loader,
+        old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let output_cell_meta =
+        zero_lock_cell_with_args(dummy_loader, new_contract, Some(type_id), args);
+
+    let root = hash_patch_upgrade_data(&input_cell_meta, &output_cell_meta, patch);
+    let header_dep = header(dummy_loader, &root);
+    let witness = WitnessArgs::new_unchecked(compact_proof_witness(0))
+        .as_builder()
+        .output_type(Some(patch.clone()).pack())
+        .build();
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(witness.as_bytes().pack());
+    (input_cell_meta, builder)
+}
+
+#[test]
+fn test_patch_upgrade_of_large_contract() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let mut rng = StdRng::seed_from_u64(14);
+    let mut old_contract = vec![0u8; 100_000];
+    rng.fill(&mut old_contract[..]);
+    let mut new_contract = old_contract.clone();
+    new_contract[60_000..60_016].copy_from_slice(&[7u8; 16]);
+    let patch = prover::make_patch(&old_contract, &new_contract);
+    assert!(patch.len() < 64);
+
+    let (input_cell_meta, builder) = patch_upgrade(
+        &mut dummy_loader,
+        &old_contract.into(),
+        &new_contract.into(),
+        &patch,
+    );
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_patch_not_producing_output_data_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 1000];
+    let mut new_contract = old_contract.clone();
+    new_contract[500] = 2;
+    let patch = prover::make_patch(&old_contract, &new_contract);
+    new_contract[600] = 3;
+
+    let (input_cell_meta, builder) = patch_upgrade(
+        &mut dummy_loader,
+        &old_contract.into(),
+        &new_contract.into(),
+        &patch,
+    );
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::PatchMismatch as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+const MIN_EPOCHS: u32 = 2;
+
+fn verify_epoch_delayed_upgrade(since: u64) -> (Result<Cycle, ckb_error::Error>, Script) {
+    verify_extension_upgrade_with_since(since, |root| {
+        structured_extension(&[
+            (EXTENSION_FIELD_MIN_EPOCHS, &MIN_EPOCHS.to_le_bytes()[..]),
+            (EXTENSION_FIELD_ROOTS, root.as_slice()),
+        ])
+    })
+}
+
+#[test]
+fn test_min_epochs_elapsed_upgrade() {
+    let since = absolute_epoch_since(HEADER_EPOCH_NUMBER + MIN_EPOCHS as u64);
+    let (verify_result, _lock) = verify_epoch_delayed_upgrade(since);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_upgrade_in_committed_epoch_fails_verification() {
+    for (since, error) in [
+        (0, Error::SinceNotEpoch),
+        (
+            absolute_epoch_since(HEADER_EPOCH_NUMBER),
+            Error::NotEnoughEpochs,
+        ),
+    ] {
+        let (verify_result, lock) = verify_epoch_delayed_upgrade(since);
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, error as i8).input_lock_script(0),
+        );
+    }
+}
+
+const MIN_CONFIRMATIONS: u32 = 24;
+
+fn verify_confirmed_upgrade(since: u64) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(
+        LOCK_FLAG_MIN_CONFIRMATIONS,
+        &MIN_CONFIRMATIONS.to_le_bytes(),
+    );
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &new_contract, Some(type_id), args);
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header_at(&mut dummy_loader, &root, 10000);
+    let header_view = dummy_loader.headers[&header_dep].clone();
+
+    let tip = HeaderBuilder::default().number(since.pack()).build();
+    assert_eq!(
+        analyze::is_final(&header_view, &tip, MIN_CONFIRMATIONS as u64),
+        since >= 10000 + MIN_CONFIRMATIONS as u64
+    );
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx_with_since(dummy_loader, builder, vec![input_cell_meta], since).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_min_confirmations_reached_upgrade() {
+    let (verify_result, _lock) = verify_confirmed_upgrade(10000 + MIN_CONFIRMATIONS as u64);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_min_confirmations_not_reached_fails_verification() {
+    let (verify_result, lock) = verify_confirmed_upgrade(10000 + MIN_CONFIRMATIONS as u64 - 1);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::NotEnoughConfirmations as i8)
+            .input_lock_script(0),
+    );
+}
+
+/// Upgrades a cell referencing zero lock code with `input_hash_type` into a
+/// cell referencing it with `hash_type`, against a leaf committing the
+/// successor with `committed`.
+fn verify_hash_type_upgrade(
+    input_hash_type: ScriptHashType,
+    hash_type: ScriptHashType,
+    committed: ScriptHashType,
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_hash_type(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        Bytes::new(),
+        input_hash_type,
+    );
+    let new_contract: Bytes = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell_with_hash_type(
+        &mut dummy_loader,
+        &new_contract,
+        Some(type_id.clone()),
+        Bytes::new(),
+        hash_type,
+    );
+    let committed_cell_meta = zero_lock_cell_with_hash_type(
+        &mut dummy_loader,
+        &new_contract,
+        Some(type_id),
+        Bytes::new(),
+        committed,
+    );
+
+    let root = hash_upgrade_data(&input_cell_meta, &committed_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_upgrade_into_type_locked_cell() {
+    let (verify_result, _lock) = verify_hash_type_upgrade(
+        ScriptHashType::Data2,
+        ScriptHashType::Type,
+        ScriptHashType::Type,
+    );
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_upgrade_into_data1_locked_cell() {
+    let (verify_result, _lock) = verify_hash_type_upgrade(
+        ScriptHashType::Data2,
+        ScriptHashType::Data1,
+        ScriptHashType::Data1,
+    );
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_data1_locked_cell_upgrade() {
+    let (verify_result, _lock) = verify_hash_type_upgrade(
+        ScriptHashType::Data1,
+        ScriptHashType::Data1,
+        ScriptHashType::Data1,
+    );
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_data1_locked_cell_upgrade_into_data2_locked_cell() {
+    let (verify_result, _lock) = verify_hash_type_upgrade(
+        ScriptHashType::Data1,
+        ScriptHashType::Data2,
+        ScriptHashType::Data2,
+    );
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_upgrade_into_type_locked_cell_not_committed_fails_verification() {
+    let (verify_result, lock) = verify_hash_type_upgrade(
+        ScriptHashType::Data2,
+        ScriptHashType::Type,
+        ScriptHashType::Data2,
+    );
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_empty_root_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let (_root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &Byte32::zero());
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::EmptyRoot as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_more_than_one_input_zero_lock_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, None);
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, None);
+    let old_contract2 = vec![3u8; 100].into();
+    let input_cell_meta2 = zero_lock_cell(&mut dummy_loader, &old_contract2, None);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(
+        dummy_loader,
+        builder,
+        vec![input_cell_meta.clone(), input_cell_meta2],
+    )
+    .0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::LeafCountMismatch as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_more_than_one_output_zero_lock_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 200].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, None);
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, None);
+    let new_contract2 = vec![3u8; 100].into();
+    let output_cell_meta2 = zero_lock_cell(&mut dummy_loader, &new_contract2, None);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .output(output_cell_meta2.cell_output.clone())
+        .output_data(output_cell_meta2.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::ExtraOutput as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+/// Upgrades the `upgraded` ones out of `count` planned cells in one
+/// transaction, in that order, against the root and witnesses built by `prove`.
+fn verify_batch_upgrade<F>(
+    count: u8,
+    upgraded: &[u32],
+    prove: F,
+) -> (Result<Cycle, ckb_error::Error>, Script)
+where
+    F: FnOnce(&[(&CellMeta, &CellMeta)]) -> (Byte32, Vec<Bytes>),
+{
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans = random_plans(&mut dummy_loader, count);
+    let all_leaves: Vec<(&CellMeta, &CellMeta)> = plans.iter().map(|(a, b)| (a, b)).collect();
+
+    let (root, witnesses) = prove(&all_leaves);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let mut builder = TransactionBuilder::default().header_dep(header_dep);
+    for (i, leaf) in upgraded.iter().enumerate() {
+        let output_cell_meta = &plans[*leaf as usize].1;
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .witness(witnesses.get(i).cloned().unwrap_or_default().pack());
+    }
+    let input_cells: Vec<CellMeta> = upgraded
+        .iter()
+        .map(|leaf| plans[*leaf as usize].0.clone())
+        .collect();
+
+    let lock = input_cells[0].cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, input_cells).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+fn lock_only_witness(lock: Bytes) -> Vec<Bytes> {
+    vec![WitnessArgs::new_builder()
+        .lock(Some(lock).pack())
+        .build()
+        .as_bytes()]
+}
+
+#[test]
+fn test_batch_zero_lock_upgrade() {
+    let (verify_result, _lock) = verify_batch_upgrade(5, &[0, 2, 3], |leaves| {
+        build_merkle_root_n_batch_proof(leaves, &[0, 2, 3], 0)
+    });
+    verify_result.expect("pass verification");
+}
+
+/// Upgrades two cells by leaves committing their output cells at indices 0
+/// and 2, around an output cell of another lock at index 1. Output cells are
+/// laid out in `order`, given as indices into that committed layout.
+fn verify_indexed_batch_upgrade(
+    order: [usize; 3],
+) -> (Result<Cycle, ckb_error::Error>, Script, Result<(), Error>) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans = random_plans(&mut dummy_loader, 2);
+    let other_cell_meta = always_success_cell(&mut dummy_loader, 100);
+    let proof_builder = UpgradeProofBuilder::default()
+        .indexed_leaf(
+            LeafTag::Upgrade,
+            hash_indexed_upgrade_data(&plans[0].0, &plans[0].1, 0),
+        )
+        .indexed_leaf(
+            LeafTag::Upgrade,
+            hash_indexed_upgrade_data(&plans[1].0, &plans[1].1, 2),
+        );
+    let witness = serialize_witness(proof_builder.build(0, &[0, 1]).unwrap(), None, None);
+    let header_dep = header(&mut dummy_loader, &proof_builder.root());
+
+    let committed = [&plans[0].1, &other_cell_meta, &plans[1].1];
+    let mut builder = TransactionBuilder::default()
+        .header_dep(header_dep)
+        .witness(witness.pack())
+        .witness(Bytes::new().pack());
+    for i in order {
+        builder = builder
+            .output(committed[i].cell_output.clone())
+            .output_data(committed[i].mem_cell_data.clone().unwrap().pack());
+    }
+
+    let lock = plans[0].0.cell_output.lock();
+    let input_cells = plans.into_iter().map(|(input, _)| input).collect();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, input_cells);
+    let reference_result = reference::verify(&rtx, &lock, &dummy_loader);
+
+    (verifier.verify(MAX_CYCLES), lock, reference_result)
+}
+
+#[test]
+fn test_indexed_batch_upgrade() {
+    let (verify_result, _lock, reference_result) = verify_indexed_batch_upgrade([0, 1, 2]);
+    verify_result.expect("pass verification");
+    assert_eq!(reference_result, Ok(()));
+}
+
+#[test]
+fn test_indexed_batch_with_reordered_outputs_fails_verification() {
+    // Moving the output cell of another lock keeps the order of output cells
+    // using zero lock, only the committed indices tell the layouts apart
+    for order in [[1, 0, 2], [0, 2, 1]] {
+        let (verify_result, lock, reference_result) = verify_indexed_batch_upgrade(order);
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+        );
+        assert_eq!(reference_result, Err(Error::RootMismatch));
+    }
+}
+
+/// Cycles of a transaction upgrading `count` cells without type scripts,
+/// all buried in the same tree. With `grouped` set the cells share one lock
+/// and get verified by a single multi-leaf proof, otherwise each cell uses
+/// its own committee id and proves its own leaf in its own script group.
+fn batch_cycles(count: u32, grouped: bool) -> Cycle {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans: Vec<(CellMeta, CellMeta)> = (0..count)
+        .map(|i| {
+            let committee_id = if grouped {
+                vec![]
+            } else {
+                i.to_le_bytes().to_vec()
+            };
+            let args = zero_lock_args(0, &committee_id);
+            (
+                zero_lock_cell_with_args(
+                    &mut dummy_loader,
+                    &vec![1u8; 100].into(),
+                    None,
+                    args.clone(),
+                ),
+                zero_lock_cell_with_args(&mut dummy_loader, &vec![2u8; 100].into(), None, args),
+            )
+        })
+        .collect();
+    let builder = UpgradeProofBuilder::default().leaves(
+        plans
+            .iter()
+            .map(|(old, new)| (LeafTag::Upgrade, hash_upgrade_data(old, new, None, None))),
+    );
+    let root = builder.root();
+
+    let all: Vec<u32> = (0..count).collect();
+    let witnesses: Vec<Bytes> = if grouped {
+        let batch_witness = serialize_witness(builder.build(0, &all).unwrap(), None, None);
+        (0..count)
+            .map(|i| {
+                if i == 0 {
+                    batch_witness.clone()
+                } else {
+                    Bytes::new()
+                }
+            })
+            .collect()
+    } else {
+        all.iter()
+            .map(|i| serialize_witness(builder.build(0, &[*i]).unwrap(), None, None))
+            .collect()
+    };
+    let header_dep = if grouped {
+        header(&mut dummy_loader, &root)
+    } else {
+        // Every slot holds the same root, whichever slot a committee id picks
+        let committee_ids: Vec<[u8; 4]> = all.iter().map(|i| i.to_le_bytes()).collect();
+        let committees: Vec<(&[u8], Byte32)> = committee_ids
+            .iter()
+            .map(|id| (&id[..], root.clone()))
+            .collect();
+        let extension = build_extension(&committees, 16);
+        header_with_extension(&mut dummy_loader, 10000, Some(extension))
+    };
+
+    let mut builder = TransactionBuilder::default().header_dep(header_dep);
+    for ((_input_cell_meta, output_cell_meta), witness) in plans.iter().zip(witnesses) {
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .witness(witness.pack());
+    }
+    let inputs = plans.into_iter().map(|(input, _output)| input).collect();
+    let verifier = complete_tx(dummy_loader, builder, inputs).0;
+
+    verifier.verify(MAX_CYCLES).expect("pass verification")
+}
+
+#[test]
+fn test_batched_proof_uses_fewer_cycles_than_per_cell_proofs() {
+    // A script group hashes all its leaves and walks shared lemmas once,
+    // per-cell proofs repeat the fixed cost and lemma traversal per cell.
+    let batched = batch_cycles(10, true);
+    let per_cell = batch_cycles(10, false);
+    assert!(
+        batched * 3 < per_cell * 2,
+        "batched: {}, per cell: {}",
+        batched,
+        per_cell
+    );
+}
+
+#[test]
+fn test_batch_with_leaf_missing_from_proof_fails_verification() {
+    let (verify_result, lock) = verify_batch_upgrade(5, &[0, 2, 3], |leaves| {
+        build_merkle_root_n_batch_proof(leaves, &[0, 2], 0)
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::LeafCountMismatch as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_batch_with_decreasing_indices_fails_verification() {
+    // Both leaves of a two leaf tree are proven without any lemma
+    let (verify_result, lock) = verify_batch_upgrade(2, &[0, 1], |leaves| {
+        let (root, _witnesses) = build_merkle_root_n_batch_proof(leaves, &[0, 1], 0);
+        (root, lock_only_witness(proof_lock_data(0, &[2, 1], &[])))
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidWitness as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_batch_with_inputs_out_of_tree_order() {
+    // Leaves are paired with node indices sorted by hash, whichever order
+    // the input cells spend them in
+    for upgraded in [[2, 0, 3], [3, 2, 0]] {
+        let (verify_result, _lock) = verify_batch_upgrade(5, &upgraded, |leaves| {
+            build_merkle_root_n_batch_proof(leaves, &upgraded, 0)
+        });
+        verify_result.expect("pass verification");
+    }
+}
+
+#[test]
+fn test_batch_with_duplicate_indices_fails_verification() {
+    // Both leaves of a two leaf tree are proven without any lemma
+    let (verify_result, lock) = verify_batch_upgrade(2, &[0, 1], |leaves| {
+        let (root, _witnesses) = build_merkle_root_n_batch_proof(leaves, &[0, 1], 0);
+        (root, lock_only_witness(proof_lock_data(0, &[1, 1], &[])))
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidWitness as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_identical_leaves_fail_verification() {
+    // Spending one out point twice yields the same leaf for both input cells.
+    // Consensus never lets such a transaction through, zero lock rejects it
+    // anyway rather than proving one committed leaf for two spends
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, None);
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, None);
+
+    let leaf = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let proof_builder = UpgradeProofBuilder::default()
+        .leaf(LeafTag::Upgrade, leaf.clone())
+        .leaf(LeafTag::Upgrade, leaf);
+    assert_eq!(proof_builder.build(0, &[0, 1]), None);
+    let header_dep = header(&mut dummy_loader, &proof_builder.root());
+
+    let mut builder = TransactionBuilder::default()
+        .header_dep(header_dep)
+        .witness(lock_only_witness(proof_lock_data(0, &[1, 2], &[]))[0].pack())
+        .witness(Bytes::new().pack());
+    for _ in 0..2 {
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(new_contract.pack());
+    }
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(
+        dummy_loader,
+        builder,
+        vec![input_cell_meta.clone(), input_cell_meta],
+    );
+    assert_eq!(
+        reference::verify(&rtx, &lock, &dummy_loader),
+        Err(Error::DuplicateLeaf)
+    );
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::DuplicateLeaf as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_indices_count_beyond_witness_lock_fails_verification() {
+    for declared in [2u32, u32::MAX] {
+        let (verify_result, lock) = verify_batch_upgrade(1, &[0], |leaves| {
+            let root = hash_upgrade_data(leaves[0].0, leaves[0].1, None, None);
+            let mut lock = proof_lock_data(0, &[0], &[]).to_vec();
+            // Indices count follows the header index
+            lock[4..8].copy_from_slice(&declared.to_le_bytes());
+            (root, lock_only_witness(lock.into()))
+        });
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, Error::InvalidWitness as i8)
+                .input_lock_script(0),
+        );
+    }
+}
+
+#[test]
+fn test_empty_witness_lock_fails_verification() {
+    let (verify_result, lock) = verify_batch_upgrade(1, &[0], |leaves| {
+        let root = hash_upgrade_data(leaves[0].0, leaves[0].1, None, None);
+        (root, lock_only_witness(Bytes::new()))
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::MissingProof as i8).input_lock_script(0),
+    );
+}
+
+/// Upgrades a single cell whose leaf is committed as root, attaching the
+/// given witnesses to the transaction.
+fn verify_upgrade_with_witnesses(
+    witnesses: Vec<Bytes>,
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witnesses(witnesses.into_iter().map(|witness| witness.pack()));
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_upgrade_without_witness_fails_verification() {
+    let (verify_result, lock) = verify_upgrade_with_witnesses(vec![]);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::MissingProof as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_witness_without_lock_fails_verification() {
+    let witness = WitnessArgs::new_builder()
+        .output_type(Some(Bytes::from(vec![3u8; 10])).pack())
+        .build()
+        .as_bytes();
+    let (verify_result, lock) = verify_upgrade_with_witnesses(vec![witness]);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::MissingProof as i8).input_lock_script(0),
+    );
+}
+
+/// Upgrades the first of four planned cells against its proof, with lemmas
+/// tampered by `tamper` which returns the lemma count to declare.
+fn verify_lemma_count_upgrade<F>(tamper: F) -> (Result<Cycle, ckb_error::Error>, Script)
+where
+    F: FnOnce(&mut Vec<Byte32>) -> u32,
+{
+    verify_batch_upgrade(4, &[0], |leaves| {
+        let hashes: Vec<Byte32> = leaves
+            .iter()
+            .map(|(old, new)| hash_upgrade_data(old, new, None, None))
+            .collect();
+        let tree = CBMT::<Byte32, Blake2bHash>::build_merkle_tree(&hashes);
+        let mut lemmas = tree.build_proof(&[0]).unwrap().lemmas().to_vec();
+        let declared = tamper(&mut lemmas);
+        let mut lock = proof_lock_data(0, &[3], &lemmas).to_vec();
+        // Lemma count follows header index, indices count, the index and its tag
+        lock[13..17].copy_from_slice(&declared.to_le_bytes());
+        (tree.root(), lock_only_witness(lock.into()))
+    })
+}
+
+#[test]
+fn test_lemma_count_beyond_serialized_lemmas_fails_verification() {
+    let (verify_result, _lock) = verify_lemma_count_upgrade(|lemmas| lemmas.len() as u32);
+    verify_result.expect("pass verification");
+
+    let (verify_result, lock) = verify_lemma_count_upgrade(|lemmas| lemmas.len() as u32 + 1);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidWitness as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_lemma_left_over_fails_verification() {
+    let (verify_result, lock) = verify_lemma_count_upgrade(|lemmas| {
+        lemmas.push(Byte32::zero());
+        lemmas.len() as u32
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidWitness as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_absurd_lemma_count_fails_verification() {
+    let (verify_result, lock) = verify_lemma_count_upgrade(|_lemmas| SPLIT_LEMMAS - 1);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::TreeTooDeep as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_proof_without_indices_fails_verification() {
+    let (verify_result, lock) = verify_batch_upgrade(1, &[0], |leaves| {
+        let root = hash_upgrade_data(leaves[0].0, leaves[0].1, None, None);
+        (root, lock_only_witness(proof_lock_data(0, &[], &[])))
+    });
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidWitness as i8).input_lock_script(0),
+    );
+}
+
+/// Upgrades two cells without type scripts in one transaction, putting their
+/// successors in `output_order`.
+fn verify_no_type_script_batch_upgrade(
+    output_order: [usize; 2],
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans: Vec<(CellMeta, CellMeta)> = (0..2u8)
+        .map(|i| {
+            let old_contract = vec![i; 100].into();
+            let new_contract = vec![i + 100; 100].into();
+            (
+                zero_lock_cell(&mut dummy_loader, &old_contract, None),
+                zero_lock_cell(&mut dummy_loader, &new_contract, None),
+            )
+        })
+        .collect();
+    let all_leaves: Vec<(&CellMeta, &CellMeta)> = plans.iter().map(|(a, b)| (a, b)).collect();
+
+    let (root, witnesses) = build_merkle_root_n_batch_proof(&all_leaves, &[0, 1], 0);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let mut builder = TransactionBuilder::default().header_dep(header_dep);
+    for (witness, i) in witnesses.into_iter().zip(output_order) {
+        let output_cell_meta = &plans[i].1;
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .witness(witness.pack());
+    }
+    let input_cells: Vec<CellMeta> = plans.into_iter().map(|(input, _)| input).collect();
+
+    let lock = input_cells[0].cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, input_cells).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_no_type_script_batch_upgrade() {
+    let (verify_result, _lock) = verify_no_type_script_batch_upgrade([0, 1]);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_no_type_script_batch_with_swapped_outputs_fails_verification() {
+    let (verify_result, lock) = verify_no_type_script_batch_upgrade([1, 0]);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_unknown_leaf_tags_fail_verification() {
+    // 0 and 9 to 63 are unassigned, 3 is reserved for creation. Deletions
+    // have no output cell to index, and only upgrades retype their output
+    // cell
+    for tag in [
+        0u8,
+        3,
+        9,
+        3 | LEAF_TAG_FLAG_OUTPUT_INDEX,
+        9 | LEAF_TAG_FLAG_RETYPE,
+        LeafTag::Deletion as u8 | LEAF_TAG_FLAG_OUTPUT_INDEX,
+        LeafTag::Deletion as u8 | LEAF_TAG_FLAG_RETYPE,
+        LeafTag::Migration as u8 | LEAF_TAG_FLAG_RETYPE,
+    ] {
+        let (verify_result, lock) = verify_batch_upgrade(1, &[0], |leaves| {
+            let root = hash_upgrade_data(leaves[0].0, leaves[0].1, None, None);
+            (
+                root,
+                lock_only_witness(proof_lock_data_with_tags(0, &[0], &[tag], &[])),
+            )
+        });
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, Error::UnknownLeafTag as i8)
+                .input_lock_script(0),
+        );
+    }
+}
+
+/// Deletes a cell and upgrades another one in one transaction. The committed
+/// deletion leaf is for a cell with the same data and type script as the one
+/// spent when `spend_copy` is set.
+fn verify_deletion_with_upgrade(spend_copy: bool) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract: Bytes = vec![1u8; 100].into();
+    let type_id = random_type_id_script();
+    let deleted = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let copy = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id));
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &vec![2u8; 100].into(), None);
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &vec![3u8; 100].into(), None);
+
+    // Both leaves of a two leaf tree are proven without any lemma
+    let root = sorted_tree_root(&[
+        hash_deletion_data(&deleted, None, None),
+        hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None),
+    ]);
+    let header_dep = header(&mut dummy_loader, &root);
+    let proof = proof_lock_data_with_tags(
+        0,
+        &[1, 2],
+        &[LeafTag::Deletion as u8, LeafTag::Upgrade as u8],
+        &[],
+    );
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(lock_only_witness(proof)[0].pack())
+        .witness(Bytes::new().pack());
+    let spent = if spend_copy { copy } else { deleted };
+
+    let lock = spent.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![spent, input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_deletion_with_upgrade() {
+    let (verify_result, _lock) = verify_deletion_with_upgrade(false);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_deletion_of_identical_cell_fails_verification() {
+    let (verify_result, lock) = verify_deletion_with_upgrade(true);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+}
+
+/// Migrates a zero lock cell into a cell locked by always success. The output
+/// cell of the transaction is locked by another always success lock than the
+/// committed one when `swap_lock` is set.
+fn verify_migration(swap_lock: bool) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let mut output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+    output_cell_meta.cell_output = output_cell_meta
+        .cell_output
+        .as_builder()
+        .lock(always_success_lock())
+        .build();
+
+    let root = hash_migration_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+    let proof = proof_lock_data_with_tags(0, &[0], &[LeafTag::Migration as u8], &[]);
+
+    let output = if swap_lock {
+        let lock = always_success_lock()
+            .as_builder()
+            .args(Bytes::from(vec![1u8]).pack())
+            .build();
+        output_cell_meta.cell_output.as_builder().lock(lock).build()
+    } else {
+        output_cell_meta.cell_output.clone()
+    };
+    let builder = TransactionBuilder::default()
+        .output(output)
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(lock_only_witness(proof)[0].pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_migration_to_always_success_lock() {
+    let (verify_result, _lock) = verify_migration(false);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_migration_to_swapped_lock_fails_verification() {
+    let (verify_result, lock) = verify_migration(true);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+}
+
+/// Migrates a zero lock cell into a cell locked by always success, against
+/// an extension listing `code_hashes` as the only locks to migrate to.
+fn verify_whitelisted_migration(
+    code_hashes: &[Byte32],
+) -> (Result<Cycle, ckb_error::Error>, Script, Result<(), Error>) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let input_cell_meta = zero_lock_cell(
+        &mut dummy_loader,
+        &vec![1u8; 100].into(),
+        Some(type_id.clone()),
+    );
+    let mut output_cell_meta =
+        zero_lock_cell(&mut dummy_loader, &vec![2u8; 100].into(), Some(type_id));
+    output_cell_meta.cell_output = output_cell_meta
+        .cell_output
+        .as_builder()
+        .lock(always_success_lock())
+        .build();
+
+    let root = hash_migration_data(&input_cell_meta, &output_cell_meta, None, None);
+    let extension = successor_whitelist_extension(&root, code_hashes);
+    let header_dep = header_with_extension(&mut dummy_loader, 10000, Some(extension));
+    let proof = proof_lock_data_with_tags(0, &[0], &[LeafTag::Migration as u8], &[]);
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(lock_only_witness(proof)[0].pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    let reference_result = reference::verify(&rtx, &lock, &dummy_loader);
+
+    (verifier.verify(MAX_CYCLES), lock, reference_result)
+}
+
+#[test]
+fn test_migration_to_second_whitelisted_lock() {
+    let code_hashes = [Byte32::new([3u8; 32]), always_success_lock().code_hash()];
+    let (verify_result, _lock, reference_result) = verify_whitelisted_migration(&code_hashes);
+    verify_result.expect("pass verification");
+    assert_eq!(reference_result, Ok(()));
+}
+
+#[test]
+fn test_migration_to_lock_missing_from_whitelist_fails_verification() {
+    let code_hashes = [Byte32::new([3u8; 32]), Byte32::new([4u8; 32])];
+    let (verify_result, lock, reference_result) = verify_whitelisted_migration(&code_hashes);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::SuccessorNotWhitelisted as i8)
+            .input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::SuccessorNotWhitelisted));
+}
+
+#[test]
+fn test_upgrade_to_foreign_lock_fails_verification() {
+    // Only migration leaves hand a cell over to another lock, an upgrade leaf
+    // committing the foreign lock still needs an output cell using zero lock
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let mut output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+    output_cell_meta.cell_output = output_cell_meta
+        .cell_output
+        .as_builder()
+        .lock(always_success_lock())
+        .build();
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+    let proof = proof_lock_data_with_tags(0, &[0], &[LeafTag::Upgrade as u8], &[]);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(lock_only_witness(proof)[0].pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    assert_eq!(reference::verify(&rtx, &lock, &dummy), Err(Error::NoOutput));
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::NoOutput as i8).input_lock_script(0),
+    );
+}
+
+/// Upgrades a cell to code referenced by hash, with `dep_contract` deployed
+/// in a cell dep of the transaction.
+fn verify_code_dep_upgrade(dep_contract: Bytes) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_CODE_DEP]);
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_contract = vec![2u8; 100];
+    let mut output_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &code_dep_data(&new_contract),
+        Some(type_id),
+        args,
+    );
+    output_cell_meta.cell_output = output_cell_meta
+        .cell_output
+        .as_builder()
+        .capacity(input_cell_meta.cell_output.capacity())
+        .build();
+    let code_cell_meta = script_cell(&mut dummy_loader, &dep_contract);
+
+    let root = hash_code_dep_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx_with_cell_deps(
+        dummy_loader,
+        builder,
+        vec![input_cell_meta],
+        0,
+        vec![code_cell_meta],
+    )
+    .0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_upgrade_to_code_in_cell_dep() {
+    let (verify_result, _lock) = verify_code_dep_upgrade(vec![2u8; 100].into());
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_upgrade_to_code_missing_from_cell_deps_fails_verification() {
+    let (verify_result, lock) = verify_code_dep_upgrade(vec![3u8; 100].into());
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::MissingCodeDep as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_batch_upgrade_to_code_in_shared_cell_dep() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_CODE_DEP]);
+    let new_contract: Bytes = vec![2u8; 100].into();
+    let plans: Vec<(CellMeta, CellMeta)> = (0..3u8)
+        .map(|i| {
+            let type_id = random_type_id_script();
+            let input_cell_meta = zero_lock_cell_with_args(
+                &mut dummy_loader,
+                &vec![i; 100].into(),
+                Some(type_id.clone()),
+                args.clone(),
+            );
+            let mut output_cell_meta = zero_lock_cell_with_args(
+                &mut dummy_loader,
+                &code_dep_data(&new_contract),
+                Some(type_id),
+                args.clone(),
+            );
+            output_cell_meta.cell_output = output_cell_meta
+                .cell_output
+                .as_builder()
+                .capacity(input_cell_meta.cell_output.capacity())
+                .build();
+            (input_cell_meta, output_cell_meta)
+        })
+        .collect();
+    // A single cell dep carries the code every output cell references
+    let code_cell_meta = script_cell(&mut dummy_loader, &new_contract);
+
+    let proof_builder = UpgradeProofBuilder::default().leaves(plans.iter().map(|(old, new)| {
+        (
+            LeafTag::Upgrade,
+            hash_code_dep_upgrade_data(old, new, None, None),
+        )
+    }));
+    let proof = proof_builder.build(0, &[0, 1, 2]).unwrap();
+    let header_dep = header(&mut dummy_loader, &proof_builder.root());
+
+    let mut builder = TransactionBuilder::default()
+        .header_dep(header_dep)
+        .witness(serialize_witness(proof, None, None).pack())
+        .witness(Bytes::new().pack())
+        .witness(Bytes::new().pack());
+    for (_, output_cell_meta) in &plans {
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack());
+    }
+
+    let input_cells = plans.iter().map(|(input, _)| input.clone()).collect();
+    let (verifier, rtx, _) =
+        complete_tx_with_cell_deps(dummy_loader, builder, input_cells, 0, vec![code_cell_meta]);
+    verifier.verify(MAX_CYCLES).expect("pass verification");
+
+    // Each output cell keeps its capacity and carries the hash of the one
+    // code cell, instead of a copy of the code
+    let code_hash = CellOutput::calc_data_hash(&new_contract);
+    for (i, (input_cell_meta, _)) in plans.iter().enumerate() {
+        let output = rtx.transaction.outputs().get(i).unwrap();
+        assert_eq!(
+            output.capacity().as_slice(),
+            input_cell_meta.cell_output.capacity().as_slice()
+        );
+        let output_data = rtx.transaction.outputs_data().get(i).unwrap().raw_data();
+        assert_eq!(&output_data[..], code_hash.as_slice());
+    }
+    let code_deps = rtx
+        .resolved_cell_deps
+        .iter()
+        .filter(|dep| CellOutput::calc_data_hash(dep.mem_cell_data.as_ref().unwrap()) == code_hash)
+        .count();
+    assert_eq!(code_deps, 1);
+}
+
+/// Upgrades three cells locked with `ext_flags` in a batch, proven by a
+/// varint proof. The result of the reference verifier comes last.
+fn verify_varint_proof_batch_upgrade(
+    ext_flags: u8,
+) -> (Result<Cycle, ckb_error::Error>, Script, Result<(), Error>) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[ext_flags]);
+    let plans: Vec<(CellMeta, CellMeta)> = (0..3)
+        .map(|_| {
+            let type_id = random_type_id_script();
+            (
+                zero_lock_cell_with_args(
+                    &mut dummy_loader,
+                    &vec![1u8; 100].into(),
+                    Some(type_id.clone()),
+                    args.clone(),
+                ),
+                zero_lock_cell_with_args(
+                    &mut dummy_loader,
+                    &vec![2u8; 100].into(),
+                    Some(type_id),
+                    args.clone(),
+                ),
+            )
+        })
+        .collect();
+    let proof_builder = UpgradeProofBuilder::default().leaves(
+        plans
+            .iter()
+            .map(|(old, new)| (LeafTag::Upgrade, hash_upgrade_data(old, new, None, None))),
+    );
+    let witness = serialize_witness(
+        proof_builder.build_varint(0, &[0, 1, 2]).unwrap(),
+        None,
+        None,
+    );
+    // The fixed layout reads the format byte and the first varints as the
+    // header index, then a count of indices far beyond the witness
+    assert_eq!(
+        reference::parse_proof(witness.clone(), 0),
+        Err(Error::InvalidWitness)
+    );
+    let header_dep = header(&mut dummy_loader, &proof_builder.root());
+
+    let mut builder = TransactionBuilder::default()
+        .header_dep(header_dep)
+        .witness(witness.pack())
+        .witness(Bytes::new().pack())
+        .witness(Bytes::new().pack());
+    for (_, output_cell_meta) in &plans {
+        builder = builder
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack());
+    }
+
+    let lock = plans[0].0.cell_output.lock();
+    let input_cells = plans.into_iter().map(|(input, _)| input).collect();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, input_cells);
+    let reference_result = reference::verify(&rtx, &lock, &dummy_loader);
+
+    (verifier.verify(MAX_CYCLES), lock, reference_result)
+}
+
+#[test]
+fn test_varint_proof_batch_upgrade() {
+    let (verify_result, _lock, reference_result) =
+        verify_varint_proof_batch_upgrade(LOCK_EXT_FLAG_PROOF_FORMAT);
+    verify_result.expect("pass verification");
+    assert_eq!(reference_result, Ok(()));
+}
+
+#[test]
+fn test_varint_proof_without_proof_format_fails_verification() {
+    let (verify_result, lock, reference_result) = verify_varint_proof_batch_upgrade(0);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::InvalidWitness as i8).input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::InvalidWitness));
+}
+
+#[test]
+fn test_varint_proof_delta_encodes_lemmas() {
+    // Siblings sharing all but their last byte are each other's lemma
+    let mut leaves = vec![Byte32::new([7u8; 32]); 4];
+    let mut buf = [7u8; 32];
+    buf[31] = 8;
+    leaves[1] = Byte32::new(buf);
+    buf[30] = 9;
+    leaves[2] = Byte32::new(buf);
+    buf[31] = 9;
+    leaves[3] = Byte32::new(buf);
+    let builder = UpgradeProofBuilder::default()
+        .leaves(leaves.into_iter().map(|leaf| (LeafTag::Upgrade, leaf)));
+    let fixed = builder.build(0, &[0, 3]).unwrap();
+    let varint = builder.build_varint(0, &[0, 3]).unwrap();
+    // Of the second lemma, only the shared prefix length and the bytes after
+    // the prefix are carried
+    assert!(varint.len() < fixed.len() - 32);
+
+    let fixed = reference::parse_proof(serialize_witness(fixed, None, None), 0).unwrap();
+    let varint = reference::parse_proof(
+        serialize_witness(varint, None, None),
+        LOCK_EXT_FLAG_PROOF_FORMAT,
+    )
+    .unwrap();
+    assert_eq!(fixed, varint);
+}
+
+#[test]
+fn test_witness_proof_fields_roundtrip() {
+    // Every field of WitnessProof in schemas/witness_proof.mol, read back by
+    // the parser and serialized again into the same bytes
+    let leaves = random_leaves(&mut StdRng::seed_from_u64(0), 5);
+    let builder = UpgradeProofBuilder::default()
+        .leaves(leaves.iter().map(|leaf| (LeafTag::Upgrade, leaf.clone())));
+    let proof = builder.build(7, &[1, 4]).unwrap();
+    let witness = append_leaf_hash(
+        serialize_witness(serialize_root_indexed_proof(3, &proof), None, None),
+        &leaves[1],
+    );
+
+    let parsed = reference::parse_proof(witness, LOCK_EXT_FLAG_ROOT_INDEX).unwrap();
+    assert_eq!(parsed.root_index, Some(3));
+    assert_eq!(parsed.header_index, 7);
+    assert_eq!(parsed.leaf_tags, vec![LeafTag::Upgrade as u8; 2]);
+    assert_eq!(parsed.indices.len(), 2);
+    assert_eq!(parsed.leaf_hash, Some(leaves[1].clone()));
+    assert_eq!(
+        serialize_proof(
+            parsed.header_index,
+            &parsed.indices,
+            &parsed.leaf_tags,
+            &parsed.lemmas
+        ),
+        proof
+    );
+}
+
+/// Upgrades a cell against the root in a governance cell dep, which carries
+/// `governance_type` while lock args expect `expected_type`.
+fn verify_governance_cell_upgrade(
+    governance_type: Script,
+    expected_type: &Script,
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let mut args = vec![LOCK_EXT_FLAG_GOVERNANCE_CELL];
+    args.extend(expected_type.calc_script_hash().as_slice());
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &args);
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &new_contract, Some(type_id), args);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    // The header index in witness names the governance cell, the first cell dep
+    let governance_cell_meta = governance_cell(&mut dummy_loader, governance_type, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx_with_cell_deps(
+        dummy_loader,
+        builder,
+        vec![input_cell_meta],
+        0,
+        vec![governance_cell_meta],
+    )
+    .0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_root_from_governance_cell_dep_upgrade() {
+    let governance_type = random_type_id_script();
+    let (verify_result, _lock) =
+        verify_governance_cell_upgrade(governance_type.clone(), &governance_type);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_governance_cell_dep_with_other_type_id_fails_verification() {
+    let (verify_result, lock) =
+        verify_governance_cell_upgrade(random_type_id_script(), &random_type_id_script());
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::GovernanceCellMismatch as i8)
+            .input_lock_script(0),
+    );
+}
+
+/// Rotates the governance cell to a new root, with the rotation leaf
+/// committing `committed_root` instead when given.
+fn verify_governance_rotation(
+    committed_root: Option<Byte32>,
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let mut args = vec![LOCK_EXT_FLAG_GOVERNANCE_CELL];
+    args.extend(type_id.calc_script_hash().as_slice());
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &args);
+    let placeholder = build_extension(&[(&[][..], Byte32::zero())], 1);
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &placeholder,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_root = Byte32::new([7u8; 32]);
+    let new_roots = build_extension(&[(&[][..], new_root.clone())], 1);
+    let output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &new_roots, Some(type_id), args);
+    let committed_cell_meta = CellMetaBuilder::from_cell_output(
+        output_cell_meta.cell_output.clone(),
+        build_extension(&[(&[][..], committed_root.unwrap_or(new_root))], 1),
+    )
+    .build();
+
+    // The leaf only pins the out point of the governance cell, so its data can
+    // carry the single leaf tree proving the rotation
+    let root = hash_rotation_data(&input_cell_meta, &committed_cell_meta);
+    let input_cell_meta = CellMetaBuilder::from_cell_output(
+        input_cell_meta.cell_output.clone(),
+        build_extension(&[(&[][..], root)], 1),
+    )
+    .out_point(input_cell_meta.out_point.clone())
+    .build();
+    insert_cell(&mut dummy_loader, &input_cell_meta);
+    let proof = proof_lock_data_with_tags(0, &[0], &[LeafTag::Rotation as u8], &[]);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .witness(lock_only_witness(proof)[0].pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_governance_rotation() {
+    let (verify_result, _lock) = verify_governance_rotation(None);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_governance_rotation_to_uncommitted_root_fails_verification() {
+    let (verify_result, lock) = verify_governance_rotation(Some(Byte32::new([8u8; 32])));
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+}
+
+/// Freezes a cell by a proven freeze leaf, then returns the frozen output
+/// cell as a live cell of the data loader.
+fn freeze_cell() -> (DummyDataLoader, CellMeta) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let contract = vec![1u8; 100];
+    let input_cell_meta = zero_lock_cell(
+        &mut dummy_loader,
+        &contract.clone().into(),
+        Some(type_id.clone()),
+    );
+    let output_cell_meta =
+        zero_lock_cell(&mut dummy_loader, &frozen_data(&contract), Some(type_id));
+
+    let root = hash_freeze_data(&input_cell_meta, &output_cell_meta);
+    let header_dep = header(&mut dummy_loader, &root);
+    let proof = proof_lock_data_with_tags(0, &[0], &[LeafTag::Freeze as u8], &[]);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(lock_only_witness(proof)[0].pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    verifier.verify(MAX_CYCLES).expect("pass verification");
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), Ok(()));
+
+    (dummy_loader, output_cell_meta)
+}
+
+/// Upgrades a frozen cell to `new_data` by a valid upgrade proof.
+fn verify_frozen_cell_upgrade(new_data: Bytes) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let (mut dummy_loader, input_cell_meta) = freeze_cell();
+    let output_cell_meta = zero_lock_cell(
+        &mut dummy_loader,
+        &new_data,
+        input_cell_meta.cell_output.type_().to_opt(),
+    );
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_freeze_then_upgrade_fails_verification() {
+    // Long enough to keep the capacity of the frozen cell
+    let (verify_result, lock) = verify_frozen_cell_upgrade(vec![2u8; 104].into());
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::Frozen as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_move_frozen_cell_keeping_data() {
+    let (verify_result, _lock) = verify_frozen_cell_upgrade(frozen_data(&[1u8; 100]));
+    verify_result.expect("pass verification");
+}
+
+/// Reconfigures a cell whose data is 60 bytes of code followed by 40 bytes of
+/// config into `new_data`, by a proven reconfiguration leaf keeping the code.
+fn verify_reconfiguration(
+    new_data: Bytes,
+) -> (Result<Cycle, ckb_error::Error>, Script, Result<(), Error>) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_data = [vec![1u8; 60], vec![3u8; 40]].concat().into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_data, Some(type_id.clone()));
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_data, Some(type_id));
+
+    let root = hash_reconfiguration_data(&input_cell_meta, &output_cell_meta, 60);
+    let header_dep = header(&mut dummy_loader, &root);
+    let proof = proof_lock_data_with_tags(0, &[0], &[LeafTag::Reconfiguration as u8], &[]);
+    let code_length = Bytes::from(60u32.to_le_bytes().to_vec());
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(serialize_witness(proof, None, Some(code_length)).pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    let reference_result = reference::verify(&rtx, &lock, &dummy_loader);
+
+    (verifier.verify(MAX_CYCLES), lock, reference_result)
+}
+
+#[test]
+fn test_reconfiguration_keeping_code() {
+    let new_data = [vec![1u8; 60], vec![4u8; 40]].concat().into();
+    let (verify_result, _lock, reference_result) = verify_reconfiguration(new_data);
+    verify_result.expect("pass verification");
+    assert_eq!(reference_result, Ok(()));
+}
+
+#[test]
+fn test_reconfiguration_changing_code_fails_verification() {
+    // The committee commits the new code as well, but under the wrong tag
+    let new_data = [vec![1u8; 59], vec![2u8], vec![4u8; 40]].concat().into();
+    let (verify_result, lock, reference_result) = verify_reconfiguration(new_data);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::CodeChanged as i8).input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::CodeChanged));
+}
+
+/// Upgrades a cell of 100 bytes of data into one of 60 bytes, freeing 40
+/// bytes of capacity. The leaf releases `released_bytes` to always success
+/// lock, whose output cell receives `received_bytes`.
+fn verify_release(
+    released_bytes: usize,
+    received_bytes: usize,
+) -> (Result<Cycle, ckb_error::Error>, Script, Result<(), Error>) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 60].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+    let receiver = CellOutput::new_builder()
+        .lock(always_success_lock())
+        .capacity(Capacity::bytes(received_bytes).unwrap().pack())
+        .build();
+
+    let released = Capacity::bytes(released_bytes).unwrap().as_u64();
+    let lock_hash = always_success_lock().calc_script_hash();
+    let root = hash_release_data(&input_cell_meta, &output_cell_meta, released, &lock_hash);
+    let header_dep = header(&mut dummy_loader, &root);
+    let proof = proof_lock_data_with_tags(0, &[0], &[LeafTag::Release as u8], &[]);
+    let output_type = release_output_type(released, &lock_hash);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .output(receiver)
+        .output_data(Bytes::new().pack())
+        .header_dep(header_dep)
+        .witness(serialize_witness(proof, None, Some(output_type)).pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    let reference_result = reference::verify(&rtx, &lock, &dummy_loader);
+
+    (verifier.verify(MAX_CYCLES), lock, reference_result)
+}
+
+#[test]
+fn test_release_paid_to_committed_lock() {
+    let (verify_result, _lock, reference_result) = verify_release(40, 40);
+    verify_result.expect("pass verification");
+    assert_eq!(reference_result, Ok(()));
+}
+
+#[test]
+fn test_release_beyond_committed_capacity_fails_verification() {
+    let (verify_result, lock, reference_result) = verify_release(39, 40);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::CapacityLowered as i8).input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::CapacityLowered));
+}
+
+#[test]
+fn test_release_not_received_fails_verification() {
+    let (verify_result, lock, reference_result) = verify_release(40, 39);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::ReleaseNotReceived as i8)
+            .input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::ReleaseNotReceived));
+}
+
+/// Upgrades a cell governed by three committees under `threshold`, proving
+/// the leaf against the first committee's root and co-proving it against the
+/// roots at `co_proof_slots`.
+fn verify_threshold_upgrade(
+    threshold: u8,
+    co_proof_slots: &[u32],
+) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_THRESHOLD_PROOFS]);
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &old_contract,
+        Some(type_id.clone()),
+        args.clone(),
+    );
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &new_contract, Some(type_id), args);
+
+    let (roots, witness) =
+        build_threshold_roots_n_witness(&input_cell_meta, &output_cell_meta, 3, co_proof_slots);
+    let extension = threshold_extension(&roots, threshold);
+    let header_dep = header_with_extension(&mut dummy_loader, 10000, Some(extension));
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    let verify_result = verifier.verify(MAX_CYCLES);
+    let reference_result = reference::verify(&rtx, &lock, &dummy_loader);
+    assert_eq!(verify_result.is_ok(), reference_result.is_ok());
+
+    (verify_result, lock)
+}
+
+#[test]
+fn test_threshold_upgrade() {
+    let (verify_result, _lock) = verify_threshold_upgrade(3, &[1, 2]);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_threshold_upgrade_missing_co_proof_fails_verification() {
+    let (verify_result, lock) = verify_threshold_upgrade(3, &[1]);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::ThresholdNotMet as i8).input_lock_script(0),
+    );
+}
+
+/// Upgrades a cell proven by two committees, with the proof and its co-proof
+/// naming the first header dep. Header deps carry the extensions built from
+/// the roots of the committees by `build`.
+fn verify_threshold_header_deps_upgrade<F>(
+    build: F,
+) -> (Result<Cycle, ckb_error::Error>, Script, Result<(), Error>)
+where
+    F: FnOnce(&[Byte32]) -> Vec<Option<Bytes>>,
+{
+    let mut dummy_loader = DummyDataLoader::default();
+    let args = zero_lock_args(LOCK_FLAG_EXTENDED, &[LOCK_EXT_FLAG_THRESHOLD_PROOFS]);
+    let input_cell_meta = zero_lock_cell_with_args(
+        &mut dummy_loader,
+        &vec![1u8; 100].into(),
+        None,
+        args.clone(),
+    );
+    let output_cell_meta =
+        zero_lock_cell_with_args(&mut dummy_loader, &vec![2u8; 100].into(), None, args);
+
+    let (roots, witness) =
+        build_threshold_roots_n_witness(&input_cell_meta, &output_cell_meta, 2, &[1]);
+    let mut builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .witness(witness.pack());
+    for (number, extension) in (10000..).zip(build(&roots)) {
+        builder = builder.header_dep(header_with_extension(&mut dummy_loader, number, extension));
+    }
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+    let reference_result = reference::verify(&rtx, &lock, &dummy_loader);
+
+    (verifier.verify(MAX_CYCLES), lock, reference_result)
+}
+
+#[test]
+fn test_threshold_upgrade_with_unrelated_header_dep() {
+    // The second header dep is only there for another script, e.g. a DAO
+    // withdrawal, and carries no extension at all
+    let (verify_result, _lock, reference_result) = verify_threshold_header_deps_upgrade(|roots| {
+        vec![Some(threshold_extension(roots, 2)), None]
+    });
+    verify_result.expect("pass verification");
+    assert_eq!(reference_result, Ok(()));
+}
+
+#[test]
+fn test_threshold_upgrade_naming_missing_header_dep_fails_verification() {
+    let (verify_result, lock, reference_result) = verify_threshold_header_deps_upgrade(|_| vec![]);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::ProofHeaderCountMismatch as i8)
+            .input_lock_script(0),
+    );
+    assert_eq!(reference_result, Err(Error::ProofHeaderCountMismatch));
+}
+
+#[test]
+fn test_threshold_upgrade_with_duplicate_root_fails_verification() {
+    let (verify_result, lock) = verify_threshold_upgrade(3, &[1, 1]);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::DuplicateRoot as i8).input_lock_script(0),
+    );
+}
+
+/// Builds zero lock cells governed by a sparse merkle tree upgrading from
+/// `vec![i; 50]` to `vec![i + 100; 50]`.
+fn smt_plans(dummy: &mut DummyDataLoader, count: u8) -> Vec<(CellMeta, CellMeta)> {
+    (0..count)
+        .map(|i| {
+            let type_id = random_type_id_script();
+            let args = zero_lock_args(LOCK_FLAG_SMT, &[]);
+            (
+                zero_lock_cell_with_args(
+                    dummy,
+                    &vec![i; 50].into(),
+                    Some(type_id.clone()),
+                    args.clone(),
+                ),
+                zero_lock_cell_with_args(dummy, &vec![i + 100; 50].into(), Some(type_id), args),
+            )
+        })
+        .collect()
+}
+
+/// Upgrades the first plan against a sparse merkle tree root, which commits
+/// all plans when `committed` is set and all but the upgraded one otherwise.
+fn verify_smt_upgrade(committed: bool) -> (Result<Cycle, ckb_error::Error>, Script) {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans = smt_plans(&mut dummy_loader, 4);
+    let plans: Vec<(&CellMeta, &CellMeta)> = plans.iter().map(|(a, b)| (a, b)).collect();
+    let (input_cell_meta, output_cell_meta) = plans[0];
+    let tree_plans = if committed { &plans[..] } else { &plans[1..] };
+
+    let (root, proof_witness) = build_smt_root_n_proof(tree_plans, input_cell_meta, 0);
+    let extension = structured_extension(&[(EXTENSION_FIELD_SMT_ROOT, root.as_slice())]);
+    let header_dep = header_with_extension(&mut dummy_loader, 10000, Some(extension));
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    (verifier.verify(MAX_CYCLES), lock)
+}
+
+#[test]
+fn test_smt_upgrade() {
+    let (verify_result, _lock) = verify_smt_upgrade(true);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_smt_upgrade_with_exclusion_proof_fails_verification() {
+    let (verify_result, lock) = verify_smt_upgrade(false);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_smt_exclusion_proof() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans = smt_plans(&mut dummy_loader, 5);
+    let mut tree = smt::SparseMerkleTree::default();
+    for (old_cell, new_cell) in &plans[1..] {
+        let key = blake2b_256(old_cell.mem_cell_data.as_ref().unwrap());
+        tree.insert(
+            &key.pack(),
+            &hash_upgrade_data(old_cell, new_cell, None, None),
+        );
+    }
+    let root = tree.root();
+
+    let (old_cell, new_cell) = &plans[0];
+    let key = blake2b_256(old_cell.mem_cell_data.as_ref().unwrap()).pack();
+    let proof = tree.proof(&key);
+    assert!(proof.verify(&root, &key, None));
+    let leaf = hash_upgrade_data(old_cell, new_cell, None, None);
+    assert!(!proof.verify(&root, &key, Some(&leaf)));
+}
+
+#[test]
+fn test_smt_forged_exclusion_proof_fails() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans = smt_plans(&mut dummy_loader, 5);
+    let mut tree = smt::SparseMerkleTree::default();
+    for (old_cell, new_cell) in &plans {
+        let key = blake2b_256(old_cell.mem_cell_data.as_ref().unwrap());
+        tree.insert(
+            &key.pack(),
+            &hash_upgrade_data(old_cell, new_cell, None, None),
+        );
+    }
+    let root = tree.root();
+
+    for (old_cell, new_cell) in &plans {
+        let key = blake2b_256(old_cell.mem_cell_data.as_ref().unwrap()).pack();
+        let leaf = hash_upgrade_data(old_cell, new_cell, None, None);
+        let proof = tree.proof(&key);
+        assert!(proof.verify(&root, &key, Some(&leaf)));
+        // Claiming a committed key is absent must not reproduce the root
+        assert!(!proof.verify(&root, &key, None));
+
+        // Neither does dropping the sibling next to the leaf from the proof
+        let mut forged = proof.clone();
+        let height = (0..smt::SMT_HEIGHT)
+            .find(|h| forged.bitmap[h / 8] & (1 << (h % 8)) != 0)
+            .unwrap();
+        forged.bitmap[height / 8] &= !(1 << (height % 8));
+        forged.siblings.remove(0);
+        assert!(!forged.verify(&root, &key, None));
+    }
+}
+
+#[test]
+fn test_input_zero_lock_at_other_indices() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 120].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let input_cell2 = always_success_cell(&mut dummy_loader, 150);
+    let input_cell3 = always_success_cell(&mut dummy_loader, 200);
+    let output_cell2 = always_success_cell(&mut dummy_loader, 320);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .output(output_cell2.cell_output.clone())
+        .output_data(output_cell2.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(Bytes::new().pack())
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(
+        dummy_loader,
+        builder,
+        vec![input_cell2, input_cell_meta, input_cell3],
+    )
+    .0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_zero_lock_ignores_other_inputs_and_their_witnesses() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 120].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let input_cell2 = always_success_cell(&mut dummy_loader, 150);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    // The always success input carries a witness that is not even a valid
+    // WitnessArgs, zero lock must never look at it.
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(Bytes::from(vec![0xffu8; 37]).pack())
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell2, input_cell_meta]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_output_zero_lock_at_other_indices() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 120].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let input_cell2 = always_success_cell(&mut dummy_loader, 150);
+    let input_cell3 = always_success_cell(&mut dummy_loader, 200);
+    let output_cell2 = always_success_cell(&mut dummy_loader, 320);
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell2.cell_output.clone())
+        .output_data(output_cell2.mem_cell_data.clone().unwrap().pack())
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(
+        dummy_loader,
+        builder,
+        vec![input_cell_meta, input_cell2, input_cell3],
+    )
+    .0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_missing_header_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let _header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::MissingExtension as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_root_only_in_unlisted_header_fails_verification() {
+    // The header carrying the root is known to the loader, the only header
+    // dep listed carries another root
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let (root, proof_witness) =
+        build_merkle_root_n_proof(&[(&input_cell_meta, &output_cell_meta)], 0, 0, None, None);
+    let _unlisted_header_dep = header(&mut dummy_loader, &root);
+    let header_dep = header(&mut dummy_loader, &Byte32::new([2u8; 32]));
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let lock = input_cell_meta.cell_output.lock();
+    let (verifier, rtx, dummy_loader) = complete_tx(dummy_loader, builder, vec![input_cell_meta]);
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+    );
+    assert_eq!(
+        reference::verify(&rtx, &lock, &dummy_loader),
+        Err(Error::RootMismatch)
+    );
+}
+
+#[test]
+fn test_found_leaf_index_builds_valid_proof() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let other_cells: Vec<(CellMeta, CellMeta)> = (0..6u8)
+        .map(|i| {
+            let old_contract = vec![i; 50].into();
+            let new_contract = vec![i + 100; 50].into();
+            (
+                zero_lock_cell(&mut dummy_loader, &old_contract, None),
+                zero_lock_cell(&mut dummy_loader, &new_contract, None),
+            )
+        })
+        .collect();
+    let mut all_leaves: Vec<(&CellMeta, &CellMeta)> =
+        other_cells.iter().map(|(a, b)| (a, b)).collect();
+    all_leaves.insert(4, (&input_cell_meta, &output_cell_meta));
+
+    let hashed_leaves: Vec<Byte32> = all_leaves
+        .iter()
+        .map(|(old_cell, new_cell)| hash_upgrade_data(old_cell, new_cell, None, None))
+        .collect();
+    let leaf = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let selected = prover::find_leaf_index(&hashed_leaves, &leaf).expect("find leaf");
+    assert_eq!(selected, 4);
+    assert_eq!(
+        prover::find_leaf_index(&hashed_leaves, &Byte32::zero()),
+        None
+    );
+
+    let (root, proof_witness) = build_merkle_root_n_proof(&all_leaves, selected, 0, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_upgrade_proof_builder_rejects_missing_leaf() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let leaves = random_leaves(&mut rng, 5);
+    let builder = ckb_zero_lock_types::UpgradeProofBuilder::default()
+        .leaves(leaves.iter().map(|leaf| (LeafTag::Upgrade, leaf.clone())));
+    assert_eq!(builder.root(), sorted_tree_root(&leaves));
+    assert!(builder.build(0, &[1, 4]).is_some());
+    assert!(builder.build(0, &[1, 5]).is_none());
+}
+
+#[test]
+fn test_upgrade_leaf_vector() {
+    let out_point = OutPoint::new(Byte32::new([0x11; 32]), 0);
+    let new_cell = CellOutput::new_builder()
+        .capacity(100_000_000_000u64.pack())
+        .lock(
+            Script::new_builder()
+                .code_hash(Byte32::new([0x33; 32]))
+                .hash_type(ScriptHashType::Type.into())
+                .args(Bytes::from(vec![0x44; 20]).pack())
+                .build(),
+        )
+        .build();
+    let leaf = ckb_zero_lock_types::upgrade_leaf(
+        LeafTag::Upgrade as u8,
+        out_point.as_slice(),
+        &[0x22; 16],
+        &new_cell,
+    );
+    // Pins the preimage layout, any change here breaks committed leaves
+    assert_eq!(
+        format!("{:x}", leaf),
+        "c25b659ab63e716f99bdd825420f2be3ed904491c6a7fc8182bbea1b21a80cfe"
+    );
+}
+
+#[test]
+fn test_blake2b_merge_vector() {
+    let lhs = Byte32::new([0x11; 32]);
+    let rhs = Byte32::new([0x22; 32]);
+    let merged = Blake2bHash::merge(&lhs, &rhs);
+    // Pins the personalization and hash length of merged nodes, any change
+    // here breaks committed roots
+    assert_eq!(
+        format!("{:x}", merged),
+        "e994f19a5b320be7699c49732d5d6b029223e2bfdd128707a4c5991ef5d5b42f"
+    );
+    let mut hasher = ckb_hash::new_blake2b();
+    hasher.update(lhs.as_slice());
+    hasher.update(rhs.as_slice());
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash[..]);
+    assert_eq!(merged, Byte32::new(hash));
+}
+
+#[test]
+fn test_upgrade_leaf_matches_committed_leaf() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let leaf = ckb_zero_lock_types::upgrade_leaf(
+        LeafTag::Upgrade as u8,
+        input_cell_meta.out_point.as_slice(),
+        &new_contract,
+        &output_cell_meta.cell_output,
+    );
+    assert_eq!(
+        leaf,
+        hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None)
+    );
+
+    // The single leaf tree has the leaf as root, which zero lock accepts
+    let header_dep = header(&mut dummy_loader, &leaf);
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta]).0;
+    verifier.verify(MAX_CYCLES).expect("pass verification");
+}
+
+/// Root of the tree holding `leaves` sorted by hash, the way
+/// `UpgradeProofBuilder` builds it.
+fn sorted_tree_root(leaves: &[Byte32]) -> Byte32 {
+    let mut leaves = leaves.to_vec();
+    leaves.sort_by(|a, b| a.as_slice().cmp(b.as_slice()));
+    CBMT::<Byte32, Blake2bHash>::build_merkle_root(&leaves)
+}
+
+fn random_leaves(rng: &mut StdRng, count: usize) -> Vec<Byte32> {
+    (0..count)
+        .map(|_| {
+            let mut buf = [0u8; 32];
+            rng.fill(&mut buf);
+            Byte32::new(buf)
+        })
+        .collect()
+}
+
+#[test]
+fn test_audit_verify_append() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let old_leaves = random_leaves(&mut rng, 5);
+    let mut new_leaves = old_leaves.clone();
+    new_leaves.extend(random_leaves(&mut rng, 3));
+    let old_root = sorted_tree_root(&old_leaves);
+    let new_root = sorted_tree_root(&new_leaves);
+
+    assert!(audit::verify_append(
+        &old_leaves,
+        &new_leaves,
+        &old_root,
+        &new_root
+    ));
+}
+
+#[test]
+fn test_audit_verify_append_rejects_mutated_leaf() {
+    let mut rng = StdRng::seed_from_u64(7);
+    let old_leaves = random_leaves(&mut rng, 5);
+    let mut new_leaves = old_leaves.clone();
+    new_leaves.extend(random_leaves(&mut rng, 3));
+    new_leaves[2] = Byte32::zero();
+    let old_root = sorted_tree_root(&old_leaves);
+    let new_root = sorted_tree_root(&new_leaves);
+
+    assert!(!audit::verify_append(
+        &old_leaves,
+        &new_leaves,
+        &old_root,
+        &new_root
+    ));
+}
+
+fn random_plans(dummy: &mut DummyDataLoader, count: u8) -> Vec<(CellMeta, CellMeta)> {
+    (0..count)
+        .map(|i| {
+            let type_id = random_type_id_script();
+            let old_contract = vec![i; 50].into();
+            let new_contract = vec![i + 100; 50].into();
+            (
+                zero_lock_cell(dummy, &old_contract, Some(type_id.clone())),
+                zero_lock_cell(dummy, &new_contract, Some(type_id)),
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn test_audit_verify_published() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans = random_plans(&mut dummy_loader, 6);
+    let plans: Vec<(&CellMeta, &CellMeta)> = plans.iter().map(|(a, b)| (a, b)).collect();
+    let (root, _proof_witness) = build_merkle_root_n_proof(&plans, 0, 0, None, None);
+
+    assert!(audit::verify_published(&plans, &root));
+}
+
+#[test]
+fn test_audit_verify_published_rejects_dropped_plan() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let plans = random_plans(&mut dummy_loader, 6);
+    let mut plans: Vec<(&CellMeta, &CellMeta)> = plans.iter().map(|(a, b)| (a, b)).collect();
+    let (root, _proof_witness) = build_merkle_root_n_proof(&plans, 0, 0, None, None);
+    plans.remove(3);
+
+    assert!(!audit::verify_published(&plans, &root));
+}
+
+#[test]
+fn test_split_witness_upgrade() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let mut rng = StdRng::seed_from_u64(11);
+    let (root, proof_witness) = bury_in_merkle_tree(
+        &input_cell_meta,
+        &output_cell_meta,
+        20,
+        &mut rng,
+        0,
+        None,
+        None,
+    );
+    let header_dep = header(&mut dummy_loader, &root);
+
+    // Both the unified and the split witness verify against the same root
+    for witness in [proof_witness.clone(), split_proof_witness(proof_witness)] {
+        let builder = TransactionBuilder::default()
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .header_dep(header_dep.clone())
+            .witness(witness.pack());
+
+        let verifier = complete_tx(dummy_loader.clone(), builder, vec![input_cell_meta.clone()]).0;
+
+        let verify_result = verifier.verify(MAX_CYCLES);
+        verify_result.expect("pass verification");
+    }
+}
+
+#[test]
+fn test_split_witness_missing_input_type_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let mut rng = StdRng::seed_from_u64(11);
+    let (root, proof_witness) = bury_in_merkle_tree(
+        &input_cell_meta,
+        &output_cell_meta,
+        20,
+        &mut rng,
+        0,
+        None,
+        None,
+    );
+    let proof_witness = WitnessArgs::new_unchecked(split_proof_witness(proof_witness))
+        .as_builder()
+        .input_type(None::<Bytes>.pack())
+        .build()
+        .as_bytes();
+    let header_dep = header(&mut dummy_loader, &root);
+
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(proof_witness.pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::InvalidWitness as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_raw_witness_upgrade() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let mut rng = StdRng::seed_from_u64(12);
+    let (root, proof_witness) = bury_in_merkle_tree(
+        &input_cell_meta,
+        &output_cell_meta,
+        20,
+        &mut rng,
+        0,
+        None,
+        None,
+    );
+    let header_dep = header(&mut dummy_loader, &root);
+
+    // Both the WitnessArgs and the raw witness verify against the same root
+    for witness in [proof_witness.clone(), raw_proof_witness(proof_witness)] {
+        let builder = TransactionBuilder::default()
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .header_dep(header_dep.clone())
+            .witness(witness.pack());
+
+        let verifier = complete_tx(dummy_loader.clone(), builder, vec![input_cell_meta.clone()]).0;
+
+        let verify_result = verifier.verify(MAX_CYCLES);
+        verify_result.expect("pass verification");
+    }
+}
+
+#[test]
+fn test_witness_layout_upgrade() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let mut rng = StdRng::seed_from_u64(13);
+    let (root, proof_witness) = bury_in_merkle_tree(
+        &input_cell_meta,
+        &output_cell_meta,
+        20,
+        &mut rng,
+        0,
+        None,
+        None,
+    );
+    let header_dep = header(&mut dummy_loader, &root);
+
+    // The proof is read from the seal of SighashAllOnly and SighashAll alike,
+    // an empty Message is a table without fields
+    let empty_message = Bytes::from(vec![4u8, 0, 0, 0]);
+    for message in [None, Some(empty_message)] {
+        let builder = TransactionBuilder::default()
+            .output(output_cell_meta.cell_output.clone())
+            .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+            .header_dep(header_dep.clone())
+            .witness(witness_layout_proof_witness(proof_witness.clone(), message).pack());
+
+        let (verifier, rtx, dummy) =
+            complete_tx(dummy_loader.clone(), builder, vec![input_cell_meta.clone()]);
+        let lock = input_cell_meta.cell_output.lock();
+        assert_eq!(reference::verify(&rtx, &lock, &dummy), Ok(()));
+
+        let verify_result = verifier.verify(MAX_CYCLES);
+        verify_result.expect("pass verification");
+    }
+}
+
+#[test]
+fn test_unparsable_witness_fails_verification() {
+    let mut dummy_loader = DummyDataLoader::default();
+    let type_id = random_type_id_script();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, Some(type_id.clone()));
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, Some(type_id));
+
+    let root = hash_upgrade_data(&input_cell_meta, &output_cell_meta, None, None);
+    let header_dep = header(&mut dummy_loader, &root);
+
+    // Neither a WitnessArgs nor long enough to hold a header index
+    let builder = TransactionBuilder::default()
+        .output(output_cell_meta.cell_output.clone())
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(Bytes::from(vec![0xffu8; 3]).pack());
+
+    let verifier = complete_tx(dummy_loader, builder, vec![input_cell_meta.clone()]).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    assert_error_eq!(
+        verify_result.unwrap_err(),
+        ScriptError::validation_failure(
+            &input_cell_meta.cell_output.lock(),
+            Error::InvalidProof as i8
+        )
+        .input_lock_script(0),
+    );
+}
+
+#[test]
+fn test_testgen_baseline_passes_verification() {
+    let (dummy_loader, builder, inputs, since, cell_deps) = testgen::baseline();
+    let verifier = complete_tx_with_cell_deps(dummy_loader, builder, inputs, since, cell_deps).0;
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+#[test]
+fn test_testgen_repros_fail_verification() {
+    // Each repro is rejected by zero lock with exactly the code it is built
+    // for, rather than by whichever check happens to fail first.
+    let max_code = *testgen::REPRODUCIBLE_CODES.iter().max().unwrap();
+    for code in 0..=max_code + 1 {
+        let repro = testgen::repro_for(code);
+        assert_eq!(
+            repro.is_some(),
+            testgen::REPRODUCIBLE_CODES.contains(&code),
+            "code {}",
+            code
+        );
+        let Some((dummy_loader, builder, inputs, since, cell_deps)) = repro else {
+            continue;
+        };
+        let lock = inputs[0].cell_output.lock();
+        let verifier =
+            complete_tx_with_cell_deps(dummy_loader, builder, inputs, since, cell_deps).0;
+
+        let verify_result = verifier.verify(MAX_CYCLES);
+        assert_error_eq!(
+            verify_result.unwrap_err(),
+            ScriptError::validation_failure(&lock, code as i8).input_lock_script(0),
+        );
+    }
+}
+
+#[test]
+fn test_reference_verifier_passes_baseline() {
+    let (dummy_loader, builder, inputs, since, cell_deps) = testgen::baseline();
+    let lock = inputs[0].cell_output.lock();
+    let (verifier, rtx, dummy_loader) =
+        complete_tx_with_cell_deps(dummy_loader, builder, inputs, since, cell_deps);
+
+    verifier.verify(MAX_CYCLES).expect("pass verification");
+    assert_eq!(reference::verify(&rtx, &lock, &dummy_loader), Ok(()));
+}
+
+#[test]
+fn test_reference_verifier_agrees_on_repros() {
+    for code in testgen::REPRODUCIBLE_CODES {
+        let (dummy_loader, builder, inputs, since, cell_deps) = testgen::repro_for(*code).unwrap();
+        let lock = inputs[0].cell_output.lock();
+        let (verifier, rtx, dummy_loader) =
+            complete_tx_with_cell_deps(dummy_loader, builder, inputs, since, cell_deps);
+
+        assert!(verifier.verify(MAX_CYCLES).is_err(), "code {}", code);
+        assert_eq!(
+            reference::verify(&rtx, &lock, &dummy_loader).map_err(reference::Error::code),
+            Err(*code)
+        );
+    }
+}
+
+/// Verifies a repro with a debug printer collecting the messages emitted by
+/// zero lock under its debug-log feature.
+#[cfg(feature = "debug-log")]
+fn debug_log_messages(
+    repro: testgen::Repro,
+) -> (
+    Result<Cycle, ckb_error::Error>,
+    Arc<ResolvedTransaction>,
+    Vec<String>,
+) {
+    let (dummy_loader, builder, inputs, since, cell_deps) = repro;
+    let (mut verifier, rtx, _dummy_loader) =
+        complete_tx_with_cell_deps(dummy_loader, builder, inputs, since, cell_deps);
+    let messages = Arc::new(Mutex::new(vec![]));
+    let sink = messages.clone();
+    verifier.set_debug_printer(move |_hash: &Byte32, message: &str| {
+        eprintln!("DEBUG OUTPUT: {}", message);
+        sink.lock().unwrap().push(message.to_string());
+    });
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    let messages = messages
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|message| message.starts_with("zero-lock "))
+        .cloned()
+        .collect();
+    (verify_result, rtx, messages)
+}
+
+#[cfg(feature = "debug-log")]
+#[test]
+fn test_debug_log_traces_baseline() {
+    let (verify_result, rtx, messages) = debug_log_messages(testgen::baseline());
+    verify_result.expect("pass verification");
+    // The baseline is a single leaf tree, its leaf is the root
+    let cell = &rtx.resolved_inputs[0];
+    let output = rtx.transaction.output_with_data(0).unwrap();
+    let output = CellMetaBuilder::from_cell_output(output.0, output.1).build();
+    let leaf = hash_upgrade_data(cell, &output, None, None);
+    let header_hash = rtx.transaction.header_deps().get(0).unwrap();
+    assert_eq!(
+        messages,
+        vec![
+            format!("zero-lock leaf index=0 tag=Upgrade hash={:x}", leaf),
+            format!("zero-lock header index=0 hash={:x}", header_hash),
+            format!("zero-lock root found header=0 hash={:x}", leaf),
+            "zero-lock proof verified leaves=1".to_string(),
+            "zero-lock input index=0 passed".to_string(),
+        ]
+    );
+}
+
+#[cfg(feature = "debug-log")]
+#[test]
+fn test_debug_log_traces_root_mismatch() {
+    let (verify_result, rtx, messages) = debug_log_messages(testgen::repro_for(8).unwrap());
+    assert!(verify_result.is_err());
+    let cell = &rtx.resolved_inputs[0];
+    let output = rtx.transaction.output_with_data(0).unwrap();
+    let output = CellMetaBuilder::from_cell_output(output.0, output.1).build();
+    let leaf = hash_upgrade_data(cell, &output, None, None);
+    let expected = Byte32::new([1u8; 32]);
+    assert_eq!(
+        &messages[messages.len() - 2..],
+        [
+            format!(
+                "zero-lock root mismatch actual={:x} expected={:x}",
+                leaf, expected
+            ),
+            "zero-lock failed error=RootMismatch code=8".to_string(),
+        ]
+    );
+}
+
+fn fingerprint_tx(output_capacity: u64) -> Byte32 {
+    let mut dummy_loader = DummyDataLoader::default();
+    let old_contract = vec![1u8; 100].into();
+    let input_cell_meta = zero_lock_cell(&mut dummy_loader, &old_contract, None);
+    let new_contract = vec![2u8; 100].into();
+    let output_cell_meta = zero_lock_cell(&mut dummy_loader, &new_contract, None);
+    let header_dep = header(&mut dummy_loader, &Byte32::zero());
+
+    let builder = TransactionBuilder::default()
+        .output(
+            output_cell_meta
+                .cell_output
+                .as_builder()
+                .capacity(output_capacity.pack())
+                .build(),
+        )
+        .output_data(output_cell_meta.mem_cell_data.clone().unwrap().pack())
+        .header_dep(header_dep)
+        .witness(compact_proof_witness(0).pack());
+
+    let rtx = complete_tx(dummy_loader, builder, vec![input_cell_meta]).1;
+    testutil::tx_fingerprint(&rtx)
+}
+
+#[test]
+fn test_tx_fingerprint_ignores_out_points() {
+    // Every cell, including script cells, gets a random out point
+    assert_eq!(
+        fingerprint_tx(10_000_000_000),
+        fingerprint_tx(10_000_000_000)
+    );
+    assert_ne!(
+        fingerprint_tx(10_000_000_000),
+        fingerprint_tx(10_000_000_001)
+    );
+}
+
+#[cfg(feature = "rpc")]
+#[test]
+fn test_rpc_reverify_transaction() {
+    // Set CKB_RPC_URL and ZERO_LOCK_TX_HASH to re-verify a transaction on chain
+    let (Ok(url), Ok(tx_hash)) = (
+        std::env::var("CKB_RPC_URL"),
+        std::env::var("ZERO_LOCK_TX_HASH"),
+    ) else {
+        return;
+    };
+    let tx_hash: ckb_types::H256 = tx_hash
+        .trim_start_matches("0x")
+        .parse()
+        .expect("parse tx hash");
+    let loader = rpc::RpcDataLoader::new(&url);
+    let rtx = loader
+        .resolve_transaction(&tx_hash.pack())
+        .expect("resolve transaction");
+
+    let verifier = build_verifier(Arc::new(rtx), loader);
+
+    let verify_result = verifier.verify(MAX_CYCLES);
+    verify_result.expect("pass verification");
+}
+
+proptest! {
+    #[test]
+    fn test_varint_proof_roundtrip(
+        leaves_count in 1..300usize,
+        header_index: u32,
+        seed: u64,
+    ) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let leaves = random_leaves(&mut rng, leaves_count);
+        let builder = UpgradeProofBuilder::default()
+            .leaves(leaves.into_iter().map(|leaf| (LeafTag::Upgrade, leaf)));
+        let mut selected: Vec<u32> = (0..leaves_count as u32).filter(|_| rng.gen_bool(0.3)).collect();
+        if selected.is_empty() {
+            selected.push(rng.gen_range(0..leaves_count as u32));
+        }
+
+        let fixed = serialize_witness(builder.build(header_index, &selected).unwrap(), None, None);
+        let varint = serialize_witness(builder.build_varint(header_index, &selected).unwrap(), None, None);
+        let mut prefixed = vec![PROOF_FORMAT_FIXED];
+        prefixed.extend(builder.build(header_index, &selected).unwrap());
+        let prefixed = serialize_witness(Bytes::from(prefixed), None, None);
+
+        let expected = reference::parse_proof(fixed, 0).unwrap();
+        prop_assert_eq!(&reference::parse_proof(varint, LOCK_EXT_FLAG_PROOF_FORMAT).unwrap(), &expected);
+        prop_assert_eq!(&reference::parse_proof(prefixed, LOCK_EXT_FLAG_PROOF_FORMAT).unwrap(), &expected);
+    }
+
+    #[test]
+    fn test_batch_proof_roundtrip(
+        count in 1..40u8,
+        seed: u64,
+        flip_bit: usize,
+    ) {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut selected: Vec<u32> = (0..count as u32).filter(|_| rng.gen_bool(0.3)).collect();
+        if selected.is_empty() {
+            selected.push(rng.gen_range(0..count as u32));
+        }
+
+        let (verify_result, _lock) = verify_batch_upgrade(count, &selected, |leaves| {
+            build_merkle_root_n_batch_proof(leaves, &selected, 0)
+        });
+        verify_result.expect("pass verification");
+
+        // Lemmas end the proof in witness lock, after the lemma count
+        let mut flipped = false;
+        let (verify_result, lock) = verify_batch_upgrade(count, &selected, |leaves| {
+            let (root, mut witnesses) = build_merkle_root_n_batch_proof(leaves, &selected, 0);
+            let mut lock = WitnessArgs::new_unchecked(witnesses[0].clone())
+                .lock().to_opt().unwrap().raw_data().to_vec();
+            let lemmas_offset = 12 + selected.len() * 5;
+            if lock.len() > lemmas_offset {
+                let flip_bit = flip_bit % ((lock.len() - lemmas_offset) * 8);
+                lock[lemmas_offset + flip_bit / 8] ^= 1 << (flip_bit % 8);
+                flipped = true;
+            }
+            witnesses[0] = lock_only_witness(Bytes::from(lock))[0].clone();
+            (root, witnesses)
+        });
+        if flipped {
+            assert_error_eq!(
+                verify_result.unwrap_err(),
+                ScriptError::validation_failure(&lock, Error::RootMismatch as i8).input_lock_script(0),
+            );
+        } else {
+            // Every leaf is selected, or the tree is a single leaf
+            verify_result.expect("pass verification");
+        }
+    }
+
     #[test]
     fn test_single_zero_lock_long_witness_upgrade(
         witness_extra_bytes in 1..409600usize,
@@ -457,7 +5183,7 @@ proptest! {
         let verify_result = verifier.verify(MAX_CYCLES);
         assert_error_eq!(
             verify_result.unwrap_err(),
-            ScriptError::validation_failure(&input_cell_meta.cell_output.lock(), -61)
+            ScriptError::validation_failure(&input_cell_meta.cell_output.lock(), Error::RootMismatch as i8)
                 .input_lock_script(0),
         );
     }
@@ -0,0 +1,48 @@
+use ckb_hash::{blake2b_256, new_blake2b, Blake2b};
+use ckb_types::{
+    core::cell::{CellMeta, ResolvedTransaction},
+    packed::Byte32,
+    prelude::*,
+};
+
+/// Hashes the structurally significant parts of a resolved transaction, so
+/// fixtures built with random out points stay comparable. Out points of
+/// inputs and cell deps are left out, as well as header dep hashes since
+/// test headers carry random fields too.
+pub fn tx_fingerprint(rtx: &ResolvedTransaction) -> Byte32 {
+    let tx = &rtx.transaction;
+    let mut hasher = new_blake2b();
+    hasher.update(tx.data().raw().version().as_slice());
+    for input in tx.inputs().into_iter() {
+        hasher.update(input.since().as_slice());
+    }
+    for cell in &rtx.resolved_inputs {
+        update_cell(&mut hasher, cell);
+    }
+    for cell_dep in tx.cell_deps().into_iter() {
+        hasher.update(cell_dep.dep_type().as_slice());
+    }
+    for cell in rtx
+        .resolved_cell_deps
+        .iter()
+        .chain(rtx.resolved_dep_groups.iter())
+    {
+        update_cell(&mut hasher, cell);
+    }
+    hasher.update(&(tx.header_deps().len() as u64).to_le_bytes());
+    for (output, data) in tx.outputs_with_data_iter() {
+        hasher.update(output.as_slice());
+        hasher.update(&blake2b_256(&data));
+    }
+    for witness in tx.witnesses().into_iter() {
+        hasher.update(&blake2b_256(witness.raw_data()));
+    }
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash[..]);
+    Byte32::new(hash)
+}
+
+fn update_cell(hasher: &mut Blake2b, cell: &CellMeta) {
+    hasher.update(cell.cell_output.as_slice());
+    hasher.update(&blake2b_256(cell.mem_cell_data.as_ref().unwrap()));
+}
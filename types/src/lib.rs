@@ -0,0 +1,764 @@
+//! Off-chain helpers building zero lock witnesses: leaf hashing, merkle
+//! proofs over leaves and the serialization of proofs in witness lock. The
+//! layouts here are the ones zero lock reads, tooling constructing upgrade
+//! transactions should go through this crate instead of reimplementing them.
+//! `verifier` runs the contract's checks on the host, to find out why an
+//! upgrade fails.
+
+use ckb_hash::{blake2b_256, new_blake2b};
+use ckb_types::{
+    bytes::Bytes,
+    core::cell::CellMeta,
+    packed::{Byte32, CellOutput, WitnessArgs},
+    prelude::*,
+    H256,
+};
+use merkle_cbt::{merkle_tree::Merge, MerkleTree, CBMT};
+use sha2::{Digest, Sha256};
+
+extern crate alloc;
+
+// Shared with the contract, which lays out, merges and parses by the very
+// same constants and functions
+#[path = "../../src/constants.rs"]
+mod constants;
+#[path = "../../src/error.rs"]
+mod error;
+#[path = "../../src/extension.rs"]
+mod extension;
+#[path = "../../src/merge.rs"]
+mod merge;
+#[path = "../../src/proof_reader.rs"]
+mod proof_reader;
+#[path = "../../src/smt.rs"]
+mod smt_proof;
+#[path = "../../src/threshold.rs"]
+mod threshold;
+
+pub mod smt;
+pub mod verifier;
+
+/// Stands in for `ckb_std::debug` in the modules shared with the contract,
+/// whose messages are only of use on chain.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        let _ = format_args!($($arg)*);
+    };
+}
+pub(crate) use debug;
+
+pub use constants::*;
+pub use merge::{merge_blake2b, CKB_HASH_PERSONALIZATION};
+
+/// Mirrors the contract side deployment id of committee ids, tagging their
+/// roots in `EXTENSION_FIELD_TAGGED_ROOTS`.
+pub fn deployment_id(committee_id: &[u8]) -> [u8; 32] {
+    blake2b_256(committee_id)
+}
+
+/// Mirrors the contract side slot selection for committee ids.
+pub fn slot_for(committee_id: &[u8], num_slots: usize) -> usize {
+    let hash = deployment_id(committee_id);
+    let mut t = [0u8; 4];
+    t.copy_from_slice(&hash[0..4]);
+    u32::from_le_bytes(t) as usize % num_slots
+}
+
+/// Bytes of a hash, as the contract merges and compares them.
+fn hash_bytes(hash: &Byte32) -> [u8; 32] {
+    let hash: H256 = hash.unpack();
+    hash.0
+}
+
+/// Merges merkle tree nodes by blake2b with `merge_blake2b`, which zero lock
+/// merges its nodes by as well.
+#[derive(Debug)]
+pub struct Blake2bHash;
+
+impl Merge for Blake2bHash {
+    type Item = Byte32;
+
+    fn merge(lhs: &Self::Item, rhs: &Self::Item) -> Self::Item {
+        Byte32::new(merge_blake2b(&hash_bytes(lhs), &hash_bytes(rhs)))
+    }
+}
+
+/// Merges merkle tree nodes by SHA-256, for trees of extensions setting
+/// `EXTENSION_FIELD_MERGE_HASH` to `MERGE_HASH_SHA256`. Leaves are blake2b
+/// hashes either way.
+pub struct Sha256Hash;
+
+impl Merge for Sha256Hash {
+    type Item = Byte32;
+
+    fn merge(lhs: &Self::Item, rhs: &Self::Item) -> Self::Item {
+        let mut hasher = Sha256::new();
+        hasher.update(lhs.as_slice());
+        hasher.update(rhs.as_slice());
+        Byte32::new(hasher.finalize().into())
+    }
+}
+
+/// Hashes the leaf an upgrade or migration produces in zero lock, for
+/// auditors recomputing leaves from the raw parts of a transaction. `tag` is
+/// the domain separation byte starting the preimage, one of the `LeafTag`
+/// values, `old` is the serialized out point of the input cell and `new` the
+/// data of the output cell `new_cell`. Witness input_type and output_type are
+/// committed as absent, leaves of cells locked with `LOCK_FLAG_ACTIVATION`,
+/// `LOCK_FLAG_PATCH` or `LOCK_EXT_FLAG_CODE_DEP` are not covered.
+///
+/// The preimage is the tag, `old`, the blake2b hash of `new`, the serialized
+/// `new_cell` and a zero byte for each of the absent witness fields. This
+/// layout is stable, a change to it is a change to zero lock.
+pub fn upgrade_leaf(tag: u8, old: &[u8], new: &[u8], new_cell: &CellOutput) -> Byte32 {
+    compute_leaf(
+        tag,
+        old,
+        &blake2b_256(new),
+        new_cell.as_slice(),
+        None,
+        None,
+        None,
+    )
+}
+
+/// Hashes the leaf upgrading `old_cell` into `new_cell`. The old contract is
+/// committed through the out point of `old_cell`. Witness input_type and
+/// output_type of the input cell are committed as given, the since value of
+/// the input cell only for cells locked with `LOCK_FLAG_ACTIVATION`.
+pub fn compute_upgrade_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+    since: Option<u64>,
+) -> Byte32 {
+    let data_hash = blake2b_256(new_cell.mem_cell_data.as_ref().unwrap());
+    compute_leaf(
+        LeafTag::Upgrade as u8,
+        old_cell.out_point.as_slice(),
+        &data_hash,
+        new_cell.cell_output.as_slice(),
+        input_type,
+        output_type,
+        since,
+    )
+}
+
+/// Hashes the leaf upgrading `old_cell` into the output cell at
+/// `output_index`, which must be `new_cell`. The tag carries
+/// `LEAF_TAG_FLAG_OUTPUT_INDEX`, so the leaf stops matching once the output
+/// cells of a batch are reordered, even around output cells of other locks.
+pub fn compute_indexed_upgrade_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    output_index: u32,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    let data_hash = blake2b_256(new_cell.mem_cell_data.as_ref().unwrap());
+    // The index follows the serialized output cell in the preimage
+    let mut cell = new_cell.cell_output.as_slice().to_vec();
+    cell.extend(output_index.to_le_bytes());
+    compute_leaf(
+        LeafTag::Upgrade as u8 | LEAF_TAG_FLAG_OUTPUT_INDEX,
+        old_cell.out_point.as_slice(),
+        &data_hash,
+        &cell,
+        input_type,
+        output_type,
+        None,
+    )
+}
+
+/// Hashes the leaf upgrading `old_cell` into `new_cell` under a new type
+/// script. The tag carries `LEAF_TAG_FLAG_RETYPE`, so zero lock lets the type
+/// script of `new_cell` differ from the one of `old_cell`.
+pub fn compute_retype_upgrade_leaf(old_cell: &CellMeta, new_cell: &CellMeta) -> Byte32 {
+    let data_hash = blake2b_256(new_cell.mem_cell_data.as_ref().unwrap());
+    compute_leaf(
+        LeafTag::Upgrade as u8 | LEAF_TAG_FLAG_RETYPE,
+        old_cell.out_point.as_slice(),
+        &data_hash,
+        new_cell.cell_output.as_slice(),
+        None,
+        None,
+        None,
+    )
+}
+
+/// Hashes the leaf of an upgrade for cells locked with `LOCK_FLAG_PATCH`,
+/// the patch is carried in witness output_type.
+pub fn compute_patch_upgrade_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    patch: &Bytes,
+) -> Byte32 {
+    compute_leaf(
+        LeafTag::Upgrade as u8,
+        old_cell.out_point.as_slice(),
+        &blake2b_256(patch),
+        new_cell.cell_output.as_slice(),
+        None,
+        Some(patch.clone()),
+        None,
+    )
+}
+
+/// Builds the data of a cell referencing `code` by data hash under
+/// `LOCK_EXT_FLAG_CODE_DEP`, the code itself goes to a cell dep of the
+/// upgrade transaction.
+pub fn code_dep_data(code: &[u8]) -> Bytes {
+    Bytes::from(blake2b_256(code).to_vec())
+}
+
+/// Hashes the leaf of an upgrade for cells locked with
+/// `LOCK_EXT_FLAG_CODE_DEP`. The data of `new_cell` is the code hash built by
+/// `code_dep_data`, which the leaf commits as is.
+pub fn compute_code_dep_upgrade_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    let code_hash: [u8; 32] = new_cell.mem_cell_data.as_ref().unwrap()[..]
+        .try_into()
+        .expect("code hash in cell data");
+    compute_leaf(
+        LeafTag::Upgrade as u8,
+        old_cell.out_point.as_slice(),
+        &code_hash,
+        new_cell.cell_output.as_slice(),
+        input_type,
+        output_type,
+        None,
+    )
+}
+
+/// Hashes the leaf migrating `old_cell` into `new_cell`, which may use any
+/// lock but must keep the type script of `old_cell`.
+pub fn compute_migration_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    let data_hash = blake2b_256(new_cell.mem_cell_data.as_ref().unwrap());
+    compute_leaf(
+        LeafTag::Migration as u8,
+        old_cell.out_point.as_slice(),
+        &data_hash,
+        new_cell.cell_output.as_slice(),
+        input_type,
+        output_type,
+        None,
+    )
+}
+
+/// Hashes the leaf rotating the governance cell `old_cell` into `new_cell`
+/// under `LOCK_EXT_FLAG_GOVERNANCE_CELL`. The leaf is proven against the
+/// roots in the data of `old_cell`, the data of `new_cell` holds the roots
+/// governing from then on.
+pub fn compute_rotation_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    let data_hash = blake2b_256(new_cell.mem_cell_data.as_ref().unwrap());
+    compute_leaf(
+        LeafTag::Rotation as u8,
+        old_cell.out_point.as_slice(),
+        &data_hash,
+        new_cell.cell_output.as_slice(),
+        input_type,
+        output_type,
+        None,
+    )
+}
+
+/// Hashes the leaf freezing `old_cell` into `new_cell`, whose data must start
+/// with `FROZEN_MAGIC`. The frozen cell can still be moved by an upgrade leaf
+/// keeping its data, but its data never changes again.
+pub fn compute_freeze_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    let data_hash = blake2b_256(new_cell.mem_cell_data.as_ref().unwrap());
+    compute_leaf(
+        LeafTag::Freeze as u8,
+        old_cell.out_point.as_slice(),
+        &data_hash,
+        new_cell.cell_output.as_slice(),
+        input_type,
+        output_type,
+        None,
+    )
+}
+
+/// Hashes the leaf reconfiguring `old_cell` into `new_cell`, whose data
+/// starts with the same `code_length` bytes. Witness output_type carries the
+/// code length as little-endian u32, which the leaf commits along with the
+/// data hash of `old_cell`.
+pub fn compute_reconfiguration_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    code_length: u32,
+    input_type: Option<Bytes>,
+) -> Byte32 {
+    let data_hash = blake2b_256(new_cell.mem_cell_data.as_ref().unwrap());
+    // The old data hash follows the out point in the preimage
+    let mut old = old_cell.out_point.as_slice().to_vec();
+    old.extend(blake2b_256(old_cell.mem_cell_data.as_ref().unwrap()));
+    compute_leaf(
+        LeafTag::Reconfiguration as u8,
+        &old,
+        &data_hash,
+        new_cell.cell_output.as_slice(),
+        input_type,
+        Some(Bytes::from(code_length.to_le_bytes().to_vec())),
+        None,
+    )
+}
+
+/// Hashes the leaf releasing capacity of `old_cell` into `new_cell`, which
+/// holds up to `released` less capacity. Witness output_type carries
+/// `released` as little-endian u64 followed by `lock_hash`, the lock hash of
+/// the output cells receiving the released capacity.
+pub fn compute_release_leaf(
+    old_cell: &CellMeta,
+    new_cell: &CellMeta,
+    released: u64,
+    lock_hash: &Byte32,
+    input_type: Option<Bytes>,
+) -> Byte32 {
+    let data_hash = blake2b_256(new_cell.mem_cell_data.as_ref().unwrap());
+    compute_leaf(
+        LeafTag::Release as u8,
+        old_cell.out_point.as_slice(),
+        &data_hash,
+        new_cell.cell_output.as_slice(),
+        input_type,
+        Some(release_output_type(released, lock_hash)),
+        None,
+    )
+}
+
+/// Witness output_type of a release leaf, releasing up to `released` to the
+/// output cells locked by `lock_hash`.
+pub fn release_output_type(released: u64, lock_hash: &Byte32) -> Bytes {
+    let mut output_type = released.to_le_bytes().to_vec();
+    output_type.extend(lock_hash.as_slice());
+    Bytes::from(output_type)
+}
+
+/// Prefixes `data` with `FROZEN_MAGIC`, for the output cell of a freeze leaf.
+pub fn frozen_data(data: &[u8]) -> Bytes {
+    let mut frozen = FROZEN_MAGIC.to_vec();
+    frozen.extend(data);
+    Bytes::from(frozen)
+}
+
+/// Hashes the leaf deleting a zero lock cell, committing its data hash and
+/// type script hash instead of a successor cell.
+pub fn compute_deletion_leaf(
+    old_cell: &CellMeta,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Byte32 {
+    let data_hash = blake2b_256(old_cell.mem_cell_data.as_ref().unwrap());
+    let type_hash = old_cell
+        .cell_output
+        .type_()
+        .to_opt()
+        .map(|script| script.calc_script_hash())
+        .unwrap_or_else(Byte32::zero);
+    compute_leaf(
+        LeafTag::Deletion as u8,
+        old_cell.out_point.as_slice(),
+        &data_hash,
+        type_hash.as_slice(),
+        input_type,
+        output_type,
+        None,
+    )
+}
+
+fn compute_leaf(
+    tag: u8,
+    out_point: &[u8],
+    data_hash: &[u8; 32],
+    cell: &[u8],
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+    since: Option<u64>,
+) -> Byte32 {
+    let mut hasher = new_blake2b();
+    hasher.update(&[tag]);
+    hasher.update(out_point);
+    hasher.update(&data_hash[..]);
+    hasher.update(cell);
+    for field in [input_type, output_type] {
+        if let Some(field) = field {
+            hasher.update(&[1u8]);
+            hasher.update(&TryInto::<u32>::try_into(field.len()).unwrap().to_le_bytes());
+            hasher.update(&field);
+        } else {
+            hasher.update(&[0u8]);
+        }
+    }
+    if let Some(since) = since {
+        hasher.update(&since.to_le_bytes());
+    }
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash[..]);
+    Byte32::new(hash)
+}
+
+/// Serializes a merkle proof in the layout zero lock expects in witness
+/// lock: header index, indices count, CBMT node indices of the proven leaves,
+/// a tag per proven leaf, lemmas count and lemmas.
+pub fn serialize_proof(
+    header_index: u32,
+    indices: &[u32],
+    tags: &[u8],
+    lemmas: &[Byte32],
+) -> Bytes {
+    let mut data = vec![];
+    data.extend(header_index.to_le_bytes());
+    data.extend(
+        TryInto::<u32>::try_into(indices.len())
+            .unwrap()
+            .to_le_bytes(),
+    );
+    for index in indices {
+        data.extend(index.to_le_bytes());
+    }
+    data.extend(tags);
+    data.extend(
+        TryInto::<u32>::try_into(lemmas.len())
+            .unwrap()
+            .to_le_bytes(),
+    );
+    for lemma in lemmas {
+        data.extend(lemma.as_slice());
+    }
+    Bytes::from(data)
+}
+
+/// Serializes a merkle proof in the compact layout for cells locked with
+/// `LOCK_EXT_FLAG_PROOF_FORMAT`, starting with `PROOF_FORMAT_VARINT`. The
+/// root index, when the cell is locked with `LOCK_EXT_FLAG_ROOT_INDEX`, the
+/// header index, counts and indices are LEB128 varints. Each lemma is the
+/// length of the prefix it shares with the previous lemma, followed by the
+/// remaining bytes. Proofs in the layout of `serialize_proof` are prefixed
+/// with `PROOF_FORMAT_FIXED` instead under the flag.
+pub fn serialize_varint_proof(
+    root_index: Option<u32>,
+    header_index: u32,
+    indices: &[u32],
+    tags: &[u8],
+    lemmas: &[Byte32],
+) -> Bytes {
+    let mut data = vec![PROOF_FORMAT_VARINT];
+    if let Some(root_index) = root_index {
+        push_varint(&mut data, root_index);
+    }
+    push_varint(&mut data, header_index);
+    push_varint(&mut data, TryInto::<u32>::try_into(indices.len()).unwrap());
+    for index in indices {
+        push_varint(&mut data, *index);
+    }
+    data.extend(tags);
+    push_varint(&mut data, TryInto::<u32>::try_into(lemmas.len()).unwrap());
+    let mut previous: Option<&[u8]> = None;
+    for lemma in lemmas {
+        let lemma = lemma.as_slice();
+        let shared = previous.map_or(0, |previous| {
+            previous
+                .iter()
+                .zip(lemma)
+                .take_while(|(a, b)| a == b)
+                .count()
+        });
+        data.push(shared as u8);
+        data.extend(&lemma[shared..]);
+        previous = Some(lemma);
+    }
+    Bytes::from(data)
+}
+
+/// Appends `value` as an unsigned LEB128 varint.
+fn push_varint(data: &mut Vec<u8>, mut value: u32) {
+    while value >= 0x80 {
+        data.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    data.push(value as u8);
+}
+
+/// Prefixes serialized proof data with the index of the merkle root slot it
+/// is verified against, for cells with `LOCK_EXT_FLAG_ROOT_INDEX`.
+pub fn serialize_root_indexed_proof(root_index: u32, proof: &[u8]) -> Bytes {
+    let mut data = root_index.to_le_bytes().to_vec();
+    data.extend(proof);
+    Bytes::from(data)
+}
+
+/// Serializes the compact proof form for a single leaf tree, which only
+/// carries the header index. The merkle root of such a tree is the leaf
+/// itself.
+pub fn serialize_compact_proof(header_index: u32) -> Bytes {
+    Bytes::from(header_index.to_le_bytes().to_vec())
+}
+
+/// Wraps serialized proof data into the WitnessArgs of the first input cell,
+/// next to the input_type and output_type committed in its leaf.
+pub fn serialize_witness(
+    lock: Bytes,
+    input_type: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Bytes {
+    WitnessArgs::new_builder()
+        .lock(Some(lock).pack())
+        .input_type(input_type.pack())
+        .output_type(output_type.pack())
+        .build()
+        .as_bytes()
+}
+
+/// Wraps serialized proof data into the seal of a `WitnessLayout`, as
+/// `SighashAll` when a message is given and `SighashAllOnly` otherwise.
+/// `message` is the serialized `Message` the seal is not read from.
+pub fn serialize_witness_layout(seal: Bytes, message: Option<Bytes>) -> Bytes {
+    let (id, fields): (u32, Vec<Bytes>) = match message {
+        Some(message) => (0xff00_0001, vec![message, seal.pack().as_bytes()]),
+        None => (0xff00_0002, vec![seal.pack().as_bytes()]),
+    };
+    let header_size = 4 + 4 * fields.len();
+    let total_size = header_size + fields.iter().map(Bytes::len).sum::<usize>();
+    let mut witness = id.to_le_bytes().to_vec();
+    witness.extend((total_size as u32).to_le_bytes());
+    let mut offset = header_size;
+    for field in &fields {
+        witness.extend((offset as u32).to_le_bytes());
+        offset += field.len();
+    }
+    for field in &fields {
+        witness.extend(field);
+    }
+    Bytes::from(witness)
+}
+
+/// Wraps serialized proof data into witness input_type of the first input
+/// cell, for cells locked with `LOCK_EXT_FLAG_INPUT_TYPE_PROOF`. Witness lock
+/// is free for other scripts and is not committed in the leaf, which commits
+/// input_type as absent.
+pub fn serialize_input_type_witness(
+    proof: Bytes,
+    lock: Option<Bytes>,
+    output_type: Option<Bytes>,
+) -> Bytes {
+    WitnessArgs::new_builder()
+        .lock(lock.pack())
+        .input_type(Some(proof).pack())
+        .output_type(output_type.pack())
+        .build()
+        .as_bytes()
+}
+
+/// Wraps serialized proof data into witness lock of the first input cell and
+/// co-proofs into its input_type, for cells locked with
+/// `LOCK_EXT_FLAG_THRESHOLD_PROOFS`. The leaf commits input_type as absent.
+pub fn serialize_threshold_witness(
+    proof: Bytes,
+    co_proofs: &[Bytes],
+    output_type: Option<Bytes>,
+) -> Bytes {
+    WitnessArgs::new_builder()
+        .lock(Some(proof).pack())
+        .input_type(Some(Bytes::from(co_proofs.concat())).pack())
+        .output_type(output_type.pack())
+        .build()
+        .as_bytes()
+}
+
+/// Collects the leaves of a committee's tree, then builds its merkle root and
+/// proofs of the leaves upgraded in a transaction. Tree nodes are merged by
+/// blake2b unless another merge hash is picked.
+///
+/// The tree holds its leaves sorted by hash: zero lock sorts the leaves of
+/// the input cells a proof covers the same way before pairing them with the
+/// node indices proven, so a transaction upgrading several cells may list
+/// them in any order. Leaves are still selected by the order they are
+/// appended in. A leaf joining the tree may move committed ones, proofs are
+/// built against the root of the tree that is published.
+#[derive(Clone, Debug, Default)]
+pub struct UpgradeProofBuilder {
+    /// Tag byte declared in the proof and hash of each leaf
+    leaves: Vec<(u8, Byte32)>,
+    merge_hash: u8,
+}
+
+impl UpgradeProofBuilder {
+    /// Merges tree nodes by the given `MERGE_HASH_*` hash, which the
+    /// extension publishing the root must pick as well.
+    pub fn merge_hash(mut self, merge_hash: u8) -> Self {
+        self.merge_hash = merge_hash;
+        self
+    }
+
+    /// Appends a leaf to the tree.
+    pub fn leaf(mut self, tag: LeafTag, leaf: Byte32) -> Self {
+        self.leaves.push((tag as u8, leaf));
+        self
+    }
+
+    /// Appends a leaf committing the index of its output cell, as built by
+    /// `compute_indexed_upgrade_leaf`.
+    pub fn indexed_leaf(mut self, tag: LeafTag, leaf: Byte32) -> Self {
+        self.leaves
+            .push((tag as u8 | LEAF_TAG_FLAG_OUTPUT_INDEX, leaf));
+        self
+    }
+
+    /// Appends an upgrade leaf letting its output cell carry another type
+    /// script, as built by `compute_retype_upgrade_leaf`.
+    pub fn retype_leaf(mut self, leaf: Byte32) -> Self {
+        self.leaves
+            .push((LeafTag::Upgrade as u8 | LEAF_TAG_FLAG_RETYPE, leaf));
+        self
+    }
+
+    /// Appends leaves to the tree.
+    pub fn leaves<I>(mut self, leaves: I) -> Self
+    where
+        I: IntoIterator<Item = (LeafTag, Byte32)>,
+    {
+        self.leaves
+            .extend(leaves.into_iter().map(|(tag, leaf)| (tag as u8, leaf)));
+        self
+    }
+
+    /// Leaves in the order the tree holds them, sorted by hash.
+    fn sorted_leaves(&self) -> Vec<Byte32> {
+        let mut leaves: Vec<Byte32> = self.leaves.iter().map(|(_, leaf)| leaf.clone()).collect();
+        leaves.sort_by(|a, b| a.as_slice().cmp(b.as_slice()));
+        leaves
+    }
+
+    fn tree<M: Merge<Item = Byte32>>(&self) -> MerkleTree<Byte32, M> {
+        CBMT::build_merkle_tree(&self.sorted_leaves())
+    }
+
+    pub fn root(&self) -> Byte32 {
+        match self.merge_hash {
+            MERGE_HASH_SHA256 => self.tree::<Sha256Hash>().root(),
+            _ => self.tree::<Blake2bHash>().root(),
+        }
+    }
+
+    /// Serializes the proof of the `selected` leaves for witness lock of the
+    /// first input cell, proving them against the header dep at
+    /// `header_index`. Leaves are selected in the order of the input cells
+    /// upgrading them, which is the order their tags are declared in.
+    /// `None` when a selected leaf is not in the tree, or two selected
+    /// leaves are identical, which zero lock rejects.
+    pub fn build(&self, header_index: u32, selected: &[u32]) -> Option<Bytes> {
+        let (indices, lemmas) = self.proof(selected)?;
+        let tags: Vec<u8> = selected
+            .iter()
+            .map(|i| self.leaves[*i as usize].0)
+            .collect();
+        Some(serialize_proof(header_index, &indices, &tags, &lemmas))
+    }
+
+    /// Same as `build`, but serializes the proof with `serialize_varint_proof`
+    /// for cells locked with `LOCK_EXT_FLAG_PROOF_FORMAT`.
+    pub fn build_varint(&self, header_index: u32, selected: &[u32]) -> Option<Bytes> {
+        let (indices, lemmas) = self.proof(selected)?;
+        let tags: Vec<u8> = selected
+            .iter()
+            .map(|i| self.leaves[*i as usize].0)
+            .collect();
+        Some(serialize_varint_proof(
+            None,
+            header_index,
+            &indices,
+            &tags,
+            &lemmas,
+        ))
+    }
+
+    /// Serializes the co-proof of the `selected` leaves for witness
+    /// input_type, proving them against the merkle root slot at `root_index`
+    /// under `LOCK_EXT_FLAG_THRESHOLD_PROOFS`. Co-proofs of several
+    /// committees are concatenated by `serialize_threshold_witness`.
+    pub fn build_co_proof(&self, root_index: u32, selected: &[u32]) -> Option<Bytes> {
+        let (indices, lemmas) = self.proof(selected)?;
+        let mut data = root_index.to_le_bytes().to_vec();
+        data.extend(
+            TryInto::<u32>::try_into(indices.len())
+                .unwrap()
+                .to_le_bytes(),
+        );
+        for index in indices {
+            data.extend(index.to_le_bytes());
+        }
+        data.extend(
+            TryInto::<u32>::try_into(lemmas.len())
+                .unwrap()
+                .to_le_bytes(),
+        );
+        for lemma in lemmas {
+            data.extend(lemma.as_slice());
+        }
+        Some(Bytes::from(data))
+    }
+
+    /// CBMT node indices and lemmas proving the `selected` leaves.
+    fn proof(&self, selected: &[u32]) -> Option<(Vec<u32>, Vec<Byte32>)> {
+        if selected.iter().any(|i| *i as usize >= self.leaves.len()) {
+            return None;
+        }
+        if selected.iter().enumerate().any(|(n, i)| {
+            selected[..n]
+                .iter()
+                .any(|j| self.leaves[*j as usize].1 == self.leaves[*i as usize].1)
+        }) {
+            return None;
+        }
+        // Positions of the selected leaves in the sorted tree, in the order
+        // zero lock pairs them with the leaves it sorts
+        let sorted_leaves = self.sorted_leaves();
+        let mut positions: Vec<u32> = selected
+            .iter()
+            .map(|i| {
+                let leaf = &self.leaves[*i as usize].1;
+                let position = sorted_leaves
+                    .iter()
+                    .position(|other| other == leaf)
+                    .unwrap();
+                TryInto::<u32>::try_into(position).unwrap()
+            })
+            .collect();
+        positions.sort_unstable();
+        let lemmas = match self.merge_hash {
+            MERGE_HASH_SHA256 => self
+                .tree::<Sha256Hash>()
+                .build_proof(&positions)?
+                .lemmas()
+                .to_vec(),
+            _ => self
+                .tree::<Blake2bHash>()
+                .build_proof(&positions)?
+                .lemmas()
+                .to_vec(),
+        };
+        let leaves_count = TryInto::<u32>::try_into(self.leaves.len()).unwrap();
+        let indices = positions.iter().map(|i| i + leaves_count - 1).collect();
+        Some((indices, lemmas))
+    }
+}
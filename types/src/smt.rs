@@ -0,0 +1,141 @@
+use crate::{hash_bytes, merge::merge_blake2b};
+use ckb_types::{bytes::Bytes, packed::Byte32};
+use std::collections::BTreeMap;
+
+/// Height of the sparse merkle tree, one level per bit of a 32-byte key.
+pub const SMT_HEIGHT: usize = 256;
+
+fn merge(lhs: &[u8; 32], rhs: &[u8; 32]) -> [u8; 32] {
+    if *lhs == [0u8; 32] && *rhs == [0u8; 32] {
+        [0u8; 32]
+    } else {
+        merge_blake2b(lhs, rhs)
+    }
+}
+
+fn bit(key: &[u8; 32], depth: usize) -> bool {
+    key[depth / 8] & (0x80 >> (depth % 8)) != 0
+}
+
+/// Proof of the value held at a key, siblings that are empty subtrees are
+/// only marked in the bitmap.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SmtProof {
+    pub bitmap: [u8; 32],
+    pub siblings: Vec<[u8; 32]>,
+}
+
+impl SmtProof {
+    /// Mirrors the contract side root computation, an absent value proves
+    /// that `key` is not in the tree.
+    pub fn root(&self, key: &Byte32, value: Option<&Byte32>) -> Option<Byte32> {
+        let key = hash_bytes(key);
+        let mut node = match value {
+            Some(value) => merge_blake2b(&key, &hash_bytes(value)),
+            None => [0u8; 32],
+        };
+        let mut siblings = self.siblings.iter();
+        for height in 0..SMT_HEIGHT {
+            let sibling = if self.bitmap[height / 8] & (1 << (height % 8)) != 0 {
+                *siblings.next()?
+            } else {
+                [0u8; 32]
+            };
+            node = if bit(&key, SMT_HEIGHT - 1 - height) {
+                merge(&sibling, &node)
+            } else {
+                merge(&node, &sibling)
+            };
+        }
+        if siblings.next().is_some() {
+            return None;
+        }
+        Some(Byte32::new(node))
+    }
+
+    /// True when the proof shows `key` holding `value` in the tree of `root`,
+    /// or `key` being absent from it when `value` is `None`.
+    pub fn verify(&self, root: &Byte32, key: &Byte32, value: Option<&Byte32>) -> bool {
+        self.root(key, value).as_ref() == Some(root)
+    }
+}
+
+/// Sparse merkle tree of 32-byte values keyed by 32-byte keys, as committed
+/// in the extension for cells locked with `LOCK_FLAG_SMT`.
+#[derive(Clone, Debug, Default)]
+pub struct SparseMerkleTree {
+    leaves: BTreeMap<[u8; 32], [u8; 32]>,
+}
+
+impl SparseMerkleTree {
+    pub fn insert(&mut self, key: &Byte32, value: &Byte32) {
+        self.leaves.insert(hash_bytes(key), hash_bytes(value));
+    }
+
+    /// Root of the subtree at `depth` holding `leaves`, which share the path
+    /// down to it.
+    fn subtree_root(leaves: &[([u8; 32], [u8; 32])], depth: usize) -> [u8; 32] {
+        if leaves.is_empty() {
+            return [0u8; 32];
+        }
+        if depth == SMT_HEIGHT {
+            let (key, value) = &leaves[0];
+            return merge_blake2b(key, value);
+        }
+        let split = leaves.partition_point(|(key, _)| !bit(key, depth));
+        merge(
+            &Self::subtree_root(&leaves[..split], depth + 1),
+            &Self::subtree_root(&leaves[split..], depth + 1),
+        )
+    }
+
+    fn sorted_leaves(&self) -> Vec<([u8; 32], [u8; 32])> {
+        self.leaves.iter().map(|(k, v)| (*k, *v)).collect()
+    }
+
+    pub fn root(&self) -> Byte32 {
+        Byte32::new(Self::subtree_root(&self.sorted_leaves(), 0))
+    }
+
+    /// Builds the proof for `key`, proving its value when it is in the tree
+    /// and its absence otherwise.
+    pub fn proof(&self, key: &Byte32) -> SmtProof {
+        let key = hash_bytes(key);
+        let all_leaves = self.sorted_leaves();
+        let mut leaves = &all_leaves[..];
+        let mut siblings_by_height = vec![[0u8; 32]; SMT_HEIGHT];
+        for depth in 0..SMT_HEIGHT {
+            let split = leaves.partition_point(|(k, _)| !bit(k, depth));
+            let (left, right) = leaves.split_at(split);
+            let (path, other) = if bit(&key, depth) {
+                (right, left)
+            } else {
+                (left, right)
+            };
+            siblings_by_height[SMT_HEIGHT - 1 - depth] = Self::subtree_root(other, depth + 1);
+            leaves = path;
+        }
+        let mut bitmap = [0u8; 32];
+        let mut siblings = vec![];
+        for (height, sibling) in siblings_by_height.into_iter().enumerate() {
+            if sibling != [0u8; 32] {
+                bitmap[height / 8] |= 1 << (height % 8);
+                siblings.push(sibling);
+            }
+        }
+        SmtProof { bitmap, siblings }
+    }
+}
+
+/// Serializes a sparse merkle proof in the layout zero lock expects in the
+/// witness lock field.
+pub fn smt_proof_lock_data(header_index: u32, tag: u8, proof: &SmtProof) -> Bytes {
+    let mut data = vec![];
+    data.extend(header_index.to_le_bytes());
+    data.push(tag);
+    data.extend(proof.bitmap);
+    for sibling in &proof.siblings {
+        data.extend(sibling);
+    }
+    Bytes::from(data)
+}
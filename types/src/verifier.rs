@@ -0,0 +1,1137 @@
+//! Host side reference of the checks zero lock runs on chain. It follows the
+//! contract step by step, failing with the same error code at the same point,
+//! so a failing upgrade can be narrowed down without running the script.
+//! Proofs, co-proofs, sparse merkle proofs and extensions are parsed by the
+//! contract's own modules, what is left here stands in for the syscalls
+//! zero lock loads the transaction through.
+
+use super::{
+    extension::Extension,
+    hash_bytes,
+    merge::{Blake2bHash, Data, Sha256Hash},
+    proof_reader::{read_u32, ProofVisitor, WitnessProof},
+    smt_proof::SmtProof,
+    threshold::CoProof,
+    LeafTag, EPOCH_NUMBER_MASK, FROZEN_MAGIC, KNOWN_LOCK_EXT_FLAGS, LEAF_TAG_FLAG_OUTPUT_INDEX,
+    LEAF_TAG_FLAG_RETYPE, LOCK_EXT_FLAG_ALLOW_SAME_DATA, LOCK_EXT_FLAG_CODE_DEP,
+    LOCK_EXT_FLAG_GOVERNANCE_CELL, LOCK_EXT_FLAG_INPUT_TYPE_PROOF, LOCK_EXT_FLAG_PROOF_FORMAT,
+    LOCK_EXT_FLAG_ROOT_INDEX, LOCK_EXT_FLAG_THRESHOLD_PROOFS, LOCK_FLAG_ACTIVATION,
+    LOCK_FLAG_EXTENDED, LOCK_FLAG_MIN_CONFIRMATIONS, LOCK_FLAG_PATCH, LOCK_FLAG_PRESERVE_STRUCTURE,
+    LOCK_FLAG_SALTED_ROOT, LOCK_FLAG_SMT, LOCK_FLAG_VERSIONED, MERGE_HASH_SHA256, PATCH_OP_COPY,
+    PATCH_OP_INSERT, SCRIPT_HASH_TYPE_DATA, SCRIPT_HASH_TYPE_DATA1, SCRIPT_HASH_TYPE_DATA2,
+    SCRIPT_HASH_TYPE_TYPE, SINCE_FLAGS_ABSOLUTE_EPOCH, SINCE_FLAGS_MASK,
+    WITNESS_LAYOUT_SIGHASH_ALL, WITNESS_LAYOUT_SIGHASH_ALL_ONLY,
+};
+use ckb_hash::{blake2b_256, new_blake2b, Blake2b};
+use ckb_traits::{ExtensionProvider, HeaderProvider};
+use ckb_types::{
+    bytes::Bytes,
+    core::{
+        cell::{CellMeta, ResolvedTransaction},
+        HeaderView,
+    },
+    packed::{Byte32, CellOutput, Script, WitnessArgs, WitnessArgsReader},
+    prelude::*,
+};
+use merkle_cbt::{merkle_tree::Merge, MerkleProof};
+
+pub use super::error::Error;
+
+impl Error {
+    /// Error code the contract fails with.
+    pub fn code(self) -> u32 {
+        self as u32
+    }
+}
+
+fn byte32(data: &Data) -> Byte32 {
+    Byte32::new(data.0)
+}
+
+fn cell_data(cell: &CellMeta) -> &Bytes {
+    cell.mem_cell_data.as_ref().expect("cell data in memory")
+}
+
+/// Feeds a whole witness field to the proof, `None` when it is malformed.
+fn feed(proof: &mut ProofVisitor, data: &[u8]) -> Option<()> {
+    proof.expect(data.len());
+    (proof.process(data) == 0).then_some(())
+}
+
+fn update_remainder(hasher: &mut Blake2b, field: Option<Bytes>) {
+    match field {
+        Some(field) => {
+            hasher.update(&[1u8]);
+            hasher.update(&(field.len() as u32).to_le_bytes());
+            hasher.update(&field);
+        }
+        None => hasher.update(&[0u8]),
+    }
+}
+
+/// Where the merkle proof is carried in witness, picked by lock args.
+#[derive(Clone, Copy, Default)]
+struct ProofLayout {
+    root_index: bool,
+    input_type: bool,
+    /// Witness input_type carries co-proofs, hashed as absent
+    co_proofs: bool,
+    format_byte: bool,
+}
+
+/// Content of the seal of a witness laid out as `SighashAll` or
+/// `SighashAllOnly`, the last field of both. `None` for any other witness.
+fn witness_layout_seal(witness: &Bytes) -> Option<Bytes> {
+    let u32_at = |offset: usize| {
+        witness
+            .get(offset..offset + 4)
+            .map(|n| u32::from_le_bytes(n.try_into().unwrap()) as usize)
+    };
+    let fields = match u32_at(0)? as u32 {
+        WITNESS_LAYOUT_SIGHASH_ALL => 2,
+        WITNESS_LAYOUT_SIGHASH_ALL_ONLY => 1,
+        _ => return None,
+    };
+    if witness.len() < 16 || u32_at(4)? != witness.len() - 4 || u32_at(8)? != 4 + 4 * fields {
+        return None;
+    }
+    let seal_offset = 4 + u32_at(4 + 4 * fields)?;
+    let seal_length = u32_at(seal_offset)?;
+    if seal_offset.checked_add(4 + seal_length) != Some(witness.len()) {
+        return None;
+    }
+    Some(witness.slice(seal_offset + 4..))
+}
+
+/// True when a witness carries a non-empty proof where `layout` expects it,
+/// raw witnesses are the proof themselves.
+fn has_proof(witness: Option<&Bytes>, layout: ProofLayout) -> bool {
+    let Some(witness) = witness else {
+        return false;
+    };
+    if WitnessArgsReader::verify(witness, false).is_err() {
+        return !witness_layout_seal(witness)
+            .unwrap_or_else(|| witness.clone())
+            .is_empty();
+    }
+    let witness_args = WitnessArgs::new_unchecked(witness.clone());
+    let field = if layout.input_type {
+        witness_args.input_type()
+    } else {
+        witness_args.lock()
+    };
+    field
+        .to_opt()
+        .map_or(false, |field| !field.raw_data().is_empty())
+}
+
+/// Reads a witness like the contract's witness reader: the proof is parsed
+/// when `lock` is set, the remainder is hashed when a hasher is given.
+/// Witnesses that are not a valid WitnessArgs are read as the raw proof, or
+/// the seal of a `WitnessLayout`. Fails with `TreeTooDeep` for proofs with
+/// too many lemmas, and with `InvalidWitness` otherwise.
+fn read_witness(
+    witness: Option<Bytes>,
+    lock: bool,
+    layout: ProofLayout,
+    remainder_hasher: Option<&mut Blake2b>,
+) -> Result<ProofVisitor, Error> {
+    let proof = if layout.root_index {
+        ProofVisitor::with_root_index()
+    } else {
+        ProofVisitor::default()
+    };
+    let mut proof = if layout.format_byte {
+        proof.with_format_byte()
+    } else {
+        proof
+    };
+    match read_witness_into(&mut proof, witness, lock, layout, remainder_hasher) {
+        Some(()) => Ok(proof),
+        None if proof.too_deep() => Err(Error::TreeTooDeep),
+        None => Err(Error::InvalidWitness),
+    }
+}
+
+fn read_witness_into(
+    proof: &mut ProofVisitor,
+    witness: Option<Bytes>,
+    lock: bool,
+    layout: ProofLayout,
+    remainder_hasher: Option<&mut Blake2b>,
+) -> Option<()> {
+    let witness = witness?;
+    if WitnessArgsReader::verify(&witness, false).is_err() {
+        if !lock {
+            return None;
+        }
+        feed(proof, &witness_layout_seal(&witness).unwrap_or(witness))?;
+        if let Some(hasher) = remainder_hasher {
+            hasher.update(&[0u8]);
+            hasher.update(&[0u8]);
+        }
+        return Some(());
+    }
+    let witness_args = WitnessArgs::new_unchecked(witness);
+    if lock && layout.input_type {
+        // Witness lock is left to other scripts, input_type is hashed as absent
+        feed(proof, &witness_args.input_type().to_opt()?.raw_data())?;
+        if let Some(hasher) = remainder_hasher {
+            hasher.update(&[0u8]);
+            update_remainder(
+                hasher,
+                witness_args.output_type().to_opt().map(|b| b.raw_data()),
+            );
+        }
+        return Some(());
+    }
+    if lock {
+        feed(proof, &witness_args.lock().to_opt()?.raw_data())?;
+    }
+    if let Some(hasher) = remainder_hasher {
+        let input_type = witness_args.input_type().to_opt().map(|b| b.raw_data());
+        if proof.awaiting_split_lemmas() {
+            if layout.co_proofs {
+                return None;
+            }
+            // Lemmas in input_type are hashed as absent input_type
+            proof.resume_split_lemmas();
+            hasher.update(&[0u8]);
+            feed(proof, &input_type?)?;
+        } else if layout.co_proofs {
+            hasher.update(&[0u8]);
+        } else {
+            update_remainder(hasher, input_type);
+        }
+        update_remainder(
+            hasher,
+            witness_args.output_type().to_opt().map(|b| b.raw_data()),
+        );
+    }
+    Some(())
+}
+
+/// Proof parsed out of a witness by `parse_proof`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsedProof {
+    pub root_index: Option<u32>,
+    pub header_index: u32,
+    pub leaf_tags: Vec<u8>,
+    /// CBMT node indices of the proven leaves, empty for the compact form
+    pub indices: Vec<u32>,
+    pub lemmas: Vec<Byte32>,
+    pub leaf_hash: Option<Byte32>,
+}
+
+/// Parses the witness of the first input cell like the contract does, for a
+/// cell whose lock args carry the given extended flags. Well-formed proofs
+/// may still fail verification, malformed ones fail with `InvalidWitness`,
+/// `InvalidProof` or `TreeTooDeep`, and absent ones with `MissingProof`.
+pub fn parse_proof(witness: Bytes, ext_flags: u8) -> Result<ParsedProof, Error> {
+    let layout = ProofLayout {
+        root_index: ext_flags & LOCK_EXT_FLAG_ROOT_INDEX != 0,
+        input_type: ext_flags & LOCK_EXT_FLAG_INPUT_TYPE_PROOF != 0,
+        co_proofs: ext_flags & LOCK_EXT_FLAG_THRESHOLD_PROOFS != 0,
+        format_byte: ext_flags & LOCK_EXT_FLAG_PROOF_FORMAT != 0,
+    };
+    if !has_proof(Some(&witness), layout) {
+        return Err(Error::MissingProof);
+    }
+    // Lemmas split into input_type are only read along with the remainder
+    let mut hasher = new_blake2b();
+    let proof = read_witness(Some(witness), true, layout, Some(&mut hasher))?;
+    let leaf_tags = proof.leaf_tags().ok_or(Error::InvalidProof)?.to_vec();
+    let WitnessProof {
+        header_index,
+        root_index,
+        merkle_proof,
+        leaf_hash,
+    } = proof.build::<Blake2bHash>().ok_or(Error::InvalidProof)?;
+    let (indices, lemmas) = match merkle_proof {
+        Some(merkle_proof) => (
+            merkle_proof.indices().to_vec(),
+            merkle_proof.lemmas().iter().map(byte32).collect(),
+        ),
+        None => Default::default(),
+    };
+    Ok(ParsedProof {
+        root_index,
+        header_index,
+        leaf_tags,
+        indices,
+        lemmas,
+        leaf_hash: leaf_hash.as_ref().map(byte32),
+    })
+}
+
+/// Resolves the data hash of the code a script runs, `None` if the code
+/// cannot be found in cell deps.
+fn code_data_hash(rtx: &ResolvedTransaction, script: &Script) -> Option<Byte32> {
+    let code_hash = script.code_hash();
+    match script.hash_type().as_slice()[0] {
+        SCRIPT_HASH_TYPE_DATA | SCRIPT_HASH_TYPE_DATA1 | SCRIPT_HASH_TYPE_DATA2 => Some(code_hash),
+        SCRIPT_HASH_TYPE_TYPE => rtx
+            .resolved_cell_deps
+            .iter()
+            .find(|dep| {
+                dep.cell_output
+                    .type_()
+                    .to_opt()
+                    .map(|type_script| type_script.calc_script_hash())
+                    == Some(code_hash.clone())
+            })
+            .map(|dep| CellOutput::calc_data_hash(cell_data(dep))),
+        _ => None,
+    }
+}
+
+fn load_version(offset: usize, data: &[u8]) -> Result<u32, Error> {
+    match data.get(offset..offset.saturating_add(4)) {
+        Some(version) => Ok(u32::from_le_bytes(version.try_into().unwrap())),
+        None => Err(Error::MissingVersion),
+    }
+}
+
+fn hash_patched_data(patch: &[u8], data: &[u8]) -> Result<[u8; 32], Error> {
+    let mut hasher = new_blake2b();
+    let mut rest = patch;
+    while let Some((op, r)) = rest.split_first() {
+        rest = match *op {
+            PATCH_OP_COPY => {
+                let (offset, r) = read_u32(r).ok_or(Error::InvalidPatch)?;
+                let (length, r) = read_u32(r).ok_or(Error::InvalidPatch)?;
+                // Empty copies never load anything, wherever they point to
+                if length > 0 {
+                    hasher.update(
+                        data.get(offset..offset + length)
+                            .ok_or(Error::InvalidPatch)?,
+                    );
+                }
+                r
+            }
+            PATCH_OP_INSERT => {
+                let (length, r) = read_u32(r).ok_or(Error::InvalidPatch)?;
+                if r.len() < length {
+                    return Err(Error::InvalidPatch);
+                }
+                let (inserted, r) = r.split_at(length);
+                hasher.update(inserted);
+                r
+            }
+            _ => return Err(Error::InvalidPatch),
+        };
+    }
+    let mut hash = [0u8; 32];
+    hasher.finalize(&mut hash[..]);
+    Ok(hash)
+}
+
+/// Input cell of the script group, with its since value and witness.
+struct GroupInput<'a> {
+    cell: &'a CellMeta,
+    since: u64,
+    witness: Option<Bytes>,
+}
+
+/// Settings of zero lock, read from lock args the same way as the contract.
+struct LockArgs<'a> {
+    flags: u8,
+    ext_flags: u8,
+    layout: ProofLayout,
+    salt: Option<Data>,
+    version_offset: Option<usize>,
+    min_confirmations: Option<u64>,
+    governance_type_hash: Option<Byte32>,
+    committee_id: &'a [u8],
+}
+
+impl<'a> LockArgs<'a> {
+    fn parse(args: &'a [u8]) -> Result<Self, Error> {
+        let (flags, committee_id) = match args.split_first() {
+            Some((flags, committee_id)) => (*flags, committee_id),
+            None => (0, args),
+        };
+        let (ext_flags, committee_id) = if flags & LOCK_FLAG_EXTENDED != 0 {
+            let (ext_flags, committee_id) =
+                committee_id.split_first().ok_or(Error::InvalidLockArgs)?;
+            (*ext_flags, committee_id)
+        } else {
+            (0, committee_id)
+        };
+        if ext_flags & !KNOWN_LOCK_EXT_FLAGS != 0 {
+            return Err(Error::UnknownLockFlags);
+        }
+        if ext_flags & LOCK_EXT_FLAG_CODE_DEP != 0 && flags & LOCK_FLAG_PATCH != 0 {
+            return Err(Error::InvalidLockArgs);
+        }
+        let (salt, committee_id) = if flags & LOCK_FLAG_SALTED_ROOT != 0 {
+            if committee_id.len() < 32 {
+                return Err(Error::InvalidLockArgs);
+            }
+            let (salt, committee_id) = committee_id.split_at(32);
+            (Some(Data::from_slice(salt)), committee_id)
+        } else {
+            (None, committee_id)
+        };
+        let (version_offset, committee_id) = if flags & LOCK_FLAG_VERSIONED != 0 {
+            let (version_offset, committee_id) =
+                read_u32(committee_id).ok_or(Error::InvalidLockArgs)?;
+            (Some(version_offset), committee_id)
+        } else {
+            (None, committee_id)
+        };
+        let (min_confirmations, committee_id) = if flags & LOCK_FLAG_MIN_CONFIRMATIONS != 0 {
+            let (min_confirmations, committee_id) =
+                read_u32(committee_id).ok_or(Error::InvalidLockArgs)?;
+            (Some(min_confirmations as u64), committee_id)
+        } else {
+            (None, committee_id)
+        };
+        let (governance_type_hash, committee_id) = if ext_flags & LOCK_EXT_FLAG_GOVERNANCE_CELL != 0
+        {
+            if committee_id.len() < 32 || min_confirmations.is_some() {
+                return Err(Error::InvalidLockArgs);
+            }
+            let (type_hash, committee_id) = committee_id.split_at(32);
+            (Some(Byte32::from_slice(type_hash).unwrap()), committee_id)
+        } else {
+            (None, committee_id)
+        };
+        let layout = ProofLayout {
+            root_index: ext_flags & LOCK_EXT_FLAG_ROOT_INDEX != 0,
+            input_type: ext_flags & LOCK_EXT_FLAG_INPUT_TYPE_PROOF != 0,
+            co_proofs: ext_flags & LOCK_EXT_FLAG_THRESHOLD_PROOFS != 0,
+            format_byte: ext_flags & LOCK_EXT_FLAG_PROOF_FORMAT != 0,
+        };
+        if layout.input_type && layout.co_proofs {
+            return Err(Error::InvalidLockArgs);
+        }
+        if flags & LOCK_FLAG_SMT != 0
+            && (layout.input_type || layout.co_proofs || layout.format_byte)
+        {
+            return Err(Error::InvalidLockArgs);
+        }
+        Ok(Self {
+            flags,
+            ext_flags,
+            layout,
+            salt,
+            version_offset,
+            min_confirmations,
+            governance_type_hash,
+            committee_id,
+        })
+    }
+}
+
+/// Upgrade of an input cell into the output cell at `output_index`, as
+/// declared by its leaf.
+struct Upgrade {
+    output_index: usize,
+    commit_output_index: bool,
+    /// Capacity the output cell may hold less than the input cell
+    released: u64,
+    retype: bool,
+}
+
+fn hash_upgrade(
+    hasher: &mut Blake2b,
+    rtx: &ResolvedTransaction,
+    args: &LockArgs,
+    input: &GroupInput,
+    upgrade: &Upgrade,
+) -> Result<(), Error> {
+    let Upgrade {
+        output_index,
+        commit_output_index,
+        released,
+        retype,
+    } = *upgrade;
+    let tx = &rtx.transaction;
+    let output = tx.outputs().get(output_index).unwrap();
+    let output_data = tx.outputs_data().get(output_index).unwrap().raw_data();
+    let input_data = cell_data(input.cell);
+
+    let input_capacity: u64 = input.cell.cell_output.capacity().unpack();
+    let output_capacity: u64 = output.capacity().unpack();
+    if output_capacity < input_capacity.saturating_sub(released) {
+        return Err(Error::CapacityLowered);
+    }
+
+    if let Some(input_type) = input.cell.cell_output.type_().to_opt().filter(|_| !retype) {
+        let output_type_hash = output.type_().to_opt().map(|s| s.calc_script_hash());
+        if output_type_hash != Some(input_type.calc_script_hash()) {
+            return Err(Error::TypeScriptChanged);
+        }
+    }
+
+    if args.flags & LOCK_FLAG_PRESERVE_STRUCTURE != 0
+        && (input_capacity != output_capacity || input_data.len() != output_data.len())
+    {
+        return Err(Error::StructureNotPreserved);
+    }
+
+    if let Some(version_offset) = args.version_offset {
+        let old_version = load_version(version_offset, input_data)?;
+        let new_version = load_version(version_offset, &output_data)?;
+        if new_version <= old_version {
+            return Err(Error::VersionNotIncreased);
+        }
+    }
+
+    let output_data_hash = blake2b_256(&output_data);
+    if args.ext_flags & LOCK_EXT_FLAG_CODE_DEP != 0 {
+        let code_hash = Byte32::from_slice(&output_data).map_err(|_| Error::MissingCodeDep)?;
+        if !rtx
+            .resolved_cell_deps
+            .iter()
+            .any(|dep| CellOutput::calc_data_hash(cell_data(dep)) == code_hash)
+        {
+            return Err(Error::MissingCodeDep);
+        }
+        hasher.update(code_hash.as_slice());
+    } else if args.flags & LOCK_FLAG_PATCH != 0 {
+        let patch = input
+            .witness
+            .as_ref()
+            .filter(|witness| WitnessArgsReader::verify(witness, false).is_ok())
+            .and_then(|witness| {
+                WitnessArgs::new_unchecked(witness.clone())
+                    .output_type()
+                    .to_opt()
+            })
+            .ok_or(Error::MissingPatch)?
+            .raw_data();
+        if hash_patched_data(&patch, input_data)? != output_data_hash {
+            return Err(Error::PatchMismatch);
+        }
+        hasher.update(&blake2b_256(&patch));
+    } else {
+        hasher.update(&output_data_hash);
+    }
+    hasher.update(output.as_slice());
+    if commit_output_index {
+        hasher.update(&(output_index as u32).to_le_bytes());
+    }
+    Ok(())
+}
+
+/// Requires both cells of a reconfiguration to start with the code whose
+/// length witness output_type carries.
+fn verify_code_unchanged(
+    rtx: &ResolvedTransaction,
+    input: &GroupInput,
+    output_index: usize,
+) -> Result<(), Error> {
+    let code_length = input
+        .witness
+        .as_ref()
+        .filter(|witness| WitnessArgsReader::verify(witness, false).is_ok())
+        .and_then(|witness| {
+            WitnessArgs::new_unchecked(witness.clone())
+                .output_type()
+                .to_opt()
+        })
+        .and_then(|output_type| match read_u32(&output_type.raw_data()) {
+            Some((length, rest)) if rest.is_empty() => Some(length),
+            _ => None,
+        })
+        .ok_or(Error::InvalidWitness)?;
+    let input_data = cell_data(input.cell);
+    let output_data = rtx
+        .transaction
+        .outputs_data()
+        .get(output_index)
+        .unwrap()
+        .raw_data();
+    match (
+        input_data.get(..code_length),
+        output_data.get(..code_length),
+    ) {
+        (Some(input_code), Some(output_code)) if input_code == output_code => Ok(()),
+        _ => Err(Error::CodeChanged),
+    }
+}
+
+/// Reads the most capacity a release leaf frees and the lock hash receiving
+/// it from witness output_type.
+fn load_release(input: &GroupInput) -> Result<(u64, Byte32), Error> {
+    let output_type = input
+        .witness
+        .as_ref()
+        .filter(|witness| WitnessArgsReader::verify(witness, false).is_ok())
+        .and_then(|witness| {
+            WitnessArgs::new_unchecked(witness.clone())
+                .output_type()
+                .to_opt()
+        })
+        .map(|output_type| output_type.raw_data())
+        .filter(|output_type| output_type.len() == 8 + 32)
+        .ok_or(Error::InvalidWitness)?;
+    let released = u64::from_le_bytes(output_type[..8].try_into().unwrap());
+    Ok((released, Byte32::from_slice(&output_type[8..]).unwrap()))
+}
+
+fn load_header<DL: HeaderProvider>(
+    rtx: &ResolvedTransaction,
+    loader: &DL,
+    header_index: u32,
+) -> Result<HeaderView, Error> {
+    rtx.transaction
+        .header_deps()
+        .get(header_index as usize)
+        .and_then(|hash| loader.get_header(&hash))
+        .ok_or(Error::MissingExtension)
+}
+
+/// Loads the extension a proof naming `header_index` is verified against,
+/// from the header dep, the governance cell dep, or the governance cell a
+/// rotation consumes. Only the governance cell dep may carry the kill switch.
+fn load_extension<DL: ExtensionProvider>(
+    rtx: &ResolvedTransaction,
+    loader: &DL,
+    args: &LockArgs,
+    inputs: &[GroupInput],
+    rotation: bool,
+    header_index: u32,
+) -> Result<Extension, Error> {
+    let parse = |data: &[u8]| Extension::parse(data).ok_or(Error::InvalidExtension);
+    match &args.governance_type_hash {
+        Some(_) if rotation => parse(cell_data(inputs[0].cell)),
+        Some(type_hash) => {
+            let cell = rtx
+                .resolved_cell_deps
+                .get(header_index as usize)
+                .filter(|dep| {
+                    dep.cell_output
+                        .type_()
+                        .to_opt()
+                        .map(|type_script| type_script.calc_script_hash())
+                        == Some(type_hash.clone())
+                })
+                .ok_or(Error::GovernanceCellMismatch)?;
+            let extension = parse(cell_data(cell))?;
+            if extension.kill_switch == Some(true) {
+                return Err(Error::Paused);
+            }
+            Ok(extension)
+        }
+        None => {
+            let extension = rtx
+                .transaction
+                .header_deps()
+                .get(header_index as usize)
+                .and_then(|hash| loader.get_block_extension(&hash))
+                .ok_or(Error::MissingExtension)?;
+            let extension = parse(&extension.raw_data())?;
+            if extension.kill_switch.is_some() {
+                return Err(Error::InvalidExtension);
+            }
+            Ok(extension)
+        }
+    }
+}
+
+/// Runs the checks of zero lock for the script group of `lock` in `rtx`,
+/// loading header deps and their extensions from `loader`. Resolved cells
+/// must carry their data in memory. A lock no input cell uses has no script
+/// group to run, which passes.
+pub fn verify<DL>(rtx: &ResolvedTransaction, lock: &Script, loader: &DL) -> Result<(), Error>
+where
+    DL: HeaderProvider + ExtensionProvider,
+{
+    let tx = &rtx.transaction;
+    let script_hash = lock.calc_script_hash();
+    let inputs: Vec<GroupInput> = rtx
+        .resolved_inputs
+        .iter()
+        .enumerate()
+        .filter(|(_, cell)| cell.cell_output.lock().calc_script_hash() == script_hash)
+        .map(|(i, cell)| GroupInput {
+            cell,
+            since: tx.inputs().get(i).unwrap().since().unpack(),
+            witness: tx.witnesses().get(i).map(|witness| witness.raw_data()),
+        })
+        .collect();
+    if inputs.is_empty() {
+        return Ok(());
+    }
+
+    let code = code_data_hash(rtx, lock);
+    let raw_args = lock.args().raw_data();
+    let args = LockArgs::parse(&raw_args)?;
+    let smt_proof = if args.flags & LOCK_FLAG_SMT != 0 {
+        let witness = inputs[0].witness.clone().ok_or(Error::MissingProof)?;
+        if WitnessArgsReader::verify(&witness, false).is_err() {
+            return Err(Error::InvalidWitness);
+        }
+        let witness_lock = WitnessArgs::new_unchecked(witness)
+            .lock()
+            .to_opt()
+            .map(|lock| lock.raw_data())
+            .filter(|lock| !lock.is_empty())
+            .ok_or(Error::MissingProof)?;
+        Some(SmtProof::parse(&witness_lock).ok_or(Error::InvalidProof)?)
+    } else {
+        None
+    };
+    // A proof covering fewer input cells is followed by another one in
+    // witness of the first input cell it leaves out
+    let mut leaf_tags = Vec::with_capacity(inputs.len());
+    let mut proof_starts = vec![0];
+    let mut header_indices = Vec::with_capacity(1);
+    match &smt_proof {
+        Some(smt_proof) => {
+            leaf_tags.push(smt_proof.leaf_tag);
+            header_indices.push(smt_proof.header_index);
+        }
+        None if !has_proof(inputs[0].witness.as_ref(), args.layout) => {
+            return Err(Error::MissingProof)
+        }
+        None => loop {
+            let start = *proof_starts.last().expect("first proof");
+            let proof = read_witness(inputs[start].witness.clone(), true, args.layout, None)?;
+            leaf_tags.extend_from_slice(proof.leaf_tags().ok_or(Error::InvalidProof)?);
+            header_indices.push(proof.header_index().ok_or(Error::InvalidProof)?);
+            let next = leaf_tags.len();
+            if next >= inputs.len() || !has_proof(inputs[next].witness.as_ref(), args.layout) {
+                break;
+            }
+            if args.layout.co_proofs {
+                return Err(Error::InvalidProof);
+            }
+            proof_starts.push(next);
+        },
+    }
+    let indexed_outputs: Vec<bool> = leaf_tags
+        .iter()
+        .map(|tag| tag & LEAF_TAG_FLAG_OUTPUT_INDEX != 0)
+        .collect();
+    let retyped_outputs: Vec<bool> = leaf_tags
+        .iter()
+        .map(|tag| tag & LEAF_TAG_FLAG_RETYPE != 0)
+        .collect();
+    let leaf_tags = leaf_tags
+        .iter()
+        .map(|tag| {
+            let leaf_tag = match tag & !(LEAF_TAG_FLAG_OUTPUT_INDEX | LEAF_TAG_FLAG_RETYPE) {
+                1 => LeafTag::Upgrade,
+                2 if tag & LEAF_TAG_FLAG_OUTPUT_INDEX == 0 => LeafTag::Deletion,
+                4 => LeafTag::Migration,
+                5 => LeafTag::Rotation,
+                6 => LeafTag::Freeze,
+                7 => LeafTag::Reconfiguration,
+                8 => LeafTag::Release,
+                _ => return Err(Error::UnknownLeafTag),
+            };
+            if tag & LEAF_TAG_FLAG_RETYPE != 0 && leaf_tag != LeafTag::Upgrade {
+                return Err(Error::UnknownLeafTag);
+            }
+            Ok(leaf_tag)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if leaf_tags.contains(&LeafTag::Reconfiguration)
+        && (args.flags & LOCK_FLAG_PATCH != 0 || args.ext_flags & LOCK_EXT_FLAG_CODE_DEP != 0)
+    {
+        return Err(Error::UnknownLeafTag);
+    }
+    if leaf_tags.contains(&LeafTag::Release) && args.flags & LOCK_FLAG_PATCH != 0 {
+        return Err(Error::UnknownLeafTag);
+    }
+    if leaf_tags.len() != inputs.len() {
+        return Err(Error::LeafCountMismatch);
+    }
+    if args.governance_type_hash.is_none() && (args.layout.co_proofs || header_indices.len() > 1) {
+        let mut named = header_indices.clone();
+        named.sort_unstable();
+        named.dedup();
+        if named.len() != header_indices.len()
+            || named
+                .iter()
+                .any(|header_index| *header_index as usize >= tx.header_deps().len())
+        {
+            return Err(Error::ProofHeaderCountMismatch);
+        }
+    }
+    let rotation = leaf_tags.contains(&LeafTag::Rotation);
+    if rotation {
+        let type_hash = inputs[0]
+            .cell
+            .cell_output
+            .type_()
+            .to_opt()
+            .map(|script| script.calc_script_hash());
+        if inputs.len() != 1
+            || args.governance_type_hash.is_none()
+            || type_hash != args.governance_type_hash
+        {
+            return Err(Error::InvalidRotation);
+        }
+    }
+    // The kill switch halts every upgrade before any leaf is hashed
+    let extensions = header_indices
+        .iter()
+        .map(|header_index| load_extension(rtx, loader, &args, &inputs, rotation, *header_index))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let upgrades = leaf_tags
+        .iter()
+        .filter(|tag| {
+            matches!(
+                tag,
+                LeafTag::Upgrade
+                    | LeafTag::Rotation
+                    | LeafTag::Freeze
+                    | LeafTag::Reconfiguration
+                    | LeafTag::Release
+            )
+        })
+        .count();
+    let mut output_indices = Vec::with_capacity(upgrades);
+    for (i, output) in tx.outputs().into_iter().enumerate() {
+        let output_lock = output.lock();
+        let is_successor = code.is_some()
+            && output_lock.args().as_slice() == lock.args().as_slice()
+            && code_data_hash(rtx, &output_lock) == code;
+        if output_lock.calc_script_hash() == script_hash || is_successor {
+            if output_indices.len() >= upgrades {
+                return Err(Error::ExtraOutput);
+            }
+            output_indices.push(i);
+        }
+    }
+    if output_indices.is_empty() && upgrades > 0 {
+        return Err(Error::NoOutput);
+    }
+    if output_indices.len() < upgrades {
+        return Err(Error::MissingOutput);
+    }
+
+    let mut leaves = Vec::with_capacity(inputs.len());
+    let mut witness_proofs = Vec::with_capacity(proof_starts.len());
+    let mut claimed = output_indices.clone();
+    let mut output_indices = output_indices.into_iter();
+    let mut releases: Vec<(Byte32, u64)> = Vec::new();
+    for (input_index, (((input, tag), indexed_output), retyped_output)) in inputs
+        .iter()
+        .zip(leaf_tags)
+        .zip(indexed_outputs)
+        .zip(retyped_outputs)
+        .enumerate()
+    {
+        let mut hasher = new_blake2b();
+        let mut declared_tag = tag as u8;
+        if indexed_output {
+            declared_tag |= LEAF_TAG_FLAG_OUTPUT_INDEX;
+        }
+        if retyped_output {
+            declared_tag |= LEAF_TAG_FLAG_RETYPE;
+        }
+        hasher.update(&[declared_tag]);
+        hasher.update(input.cell.out_point.as_slice());
+        match tag {
+            LeafTag::Upgrade
+            | LeafTag::Rotation
+            | LeafTag::Freeze
+            | LeafTag::Reconfiguration
+            | LeafTag::Release => {
+                let output_index = output_indices.next().expect("output cell for upgrade");
+                if tag == LeafTag::Reconfiguration {
+                    hasher.update(&blake2b_256(cell_data(input.cell)));
+                    verify_code_unchanged(rtx, input, output_index)?;
+                }
+                let release = if tag == LeafTag::Release {
+                    Some(load_release(input)?)
+                } else {
+                    None
+                };
+                let upgrade = Upgrade {
+                    output_index,
+                    commit_output_index: indexed_output,
+                    released: release.as_ref().map_or(0, |(released, _)| *released),
+                    retype: retyped_output,
+                };
+                hash_upgrade(&mut hasher, rtx, &args, input, &upgrade)?;
+                if let Some((_, lock_hash)) = release {
+                    let input_capacity: u64 = input.cell.cell_output.capacity().unpack();
+                    let output_capacity: u64 =
+                        tx.outputs().get(output_index).unwrap().capacity().unpack();
+                    let released = input_capacity.saturating_sub(output_capacity);
+                    match releases.iter_mut().find(|(hash, _)| *hash == lock_hash) {
+                        Some((_, total)) => *total = total.saturating_add(released),
+                        None => releases.push((lock_hash, released)),
+                    }
+                }
+                let output_data = tx.outputs_data().get(output_index).unwrap().raw_data();
+                let same_data = *cell_data(input.cell) == output_data;
+                let frozen = cell_data(input.cell).starts_with(FROZEN_MAGIC);
+                if frozen && (tag != LeafTag::Upgrade || !same_data) {
+                    return Err(Error::Frozen);
+                }
+                if tag == LeafTag::Freeze && !output_data.starts_with(FROZEN_MAGIC) {
+                    return Err(Error::InvalidFreeze);
+                }
+                if args.ext_flags & LOCK_EXT_FLAG_ALLOW_SAME_DATA == 0 && same_data && !frozen {
+                    return Err(Error::DataUnchanged);
+                }
+                if tag == LeafTag::Rotation && Extension::parse(&output_data).is_none() {
+                    return Err(Error::InvalidExtension);
+                }
+            }
+            LeafTag::Migration => {
+                if cell_data(input.cell).starts_with(FROZEN_MAGIC) {
+                    return Err(Error::Frozen);
+                }
+                let type_hash = input
+                    .cell
+                    .cell_output
+                    .type_()
+                    .to_opt()
+                    .map(|script| script.calc_script_hash())
+                    .ok_or(Error::MissingMigrationOutput)?;
+                let output_index = tx
+                    .outputs()
+                    .into_iter()
+                    .enumerate()
+                    .find_map(|(i, output)| {
+                        let output_type_hash = output
+                            .type_()
+                            .to_opt()
+                            .map(|script| script.calc_script_hash());
+                        (output_type_hash.as_ref() == Some(&type_hash) && !claimed.contains(&i))
+                            .then_some(i)
+                    })
+                    .ok_or(Error::MissingMigrationOutput)?;
+                let proof_index = proof_starts
+                    .iter()
+                    .filter(|start| **start <= input_index)
+                    .count()
+                    - 1;
+                if let Some(code_hashes) = &extensions[proof_index].successor_code_hashes {
+                    let lock = tx.outputs().get(output_index).unwrap().lock();
+                    if !code_hashes
+                        .iter()
+                        .any(|code_hash| &code_hash[..] == lock.code_hash().as_slice())
+                    {
+                        return Err(Error::SuccessorNotWhitelisted);
+                    }
+                }
+                claimed.push(output_index);
+                let upgrade = Upgrade {
+                    output_index,
+                    commit_output_index: indexed_output,
+                    released: 0,
+                    retype: false,
+                };
+                hash_upgrade(&mut hasher, rtx, &args, input, &upgrade)?;
+            }
+            LeafTag::Deletion => {
+                hasher.update(&blake2b_256(cell_data(input.cell)));
+                let type_hash = input
+                    .cell
+                    .cell_output
+                    .type_()
+                    .to_opt()
+                    .map(|script| script.calc_script_hash())
+                    .unwrap_or_else(Byte32::zero);
+                hasher.update(type_hash.as_slice());
+            }
+        }
+
+        if smt_proof.is_some() {
+            read_witness(
+                input.witness.clone(),
+                false,
+                ProofLayout::default(),
+                Some(&mut hasher),
+            )?;
+        } else {
+            let layout = ProofLayout {
+                co_proofs: args.layout.co_proofs && input_index == 0,
+                ..args.layout
+            };
+            let proof = read_witness(input.witness.clone(), true, layout, Some(&mut hasher))?;
+            if proof_starts.contains(&input_index) {
+                witness_proofs.push(proof.build::<Blake2bHash>().ok_or(Error::InvalidProof)?);
+            } else if !proof.is_empty() {
+                return Err(Error::InvalidProof);
+            }
+        }
+
+        if args.flags & LOCK_FLAG_ACTIVATION != 0 {
+            if input.since == 0 {
+                return Err(Error::MissingActivation);
+            }
+            hasher.update(&input.since.to_le_bytes());
+        }
+
+        let mut leaf = [0u8; 32];
+        hasher.finalize(&mut leaf[..]);
+        leaves.push(Data::new(leaf));
+    }
+    if leaves
+        .iter()
+        .enumerate()
+        .any(|(i, leaf)| leaves[..i].contains(leaf))
+    {
+        return Err(Error::DuplicateLeaf);
+    }
+    for (lock_hash, released) in &releases {
+        let received = tx
+            .outputs()
+            .into_iter()
+            .enumerate()
+            .filter(|(i, output)| {
+                !claimed.contains(i) && output.lock().calc_script_hash() == *lock_hash
+            })
+            .fold(0u64, |received, (_, output)| {
+                let capacity: u64 = output.capacity().unpack();
+                received.saturating_add(capacity)
+            });
+        if received < *released {
+            return Err(Error::ReleaseNotReceived);
+        }
+    }
+    let proofs = match &smt_proof {
+        Some(smt_proof) => vec![WitnessProof {
+            header_index: smt_proof.header_index,
+            root_index: None,
+            merkle_proof: None,
+            leaf_hash: None,
+        }],
+        None => witness_proofs,
+    };
+    for (proof_index, (proof, extension)) in proofs.into_iter().zip(extensions).enumerate() {
+        let WitnessProof {
+            header_index,
+            root_index,
+            merkle_proof,
+            leaf_hash: supplied_leaf,
+        } = proof;
+        let start = proof_starts[proof_index];
+        let end = proof_starts
+            .get(proof_index + 1)
+            .copied()
+            .unwrap_or(inputs.len());
+        // Proven node indices pair with the leaves sorted by hash
+        let first_leaf = &leaves[start];
+        let mut tree_leaves = leaves[start..end].to_vec();
+        tree_leaves.sort();
+        let leaves = &tree_leaves[..];
+
+        if extension.min_epochs > 0 {
+            if args.governance_type_hash.is_some() {
+                return Err(Error::InvalidExtension);
+            }
+            let header = load_header(rtx, loader, header_index)?;
+            let header_epoch_number = header.epoch().number();
+            for input in &inputs[start..end] {
+                if input.since & SINCE_FLAGS_MASK != SINCE_FLAGS_ABSOLUTE_EPOCH {
+                    return Err(Error::SinceNotEpoch);
+                }
+                if input.since & EPOCH_NUMBER_MASK < header_epoch_number + extension.min_epochs {
+                    return Err(Error::NotEnoughEpochs);
+                }
+            }
+        }
+
+        if let Some(min_confirmations) = args.min_confirmations {
+            let header = load_header(rtx, loader, header_index)?;
+            for input in &inputs[start..end] {
+                if input.since & SINCE_FLAGS_MASK != 0 {
+                    return Err(Error::SinceNotBlockNumber);
+                }
+                if input.since < header.number().saturating_add(min_confirmations) {
+                    return Err(Error::NotEnoughConfirmations);
+                }
+            }
+        }
+
+        if let Some(supplied_leaf) = supplied_leaf {
+            if supplied_leaf != *first_leaf {
+                return Err(Error::LeafHashMismatch);
+            }
+        }
+
+        let merkle_root = match (&smt_proof, root_index) {
+            (Some(_), _) => extension.smt_root,
+            (None, Some(root_index)) => extension.root_at(root_index as usize),
+            (None, None) => extension.root_for(args.committee_id),
+        }
+        .ok_or(Error::InvalidExtension)?;
+        if merkle_root == [0u8; 32] {
+            return Err(Error::EmptyRoot);
+        }
+
+        let actual_root = match (&smt_proof, merkle_proof) {
+            (Some(smt_proof), _) => {
+                let key = hash_bytes(&CellOutput::calc_data_hash(cell_data(inputs[0].cell)));
+                Data::new(smt_proof.root(&key, &first_leaf.0))
+            }
+            (None, Some(merkle_proof)) => match extension.merge_hash {
+                MERGE_HASH_SHA256 => MerkleProof::<Data, Sha256Hash>::new(
+                    merkle_proof.indices().to_vec(),
+                    merkle_proof.lemmas().to_vec(),
+                )
+                .root(leaves),
+                _ => merkle_proof.root(leaves),
+            }
+            .ok_or(Error::InvalidProof)?,
+            (None, None) => first_leaf.clone(),
+        };
+        let actual_root = match &args.salt {
+            Some(salt) => Blake2bHash::merge(&actual_root, salt),
+            None => actual_root,
+        };
+        if actual_root.0 != merkle_root {
+            return Err(Error::RootMismatch);
+        }
+
+        let co_proofs = match &inputs[0].witness {
+            Some(witness)
+                if args.layout.co_proofs && WitnessArgsReader::verify(witness, false).is_ok() =>
+            {
+                match WitnessArgs::new_unchecked(witness.clone())
+                    .input_type()
+                    .to_opt()
+                {
+                    Some(input_type) => {
+                        CoProof::parse_all(&input_type.raw_data()).ok_or(Error::InvalidProof)?
+                    }
+                    None => Vec::new(),
+                }
+            }
+            _ => Vec::new(),
+        };
+        let mut proven_roots = vec![merkle_root];
+        for co_proof in co_proofs {
+            let root = extension
+                .root_at(co_proof.root_index as usize)
+                .ok_or(Error::InvalidExtension)?;
+            if root == [0u8; 32] {
+                return Err(Error::EmptyRoot);
+            }
+            if proven_roots.contains(&root) {
+                return Err(Error::DuplicateRoot);
+            }
+            let actual_root = match extension.merge_hash {
+                MERGE_HASH_SHA256 => co_proof.root::<Sha256Hash>(leaves),
+                _ => co_proof.root::<Blake2bHash>(leaves),
+            }
+            .ok_or(Error::InvalidProof)?;
+            let actual_root = match &args.salt {
+                Some(salt) => Blake2bHash::merge(&actual_root, salt),
+                None => actual_root,
+            };
+            if actual_root.0 != root {
+                return Err(Error::RootMismatch);
+            }
+            proven_roots.push(root);
+        }
+        if proven_roots.len() < extension.threshold as usize {
+            return Err(Error::ThresholdNotMet);
+        }
+    }
+
+    Ok(())
+}